@@ -0,0 +1,67 @@
+use crate::cmd_manager::CmdManager;
+use crate::events::EventEmitter;
+use crate::metrics::Metrics;
+use crate::rpc::Rpc;
+use crate::service::Context;
+use crate::settings::SettingsManager;
+use crate::tasks::TaskManager;
+
+/// Sets up the standard core services, so `main` only adds the app services.
+pub struct AminaCore;
+
+impl AminaCore {
+
+    /// New context with the core services initialized, see `init_core_services`.
+    pub fn bootstrap() -> Context {
+        let context = Context::new();
+        Self::init_core_services(&context);
+        context
+    }
+
+    /// Initializes `TaskManager`, `EventEmitter`, `Rpc`, `Metrics`, `CmdManager` and
+    /// `SettingsManager` in dependency order. Services the context already has are kept.
+    pub fn init_core_services(context: &Context) {
+        macro_rules! init_missing {
+            ($($service:ty),*) => {
+                $(
+                    if !context.has_service::<$service>() {
+                        context.init_service::<$service>();
+                    }
+                )*
+            };
+        }
+        init_missing!(TaskManager, EventEmitter, Rpc, Metrics, CmdManager, SettingsManager);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bootstrap::AminaCore;
+    use crate::service::{Context, LifecycleState};
+    use crate::tasks::TaskManager;
+
+    #[test]
+    fn test_bootstrap() {
+        let context = AminaCore::bootstrap();
+        assert_eq!(context.service_names(), vec![
+            "amina_core::cmd_manager::CmdManager",
+            "amina_core::events::EventEmitter",
+            "amina_core::events::EventEmitterGate",
+            "amina_core::metrics::Metrics",
+            "amina_core::rpc::Rpc",
+            "amina_core::rpc::RpcGate",
+            "amina_core::settings::SettingsManager",
+            "amina_core::tasks::TaskManager",
+        ]);
+        context.start().unwrap();
+        context.stop();
+        assert_eq!(context.state(), LifecycleState::Stopped);
+
+        // Services added before are kept
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        AminaCore::init_core_services(&context);
+        assert_eq!(context.get_service_states().len(), 8);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+
+use crate::cmd_manager::{ArgType, ArgValue, ArgsList, CmdDescription};
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParseError {
+    #[error("Unterminated quote in the value of argument '{0}'")]
+    UnterminatedQuote(String),
+    #[error("Expected 'name:value' but found '{0}'")]
+    MissingSeparator(String),
+    #[error("Missing value of argument '{0}'")]
+    MissingValue(String),
+    #[error("Invalid value '{value}' of argument '{arg}', expected {}", describe_type(.expected))]
+    InvalidValue {
+        arg: String,
+        value: String,
+        expected: ArgType,
+    },
+    #[error("Invalid value '{value}' of argument '{arg}', expected one of {}", .options.join(", "))]
+    NotAnOption {
+        arg: String,
+        value: String,
+        options: Vec<String>,
+    },
+    #[error("Argument '{0}' not found")]
+    MissingArgument(String),
+}
+
+fn describe_type(arg_type: &ArgType) -> &'static str {
+    match arg_type {
+        ArgType::U64 => "a non-negative integer",
+        ArgType::I64 => "an integer",
+        ArgType::F64 => "a number",
+        ArgType::BOOL => "'y' or 'n'",
+        ArgType::STRING => "a string",
+        ArgType::STRING_LIST => "a comma separated list",
+    }
+}
+
+/// Splits a command line into the command name and the rest, the arguments.
+pub fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    match line.find(' ') {
+        Some(args_start) => (&line[..args_start], &line[(args_start + 1)..]),
+        None => (line, ""),
+    }
+}
+
+/// Parses the arguments of a command line like `library.list limit:20 filter:'two words'`
+/// against the description of its command, the command name itself isn't checked.
+///
+/// A repeated argument takes its last value, except `STRING_LIST` which collects all of them.
+/// Omitted arguments get their default. Arguments the command doesn't have are ignored.
+pub fn parse_command_line(line: &str, description: &CmdDescription) -> Result<ArgsList, ParseError> {
+    let (_, args_str) = split_command(line);
+    let raw_args = split_args(args_str)?;
+    let mut args_list = ArgsList::new();
+
+    let mut arg_descriptions: Vec<_> = description.args.values().collect();
+    arg_descriptions.sort_by(|a, b| a.call_name.cmp(&b.call_name));
+    for arg in arg_descriptions {
+        let values = match raw_args.get(&arg.call_name) {
+            Some(values) => values,
+            None => {
+                if let Some(default) = &arg.default {
+                    args_list.put_value(&arg.call_name, default.clone());
+                } else if arg.required {
+                    return Err(ParseError::MissingArgument(arg.call_name.clone()));
+                }
+                continue;
+            },
+        };
+        let value = if arg.arg_type == ArgType::STRING_LIST {
+            parse_value(&arg.call_name, &values.join(","), &arg.arg_type)?
+        } else {
+            let raw_value = values.last().unwrap();
+            if !arg.options.is_empty() && !arg.options.contains(raw_value) {
+                return Err(ParseError::NotAnOption {
+                    arg: arg.call_name.clone(),
+                    value: raw_value.clone(),
+                    options: arg.options.clone(),
+                });
+            }
+            parse_value(&arg.call_name, raw_value, &arg.arg_type)?
+        };
+        args_list.put_value(&arg.call_name, value);
+    }
+
+    Ok(args_list)
+}
+
+/// Parses a single value of argument `arg` as typed on the CLI: `BOOL` is `y` or `n`,
+/// and a `STRING_LIST` is comma separated with empty items dropped.
+pub fn parse_value(arg: &str, value: &str, arg_type: &ArgType) -> Result<ArgValue, ParseError> {
+    let invalid = || ParseError::InvalidValue {
+        arg: arg.to_string(),
+        value: value.to_string(),
+        expected: arg_type.clone(),
+    };
+    match arg_type {
+        ArgType::U64 => value.parse().map(ArgValue::U64).map_err(|_| invalid()),
+        ArgType::I64 => value.parse().map(ArgValue::I64).map_err(|_| invalid()),
+        ArgType::F64 => value.parse().map(ArgValue::F64).map_err(|_| invalid()),
+        ArgType::BOOL => match value {
+            "y" => Ok(ArgValue::Bool(true)),
+            "n" => Ok(ArgValue::Bool(false)),
+            _ => Err(invalid()),
+        },
+        ArgType::STRING => Ok(ArgValue::String(value.to_string())),
+        ArgType::STRING_LIST => Ok(ArgValue::StringList(value.split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect())),
+    }
+}
+
+/// Values of every `name:value` pair of a command line, a name may repeat.
+///
+/// A value is either a bare word or quoted with `'` or `"`. Inside quotes `\` escapes
+/// the quote character and itself, any other backslash is kept as it is.
+pub fn split_args(args_str: &str) -> Result<HashMap<String, Vec<String>>, ParseError> {
+    let mut result = HashMap::new();
+    let mut chars = args_str.chars().peekable();
+
+    loop {
+        while chars.next_if_eq(&' ').is_some() {}
+        if chars.peek().is_none() {
+            return Ok(result);
+        }
+
+        let mut name = String::new();
+        loop {
+            match chars.next() {
+                Some(':') => break,
+                Some(' ') | None => return Err(ParseError::MissingSeparator(name)),
+                Some(c) => name.push(c),
+            }
+        }
+
+        while chars.next_if_eq(&' ').is_some() {}
+        let mut value = String::new();
+        match chars.next() {
+            None => return Err(ParseError::MissingValue(name)),
+            Some(quote) if quote == '\'' || quote == '"' => {
+                loop {
+                    match chars.next() {
+                        None => return Err(ParseError::UnterminatedQuote(name)),
+                        Some('\\') => match chars.next_if(|&c| c == quote || c == '\\') {
+                            Some(escaped) => value.push(escaped),
+                            None => value.push('\\'),
+                        },
+                        Some(c) if c == quote => break,
+                        Some(c) => value.push(c),
+                    }
+                }
+            },
+            Some(c) => {
+                value.push(c);
+                while let Some(c) = chars.next_if(|&c| c != ' ') {
+                    value.push(c);
+                }
+            },
+        }
+        result.entry(name).or_insert_with(Vec::new).push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd_manager::args_parser::{parse_command_line, split_args, split_command, ParseError};
+    use crate::cmd_manager::{ArgBuilder, ArgType, ArgValue, CmdBuilder, CmdDescription};
+
+    #[test]
+    fn test_split_args() {
+        let cases: Vec<(&str, Vec<(&str, &str)>)> = vec![
+            ("", vec![]),
+            ("  ", vec![]),
+            ("count:3", vec![("count", "3")]),
+            ("  count:3   enabled:y ", vec![("count", "3"), ("enabled", "y")]),
+            ("name: value", vec![("name", "value")]),
+            ("name:'two words'", vec![("name", "two words")]),
+            ("name:\"two words\"", vec![("name", "two words")]),
+            ("name:''", vec![("name", "")]),
+            ("name:\"it's here\"", vec![("name", "it's here")]),
+            ("name:'it\\'s here'", vec![("name", "it's here")]),
+            ("name:\"say \\\"hi\\\"\"", vec![("name", "say \"hi\"")]),
+            ("path:'C:\\dir\\file'", vec![("path", "C:\\dir\\file")]),
+            ("path:'ends with \\\\'", vec![("path", "ends with \\")]),
+            ("path:'a\\\"b'", vec![("path", "a\\\"b")]),
+            ("path:C:\\dir", vec![("path", "C:\\dir")]),
+            ("url:http://host:80/", vec![("url", "http://host:80/")]),
+            ("a:'x'b:y", vec![("a", "x"), ("b", "y")]),
+        ];
+        for (input, expected) in cases {
+            let args = split_args(input).unwrap_or_else(|err| panic!("{:?}: {}", input, err));
+            let values: usize = args.values().map(|values| values.len()).sum();
+            assert_eq!(values, expected.len(), "{:?}", input);
+            for (name, value) in expected {
+                assert_eq!(args[name], vec![value.to_string()], "{:?}", input);
+            }
+        }
+
+        let args = split_args("path:/a path:'/b c'").unwrap();
+        assert_eq!(args["path"], vec!["/a".to_string(), "/b c".to_string()]);
+    }
+
+    #[test]
+    fn test_split_args_errors() {
+        let cases = vec![
+            ("name:'open", ParseError::UnterminatedQuote("name".to_string())),
+            ("name:\"open'", ParseError::UnterminatedQuote("name".to_string())),
+            ("a:1 name:'escaped end\\'", ParseError::UnterminatedQuote("name".to_string())),
+            ("name", ParseError::MissingSeparator("name".to_string())),
+            ("a:1 name value", ParseError::MissingSeparator("name".to_string())),
+            ("name:", ParseError::MissingValue("name".to_string())),
+            ("name:   ", ParseError::MissingValue("name".to_string())),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(split_args(input), Err(expected), "{:?}", input);
+        }
+    }
+
+    fn player_play() -> CmdDescription {
+        CmdBuilder::new("player.play")
+            .add_arg(ArgBuilder::new("track", ArgType::STRING).build())
+            .add_arg(ArgBuilder::new("volume", ArgType::U64).add_default(ArgValue::U64(50)).build())
+            .add_arg(ArgBuilder::new("offset", ArgType::I64).optional().build())
+            .add_arg(ArgBuilder::new("speed", ArgType::F64).optional().build())
+            .add_arg(ArgBuilder::new("shuffle", ArgType::BOOL).optional().build())
+            .add_arg(ArgBuilder::new("mode", ArgType::STRING).add_options(&["once", "repeat"]).optional().build())
+            .add_arg(ArgBuilder::new("tags", ArgType::STRING_LIST).optional().build())
+            .build()
+    }
+
+    #[test]
+    fn test_parse_command_line() {
+        let description = player_play();
+        let cases: Vec<(&str, Vec<(&str, ArgValue)>)> = vec![
+            ("player.play track:intro", vec![
+                ("track", ArgValue::String("intro".to_string())),
+                ("volume", ArgValue::U64(50)),
+            ]),
+            ("play track:'two words' volume:80 offset:-5 speed:1.5 shuffle:y mode:repeat", vec![
+                ("track", ArgValue::String("two words".to_string())),
+                ("volume", ArgValue::U64(80)),
+                ("offset", ArgValue::I64(-5)),
+                ("speed", ArgValue::F64(1.5)),
+                ("shuffle", ArgValue::Bool(true)),
+                ("mode", ArgValue::String("repeat".to_string())),
+            ]),
+            ("player.play track:a track:b tags:rock,,pop tags:' jazz ' unknown:1", vec![
+                ("track", ArgValue::String("b".to_string())),
+                ("volume", ArgValue::U64(50)),
+                ("tags", ArgValue::StringList(vec!["rock".to_string(), "pop".to_string(), "jazz".to_string()])),
+            ]),
+        ];
+        for (line, expected) in cases {
+            let args = parse_command_line(line, &description).unwrap();
+            for (name, value) in expected.iter() {
+                assert_eq!(args.try_get_value(name).as_ref(), Some(value), "{:?} {}", line, name);
+            }
+            assert_eq!(args.render().len(), expected.len(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_parse_command_line_errors() {
+        let description = player_play();
+        let cases = vec![
+            ("player.play", "Argument 'track' not found"),
+            ("player.play track:a volume:-1", "Invalid value '-1' of argument 'volume', expected a non-negative integer"),
+            ("player.play track:a offset:x", "Invalid value 'x' of argument 'offset', expected an integer"),
+            ("player.play track:a speed:fast", "Invalid value 'fast' of argument 'speed', expected a number"),
+            ("player.play track:a shuffle:yes", "Invalid value 'yes' of argument 'shuffle', expected 'y' or 'n'"),
+            ("player.play track:a mode:twice", "Invalid value 'twice' of argument 'mode', expected one of once, repeat"),
+            ("player.play track:'a", "Unterminated quote in the value of argument 'track'"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_command_line(line, &description).unwrap_err().to_string(), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(split_command("help"), ("help", ""));
+        assert_eq!(split_command("  help cmd:ls"), ("help", "cmd:ls"));
+    }
+}
@@ -0,0 +1,82 @@
+use std::cell::RefCell;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+thread_local! {
+    static CURRENT_CALLER: RefCell<Option<CmdCaller>> = const { RefCell::new(None) };
+}
+
+/// Required to run a command, each level includes the ones before it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionLevel {
+    #[default]
+    User,
+    Admin,
+    /// The CLI and code running in the process.
+    Local,
+}
+
+impl fmt::Display for PermissionLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PermissionLevel::User => "user",
+            PermissionLevel::Admin => "admin",
+            PermissionLevel::Local => "local",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Who runs a command, checked against the command's permission level.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CmdCaller {
+    /// For logs, e.g. "cli" or the client address.
+    pub origin: String,
+    pub level: PermissionLevel,
+}
+
+impl CmdCaller {
+
+    pub fn new(origin: &str, level: PermissionLevel) -> Self {
+        Self {
+            origin: origin.to_string(),
+            level,
+        }
+    }
+
+    /// The CLI and code running in the process, allowed to run everything.
+    pub fn local(origin: &str) -> Self {
+        Self::new(origin, PermissionLevel::Local)
+    }
+
+}
+
+/// Caller set by the RPC gate for the call handled on this thread, and by `CmdManager::handle`
+/// while a command runs, so commands running other commands pass their caller on.
+pub fn current_cmd_caller() -> Option<CmdCaller> {
+    CURRENT_CALLER.with(|current| current.borrow().clone())
+}
+
+/// The current caller, or a `User` from `origin` when none is set, so calls that didn't
+/// come through a gate get the lowest level.
+pub fn current_cmd_caller_or_user(origin: &str) -> CmdCaller {
+    current_cmd_caller().unwrap_or_else(|| CmdCaller::new(origin, PermissionLevel::User))
+}
+
+/// Runs `f` with `caller` as the current caller, restoring the previous one afterwards.
+pub fn with_cmd_caller<R, F: FnOnce() -> R>(caller: Option<CmdCaller>, f: F) -> R {
+    struct Restore(Option<CmdCaller>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CURRENT_CALLER.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+
+    let previous = CURRENT_CALLER.with(|current| current.replace(caller));
+    let _restore = Restore(previous);
+    f()
+}
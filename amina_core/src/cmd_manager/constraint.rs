@@ -0,0 +1,81 @@
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Serialize, Serializer};
+
+use crate::cmd_manager::ArgValue;
+
+pub type ArgValidator = Arc<dyn Fn(&ArgValue) -> Result<(), String> + Send + Sync + 'static>;
+
+/// Check of an argument value run by `CmdManager::handle` before the handler.
+/// Serialized as its description, for UIs.
+#[derive(Clone)]
+pub struct ArgConstraint {
+    description: String,
+    validator: ArgValidator,
+}
+
+impl ArgConstraint {
+
+    /// `description` tells the user what is accepted, e.g. "an existing path".
+    pub fn new<F>(description: &str, validator: F) -> Self where
+        F: Fn(&ArgValue) -> Result<(), String> + Send + Sync + 'static
+    {
+        Self {
+            description: description.to_string(),
+            validator: Arc::new(validator),
+        }
+    }
+
+    /// Inclusive range of a numeric argument.
+    pub fn range(min: f64, max: f64) -> Self {
+        Self::new(&format!("between {} and {}", min, max), move |value| {
+            let number = match value {
+                ArgValue::U64(value) => *value as f64,
+                ArgValue::I64(value) => *value as f64,
+                ArgValue::F64(value) => *value,
+                _ => return Err("must be a number".to_string()),
+            };
+            if number < min || number > max {
+                return Err(format!("must be between {} and {}", min, max));
+            }
+            Ok(())
+        })
+    }
+
+    /// A string without only whitespace, or a list with at least one item.
+    pub fn non_empty() -> Self {
+        Self::new("not empty", |value| {
+            let empty = match value {
+                ArgValue::String(value) => value.trim().is_empty(),
+                ArgValue::StringList(value) => value.is_empty(),
+                _ => false,
+            };
+            if empty {
+                return Err("must not be empty".to_string());
+            }
+            Ok(())
+        })
+    }
+
+    pub fn get_description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn check(&self, value: &ArgValue) -> Result<(), String> {
+        (self.validator)(value)
+    }
+
+}
+
+impl fmt::Debug for ArgConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ArgConstraint").field(&self.description).finish()
+    }
+}
+
+impl Serialize for ArgConstraint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.description)
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use amina_core_derive::Event;
+
+use crate::cmd_manager::{ArgsList, CmdError, CmdResult};
+use crate::events::{Event, EventEmitter};
+use crate::service::Service;
+use crate::tasks::{TaskContext, TaskManager};
+
+/// Finished executions beyond this count are forgotten, oldest first.
+const MAX_EXECUTIONS: usize = 100;
+
+pub type AsyncCmdHandler = Arc<dyn Fn(&ArgsList, &TaskContext, &dyn ProgressSink) -> CmdResult + Send + Sync + 'static>;
+
+/// Receives progress of an asynchronous command.
+pub trait ProgressSink {
+    /// `percent` above 100 is reported as 100.
+    fn report(&self, percent: u8, message: &str);
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionState {
+    Running,
+    Completed,
+    Cancelled,
+}
+
+/// State of an asynchronous command, also emitted as an event on every change.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Event)]
+#[key = "amina_core.cmd_manager.execution_progress"]
+pub struct ExecutionStatus {
+    pub execution_id: String,
+    pub command: String,
+    pub percent: u8,
+    /// Last progress message, or the result message once finished.
+    pub message: String,
+    pub state: ExecutionState,
+    /// Set once the command returned.
+    pub result: Option<CmdResult>,
+}
+
+struct Execution {
+    status: Mutex<ExecutionStatus>,
+    /// Context of the running task and whether a cancel was requested, under one lock
+    /// so a cancel arriving before the task started isn't lost.
+    task: Mutex<(Option<Arc<TaskContext>>, bool)>,
+}
+
+struct ExecutionProgress {
+    execution: Arc<Execution>,
+    event_emitter: Service<EventEmitter>,
+}
+
+impl ProgressSink for ExecutionProgress {
+    fn report(&self, percent: u8, message: &str) {
+        let status = {
+            let mut status = self.execution.status.lock().unwrap();
+            status.percent = percent.min(100);
+            status.message = message.to_string();
+            status.clone()
+        };
+        self.event_emitter.emit_event(&status);
+    }
+}
+
+pub(crate) struct Executions {
+    next_id: AtomicU64,
+    executions: Mutex<BTreeMap<u64, Arc<Execution>>>,
+    task_manager: Service<TaskManager>,
+    event_emitter: Service<EventEmitter>,
+}
+
+impl Executions {
+
+    pub(crate) fn new(task_manager: Service<TaskManager>, event_emitter: Service<EventEmitter>) -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            executions: Mutex::new(BTreeMap::new()),
+            task_manager,
+            event_emitter,
+        }
+    }
+
+    /// Runs `handler` as a task and returns the execution id right away.
+    pub(crate) fn start(&self, command: &str, args: ArgsList, handler: AsyncCmdHandler) -> String {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let execution_id = format!("exec-{}", id);
+        let execution = Arc::new(Execution {
+            status: Mutex::new(ExecutionStatus {
+                execution_id: execution_id.clone(),
+                command: command.to_string(),
+                percent: 0,
+                message: String::new(),
+                state: ExecutionState::Running,
+                result: None,
+            }),
+            task: Mutex::new((None, false)),
+        });
+        self.insert(id, execution.clone());
+
+        let progress = ExecutionProgress {
+            execution,
+            event_emitter: self.event_emitter.clone(),
+        };
+        self.task_manager.run(move |task_context| {
+            {
+                let mut task = progress.execution.task.lock().unwrap();
+                if task.1 {
+                    task_context.stop();
+                }
+                task.0 = Some(task_context.clone());
+            }
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| handler(&args, &task_context, &progress)))
+                .unwrap_or_else(|_| CmdResult::error("Command panicked"));
+            let status = {
+                let mut status = progress.execution.status.lock().unwrap();
+                if task_context.is_interrupted() {
+                    status.state = ExecutionState::Cancelled;
+                } else {
+                    status.state = ExecutionState::Completed;
+                    status.percent = 100;
+                }
+                status.message = result.message.clone();
+                status.result = Some(result);
+                status.clone()
+            };
+            progress.event_emitter.emit_event(&status);
+        });
+        execution_id
+    }
+
+    fn insert(&self, id: u64, execution: Arc<Execution>) {
+        let mut executions = self.executions.lock().unwrap();
+        executions.insert(id, execution);
+        while executions.len() > MAX_EXECUTIONS {
+            let finished = executions.iter()
+                .find(|(_, execution)| execution.status.lock().unwrap().state != ExecutionState::Running)
+                .map(|(id, _)| *id);
+            match finished {
+                Some(finished) => executions.remove(&finished),
+                None => break,
+            };
+        }
+    }
+
+    fn get(&self, execution_id: &str) -> Result<Arc<Execution>, CmdError> {
+        execution_id.strip_prefix("exec-")
+            .and_then(|id| id.parse::<u64>().ok())
+            .and_then(|id| self.executions.lock().unwrap().get(&id).cloned())
+            .ok_or_else(|| CmdError::UnknownExecution(execution_id.to_string()))
+    }
+
+    pub(crate) fn status(&self, execution_id: &str) -> Result<ExecutionStatus, CmdError> {
+        Ok(self.get(execution_id)?.status.lock().unwrap().clone())
+    }
+
+    /// Sets the interrupt flag of the task, the handler decides when to stop.
+    /// Cancelling a finished execution does nothing.
+    pub(crate) fn cancel(&self, execution_id: &str) -> Result<(), CmdError> {
+        let execution = self.get(execution_id)?;
+        let mut task = execution.task.lock().unwrap();
+        task.1 = true;
+        if let Some(task_context) = &task.0 {
+            task_context.stop();
+        }
+        Ok(())
+    }
+
+}
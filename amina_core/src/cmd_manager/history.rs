@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::cmd_manager::ArgsList;
+
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+
+/// Settings key of the history size, followed by `SettingsManager` when there is a `CmdManager`.
+pub const HISTORY_SIZE_KEY: &str = "cmd_manager.history_size";
+
+/// A handled command as recorded by `CmdManager`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    /// Keeps counting when old entries are dropped, `history.replay` refers to it.
+    pub index: u64,
+    pub command: String,
+    /// Values as they would be typed on the CLI, sensitive ones are redacted.
+    pub args: BTreeMap<String, String>,
+    pub timestamp_ms: u64,
+    pub duration_ms: u64,
+    pub success: bool,
+}
+
+pub(crate) struct History {
+    capacity: usize,
+    next_index: u64,
+    /// Entries with the args that can be replayed, `None` when some were redacted.
+    entries: VecDeque<(HistoryEntry, Option<ArgsList>)>,
+    /// An argument whose lowercase name contains one of them is redacted.
+    sensitive_args: Vec<String>,
+}
+
+pub(crate) const REDACTED: &str = "<redacted>";
+
+impl History {
+
+    pub(crate) fn new() -> Self {
+        Self {
+            capacity: DEFAULT_HISTORY_SIZE,
+            next_index: 0,
+            entries: VecDeque::new(),
+            sensitive_args: vec!["password".to_string(), "secret".to_string(), "token".to_string()],
+        }
+    }
+
+    /// Lowering the capacity drops the oldest entries, 0 disables the history.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub(crate) fn set_sensitive_args(&mut self, sensitive_args: Vec<String>) {
+        self.sensitive_args = sensitive_args.into_iter().map(|name| name.to_lowercase()).collect();
+    }
+
+    fn is_sensitive(&self, arg_name: &str) -> bool {
+        let arg_name = arg_name.to_lowercase();
+        self.sensitive_args.iter().any(|sensitive| arg_name.contains(sensitive.as_str()))
+    }
+
+    pub(crate) fn record(&mut self, command: &str, args: &ArgsList, started: SystemTime, duration: Duration, success: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut rendered = args.render();
+        let mut redacted = false;
+        for (name, value) in rendered.iter_mut() {
+            if self.is_sensitive(name) {
+                *value = REDACTED.to_string();
+                redacted = true;
+            }
+        }
+        let entry = HistoryEntry {
+            index: self.next_index,
+            command: command.to_string(),
+            args: rendered,
+            timestamp_ms: started.duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0),
+            duration_ms: duration.as_millis() as u64,
+            success,
+        };
+        self.next_index += 1;
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((entry, if redacted { None } else { Some(args.clone()) }));
+    }
+
+    /// Oldest first.
+    pub(crate) fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.iter().map(|(entry, _)| entry.clone()).collect()
+    }
+
+    /// Command and args of entry `index`, the args are `None` if they can't be replayed.
+    pub(crate) fn get(&self, index: u64) -> Option<(String, Option<ArgsList>)> {
+        self.entries.iter()
+            .find(|(entry, _)| entry.index == index)
+            .map(|(entry, args)| (entry.command.clone(), args.clone()))
+    }
+
+}
@@ -0,0 +1,1867 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Serialize, Serializer, Deserialize};
+
+use amina_core_derive::Event;
+
+use crate::events::{Event, EventEmitter};
+use crate::rpc::{EmptyData, Rpc};
+use crate::service::{Context, Service, ServiceApi, ServiceInitializer};
+use crate::settings::{Property, PropertySubscription};
+use crate::tasks::{TaskContext, TaskManager};
+
+pub mod args_parser;
+mod caller;
+mod constraint;
+mod executions;
+mod history;
+mod output;
+mod wizards;
+
+pub use caller::{current_cmd_caller, current_cmd_caller_or_user, with_cmd_caller, CmdCaller, PermissionLevel};
+pub use constraint::{ArgConstraint, ArgValidator};
+pub use executions::{AsyncCmdHandler, ExecutionState, ExecutionStatus, ProgressSink};
+pub use history::{HistoryEntry, DEFAULT_HISTORY_SIZE, HISTORY_SIZE_KEY};
+pub use output::CmdOutput;
+pub use wizards::{WizardDefinition, WizardNext, WizardPrompt, WizardStep, DEFAULT_MAX_WIZARD_SESSIONS, DEFAULT_WIZARD_IDLE_TIMEOUT};
+use executions::Executions;
+use history::History;
+use wizards::Wizards;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ArgType {
+    U64,
+    I64,
+    F64,
+    BOOL,
+    STRING,
+    /// Comma separated on the CLI, or the argument repeated.
+    #[allow(non_camel_case_types)]
+    STRING_LIST,
+}
+
+impl ArgType {
+
+    /// Whether `value` is of this type, as put into `ArgsList`.
+    pub fn accepts(&self, value: &ArgValue) -> bool {
+        matches!((self, value),
+            (ArgType::U64, ArgValue::U64(_))
+            | (ArgType::I64, ArgValue::I64(_))
+            | (ArgType::F64, ArgValue::F64(_))
+            | (ArgType::BOOL, ArgValue::Bool(_))
+            | (ArgType::STRING, ArgValue::String(_))
+            | (ArgType::STRING_LIST, ArgValue::StringList(_)))
+    }
+
+}
+
+/// Value of an argument, serialized as the plain JSON value.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ArgValue {
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    StringList(Vec<String>),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ArgDescription {
+    pub call_name: String,
+    pub description: Option<String>,
+    pub arg_type: ArgType,
+    pub required: bool,
+    /// Filled in by `CmdManager::handle` when the argument is omitted.
+    pub default: Option<ArgValue>,
+    /// Allowed values of a `STRING` argument, empty when any value is accepted.
+    /// Checked by `CmdManager::handle` like the constraints.
+    pub options: Vec<String>,
+    /// Checked by `CmdManager::handle` when the argument is passed.
+    pub constraints: Vec<ArgConstraint>,
+}
+
+/// Serialized with the names sorted, so UIs get the same form every time.
+#[derive(Serialize, Clone, Debug)]
+pub struct CmdDescription {
+    pub call_name: String,
+    pub description: Option<String>,
+    #[serde(serialize_with = "serialize_sorted")]
+    pub args: HashMap<String, ArgDescription>,
+    /// Alternative names, e.g. `q` for `quit`.
+    pub aliases: Vec<String>,
+    /// Lowest caller level allowed to run the command.
+    pub permission: PermissionLevel,
+    /// Group shown by help and UIs, e.g. `Library`.
+    pub category: Option<String>,
+    /// Whether handling the command emits `CommandExecutedEvent` or `CommandFailedEvent`.
+    pub emit_events: bool,
+}
+
+fn serialize_sorted<S: Serializer>(args: &HashMap<String, ArgDescription>, serializer: S) -> Result<S::Ok, S::Error> {
+    args.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+pub struct CmdBuilder {
+    description: CmdDescription,
+}
+
+impl CmdBuilder {
+
+    pub fn new(call_name: &str) -> Self {
+        Self {
+            description: CmdDescription {
+                call_name: call_name.to_string(),
+                description: None,
+                args: HashMap::new(),
+                aliases: Vec::new(),
+                permission: PermissionLevel::User,
+                category: None,
+                emit_events: true,
+            }
+        }
+    }
+
+    pub fn add_description(mut self, description: &str) -> Self {
+        self.description.description = Some(description.to_string());
+        self
+    }
+
+    pub fn add_arg(mut self, arg: ArgDescription) -> Self {
+        self.description.args.insert(arg.call_name.clone(), arg);
+        self
+    }
+
+    pub fn add_alias(mut self, alias: &str) -> Self {
+        self.description.aliases.push(alias.to_string());
+        self
+    }
+
+    /// Commands need `User` unless set otherwise.
+    pub fn set_permission(mut self, permission: PermissionLevel) -> Self {
+        self.description.permission = permission;
+        self
+    }
+
+    pub fn category(mut self, category: &str) -> Self {
+        self.description.category = Some(category.to_string());
+        self
+    }
+
+    /// Off for noisy or sensitive commands.
+    pub fn set_emit_events(mut self, emit_events: bool) -> Self {
+        self.description.emit_events = emit_events;
+        self
+    }
+
+    pub fn build(self) -> CmdDescription {
+        self.description
+    }
+
+}
+
+pub struct ArgBuilder {
+    description: ArgDescription,
+}
+
+impl ArgBuilder {
+
+    pub fn new(call_name: &str, arg_type: ArgType) -> Self {
+        Self {
+            description: ArgDescription {
+                call_name: call_name.to_string(),
+                description: None,
+                arg_type,
+                required: true,
+                default: None,
+                options: Vec::new(),
+                constraints: Vec::new(),
+            }
+        }
+    }
+
+    pub fn add_description(mut self, description: &str) -> Self {
+        self.description.description = Some(description.to_string());
+        self
+    }
+
+    /// The argument may be omitted, handlers see it as absent.
+    pub fn optional(mut self) -> Self {
+        self.description.required = false;
+        self
+    }
+
+    /// Makes the argument optional, `value` is used when it's omitted.
+    pub fn add_default(mut self, value: ArgValue) -> Self {
+        self.description.required = false;
+        self.description.default = Some(value);
+        self
+    }
+
+    /// Restricts the argument to one of `options`.
+    pub fn add_options(mut self, options: &[&str]) -> Self {
+        self.description.options = options.iter().map(|option| option.to_string()).collect();
+        self
+    }
+
+    /// Rejects values for which `validator` returns an error, `constraint` describes the
+    /// accepted values to the user.
+    pub fn with_validator<F>(self, constraint: &str, validator: F) -> Self where
+        F: Fn(&ArgValue) -> Result<(), String> + Send + Sync + 'static
+    {
+        self.with_constraint(ArgConstraint::new(constraint, validator))
+    }
+
+    /// E.g. `ArgConstraint::range(1.0, 65535.0)` for a port.
+    pub fn with_constraint(mut self, constraint: ArgConstraint) -> Self {
+        self.description.constraints.push(constraint);
+        self
+    }
+
+    pub fn build(self) -> ArgDescription {
+        self.description
+    }
+
+}
+
+#[derive(Debug, Serialize, thiserror::Error)]
+pub enum CmdError {
+    #[error("Unknown command '{0}'")]
+    UnknownCommand(String),
+    #[error("Missing argument '{0}'")]
+    MissingArgument(String),
+    #[error("Name '{name}' of command '{command}' is already used by command '{existing}'")]
+    NameCollision {
+        name: String,
+        command: String,
+        existing: String,
+    },
+    #[error("Unknown execution '{0}'")]
+    UnknownExecution(String),
+    #[error("Asynchronous command '{0}' needs a command manager created by the context")]
+    AsyncUnavailable(String),
+    #[error("No history entry {0}")]
+    UnknownHistoryEntry(u64),
+    #[error("History entry {0} has redacted arguments and can't be replayed")]
+    RedactedHistoryEntry(u64),
+    /// Every argument that failed its constraints, as "name: reason".
+    #[error("Invalid arguments of command '{command}': {}", .errors.join("; "))]
+    InvalidArguments {
+        command: String,
+        errors: Vec<String>,
+    },
+    #[error("Unknown wizard session '{0}'")]
+    UnknownWizardSession(String),
+    #[error("Too many wizard sessions, at most {0} may be open")]
+    WizardLimitReached(usize),
+    #[error("Invalid answer in wizard session '{token}': {reason}")]
+    InvalidWizardAnswer {
+        token: String,
+        reason: String,
+    },
+    #[error("Command '{command}' requires '{required}' permission, the caller has '{actual}'")]
+    PermissionDenied {
+        command: String,
+        required: PermissionLevel,
+        actual: PermissionLevel,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ArgsList {
+    u64_list: HashMap<String, u64>,
+    #[serde(default)]
+    i64_list: HashMap<String, i64>,
+    #[serde(default)]
+    f64_list: HashMap<String, f64>,
+    bool_list: HashMap<String, bool>,
+    string_list: HashMap<String, String>,
+    #[serde(default)]
+    string_list_list: HashMap<String, Vec<String>>,
+}
+
+impl ArgsList {
+
+    pub fn new() -> Self {
+        Self {
+            u64_list: HashMap::new(),
+            i64_list: HashMap::new(),
+            f64_list: HashMap::new(),
+            bool_list: HashMap::new(),
+            string_list: HashMap::new(),
+            string_list_list: HashMap::new(),
+        }
+    }
+
+    pub fn try_get_u64(&self, arg_call_name: &str) -> Option<u64> {
+        self.u64_list.get(arg_call_name).copied()
+    }
+
+    pub fn get_u64(&self, arg_call_name: &str) -> Result<u64, CmdError> {
+        self.try_get_u64(arg_call_name)
+            .ok_or_else(|| CmdError::MissingArgument(arg_call_name.to_string()))
+    }
+
+    /// Panics if the argument wasn't passed.
+    pub fn get_u64_or_panic(&self, arg_call_name: &str) -> u64 {
+        self.try_get_u64(arg_call_name)
+            .unwrap_or_else(|| panic!("Missing u64 argument '{}'", arg_call_name))
+    }
+
+    pub fn get_u64_or(&self, arg_call_name: &str, default: u64) -> u64 {
+        self.try_get_u64(arg_call_name).unwrap_or(default)
+    }
+
+    pub fn put_u64(&mut self, arg_call_name: &str, value: u64) {
+        self.u64_list.insert(arg_call_name.to_string(), value);
+    }
+
+    pub fn try_get_i64(&self, arg_call_name: &str) -> Option<i64> {
+        self.i64_list.get(arg_call_name).copied()
+    }
+
+    pub fn get_i64(&self, arg_call_name: &str) -> Result<i64, CmdError> {
+        self.try_get_i64(arg_call_name)
+            .ok_or_else(|| CmdError::MissingArgument(arg_call_name.to_string()))
+    }
+
+    /// Panics if the argument wasn't passed.
+    pub fn get_i64_or_panic(&self, arg_call_name: &str) -> i64 {
+        self.try_get_i64(arg_call_name)
+            .unwrap_or_else(|| panic!("Missing i64 argument '{}'", arg_call_name))
+    }
+
+    pub fn get_i64_or(&self, arg_call_name: &str, default: i64) -> i64 {
+        self.try_get_i64(arg_call_name).unwrap_or(default)
+    }
+
+    pub fn put_i64(&mut self, arg_call_name: &str, value: i64) {
+        self.i64_list.insert(arg_call_name.to_string(), value);
+    }
+
+    pub fn try_get_f64(&self, arg_call_name: &str) -> Option<f64> {
+        self.f64_list.get(arg_call_name).copied()
+    }
+
+    pub fn get_f64(&self, arg_call_name: &str) -> Result<f64, CmdError> {
+        self.try_get_f64(arg_call_name)
+            .ok_or_else(|| CmdError::MissingArgument(arg_call_name.to_string()))
+    }
+
+    /// Panics if the argument wasn't passed.
+    pub fn get_f64_or_panic(&self, arg_call_name: &str) -> f64 {
+        self.try_get_f64(arg_call_name)
+            .unwrap_or_else(|| panic!("Missing f64 argument '{}'", arg_call_name))
+    }
+
+    pub fn get_f64_or(&self, arg_call_name: &str, default: f64) -> f64 {
+        self.try_get_f64(arg_call_name).unwrap_or(default)
+    }
+
+    pub fn put_f64(&mut self, arg_call_name: &str, value: f64) {
+        self.f64_list.insert(arg_call_name.to_string(), value);
+    }
+
+    pub fn try_get_bool(&self, arg_call_name: &str) -> Option<bool> {
+        self.bool_list.get(arg_call_name).copied()
+    }
+
+    pub fn get_bool(&self, arg_call_name: &str) -> Result<bool, CmdError> {
+        self.try_get_bool(arg_call_name)
+            .ok_or_else(|| CmdError::MissingArgument(arg_call_name.to_string()))
+    }
+
+    /// Panics if the argument wasn't passed.
+    pub fn get_bool_or_panic(&self, arg_call_name: &str) -> bool {
+        self.try_get_bool(arg_call_name)
+            .unwrap_or_else(|| panic!("Missing bool argument '{}'", arg_call_name))
+    }
+
+    pub fn get_bool_or(&self, arg_call_name: &str, default: bool) -> bool {
+        self.try_get_bool(arg_call_name).unwrap_or(default)
+    }
+
+    pub fn put_bool(&mut self, arg_call_name: &str, value: bool) {
+        self.bool_list.insert(arg_call_name.to_string(), value);
+    }
+
+    pub fn try_get_string(&self, arg_call_name: &str) -> Option<String> {
+        self.string_list.get(arg_call_name).cloned()
+    }
+
+    pub fn get_string(&self, arg_call_name: &str) -> Result<String, CmdError> {
+        self.try_get_string(arg_call_name)
+            .ok_or_else(|| CmdError::MissingArgument(arg_call_name.to_string()))
+    }
+
+    /// Panics if the argument wasn't passed.
+    pub fn get_string_or_panic(&self, arg_call_name: &str) -> String {
+        self.try_get_string(arg_call_name)
+            .unwrap_or_else(|| panic!("Missing string argument '{}'", arg_call_name))
+    }
+
+    pub fn get_string_or(&self, arg_call_name: &str, default: String) -> String {
+        self.try_get_string(arg_call_name).unwrap_or(default)
+    }
+
+    pub fn put_string(&mut self, arg_call_name: &str, value: String) {
+        self.string_list.insert(arg_call_name.to_string(), value);
+    }
+
+    pub fn try_get_string_list(&self, arg_call_name: &str) -> Option<Vec<String>> {
+        self.string_list_list.get(arg_call_name).cloned()
+    }
+
+    pub fn get_string_list(&self, arg_call_name: &str) -> Result<Vec<String>, CmdError> {
+        self.try_get_string_list(arg_call_name)
+            .ok_or_else(|| CmdError::MissingArgument(arg_call_name.to_string()))
+    }
+
+    /// Panics if the argument wasn't passed.
+    pub fn get_string_list_or_panic(&self, arg_call_name: &str) -> Vec<String> {
+        self.try_get_string_list(arg_call_name)
+            .unwrap_or_else(|| panic!("Missing string list argument '{}'", arg_call_name))
+    }
+
+    pub fn get_string_list_or(&self, arg_call_name: &str, default: Vec<String>) -> Vec<String> {
+        self.try_get_string_list(arg_call_name).unwrap_or(default)
+    }
+
+    pub fn put_string_list(&mut self, arg_call_name: &str, value: Vec<String>) {
+        self.string_list_list.insert(arg_call_name.to_string(), value);
+    }
+
+    pub fn put_value(&mut self, arg_call_name: &str, value: ArgValue) {
+        match value {
+            ArgValue::U64(value) => self.put_u64(arg_call_name, value),
+            ArgValue::I64(value) => self.put_i64(arg_call_name, value),
+            ArgValue::F64(value) => self.put_f64(arg_call_name, value),
+            ArgValue::Bool(value) => self.put_bool(arg_call_name, value),
+            ArgValue::String(value) => self.put_string(arg_call_name, value),
+            ArgValue::StringList(value) => self.put_string_list(arg_call_name, value),
+        }
+    }
+
+    pub fn try_get_value(&self, arg_call_name: &str) -> Option<ArgValue> {
+        self.try_get_u64(arg_call_name).map(ArgValue::U64)
+            .or_else(|| self.try_get_i64(arg_call_name).map(ArgValue::I64))
+            .or_else(|| self.try_get_f64(arg_call_name).map(ArgValue::F64))
+            .or_else(|| self.try_get_bool(arg_call_name).map(ArgValue::Bool))
+            .or_else(|| self.try_get_string(arg_call_name).map(ArgValue::String))
+            .or_else(|| self.try_get_string_list(arg_call_name).map(ArgValue::StringList))
+    }
+
+    /// Whether the argument was passed, either explicitly or as a default.
+    pub fn has(&self, arg_call_name: &str) -> bool {
+        self.u64_list.contains_key(arg_call_name)
+            || self.i64_list.contains_key(arg_call_name)
+            || self.f64_list.contains_key(arg_call_name)
+            || self.bool_list.contains_key(arg_call_name)
+            || self.string_list.contains_key(arg_call_name)
+            || self.string_list_list.contains_key(arg_call_name)
+    }
+
+    /// Every argument as it would be typed on the CLI.
+    pub(crate) fn render(&self) -> BTreeMap<String, String> {
+        let mut rendered = BTreeMap::new();
+        rendered.extend(self.u64_list.iter().map(|(name, value)| (name.clone(), value.to_string())));
+        rendered.extend(self.i64_list.iter().map(|(name, value)| (name.clone(), value.to_string())));
+        rendered.extend(self.f64_list.iter().map(|(name, value)| (name.clone(), value.to_string())));
+        rendered.extend(self.bool_list.iter().map(|(name, value)| (name.clone(), if *value { "y" } else { "n" }.to_string())));
+        rendered.extend(self.string_list.iter().map(|(name, value)| (name.clone(), value.clone())));
+        rendered.extend(self.string_list_list.iter().map(|(name, value)| (name.clone(), value.join(","))));
+        rendered
+    }
+
+}
+
+/// Outcome of a command, returned to the caller instead of only being logged.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CmdResult {
+    pub success: bool,
+    /// Human-readable, printed by the CLI. Empty if there is nothing to say.
+    pub message: String,
+    pub payload: Option<serde_json::Value>,
+}
+
+impl CmdResult {
+
+    pub fn empty() -> Self {
+        Self::ok("")
+    }
+
+    pub fn ok(message: &str) -> Self {
+        Self {
+            success: true,
+            message: message.to_string(),
+            payload: None,
+        }
+    }
+
+    pub fn error(message: &str) -> Self {
+        Self {
+            success: false,
+            message: message.to_string(),
+            payload: None,
+        }
+    }
+
+    /// Attaches structured data for RPC callers. A payload that can't be serialized,
+    /// e.g. a map with non-string keys, turns the result into an error.
+    pub fn with_payload<T: Serialize>(mut self, payload: &T) -> Self {
+        match serde_json::to_value(payload) {
+            Ok(payload) => {
+                self.payload = Some(payload);
+                self
+            },
+            Err(err) => {
+                log::error!("Can't serialize the command payload: {}", err);
+                Self::error(&format!("Can't serialize the command payload: {}", err))
+            },
+        }
+    }
+
+    pub fn with_output(self, output: &CmdOutput) -> Self {
+        self.with_payload(output)
+    }
+
+    /// Set while a wizard waits for an answer.
+    pub fn wizard_prompt(&self) -> Option<WizardPrompt> {
+        self.payload.as_ref().and_then(|payload| serde_json::from_value(payload.clone()).ok())
+    }
+
+    /// The payload as `CmdOutput`, if it was attached with `with_output`.
+    pub fn output(&self) -> Option<CmdOutput> {
+        self.payload.as_ref().and_then(|payload| serde_json::from_value(payload.clone()).ok())
+    }
+
+}
+
+/// Emitted when a command returned a successful result.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Event)]
+#[key = "amina_core.cmd_manager.command_executed"]
+pub struct CommandExecutedEvent {
+    pub name: String,
+    pub duration_ms: u64,
+    pub success: bool,
+}
+
+/// Emitted when a command returned a failed result, or was refused before running.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Event)]
+#[key = "amina_core.cmd_manager.command_failed"]
+pub struct CommandFailedEvent {
+    pub name: String,
+    pub duration_ms: u64,
+    pub error: String,
+}
+
+pub type CmdHandler = Arc<dyn Fn(&ArgsList) -> CmdResult + Sync + Send + 'static>;
+
+pub struct CmdWrapper {
+    pub description: CmdDescription,
+    pub handler: CmdHandler,
+}
+
+#[derive(Serialize)]
+pub struct CommandNames {
+    pub name: String,
+    pub aliases: Vec<String>,
+    /// Lets UIs hide commands the user can't run.
+    pub permission: PermissionLevel,
+    /// First line of the command description.
+    pub description: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CommandCategory {
+    /// `None` for the commands without a category.
+    pub name: Option<String>,
+    /// Sorted by their dotted segments so namespaces stay together.
+    pub commands: Vec<CommandNames>,
+}
+
+#[derive(Serialize)]
+pub struct CommandsDescription {
+    /// Canonical names of every command, sorted like the commands of a category.
+    pub flat_names: Vec<String>,
+    /// Sorted by name, the uncategorized commands come last. Empty categories are left out.
+    pub categories: Vec<CommandCategory>,
+}
+
+struct Registry {
+    cmd_map: RwLock<HashMap<String, CmdWrapper>>,
+    /// Alias to canonical command name.
+    aliases: RwLock<HashMap<String, String>>,
+}
+
+impl Registry {
+
+    /// With `handler` the command is only removed if it still runs that handler.
+    fn remove(&self, call_name: &str, handler: Option<&CmdHandler>) -> bool {
+        let mut cmd_map = self.cmd_map.write().unwrap();
+        match (cmd_map.get(call_name), handler) {
+            (None, _) => return false,
+            (Some(cmd_wrapper), Some(handler)) if !Arc::ptr_eq(&cmd_wrapper.handler, handler) => return false,
+            _ => {},
+        }
+        cmd_map.remove(call_name);
+        self.aliases.write().unwrap().retain(|_, command| command != call_name);
+        true
+    }
+
+}
+
+/// Keeps a command registered by `CmdManager::add_command_scoped`, dropping it removes the command.
+pub struct CommandRegistration {
+    registry: Weak<Registry>,
+    call_name: String,
+    handler: CmdHandler,
+}
+
+impl CommandRegistration {
+    pub fn get_call_name(&self) -> &str {
+        &self.call_name
+    }
+}
+
+impl Drop for CommandRegistration {
+    fn drop(&mut self) {
+        // A command replaced in the meantime belongs to someone else
+        if let Some(registry) = self.registry.upgrade() {
+            registry.remove(&self.call_name, Some(&self.handler));
+        }
+    }
+}
+
+pub struct CmdManager {
+    registry: Arc<Registry>,
+    /// Only present when created by the context, asynchronous commands run on its task manager.
+    executions: Option<Arc<Executions>>,
+    history: Arc<Mutex<History>>,
+    wizards: Arc<Wizards>,
+    /// Only present when created by the context, commands are handled silently without it.
+    event_emitter: Option<Service<EventEmitter>>,
+}
+
+impl CmdManager {
+
+    pub fn new() -> Self {
+        let cmd_map = HashMap::new();
+
+        Self {
+            registry: Arc::new(Registry {
+                cmd_map: RwLock::new(cmd_map),
+                aliases: RwLock::new(HashMap::new()),
+            }),
+            executions: None,
+            history: Arc::new(Mutex::new(History::new())),
+            wizards: Arc::new(Wizards::new()),
+            event_emitter: None,
+        }
+    }
+
+    /// Adding a command under an existing name replaces it. Fails if the name or one of
+    /// the aliases is already taken by another command.
+    pub fn add_command<F>(&self, description: CmdDescription, handler: F) -> Result<(), CmdError> where
+        F: Fn(&ArgsList) -> CmdResult + Send + Sync + 'static
+    {
+        self.insert(description, Arc::new(handler), false).map(|_| ())
+    }
+
+    /// Replaces a registered command, fails with `UnknownCommand` if there is none.
+    pub fn replace_command<F>(&self, description: CmdDescription, handler: F) -> Result<(), CmdError> where
+        F: Fn(&ArgsList) -> CmdResult + Send + Sync + 'static
+    {
+        self.insert(description, Arc::new(handler), true).map(|_| ())
+    }
+
+    /// Like `add_command`, for plugins that unload: the command and its aliases are removed
+    /// when the returned registration is dropped.
+    pub fn add_command_scoped<F>(&self, description: CmdDescription, handler: F) -> Result<CommandRegistration, CmdError> where
+        F: Fn(&ArgsList) -> CmdResult + Send + Sync + 'static
+    {
+        let call_name = description.call_name.clone();
+        let handler = self.insert(description, Arc::new(handler), false)?;
+        Ok(CommandRegistration {
+            registry: Arc::downgrade(&self.registry),
+            call_name,
+            handler,
+        })
+    }
+
+    /// Removes a command by its canonical name, together with its aliases.
+    /// A call of the command that is already running completes normally.
+    pub fn remove_command(&self, call_name: &str) -> bool {
+        self.registry.remove(call_name, None)
+    }
+
+    fn insert(&self, description: CmdDescription, handler: CmdHandler, must_exist: bool) -> Result<CmdHandler, CmdError> {
+        let mut cmd_map = self.registry.cmd_map.write().unwrap();
+        let mut aliases = self.registry.aliases.write().unwrap();
+        let command = &description.call_name;
+        if must_exist && !cmd_map.contains_key(command) {
+            return Err(CmdError::UnknownCommand(command.clone()));
+        }
+        let collision = |name: &str, existing: &str| CmdError::NameCollision {
+            name: name.to_string(),
+            command: command.clone(),
+            existing: existing.to_string(),
+        };
+        if let Some(existing) = aliases.get(command) {
+            if existing != command {
+                return Err(collision(command, existing));
+            }
+        }
+        for (i, alias) in description.aliases.iter().enumerate() {
+            if alias == command || description.aliases[..i].contains(alias) {
+                return Err(collision(alias, command));
+            }
+            if cmd_map.contains_key(alias) {
+                return Err(collision(alias, alias));
+            }
+            match aliases.get(alias) {
+                Some(existing) if existing != command => return Err(collision(alias, existing)),
+                _ => {},
+            }
+        }
+
+        aliases.retain(|_, existing| existing != command);
+        for alias in description.aliases.iter() {
+            aliases.insert(alias.clone(), command.clone());
+        }
+        cmd_map.insert(command.clone(), CmdWrapper {
+            description,
+            handler: handler.clone(),
+        });
+        Ok(handler)
+    }
+
+    /// Registers a command that runs as a task. Calling it returns right away with
+    /// the execution id in the payload, see `get_execution_status`.
+    pub fn add_async_command<F>(&self, description: CmdDescription, handler: F) -> Result<(), CmdError> where
+        F: Fn(&ArgsList, &TaskContext, &dyn ProgressSink) -> CmdResult + Send + Sync + 'static
+    {
+        let executions = match &self.executions {
+            Some(executions) => executions.clone(),
+            None => return Err(CmdError::AsyncUnavailable(description.call_name)),
+        };
+        let command = description.call_name.clone();
+        let handler: AsyncCmdHandler = Arc::new(handler);
+        self.add_command(description, move |args| {
+            let execution_id = executions.start(&command, args.clone(), handler.clone());
+            CmdResult::ok(&format!("Started execution {}", execution_id))
+                .with_payload(&serde_json::json!({ "execution_id": execution_id }))
+        })
+    }
+
+    pub fn get_execution_status(&self, execution_id: &str) -> Result<ExecutionStatus, CmdError> {
+        match &self.executions {
+            Some(executions) => executions.status(execution_id),
+            None => Err(CmdError::UnknownExecution(execution_id.to_string())),
+        }
+    }
+
+    /// Flips the interrupt flag of the execution's task.
+    pub fn cancel_execution(&self, execution_id: &str) -> Result<(), CmdError> {
+        match &self.executions {
+            Some(executions) => executions.cancel(execution_id),
+            None => Err(CmdError::UnknownExecution(execution_id.to_string())),
+        }
+    }
+
+    /// Registers a command that starts a session of the wizard. Its result carries a
+    /// `WizardPrompt` payload, answers go to `wizard_answer` until it returns a result
+    /// without a prompt.
+    pub fn add_wizard(&self, name: &str, definition: WizardDefinition) -> Result<(), CmdError> {
+        let mut builder = CmdBuilder::new(name).set_permission(definition.permission);
+        if let Some(description) = &definition.description {
+            builder = builder.add_description(description);
+        }
+        let wizards = self.wizards.clone();
+        let definition = Arc::new(definition);
+        let wizard = name.to_string();
+        self.add_command(builder.build(), move |_| {
+            let caller = current_cmd_caller_or_user("unknown");
+            match wizards.start(&wizard, definition.clone(), caller) {
+                Ok(result) => result,
+                Err(err) => CmdResult::error(&err.to_string()),
+            }
+        })
+    }
+
+    /// `answer` is typed like a CLI argument value.
+    pub fn wizard_answer(&self, token: &str, answer: &str, caller: &CmdCaller) -> Result<CmdResult, CmdError> {
+        self.wizards.answer(token, answer, caller)
+    }
+
+    pub fn wizard_cancel(&self, token: &str, caller: &CmdCaller) -> Result<(), CmdError> {
+        self.wizards.cancel(token, caller)
+    }
+
+    /// Sessions without an answer for `idle_timeout` are dropped.
+    pub fn set_wizard_idle_timeout(&self, idle_timeout: Duration) {
+        self.wizards.set_idle_timeout(idle_timeout);
+    }
+
+    /// Starting a wizard fails while `max_sessions` sessions are open.
+    pub fn set_max_wizard_sessions(&self, max_sessions: usize) {
+        self.wizards.set_max_sessions(max_sessions);
+    }
+
+    /// Registers a handler that returns nothing, it always reports an empty success.
+    pub fn add_command_unit<F>(&self, description: CmdDescription, handler: F) -> Result<(), CmdError> where
+        F: Fn(&ArgsList) + Send + Sync + 'static
+    {
+        self.add_command(description, move |args| {
+            handler(args);
+            CmdResult::empty()
+        })
+    }
+
+    /// Canonical name of a command name or alias.
+    pub fn resolve_name(&self, cmd_name: &str) -> Option<String> {
+        if self.registry.cmd_map.read().unwrap().contains_key(cmd_name) {
+            return Some(cmd_name.to_string());
+        }
+        self.registry.aliases.read().unwrap().get(cmd_name).cloned()
+    }
+
+    pub fn get_cmd_description(&self) -> &RwLock<HashMap<String, CmdWrapper>> {
+        &self.registry.cmd_map
+    }
+
+    /// `cmd_call_name` may be an alias. Handled commands are recorded in the history.
+    /// Fails with `PermissionDenied` if the level of `caller` is below the command's, and with
+    /// `InvalidArguments` for missing, mistyped or rejected arguments. Omitted arguments get their default.
+    pub fn handle(&self, cmd_call_name: &str, args: &ArgsList, caller: &CmdCaller) -> Result<CmdResult, CmdError> {
+        // Validators and handlers are called without the lock, so they may look up or register other commands
+        let (description, handler) = {
+            let cmd_map = self.registry.cmd_map.read().unwrap();
+            let cmd_wrapper = self.lookup(&cmd_map, cmd_call_name)?;
+            (cmd_wrapper.description.clone(), cmd_wrapper.handler.clone())
+        };
+        let emit_events = description.emit_events;
+        let checked = check_call(&description, args, caller);
+        let command = description.call_name;
+        let args = match checked {
+            Ok(args) => args,
+            Err(err) => {
+                if emit_events {
+                    self.emit_failed(&command, Duration::ZERO, &err.to_string());
+                }
+                return Err(err);
+            },
+        };
+        let started = SystemTime::now();
+        let start = Instant::now();
+        let result = with_cmd_caller(Some(caller.clone()), || handler(&args));
+        let duration = start.elapsed();
+        // Browsing the history isn't worth recording, the replayed command itself is
+        if command != HISTORY_CMD && command != HISTORY_REPLAY_CMD {
+            self.history.lock().unwrap().record(&command, &args, started, duration, result.success);
+        }
+        if emit_events {
+            if result.success {
+                if let Some(event_emitter) = &self.event_emitter {
+                    event_emitter.emit_event(&CommandExecutedEvent {
+                        name: command,
+                        duration_ms: duration.as_millis() as u64,
+                        success: true,
+                    });
+                }
+            } else {
+                self.emit_failed(&command, duration, &result.message);
+            }
+        }
+        Ok(result)
+    }
+
+    fn emit_failed(&self, command: &str, duration: Duration, error: &str) {
+        if let Some(event_emitter) = &self.event_emitter {
+            event_emitter.emit_event(&CommandFailedEvent {
+                name: command.to_string(),
+                duration_ms: duration.as_millis() as u64,
+                error: error.to_string(),
+            });
+        }
+    }
+
+    /// Recently handled commands, oldest first.
+    pub fn get_history(&self) -> Vec<HistoryEntry> {
+        self.history.lock().unwrap().entries()
+    }
+
+    /// Number of kept history entries, 0 disables the history.
+    pub fn set_history_size(&self, size: usize) {
+        self.history.lock().unwrap().set_capacity(size);
+    }
+
+    /// Follows `property` for the history size, e.g. `settings.get_i64_or(HISTORY_SIZE_KEY, 100)`.
+    /// `SettingsManager` binds `HISTORY_SIZE_KEY` of its settings when it starts.
+    pub fn bind_history_size(&self, property: &Property<i64>) -> PropertySubscription {
+        self.set_history_size(property.get().max(0) as usize);
+        let history = Arc::downgrade(&self.history);
+        property.on_change(move |size| {
+            if let Some(history) = history.upgrade() {
+                history.lock().unwrap().set_capacity((*size).max(0) as usize);
+            }
+        })
+    }
+
+    /// Arguments whose name contains one of `names`, ignoring case, are stored redacted.
+    /// By default these are "password", "secret" and "token".
+    pub fn set_sensitive_args(&self, names: &[&str]) {
+        self.history.lock().unwrap().set_sensitive_args(names.iter().map(|name| name.to_string()).collect());
+    }
+
+    /// Runs a history entry again through `handle`.
+    pub fn replay(&self, index: u64, caller: &CmdCaller) -> Result<CmdResult, CmdError> {
+        let (command, args) = self.history.lock().unwrap().get(index)
+            .ok_or(CmdError::UnknownHistoryEntry(index))?;
+        let args = args.ok_or(CmdError::RedactedHistoryEntry(index))?;
+        self.handle(&command, &args, caller)
+    }
+
+    fn lookup<'a>(&self, cmd_map: &'a HashMap<String, CmdWrapper>, cmd_name: &str) -> Result<&'a CmdWrapper, CmdError> {
+        if let Some(cmd_wrapper) = cmd_map.get(cmd_name) {
+            return Ok(cmd_wrapper);
+        }
+        self.registry.aliases.read().unwrap().get(cmd_name)
+            .and_then(|canonical| cmd_map.get(canonical))
+            .ok_or_else(|| CmdError::UnknownCommand(cmd_name.to_string()))
+    }
+
+    pub fn get_commands_description(&self) -> CommandsDescription {
+        let cmd_map = self.registry.cmd_map.read().unwrap();
+        let mut flat_names: Vec<String> = cmd_map.keys().cloned().collect();
+        flat_names.sort_by(|a, b| a.split('.').cmp(b.split('.')));
+
+        // Built from the registered commands, so removing the last command of a category drops it
+        let mut categories: BTreeMap<Option<String>, Vec<CommandNames>> = BTreeMap::new();
+        for name in flat_names.iter() {
+            let description = &cmd_map[name].description;
+            categories.entry(description.category.clone()).or_default().push(CommandNames {
+                name: name.clone(),
+                aliases: description.aliases.clone(),
+                permission: description.permission,
+                description: description.description.as_deref()
+                    .and_then(|description| description.lines().next())
+                    .map(str::to_string),
+            });
+        }
+        let mut categories: Vec<CommandCategory> = categories.into_iter()
+            .map(|(name, commands)| CommandCategory { name, commands })
+            .collect();
+        // `None` sorts first
+        if categories.first().is_some_and(|category| category.name.is_none()) {
+            categories.rotate_left(1);
+        }
+
+        CommandsDescription {
+            flat_names,
+            categories,
+        }
+    }
+
+    /// Every command with its one-line description, or the full description of `cmd_name`.
+    fn help_text(&self, cmd_name: Option<&str>) -> Result<String, CmdError> {
+        let cmd_name = match cmd_name {
+            Some(cmd_name) => cmd_name,
+            None => {
+                let description = self.get_commands_description();
+                let width = description.flat_names.iter().map(String::len).max().unwrap_or(0);
+                let mut lines = Vec::new();
+                for category in description.categories.iter() {
+                    lines.push(format!("{}:", category.name.as_deref().unwrap_or("Other")));
+                    for command in category.commands.iter() {
+                        let line = format!("  {:width$}  {}", command.name, command.description.as_deref().unwrap_or(""), width = width);
+                        lines.push(line.trim_end().to_string());
+                    }
+                }
+                return Ok(lines.join("\n"));
+            },
+        };
+
+        let description = self.get_command_description(cmd_name)?;
+        let mut text = description.call_name.clone();
+        if !description.aliases.is_empty() {
+            text += &format!(" (aliases: {})", description.aliases.join(", "));
+        }
+        if let Some(about) = &description.description {
+            text += &format!("\n{}", about);
+        }
+        let mut args: Vec<&ArgDescription> = description.args.values().collect();
+        args.sort_by(|a, b| a.call_name.cmp(&b.call_name));
+        if !args.is_empty() {
+            text += "\nArguments:";
+        }
+        for arg in args {
+            let requirement = match (&arg.default, arg.required) {
+                (Some(default), _) => format!("optional, default {}",
+                    serde_json::to_string(default).unwrap_or_else(|_| format!("{:?}", default))),
+                (None, true) => "required".to_string(),
+                (None, false) => "optional".to_string(),
+            };
+            text += &format!("\n  {}: {:?}, {}", arg.call_name, arg.arg_type, requirement);
+            if !arg.options.is_empty() {
+                text += &format!(", one of {}", arg.options.join(", "));
+            }
+            for constraint in arg.constraints.iter() {
+                text += &format!(", {}", constraint.get_description());
+            }
+            if let Some(about) = &arg.description {
+                text += &format!(". {}", about);
+            }
+        }
+        Ok(text)
+    }
+
+    /// `cmd_name` may be an alias, the returned description has the canonical name.
+    pub fn get_command_description(&self, cmd_name: &str) -> Result<CmdDescription, CmdError> {
+        let cmd_map = self.registry.cmd_map.read().unwrap();
+        self.lookup(&cmd_map, cmd_name)
+            .map(|cmd_wrapper| cmd_wrapper.description.clone())
+    }
+
+}
+
+/// Whether `caller` may run the command with `args`.
+/// The arguments the handler gets, with the defaults of omitted ones.
+fn check_call(description: &CmdDescription, args: &ArgsList, caller: &CmdCaller) -> Result<ArgsList, CmdError> {
+    if caller.level < description.permission {
+        log::warn!("Refused command '{}' from {}", description.call_name, caller.origin);
+        return Err(CmdError::PermissionDenied {
+            command: description.call_name.clone(),
+            required: description.permission,
+            actual: caller.level,
+        });
+    }
+    validate_args(description, args)
+}
+
+/// Checks the type, options and constraints of every passed argument and fills in defaults,
+/// collecting all failures. Callers other than the CLI parsers reach the handler through here.
+fn validate_args(description: &CmdDescription, args: &ArgsList) -> Result<ArgsList, CmdError> {
+    let mut arg_descriptions: Vec<&ArgDescription> = description.args.values().collect();
+    arg_descriptions.sort_by(|a, b| a.call_name.cmp(&b.call_name));
+    let mut checked_args = args.clone();
+    let mut errors = Vec::new();
+    for arg in arg_descriptions {
+        let value = match args.try_get_value(&arg.call_name) {
+            Some(value) => value,
+            None => {
+                match &arg.default {
+                    Some(default) if arg.arg_type.accepts(default) => checked_args.put_value(&arg.call_name, default.clone()),
+                    Some(_) => errors.push(format!("{}: default isn't of type {:?}", arg.call_name, arg.arg_type)),
+                    None if arg.required => errors.push(format!("{}: missing", arg.call_name)),
+                    None => {},
+                }
+                continue;
+            },
+        };
+        if !arg.arg_type.accepts(&value) {
+            errors.push(format!("{}: must be of type {:?}", arg.call_name, arg.arg_type));
+            continue;
+        }
+        if let ArgValue::String(text) = &value {
+            if !arg.options.is_empty() && !arg.options.contains(text) {
+                errors.push(format!("{}: must be one of {}", arg.call_name, arg.options.join(", ")));
+                continue;
+            }
+        }
+        for constraint in arg.constraints.iter() {
+            if let Err(reason) = constraint.check(&value) {
+                errors.push(format!("{}: {}", arg.call_name, reason));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(checked_args)
+    } else {
+        Err(CmdError::InvalidArguments {
+            command: description.call_name.clone(),
+            errors,
+        })
+    }
+}
+
+const BUILTIN_CATEGORY: &str = "System";
+const HISTORY_CMD: &str = "history";
+const HISTORY_REPLAY_CMD: &str = "history.replay";
+/// Needed to read the history, which shows the arguments of every caller.
+const HISTORY_PERMISSION: PermissionLevel = PermissionLevel::Admin;
+
+fn history_text(entries: &[HistoryEntry]) -> String {
+    let lines: Vec<String> = entries.iter().map(|entry| {
+        let mut line = format!("{:>4}  {}", entry.index, entry.command);
+        for (name, value) in entry.args.iter() {
+            line += &format!(" {}:{}", name, value);
+        }
+        line += &format!("  ({}, {} ms)", if entry.success { "ok" } else { "failed" }, entry.duration_ms);
+        line
+    }).collect();
+    lines.join("\n")
+}
+
+impl ServiceApi for CmdManager {
+
+}
+
+impl ServiceInitializer for CmdManager {
+    fn initialize(context: &Context) -> Arc<Self> {
+        let rpc = context.get_service::<Rpc>();
+        let event_emitter = context.try_get_service::<EventEmitter>();
+        let mut cmd_manager = Self::new();
+        // Progress of async commands is reported through events
+        if let (Some(task_manager), Some(event_emitter)) = (context.try_get_service::<TaskManager>(), &event_emitter) {
+            cmd_manager.executions = Some(Arc::new(Executions::new(task_manager, event_emitter.clone())));
+        }
+        cmd_manager.event_emitter = event_emitter.clone();
+        let cmd_manager = Arc::new(cmd_manager);
+
+        #[derive(Deserialize)]
+        struct HandleCmdReq {
+            cmd_name: String,
+            args: ArgsList,
+        }
+        let cmd_manager_copy = cmd_manager.clone();
+        // The RPC gate sets the caller, anything else calling over RPC is a plain user
+        rpc.on_generic_call_result_fn("amina.cmd_manager.handle", move |args: &HandleCmdReq| {
+            let caller = current_cmd_caller_or_user("rpc");
+            cmd_manager_copy.handle(args.cmd_name.as_str(), &args.args, &caller)
+        });
+
+        let cmd_manager_copy = cmd_manager.clone();
+        rpc.on_generic_call_fn("amina.cmd_manager.get_commands_description", move |_: &EmptyData| {
+            cmd_manager_copy.get_commands_description()
+        });
+
+        #[derive(Deserialize)]
+        struct GetCommandDescriptionReq {
+            cmd_name: String,
+        }
+        let cmd_manager_copy = cmd_manager.clone();
+        rpc.on_generic_call_result_fn("amina.cmd_manager.get_command_description", move |req: &GetCommandDescriptionReq| {
+            cmd_manager_copy.get_command_description(req.cmd_name.as_str())
+        });
+
+        #[derive(Deserialize)]
+        struct ExecutionReq {
+            execution_id: String,
+        }
+        let cmd_manager_copy = cmd_manager.clone();
+        rpc.on_generic_call_result_fn("amina.cmd_manager.get_execution_status", move |req: &ExecutionReq| {
+            cmd_manager_copy.get_execution_status(req.execution_id.as_str())
+        });
+
+        let cmd_manager_copy = cmd_manager.clone();
+        rpc.on_generic_call_result_fn("amina.cmd_manager.cancel_execution", move |req: &ExecutionReq| {
+            cmd_manager_copy.cancel_execution(req.execution_id.as_str())
+        });
+
+        let cmd_manager_copy = cmd_manager.clone();
+        rpc.on_generic_call_result_fn("amina.cmd_manager.get_history", move |_: &EmptyData| {
+            let caller = current_cmd_caller_or_user("rpc");
+            if caller.level < HISTORY_PERMISSION {
+                log::warn!("Refused history request from {}", caller.origin);
+                return Err(CmdError::PermissionDenied {
+                    command: HISTORY_CMD.to_string(),
+                    required: HISTORY_PERMISSION,
+                    actual: caller.level,
+                });
+            }
+            Ok(cmd_manager_copy.get_history())
+        });
+
+        #[derive(Deserialize)]
+        struct WizardAnswerReq {
+            token: String,
+            answer: String,
+        }
+        let cmd_manager_copy = cmd_manager.clone();
+        rpc.on_generic_call_result_fn("amina.cmd_manager.wizard.answer", move |req: &WizardAnswerReq| {
+            let caller = current_cmd_caller_or_user("rpc");
+            cmd_manager_copy.wizard_answer(&req.token, &req.answer, &caller)
+        });
+
+        #[derive(Deserialize)]
+        struct WizardCancelReq {
+            token: String,
+        }
+        let cmd_manager_copy = cmd_manager.clone();
+        rpc.on_generic_call_result_fn("amina.cmd_manager.wizard.cancel", move |req: &WizardCancelReq| {
+            let caller = current_cmd_caller_or_user("rpc");
+            cmd_manager_copy.wizard_cancel(&req.token, &caller)
+        });
+
+        let help_cmd = CmdBuilder::new("help")
+            .category(BUILTIN_CATEGORY)
+            .add_description("List commands, or describe one of them")
+            .add_arg(ArgBuilder::new("cmd", ArgType::STRING)
+                .add_description("Name of the command to describe")
+                .optional()
+                .build())
+            .build();
+        // Weak, the command is stored inside the manager
+        let cmd_manager_weak = Arc::downgrade(&cmd_manager);
+        cmd_manager.add_command(help_cmd, move |args| {
+            let cmd_manager = match cmd_manager_weak.upgrade() {
+                Some(cmd_manager) => cmd_manager,
+                None => return CmdResult::error("Command manager is gone"),
+            };
+            match cmd_manager.help_text(args.try_get_string("cmd").as_deref()) {
+                Ok(text) => CmdResult::ok(&text),
+                Err(err) => CmdResult::error(&err.to_string()),
+            }
+        }).unwrap();
+
+        // Shows the arguments of every caller
+        let history_cmd = CmdBuilder::new(HISTORY_CMD)
+            .category(BUILTIN_CATEGORY)
+            .set_permission(HISTORY_PERMISSION)
+            .add_description("List recently run commands")
+            .build();
+        let cmd_manager_weak = Arc::downgrade(&cmd_manager);
+        cmd_manager.add_command(history_cmd, move |_| {
+            match cmd_manager_weak.upgrade() {
+                Some(cmd_manager) => {
+                    let history = cmd_manager.get_history();
+                    CmdResult::ok(&history_text(&history))
+                },
+                None => CmdResult::error("Command manager is gone"),
+            }
+        }).unwrap();
+
+        let replay_cmd = CmdBuilder::new(HISTORY_REPLAY_CMD)
+            .category(BUILTIN_CATEGORY)
+            .set_permission(PermissionLevel::Admin)
+            .add_description("Run a command from the history again")
+            .add_arg(ArgBuilder::new("index", ArgType::U64)
+                .add_description("Index shown by 'history'")
+                .build())
+            .build();
+        let cmd_manager_weak = Arc::downgrade(&cmd_manager);
+        cmd_manager.add_command(replay_cmd, move |args| {
+            let cmd_manager = match cmd_manager_weak.upgrade() {
+                Some(cmd_manager) => cmd_manager,
+                None => return CmdResult::error("Command manager is gone"),
+            };
+            // Set by `handle`, the replayed command is checked against the same caller
+            let caller = current_cmd_caller_or_user("unknown");
+            match args.get_u64("index").and_then(|index| cmd_manager.replay(index, &caller)) {
+                Ok(result) => result,
+                Err(err) => CmdResult::error(&err.to_string()),
+            }
+        }).unwrap();
+
+        if let Some(event_emitter) = event_emitter {
+            let audit_cmd = CmdBuilder::new("events.audit")
+                .category(BUILTIN_CATEGORY)
+                .set_permission(PermissionLevel::Admin)
+                .add_description("Enable or disable the event emission audit log")
+                .add_arg(ArgBuilder::new("enabled", ArgType::BOOL)
+                    .add_description("'y' to start auditing, 'n' to stop")
+                    .build())
+                .build();
+            cmd_manager.add_command(audit_cmd, move |args| {
+                let enabled = match args.get_bool("enabled") {
+                    Ok(enabled) => enabled,
+                    Err(err) => return CmdResult::error(&err.to_string()),
+                };
+                event_emitter.set_audit_enabled(enabled);
+                CmdResult::ok(if enabled { "Event audit enabled" } else { "Event audit disabled" })
+            }).unwrap();
+        }
+
+        return cmd_manager;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::events::EventEmitter;
+    use crate::rpc::Rpc;
+    use crate::service::Context;
+    use crate::tasks::TaskManager;
+    use crate::cmd_manager::{ArgBuilder, ArgConstraint, ArgType, ArgValue, ArgsList, CmdBuilder, CmdCaller, CmdError, CmdManager, CmdResult, CommandExecutedEvent, CommandFailedEvent, ExecutionState, PermissionLevel, WizardDefinition, WizardStep};
+
+    #[test]
+    fn test_args_list() {
+        let mut args = ArgsList::new();
+        args.put_u64("count", 3);
+        args.put_string("name", "a".to_string());
+
+        assert_eq!(args.try_get_u64("count"), Some(3));
+        assert_eq!(args.get_string("name").unwrap(), "a");
+        assert_eq!(args.try_get_bool("enabled"), None);
+        assert_eq!(args.try_get_string("count"), None);
+        assert!(matches!(args.get_u64("missing"), Err(CmdError::MissingArgument(name)) if name == "missing"));
+
+        assert!(args.has("count"));
+        assert!(!args.has("enabled"));
+        assert!(args.get_bool_or("enabled", true));
+        assert_eq!(args.get_u64_or("count", 10), 3);
+        args.put_value("enabled", ArgValue::Bool(false));
+        assert!(!args.get_bool("enabled").unwrap());
+
+        let missing = std::panic::catch_unwind(|| args.get_bool_or_panic("verbose")).unwrap_err();
+        assert_eq!(missing.downcast_ref::<String>().unwrap(), "Missing bool argument 'verbose'");
+    }
+
+    #[test]
+    fn test_args_list_deserialize() {
+        let args: ArgsList = serde_json::from_str("{\"u64_list\":{},\"bool_list\":{},\"string_list\":{},\
+            \"i64_list\":{\"offset\":-5},\"f64_list\":{\"volume\":0.5},\"string_list_list\":{\"paths\":[\"/a\",\"/b\"]}}").unwrap();
+        assert_eq!(args.get_i64("offset").unwrap(), -5);
+        assert_eq!(args.get_f64("volume").unwrap(), 0.5);
+        assert_eq!(args.get_string_list("paths").unwrap(), vec!["/a".to_string(), "/b".to_string()]);
+
+        // Requests written before the new types still deserialize
+        let args: ArgsList = serde_json::from_str("{\"u64_list\":{},\"bool_list\":{},\"string_list\":{}}").unwrap();
+        assert!(!args.has("paths"));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let cmd_manager = CmdManager::new();
+        cmd_manager.add_command(CmdBuilder::new("quit").add_alias("q").build(), |_| CmdResult::ok("quit")).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("library.list").add_alias("ls").build(), |_| CmdResult::ok("list")).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("library").build(), |_| CmdResult::empty()).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("library-tools").build(), |_| CmdResult::empty()).unwrap();
+
+        assert_eq!(cmd_manager.handle("ls", &ArgsList::new(), &CmdCaller::local("test")).unwrap().message, "list");
+        assert_eq!(cmd_manager.get_command_description("q").unwrap().call_name, "quit");
+        assert_eq!(cmd_manager.resolve_name("q"), Some("quit".to_string()));
+
+        let err = cmd_manager.add_command(CmdBuilder::new("queue").add_alias("q").build(), |_| CmdResult::empty()).unwrap_err();
+        assert_eq!(err.to_string(), "Name 'q' of command 'queue' is already used by command 'quit'");
+        assert!(cmd_manager.add_command(CmdBuilder::new("list").add_alias("quit").build(), |_| CmdResult::empty()).is_err());
+        assert!(cmd_manager.add_command(CmdBuilder::new("ls").build(), |_| CmdResult::empty()).is_err());
+        assert!(cmd_manager.handle("queue", &ArgsList::new(), &CmdCaller::local("test")).is_err());
+
+        // Replacing a command drops its old aliases
+        cmd_manager.add_command(CmdBuilder::new("quit").add_alias("exit").build(), |_| CmdResult::empty()).unwrap();
+        assert!(cmd_manager.handle("q", &ArgsList::new(), &CmdCaller::local("test")).is_err());
+
+        let description = cmd_manager.get_commands_description();
+        assert_eq!(description.flat_names, vec!["library", "library.list", "library-tools", "quit"]);
+        assert_eq!(description.categories[0].commands[1].aliases, vec!["ls".to_string()]);
+    }
+
+    #[test]
+    fn test_categories() {
+        let cmd_manager = CmdManager::new();
+        cmd_manager.add_command(CmdBuilder::new("quit").build(), |_| CmdResult::empty()).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("player.play").category("Player").build(), |_| CmdResult::empty()).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("library.list").category("Library")
+            .add_description("List the library\nSecond line")
+            .build(), |_| CmdResult::empty()).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("library.scan").category("Library").build(), |_| CmdResult::empty()).unwrap();
+
+        let description = serde_json::to_value(cmd_manager.get_commands_description()).unwrap();
+        assert_eq!(description["flat_names"], serde_json::json!(["library.list", "library.scan", "player.play", "quit"]));
+        let categories: Vec<_> = description["categories"].as_array().unwrap().iter()
+            .map(|category| (category["name"].clone(), category["commands"].as_array().unwrap().len()))
+            .collect();
+        assert_eq!(categories, vec![
+            (serde_json::json!("Library"), 2),
+            (serde_json::json!("Player"), 1),
+            (serde_json::Value::Null, 1),
+        ]);
+        assert_eq!(description["categories"][0]["commands"][0]["description"], "List the library");
+
+        assert!(cmd_manager.remove_command("player.play"));
+        let names: Vec<_> = cmd_manager.get_commands_description().categories.into_iter()
+            .map(|category| category.name)
+            .collect();
+        assert_eq!(names, vec![Some("Library".to_string()), None]);
+    }
+
+    #[test]
+    fn test_help() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        let cmd_manager = context.get_service::<CmdManager>();
+        cmd_manager.add_command(CmdBuilder::new("library.list")
+            .category("Library")
+            .add_description("List the library\nSecond line")
+            .add_alias("ls")
+            .add_arg(ArgBuilder::new("limit", ArgType::U64).add_default(ArgValue::U64(20)).build())
+            .add_arg(ArgBuilder::new("filter", ArgType::STRING).add_description("Substring of the title").build())
+            .build(), |_| CmdResult::empty()).unwrap();
+
+        let result = cmd_manager.handle("help", &ArgsList::new(), &CmdCaller::local("test")).unwrap();
+        assert_eq!(result.message, "Library:\n\
+            \x20 library.list    List the library\n\
+            System:\n\
+            \x20 events.audit    Enable or disable the event emission audit log\n\
+            \x20 help            List commands, or describe one of them\n\
+            \x20 history         List recently run commands\n\
+            \x20 history.replay  Run a command from the history again");
+
+        let mut args = ArgsList::new();
+        args.put_string("cmd", "ls".to_string());
+        let result = cmd_manager.handle("help", &args, &CmdCaller::local("test")).unwrap();
+        assert_eq!(result.message, "library.list (aliases: ls)\nList the library\nSecond line\nArguments:\n\
+            \x20 filter: STRING, required. Substring of the title\n\
+            \x20 limit: U64, optional, default 20");
+
+        args.put_string("cmd", "missing".to_string());
+        assert!(!cmd_manager.handle("help", &args, &CmdCaller::local("test")).unwrap().success);
+    }
+
+    #[test]
+    fn test_remove_commands() {
+        let cmd_manager = CmdManager::new();
+        cmd_manager.add_command(CmdBuilder::new("quit").add_alias("q").build(), |_| CmdResult::ok("quit")).unwrap();
+        assert!(cmd_manager.remove_command("quit"));
+        assert!(!cmd_manager.remove_command("quit"));
+        assert_eq!(cmd_manager.resolve_name("q"), None);
+        assert!(cmd_manager.get_commands_description().flat_names.is_empty());
+        assert!(cmd_manager.get_commands_description().categories.is_empty());
+
+        assert!(matches!(cmd_manager.replace_command(CmdBuilder::new("quit").build(), |_| CmdResult::empty()),
+            Err(CmdError::UnknownCommand(_))));
+
+        let registration = cmd_manager.add_command_scoped(CmdBuilder::new("plugin.run").add_alias("run").build(),
+            |_| CmdResult::ok("first")).unwrap();
+        assert_eq!(cmd_manager.handle("run", &ArgsList::new(), &CmdCaller::local("test")).unwrap().message, "first");
+        drop(registration);
+        assert!(cmd_manager.handle("run", &ArgsList::new(), &CmdCaller::local("test")).is_err());
+        assert!(cmd_manager.handle("plugin.run", &ArgsList::new(), &CmdCaller::local("test")).is_err());
+
+        // A registration doesn't remove a command that replaced its own
+        let registration = cmd_manager.add_command_scoped(CmdBuilder::new("plugin.run").build(), |_| CmdResult::ok("first")).unwrap();
+        cmd_manager.replace_command(CmdBuilder::new("plugin.run").build(), |_| CmdResult::ok("second")).unwrap();
+        drop(registration);
+        assert_eq!(cmd_manager.handle("plugin.run", &ArgsList::new(), &CmdCaller::local("test")).unwrap().message, "second");
+    }
+
+    #[test]
+    fn test_optional_args() {
+        let limit = ArgBuilder::new("limit", ArgType::U64)
+            .add_default(ArgValue::U64(20))
+            .build();
+        assert!(!limit.required);
+        assert_eq!(serde_json::to_value(&limit).unwrap()["default"], serde_json::json!(20));
+
+        let filter = ArgBuilder::new("filter", ArgType::STRING).optional().build();
+        assert!(!filter.required);
+        assert_eq!(filter.default, None);
+        assert!(ArgBuilder::new("name", ArgType::STRING).build().required);
+    }
+
+    #[test]
+    fn test_description_json() {
+        let description = CmdBuilder::new("player.play")
+            .add_description("Play a track")
+            .add_arg(ArgBuilder::new("track", ArgType::STRING).build())
+            .add_arg(ArgBuilder::new("mode", ArgType::STRING)
+                .add_description("Playback mode")
+                .add_options(&["once", "repeat"])
+                .add_default(ArgValue::String("once".to_string()))
+                .build())
+            .build();
+
+        assert_eq!(serde_json::to_value(&description).unwrap(), serde_json::json!({
+            "call_name": "player.play",
+            "description": "Play a track",
+            "args": {
+                "mode": {
+                    "call_name": "mode",
+                    "description": "Playback mode",
+                    "arg_type": "STRING",
+                    "required": false,
+                    "default": "once",
+                    "options": ["once", "repeat"],
+                    "constraints": [],
+                },
+                "track": {
+                    "call_name": "track",
+                    "description": null,
+                    "arg_type": "STRING",
+                    "required": true,
+                    "default": null,
+                    "options": [],
+                    "constraints": [],
+                },
+            },
+            "aliases": [],
+            "permission": "user",
+            "category": null,
+            "emit_events": true,
+        }));
+    }
+
+    #[test]
+    fn test_validators() {
+        let cmd_manager = CmdManager::new();
+        cmd_manager.add_command(CmdBuilder::new("server.listen")
+            .add_arg(ArgBuilder::new("port", ArgType::U64).with_constraint(ArgConstraint::range(1.0, 65535.0)).build())
+            .add_arg(ArgBuilder::new("host", ArgType::STRING)
+                .with_constraint(ArgConstraint::non_empty())
+                .with_validator("without a scheme", |value| match value {
+                    ArgValue::String(host) if host.contains("://") => Err("must not contain a scheme".to_string()),
+                    _ => Ok(()),
+                })
+                .build())
+            .add_arg(ArgBuilder::new("backlog", ArgType::U64).with_constraint(ArgConstraint::range(1.0, 10.0)).optional().build())
+            .build(), |_| CmdResult::ok("listening")).unwrap();
+
+        let description = serde_json::to_value(cmd_manager.get_command_description("server.listen").unwrap()).unwrap();
+        assert_eq!(description["args"]["port"]["constraints"], serde_json::json!(["between 1 and 65535"]));
+        assert_eq!(description["args"]["host"]["constraints"], serde_json::json!(["not empty", "without a scheme"]));
+
+        let mut args = ArgsList::new();
+        args.put_u64("port", 0);
+        args.put_string("host", "http://".to_string());
+        let err = cmd_manager.handle("server.listen", &args, &CmdCaller::local("test")).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid arguments of command 'server.listen': \
+            host: must not contain a scheme; port: must be between 1 and 65535");
+        assert!(cmd_manager.get_history().is_empty());
+
+        args.put_u64("port", 8080);
+        args.put_string("host", " ".to_string());
+        assert!(matches!(cmd_manager.handle("server.listen", &args, &CmdCaller::local("test")),
+            Err(CmdError::InvalidArguments { errors, .. }) if errors == vec!["host: must not be empty".to_string()]));
+
+        args.put_string("host", "localhost".to_string());
+        assert_eq!(cmd_manager.handle("server.listen", &args, &CmdCaller::local("test")).unwrap().message, "listening");
+    }
+
+    #[test]
+    fn test_handle_checks_args() {
+        let cmd_manager = CmdManager::new();
+        cmd_manager.add_command(CmdBuilder::new("player.play")
+            .add_arg(ArgBuilder::new("track", ArgType::STRING).build())
+            .add_arg(ArgBuilder::new("mode", ArgType::STRING)
+                .add_options(&["once", "repeat"])
+                .add_default(ArgValue::String("once".to_string()))
+                .build())
+            .build(), |args| CmdResult::ok(&format!("{} {}", args.get_string("track").unwrap(), args.get_string("mode").unwrap()))).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("player.seek")
+            .add_arg(ArgBuilder::new("position", ArgType::U64).add_default(ArgValue::I64(0)).build())
+            .build(), |_| CmdResult::empty()).unwrap();
+        let caller = CmdCaller::local("test");
+        let invalid_args = |result: Result<CmdResult, CmdError>| match result {
+            Err(CmdError::InvalidArguments { errors, .. }) => errors,
+            other => panic!("Unexpected result {:?}", other),
+        };
+
+        // Omitted arguments get their default, as from the CLI parser
+        let mut args = ArgsList::new();
+        args.put_string("track", "intro".to_string());
+        assert_eq!(cmd_manager.handle("player.play", &args, &caller).unwrap().message, "intro once");
+        assert_eq!(cmd_manager.get_history()[0].args["mode"], "once");
+
+        assert_eq!(invalid_args(cmd_manager.handle("player.play", &ArgsList::new(), &caller)), vec!["track: missing".to_string()]);
+        args.put_string("mode", "shuffle".to_string());
+        assert_eq!(invalid_args(cmd_manager.handle("player.play", &args, &caller)), vec!["mode: must be one of once, repeat".to_string()]);
+        let mut args = ArgsList::new();
+        args.put_u64("track", 3);
+        assert_eq!(invalid_args(cmd_manager.handle("player.play", &args, &caller)), vec!["track: must be of type STRING".to_string()]);
+        assert_eq!(invalid_args(cmd_manager.handle("player.seek", &ArgsList::new(), &caller)), vec!["position: default isn't of type U64".to_string()]);
+    }
+
+    #[test]
+    fn test_validator_registers_command() {
+        let cmd_manager = Arc::new(CmdManager::new());
+        let registrar = Arc::downgrade(&cmd_manager);
+        cmd_manager.add_command(CmdBuilder::new("plugin.load")
+            .add_arg(ArgBuilder::new("name", ArgType::STRING)
+                .with_validator("a known plugin", move |value| {
+                    let cmd_manager = registrar.upgrade().ok_or("no command manager")?;
+                    let ArgValue::String(plugin) = value else { return Err("must be a string".to_string()) };
+                    let name = format!("{}.run", plugin);
+                    if cmd_manager.resolve_name(&name).is_none() {
+                        cmd_manager.add_command(CmdBuilder::new(&name).build(), |_| CmdResult::ok("ran"))
+                            .map_err(|err| err.to_string())?;
+                    }
+                    Ok(())
+                })
+                .build())
+            .build(), |_| CmdResult::ok("loaded")).unwrap();
+
+        let mut args = ArgsList::new();
+        args.put_string("name", "radio".to_string());
+        assert_eq!(cmd_manager.handle("plugin.load", &args, &CmdCaller::local("test")).unwrap().message, "loaded");
+        assert_eq!(cmd_manager.handle("radio.run", &ArgsList::new(), &CmdCaller::local("test")).unwrap().message, "ran");
+    }
+
+    #[test]
+    fn test_wizards() {
+        let cmd_manager = CmdManager::new();
+        let profile_step = |answers: &ArgsList| {
+            let profiles: &[&str] = match answers.get_string("device").unwrap().as_str() {
+                "speaker" => &["loud", "quiet"],
+                _ => &["stereo"],
+            };
+            Some(WizardStep::new("profile", "Pick a profile", ArgType::STRING).add_options(profiles))
+        };
+        cmd_manager.add_wizard("device.setup", WizardDefinition::new(
+            WizardStep::new("device", "Pick a device", ArgType::STRING)
+                .add_options(&["speaker", "headphones"])
+                .then(profile_step),
+            |answers| CmdResult::ok(&format!("{} uses {}", answers.get_string("device").unwrap(), answers.get_string("profile").unwrap())),
+        ).set_permission(PermissionLevel::Admin)).unwrap();
+        let admin = CmdCaller::new("web", PermissionLevel::Admin);
+
+        let prompt = cmd_manager.handle("device.setup", &ArgsList::new(), &admin).unwrap().wizard_prompt().unwrap();
+        assert_eq!((prompt.step.as_str(), prompt.prompt.as_str()), ("device", "Pick a device"));
+        let token = prompt.token;
+        assert!(matches!(cmd_manager.wizard_answer(&token, "tv", &admin), Err(CmdError::InvalidWizardAnswer { .. })));
+        assert!(matches!(cmd_manager.wizard_answer(&token, "speaker", &CmdCaller::new("web", PermissionLevel::User)),
+            Err(CmdError::UnknownWizardSession(_))));
+        // Another admin can't drive or cancel the session
+        let other_admin = CmdCaller::new("other", PermissionLevel::Admin);
+        assert!(matches!(cmd_manager.wizard_answer(&token, "speaker", &other_admin), Err(CmdError::UnknownWizardSession(_))));
+        assert!(matches!(cmd_manager.wizard_cancel(&token, &other_admin), Err(CmdError::UnknownWizardSession(_))));
+        assert!(token.len() > 30);
+
+        let prompt = cmd_manager.wizard_answer(&token, "speaker", &admin).unwrap().wizard_prompt().unwrap();
+        assert_eq!(prompt.options, vec!["loud", "quiet"]);
+        let result = cmd_manager.wizard_answer(&token, "quiet", &admin).unwrap();
+        assert_eq!(result.message, "speaker uses quiet");
+        assert_eq!(result.wizard_prompt(), None);
+        assert!(matches!(cmd_manager.wizard_answer(&token, "quiet", &admin), Err(CmdError::UnknownWizardSession(_))));
+
+        cmd_manager.set_max_wizard_sessions(1);
+        let token = cmd_manager.handle("device.setup", &ArgsList::new(), &admin).unwrap().wizard_prompt().unwrap().token;
+        let result = cmd_manager.handle("device.setup", &ArgsList::new(), &admin).unwrap();
+        assert!(!result.success);
+        cmd_manager.wizard_cancel(&token, &admin).unwrap();
+        assert!(cmd_manager.wizard_cancel(&token, &admin).is_err());
+
+        let token = cmd_manager.handle("device.setup", &ArgsList::new(), &admin).unwrap().wizard_prompt().unwrap().token;
+        cmd_manager.set_wizard_idle_timeout(std::time::Duration::ZERO);
+        assert!(matches!(cmd_manager.wizard_answer(&token, "speaker", &admin), Err(CmdError::UnknownWizardSession(_))));
+    }
+
+    #[test]
+    fn test_command_events() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        let cmd_manager = context.get_service::<CmdManager>();
+        cmd_manager.add_command(CmdBuilder::new("library.rescan").build(), |_| CmdResult::ok("rescanned")).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("library.remove").build(), |_| CmdResult::error("Not found")).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("account.login").set_emit_events(false).build(), |_| CmdResult::empty()).unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let failed_sender = std::sync::Mutex::new(sender.clone());
+        let sender = std::sync::Mutex::new(sender);
+        let event_emitter = context.get_service::<EventEmitter>();
+        event_emitter.on_event_fn(move |event: &CommandExecutedEvent| {
+            sender.lock().unwrap().send(format!("executed {} {}", event.name, event.success)).unwrap();
+        });
+        event_emitter.on_event_fn(move |event: &CommandFailedEvent| {
+            failed_sender.lock().unwrap().send(format!("failed {}: {}", event.name, event.error)).unwrap();
+        });
+
+        let caller = CmdCaller::new("test", PermissionLevel::User);
+        let timeout = std::time::Duration::from_secs(5);
+        cmd_manager.handle("account.login", &ArgsList::new(), &caller).unwrap();
+        cmd_manager.handle("library.rescan", &ArgsList::new(), &caller).unwrap();
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), "executed library.rescan true");
+        cmd_manager.handle("library.remove", &ArgsList::new(), &caller).unwrap();
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), "failed library.remove: Not found");
+        assert!(cmd_manager.handle("history", &ArgsList::new(), &caller).is_err());
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(),
+            "failed history: Command 'history' requires 'admin' permission, the caller has 'user'");
+        assert!(receiver.recv_timeout(std::time::Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        let cmd_manager = context.get_service::<CmdManager>();
+        let count = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let count_copy = count.clone();
+        cmd_manager.add_command(CmdBuilder::new("library.scan").build(), move |args| {
+            count_copy.fetch_add(args.get_u64_or("depth", 1), std::sync::atomic::Ordering::Relaxed);
+            CmdResult::empty()
+        }).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("account.login").build(), |_| CmdResult::error("Wrong password")).unwrap();
+
+        let mut args = ArgsList::new();
+        args.put_u64("depth", 2);
+        args.put_bool("quick", true);
+        cmd_manager.handle("library.scan", &args, &CmdCaller::local("test")).unwrap();
+        let mut args = ArgsList::new();
+        args.put_string("user", "admin".to_string());
+        args.put_string("Password", "hunter2".to_string());
+        cmd_manager.handle("account.login", &args, &CmdCaller::local("test")).unwrap();
+
+        let history = cmd_manager.get_history();
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].index, history[0].command.as_str(), history[0].success), (0, "library.scan", true));
+        assert_eq!(history[0].args["depth"], "2");
+        assert_eq!(history[0].args["quick"], "y");
+        assert!(!history[1].success);
+        assert_eq!(history[1].args["Password"], "<redacted>");
+        assert_eq!(history[1].args["user"], "admin");
+
+        let mut args = ArgsList::new();
+        args.put_u64("index", 0);
+        assert!(cmd_manager.handle("history.replay", &args, &CmdCaller::local("test")).unwrap().success);
+        assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 4);
+        assert!(matches!(cmd_manager.replay(1, &CmdCaller::local("test")), Err(CmdError::RedactedHistoryEntry(1))));
+        assert!(matches!(cmd_manager.replay(9, &CmdCaller::local("test")), Err(CmdError::UnknownHistoryEntry(9))));
+
+        // The replay is recorded, browsing the history isn't
+        let text = cmd_manager.handle("history", &ArgsList::new(), &CmdCaller::local("test")).unwrap().message;
+        assert!(text.lines().last().unwrap().starts_with("   2  library.scan depth:2 quick:y  (ok, "), "{}", text);
+
+        cmd_manager.set_history_size(1);
+        assert_eq!(cmd_manager.get_history()[0].index, 2);
+        let mut size = crate::settings::Property::new("cmd_manager.history_size", 0, Default::default());
+        let _subscription = cmd_manager.bind_history_size(&size);
+        assert!(cmd_manager.get_history().is_empty());
+        size.set(5);
+        cmd_manager.handle("library.scan", &ArgsList::new(), &CmdCaller::local("test")).unwrap();
+        assert_eq!(cmd_manager.get_history().len(), 1);
+    }
+
+    #[test]
+    fn test_permissions() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        let cmd_manager = context.get_service::<CmdManager>();
+        cmd_manager.add_command(CmdBuilder::new("library.list").build(), |_| CmdResult::ok("list")).unwrap();
+        cmd_manager.add_command(CmdBuilder::new("server.shutdown").set_permission(PermissionLevel::Local).build(),
+            |_| CmdResult::ok("shutdown")).unwrap();
+
+        let user = CmdCaller::new("192.168.1.5", PermissionLevel::User);
+        let admin = CmdCaller::new("192.168.1.6", PermissionLevel::Admin);
+        assert_eq!(cmd_manager.handle("library.list", &ArgsList::new(), &user).unwrap().message, "list");
+        let err = cmd_manager.handle("server.shutdown", &ArgsList::new(), &admin).unwrap_err();
+        assert!(matches!(&err, CmdError::PermissionDenied { required: PermissionLevel::Local, actual: PermissionLevel::Admin, .. }));
+        assert_eq!(err.to_string(), "Command 'server.shutdown' requires 'local' permission, the caller has 'admin'");
+        assert_eq!(serde_json::to_value(&err).unwrap()["PermissionDenied"]["required"], "local");
+
+        // A replay is checked against the caller of `history.replay`
+        cmd_manager.handle("server.shutdown", &ArgsList::new(), &CmdCaller::local("cli")).unwrap();
+        let mut args = ArgsList::new();
+        args.put_u64("index", 1);
+        assert!(!cmd_manager.handle("history.replay", &args, &admin).unwrap().success);
+        assert!(cmd_manager.handle("history.replay", &args, &CmdCaller::local("cli")).unwrap().success);
+
+        let rpc_gate = context.get_service::<crate::rpc::RpcGate>();
+        let request = "{\"cmd_name\":\"events.audit\",\"args\":{\"u64_list\":{},\"bool_list\":{\"enabled\":false},\"string_list\":{}}}";
+        assert!(rpc_gate.call_raw("amina.cmd_manager.handle", request).contains("PermissionDenied"));
+        let response = crate::cmd_manager::with_cmd_caller(Some(admin.clone()), || rpc_gate.call_raw("amina.cmd_manager.handle", request));
+        assert!(response.contains("Event audit disabled"), "{}", response);
+
+        // The history RPC needs the same level as the `history` command
+        assert!(rpc_gate.call_raw("amina.cmd_manager.get_history", "{}").contains("PermissionDenied"));
+        let response = crate::cmd_manager::with_cmd_caller(Some(admin), || rpc_gate.call_raw("amina.cmd_manager.get_history", "{}"));
+        assert!(response.contains("server.shutdown"), "{}", response);
+
+        let description = serde_json::to_value(cmd_manager.get_commands_description()).unwrap();
+        let shutdown = description["categories"].as_array().unwrap().iter()
+            .flat_map(|category| category["commands"].as_array().unwrap())
+            .find(|command| command["name"] == "server.shutdown")
+            .unwrap();
+        assert_eq!(shutdown["permission"], "local");
+    }
+
+    #[test]
+    fn test_handle_result() {
+        let cmd_manager = CmdManager::new();
+        cmd_manager.add_command(CmdBuilder::new("test.count").build(), |_| {
+            CmdResult::ok("Counted").with_payload(&vec![1, 2])
+        }).unwrap();
+        cmd_manager.add_command_unit(CmdBuilder::new("test.unit").build(), |_| {}).unwrap();
+
+        let result = cmd_manager.handle("test.count", &ArgsList::new(), &CmdCaller::local("test")).unwrap();
+        assert!(result.success);
+        assert_eq!(result.message, "Counted");
+        assert_eq!(result.payload, Some(serde_json::json!([1, 2])));
+        assert_eq!(cmd_manager.handle("test.unit", &ArgsList::new(), &CmdCaller::local("test")).unwrap(), CmdResult::empty());
+
+        let err = cmd_manager.handle("test.missing", &ArgsList::new(), &CmdCaller::local("test")).unwrap_err();
+        assert!(matches!(err, CmdError::UnknownCommand(_)));
+        assert_eq!(err.to_string(), "Unknown command 'test.missing'");
+
+        let by_pair: std::collections::HashMap<(i32, i32), i32> = vec![((1, 2), 3)].into_iter().collect();
+        let result = CmdResult::ok("Counted").with_payload(&by_pair);
+        assert!(!result.success);
+        assert_eq!(result.payload, None);
+        assert_eq!(result.message, "Can't serialize the command payload: key must be a string");
+        assert!(cmd_manager.get_command_description("test.missing").is_err());
+    }
+
+    #[test]
+    fn test_async_command() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        let cmd_manager = context.get_service::<CmdManager>();
+        let (reported_tx, reported_rx) = std::sync::mpsc::sync_channel(1);
+        let reported_tx = std::sync::Mutex::new(reported_tx);
+        cmd_manager.add_async_command(CmdBuilder::new("library.scan").build(), move |_, task_context, progress| {
+            progress.report(50, "Halfway");
+            reported_tx.lock().unwrap().send(()).unwrap();
+            while !task_context.is_interrupted() {
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+            CmdResult::ok("Stopped")
+        }).unwrap();
+        assert!(matches!(CmdManager::new().add_async_command(CmdBuilder::new("library.scan").build(), |_, _, _| CmdResult::empty()),
+            Err(CmdError::AsyncUnavailable(_))));
+
+        let result = cmd_manager.handle("library.scan", &ArgsList::new(), &CmdCaller::local("test")).unwrap();
+        let execution_id = result.payload.unwrap()["execution_id"].as_str().unwrap().to_string();
+        reported_rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        let status = cmd_manager.get_execution_status(&execution_id).unwrap();
+        assert_eq!(status.state, ExecutionState::Running);
+        assert_eq!((status.percent, status.message.as_str()), (50, "Halfway"));
+
+        cmd_manager.cancel_execution(&execution_id).unwrap();
+        let mut status = cmd_manager.get_execution_status(&execution_id).unwrap();
+        for _ in 0..500 {
+            if status.state != ExecutionState::Running {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            status = cmd_manager.get_execution_status(&execution_id).unwrap();
+        }
+        assert_eq!(status.state, ExecutionState::Cancelled);
+        assert_eq!(status.result, Some(CmdResult::ok("Stopped")));
+
+        assert!(matches!(cmd_manager.get_execution_status("exec-999"), Err(CmdError::UnknownExecution(_))));
+        assert!(cmd_manager.cancel_execution("missing").is_err());
+    }
+
+    #[test]
+    fn test_without_optional_services() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        let cmd_manager = context.get_service::<CmdManager>();
+
+        assert!(cmd_manager.handle("help", &ArgsList::new(), &CmdCaller::local("test")).unwrap().success);
+        assert!(matches!(cmd_manager.get_command_description("events.audit"), Err(CmdError::UnknownCommand(_))));
+        assert!(matches!(cmd_manager.add_async_command(CmdBuilder::new("library.scan").build(), |_, _, _| CmdResult::empty()),
+            Err(CmdError::AsyncUnavailable(_))));
+    }
+}
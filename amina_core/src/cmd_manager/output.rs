@@ -0,0 +1,142 @@
+use serde::{Serialize, Deserialize};
+
+/// Narrowest a column gets when a table is squeezed to fit the terminal.
+const MIN_COLUMN_WIDTH: usize = 4;
+const COLUMN_SEPARATOR: &str = "  ";
+
+/// Structured command output, attached to a `CmdResult` with `with_output`. RPC callers get it
+/// as the payload, the CLI renders it with `render`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CmdOutput {
+    Table {
+        columns: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+    KeyValue {
+        entries: Vec<(String, String)>,
+    },
+    Text {
+        text: String,
+    },
+}
+
+impl CmdOutput {
+
+    pub fn table(columns: Vec<&str>) -> Self {
+        CmdOutput::Table {
+            columns: columns.into_iter().map(str::to_string).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn keyvalue<K: ToString, V: ToString>(entries: Vec<(K, V)>) -> Self {
+        CmdOutput::KeyValue {
+            entries: entries.into_iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    pub fn text(text: &str) -> Self {
+        CmdOutput::Text {
+            text: text.to_string(),
+        }
+    }
+
+    /// Appends a table row, padded with empty cells or cut to the number of columns.
+    /// Does nothing for other outputs.
+    pub fn row<T: ToString>(mut self, cells: Vec<T>) -> Self {
+        if let CmdOutput::Table { columns, rows } = &mut self {
+            let mut row: Vec<String> = cells.iter().map(ToString::to_string).collect();
+            row.resize(columns.len(), String::new());
+            rows.push(row);
+        }
+        self
+    }
+
+    /// Renders for a terminal `max_width` characters wide. Table columns are aligned, and the
+    /// widest columns are shrunk until the table fits, cutting long cells with '…'.
+    pub fn render(&self, max_width: usize) -> String {
+        match self {
+            CmdOutput::Table { columns, rows } => {
+                let mut lines = vec![columns.clone()];
+                lines.extend(rows.iter().cloned());
+                render_columns(&lines, max_width)
+            },
+            CmdOutput::KeyValue { entries } => {
+                let lines: Vec<Vec<String>> = entries.iter()
+                    .map(|(key, value)| vec![format!("{}:", key), value.clone()])
+                    .collect();
+                render_columns(&lines, max_width)
+            },
+            CmdOutput::Text { text } => text.clone(),
+        }
+    }
+
+}
+
+fn render_columns(lines: &[Vec<String>], max_width: usize) -> String {
+    let columns_count = lines.iter().map(Vec::len).max().unwrap_or(0);
+    let mut widths = vec![0; columns_count];
+    for line in lines {
+        for (i, cell) in line.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+    let separators = COLUMN_SEPARATOR.len() * columns_count.saturating_sub(1);
+    while widths.iter().sum::<usize>() + separators > max_width {
+        let widest = widths.iter_mut().max().unwrap();
+        if *widest <= MIN_COLUMN_WIDTH {
+            break;
+        }
+        *widest -= 1;
+    }
+
+    let rendered: Vec<String> = lines.iter().map(|line| {
+        let cells: Vec<String> = line.iter().enumerate().map(|(i, cell)| {
+            format!("{:<width$}", truncate(cell, widths[i]), width = widths[i])
+        }).collect();
+        cells.join(COLUMN_SEPARATOR).trim_end().to_string()
+    }).collect();
+    rendered.join("\n")
+}
+
+fn truncate(cell: &str, width: usize) -> String {
+    if cell.chars().count() <= width {
+        return cell.to_string();
+    }
+    let mut truncated: String = cell.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd_manager::{CmdOutput, CmdResult};
+
+    #[test]
+    fn test_render() {
+        let table = CmdOutput::table(vec!["Name", "Size"])
+            .row(vec!["intro.mp3", "3"])
+            .row(vec!["a", "12345", "extra"])
+            .row(vec!["b"]);
+        assert_eq!(table.render(80), "Name       Size\nintro.mp3  3\na          12345\nb");
+        assert_eq!(table.render(11), "Name   Size\nintr…  3\na      123…\nb");
+
+        let keyvalue = CmdOutput::keyvalue(vec![("host", "localhost"), ("port", "8080")]);
+        assert_eq!(keyvalue.render(80), "host:  localhost\nport:  8080");
+        assert_eq!(CmdOutput::text("done").render(2), "done");
+    }
+
+    #[test]
+    fn test_payload() {
+        let table = CmdOutput::table(vec!["Name"]).row(vec![1]);
+        let result = CmdResult::ok("Listed").with_output(&table);
+        assert_eq!(result.payload, Some(serde_json::json!({
+            "type": "table",
+            "columns": ["Name"],
+            "rows": [["1"]],
+        })));
+        assert_eq!(result.output(), Some(table));
+        assert_eq!(CmdResult::ok("").with_payload(&vec![1]).output(), None);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::cmd_manager::args_parser::{parse_value, ParseError};
+use crate::cmd_manager::{with_cmd_caller, ArgType, ArgsList, CmdCaller, CmdError, CmdHandler, CmdResult, PermissionLevel};
+
+pub const DEFAULT_WIZARD_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub const DEFAULT_MAX_WIZARD_SESSIONS: usize = 16;
+/// Random bytes in a session token.
+const TOKEN_LEN: usize = 16;
+
+/// Computes the step after the answers so far, `None` finishes the wizard.
+pub type WizardNext = Arc<dyn Fn(&ArgsList) -> Option<WizardStep> + Send + Sync + 'static>;
+
+/// Question of a wizard, its answer is stored in the arguments under the step name.
+#[derive(Clone)]
+pub struct WizardStep {
+    name: String,
+    prompt: String,
+    arg_type: ArgType,
+    options: Vec<String>,
+    next: Option<WizardNext>,
+}
+
+impl WizardStep {
+
+    pub fn new(name: &str, prompt: &str, arg_type: ArgType) -> Self {
+        Self {
+            name: name.to_string(),
+            prompt: prompt.to_string(),
+            arg_type,
+            options: Vec::new(),
+            next: None,
+        }
+    }
+
+    /// Restricts the answer to one of `options`.
+    pub fn add_options(mut self, options: &[&str]) -> Self {
+        self.options = options.iter().map(|option| option.to_string()).collect();
+        self
+    }
+
+    /// Without it the wizard finishes after this step.
+    pub fn then<F>(mut self, next: F) -> Self where
+        F: Fn(&ArgsList) -> Option<WizardStep> + Send + Sync + 'static
+    {
+        self.next = Some(Arc::new(next));
+        self
+    }
+
+}
+
+/// Registered with `CmdManager::add_wizard`, `finish` gets the answers of every step.
+pub struct WizardDefinition {
+    first_step: WizardStep,
+    finish: CmdHandler,
+    pub(crate) description: Option<String>,
+    pub(crate) permission: PermissionLevel,
+}
+
+impl WizardDefinition {
+
+    pub fn new<F>(first_step: WizardStep, finish: F) -> Self where
+        F: Fn(&ArgsList) -> CmdResult + Send + Sync + 'static
+    {
+        Self {
+            first_step,
+            finish: Arc::new(finish),
+            description: None,
+            permission: PermissionLevel::User,
+        }
+    }
+
+    pub fn add_description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// Needed to start the wizard and to answer or cancel its steps.
+    pub fn set_permission(mut self, permission: PermissionLevel) -> Self {
+        self.permission = permission;
+        self
+    }
+
+}
+
+/// Payload of a result asking for the next answer, see `CmdManager::wizard_answer`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WizardPrompt {
+    pub token: String,
+    pub wizard: String,
+    pub step: String,
+    pub prompt: String,
+    pub arg_type: ArgType,
+    pub options: Vec<String>,
+}
+
+struct Session {
+    wizard: String,
+    definition: Arc<WizardDefinition>,
+    step: WizardStep,
+    answers: ArgsList,
+    caller: CmdCaller,
+    last_active: Instant,
+}
+
+impl Session {
+    fn prompt(&self, token: &str) -> CmdResult {
+        let prompt = WizardPrompt {
+            token: token.to_string(),
+            wizard: self.wizard.clone(),
+            step: self.step.name.clone(),
+            prompt: self.step.prompt.clone(),
+            arg_type: self.step.arg_type.clone(),
+            options: self.step.options.clone(),
+        };
+        CmdResult::ok(&prompt.prompt).with_payload(&prompt)
+    }
+}
+
+struct Sessions {
+    sessions: HashMap<String, Session>,
+    idle_timeout: Duration,
+    max_sessions: usize,
+}
+
+impl Sessions {
+    fn remove_expired(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        self.sessions.retain(|_, session| session.last_active.elapsed() < idle_timeout);
+    }
+}
+
+/// Wizard sessions of a `CmdManager`. Sessions are taken out of the map while their
+/// callbacks run, so callbacks may use the command manager.
+pub(crate) struct Wizards {
+    sessions: Mutex<Sessions>,
+}
+
+impl Wizards {
+
+    pub fn new() -> Self {
+        Self {
+            sessions: Mutex::new(Sessions {
+                sessions: HashMap::new(),
+                idle_timeout: DEFAULT_WIZARD_IDLE_TIMEOUT,
+                max_sessions: DEFAULT_MAX_WIZARD_SESSIONS,
+            }),
+        }
+    }
+
+    pub fn set_idle_timeout(&self, idle_timeout: Duration) {
+        self.sessions.lock().unwrap().idle_timeout = idle_timeout;
+    }
+
+    pub fn set_max_sessions(&self, max_sessions: usize) {
+        self.sessions.lock().unwrap().max_sessions = max_sessions;
+    }
+
+    pub fn start(&self, wizard: &str, definition: Arc<WizardDefinition>, caller: CmdCaller) -> Result<CmdResult, CmdError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.remove_expired();
+        if sessions.sessions.len() >= sessions.max_sessions {
+            return Err(CmdError::WizardLimitReached(sessions.max_sessions));
+        }
+        let token = generate_token();
+        let session = Session {
+            wizard: wizard.to_string(),
+            step: definition.first_step.clone(),
+            definition,
+            answers: ArgsList::new(),
+            caller,
+            last_active: Instant::now(),
+        };
+        let result = session.prompt(&token);
+        sessions.sessions.insert(token, session);
+        Ok(result)
+    }
+
+    /// Returns the next prompt, or the result of the wizard after the last step. An invalid
+    /// answer fails with `InvalidWizardAnswer` and the step can be answered again.
+    pub fn answer(&self, token: &str, answer: &str, caller: &CmdCaller) -> Result<CmdResult, CmdError> {
+        let mut session = self.take(token, caller)?;
+        session.last_active = Instant::now();
+
+        let value = parse_value(&session.step.name, answer, &session.step.arg_type)
+            .map_err(|err| err.to_string())
+            .and_then(|value| {
+                if session.step.options.is_empty() || session.step.options.iter().any(|option| option == answer) {
+                    Ok(value)
+                } else {
+                    Err(ParseError::NotAnOption {
+                        arg: session.step.name.clone(),
+                        value: answer.to_string(),
+                        options: session.step.options.clone(),
+                    }.to_string())
+                }
+            });
+        let value = match value {
+            Ok(value) => value,
+            Err(reason) => {
+                self.put_back(token, session);
+                return Err(CmdError::InvalidWizardAnswer {
+                    token: token.to_string(),
+                    reason,
+                });
+            },
+        };
+        session.answers.put_value(&session.step.name, value);
+
+        match session.step.next.clone().and_then(|next| next(&session.answers)) {
+            Some(step) => {
+                session.step = step;
+                let result = session.prompt(token);
+                self.put_back(token, session);
+                Ok(result)
+            },
+            None => {
+                // As the caller who started the wizard
+                let finish = session.definition.finish.clone();
+                let answers = session.answers;
+                Ok(with_cmd_caller(Some(session.caller), || finish(&answers)))
+            },
+        }
+    }
+
+    pub fn cancel(&self, token: &str, caller: &CmdCaller) -> Result<(), CmdError> {
+        self.take(token, caller).map(|_| ())
+    }
+
+    /// Only the caller who started a session may use it. Other sessions look unknown,
+    /// so tokens can't be probed.
+    fn take(&self, token: &str, caller: &CmdCaller) -> Result<Session, CmdError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.remove_expired();
+        match sessions.sessions.get(token) {
+            Some(session) if session.caller.origin == caller.origin && caller.level >= session.definition.permission => {
+                Ok(sessions.sessions.remove(token).unwrap())
+            },
+            _ => Err(CmdError::UnknownWizardSession(token.to_string())),
+        }
+    }
+
+    fn put_back(&self, token: &str, session: Session) {
+        self.sessions.lock().unwrap().sessions.insert(token.to_string(), session);
+    }
+
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("wizard-{}", hex)
+}
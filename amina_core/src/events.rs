@@ -1,7 +1,14 @@
-use std::sync::{Arc, RwLock};
+use std::any::Any;
+use std::cell::Cell;
+use std::panic::AssertUnwindSafe;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::ops::Deref;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
+use crate::metrics::KeyedCounters;
+use crate::rpc::{current_request_id, with_request_id, EmptyData, Rpc};
 use crate::service::{ServiceApi, ServiceInitializer, Context, Service};
 use crate::tasks::TaskManager;
 
@@ -23,13 +30,81 @@ pub trait Event: Send + Sync {
 }
 
 pub struct Listener {
+    /// Identifies the listener for `remove_raw_listener`.
+    id: u64,
     handler: Box<dyn Fn(&str) + Sync + Send + 'static>,
+    /// Runs the handler on the calling thread, used by `emit_event_blocking`.
+    blocking_handler: Arc<dyn Fn(&str) + Sync + Send + 'static>,
 }
 
+pub enum EventFilter {
+    All,
+    Prefix(String),
+    Keys(Vec<String>),
+}
+
+impl EventFilter {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            EventFilter::All => true,
+            EventFilter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            EventFilter::Keys(keys) => keys.iter().any(|k| k == key),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditRecord {
+    pub sequence: u64,
+    pub key: String,
+    pub payload_len: usize,
+    pub thread_name: String,
+    pub listener_count: usize,
+}
+
+pub type AuditSink = Box<dyn Fn(AuditRecord) + Sync + Send + 'static>;
+
+struct EventAudit {
+    filter: EventFilter,
+    sink: AuditSink,
+}
+
+type OrderedJob = Box<dyn FnOnce() + Send + 'static>;
+
+/// Event kept by `EventEmitter::set_replay_capacity`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecentEvent {
+    /// Raw JSON data of the event.
+    pub payload: String,
+    pub timestamp_ms: u64,
+}
+
+/// Last emitted events per key, kept for clients that subscribe late.
+#[derive(Default)]
+struct RecentEvents {
+    capacity: usize,
+    next_sequence: u64,
+    events: HashMap<String, VecDeque<(u64, RecentEvent)>>,
+}
+
+/// Registers the event inspector RPCs when `Rpc` is initialized or registered before the emitter:
+/// `amina.events.recent` (`{key, limit?}`), empty unless `EventEmitter::set_replay_capacity`
+/// was called, and `amina.events.stats` with the emit counts per key.
 pub struct EventEmitter {
     events: RwLock<HashMap<String, Vec<Listener>>>,
     observers: RwLock<Vec<Box<dyn Fn(&str, &str) + Sync + Send + 'static>>>,
     task_manager: Service<TaskManager>,
+    audit_enabled: AtomicBool,
+    audit_sequence: AtomicU64,
+    next_listener_id: AtomicU64,
+    audit: RwLock<Option<EventAudit>>,
+    emit_counters: KeyedCounters,
+    recent: Mutex<RecentEvents>,
+    /// Held for reading while an event is remembered and sent to the observers,
+    /// for writing by `subscribe_with_replay`.
+    replay_gate: RwLock<()>,
+    /// Queue of the dedicated worker thread per key with ordered listeners.
+    ordered_workers: Mutex<HashMap<String, mpsc::Sender<OrderedJob>>>,
 }
 
 impl EventEmitter {
@@ -40,16 +115,22 @@ impl EventEmitter {
     {
         let task_manager = self.task_manager.clone();
         let handler = Arc::new(handler);
+        let blocking_handler = handler.clone();
         let handler_wrapper = move |event_data: &str| {
             let value: E = serde_json::from_str(event_data).unwrap();
             let handler_clone = handler.clone();
-            task_manager.run_instant_task(move |_| {
+            task_manager.run_instant_once(move |_| {
                 handler_clone(&value);
             });
         };
 
         let listener = Listener {
+            id: self.next_listener_id.fetch_add(1, Ordering::Relaxed),
             handler: Box::new(handler_wrapper),
+            blocking_handler: Arc::new(move |event_data: &str| {
+                let value: E = serde_json::from_str(event_data).unwrap();
+                blocking_handler(&value);
+            }),
         };
 
         self.add_raw_listener(key, listener);
@@ -62,21 +143,298 @@ impl EventEmitter {
         self.on_generic_event_fn(E::get_key(), handler);
     }
 
+    /// Like `on_generic_event_fn`, but the ordered listeners of `key` run one at a time on a
+    /// worker thread dedicated to the key, in registration order and event by event.
+    /// A slow ordered listener delays every later event of the key, so keep the concurrent
+    /// default for listeners that don't depend on each other.
+    pub fn on_generic_event_fn_ordered<E, F>(&self, key: &str, handler: F) where
+            for<'de> E: Deserialize<'de> + Send + Sync + 'static,
+            F: Fn(&E) + Send + Sync + 'static
+    {
+        let worker = self.ordered_worker(key);
+        let handler = Arc::new(handler);
+        let blocking_handler = handler.clone();
+        let handler_wrapper = move |event_data: &str| {
+            let value: E = serde_json::from_str(event_data).unwrap();
+            let handler_clone = handler.clone();
+            let request_id = current_request_id();
+            // Fails only once the worker is gone, which happens with the emitter
+            let _ = worker.send(Box::new(move || {
+                with_request_id(request_id, || handler_clone(&value));
+            }));
+        };
+
+        let listener = Listener {
+            id: self.next_listener_id.fetch_add(1, Ordering::Relaxed),
+            handler: Box::new(handler_wrapper),
+            blocking_handler: Arc::new(move |event_data: &str| {
+                let value: E = serde_json::from_str(event_data).unwrap();
+                blocking_handler(&value);
+            }),
+        };
+
+        self.add_raw_listener(key, listener);
+    }
+
+    pub fn on_event_fn_ordered<E, F>(&self, handler: F) where
+            for<'de> E: Event + Deserialize<'de> + 'static,
+            F: Fn(&E) + Send + Sync + 'static
+    {
+        self.on_generic_event_fn_ordered(E::get_key(), handler);
+    }
+
+    fn ordered_worker(&self, key: &str) -> mpsc::Sender<OrderedJob> {
+        let mut workers = self.ordered_workers.lock().unwrap();
+        if let Some(worker) = workers.get(key) {
+            return worker.clone();
+        }
+        let (sender, receiver) = mpsc::channel::<OrderedJob>();
+        let worker_key = key.to_string();
+        std::thread::Builder::new()
+            .name(format!("event-{}", key))
+            .spawn(move || {
+                // Ends once the emitter and its listeners are dropped
+                for job in receiver {
+                    if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(job)) {
+                        log::error!("Ordered listener of event '{}' panicked: {}", worker_key, panic_message(panic.as_ref()));
+                    }
+                }
+            })
+            .unwrap();
+        workers.insert(key.to_string(), sender.clone());
+        sender
+    }
+
     pub fn emit<T>(&self, key: &str, value: &T) where
         T: Serialize
     {
-        let event_data = serde_json::to_string(value).unwrap();
-        self.send_raw_event(key, &event_data);
-        self.send_to_observers(key, &event_data)
+        if let Some(event_data) = serialize_event(key, value) {
+            self.dispatch(key, &event_data);
+        }
     }
 
     pub fn emit_event<E>(&self, value: &E) where
         E: Event + Serialize
     {
-        let event_data = serde_json::to_string(value).unwrap();
+        if let Some(event_data) = serialize_event(E::get_key(), value) {
+            self.dispatch(E::get_key(), &event_data);
+        }
+    }
+
+    /// Blocks until the next event of type `E` is emitted, `None` once `timeout` elapses.
+    pub fn wait_for_event<E>(&self, timeout: Duration) -> Option<E> where
+        for<'de> E: Event + Deserialize<'de> + 'static
+    {
+        self.wait_for_event_after(timeout, || {})
+    }
+
+    /// Like `wait_for_event`, the listener is registered before `trigger` runs, so an event
+    /// emitted by the trigger itself, e.g. a call to the service that emits it, isn't missed.
+    pub fn wait_for_event_after<E, F>(&self, timeout: Duration, trigger: F) -> Option<E> where
+        for<'de> E: Event + Deserialize<'de> + 'static,
+        F: FnOnce()
+    {
         let key = E::get_key();
-        self.send_raw_event(key, &event_data);
-        self.send_to_observers(key, &event_data)
+        let (sender, receiver) = mpsc::sync_channel::<String>(1);
+        let sender = Arc::new(Mutex::new(sender));
+        let blocking_sender = sender.clone();
+        // Runs on the emitting thread, later events are dropped once the first one is taken
+        let listener = Listener {
+            id: self.next_listener_id.fetch_add(1, Ordering::Relaxed),
+            handler: Box::new(move |event_data: &str| {
+                let _ = sender.lock().unwrap().try_send(event_data.to_string());
+            }),
+            blocking_handler: Arc::new(move |event_data: &str| {
+                let _ = blocking_sender.lock().unwrap().try_send(event_data.to_string());
+            }),
+        };
+        let id = listener.id;
+        self.add_raw_listener(key, listener);
+
+        trigger();
+        let received = receiver.recv_timeout(timeout);
+        self.remove_raw_listener(key, id);
+
+        let event_data = received.ok()?;
+        match serde_json::from_str(&event_data) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                log::warn!("Can't decode awaited event '{}': {}", key, err);
+                None
+            },
+        }
+    }
+
+    /// Runs every listener on the calling thread and returns once all of them have completed,
+    /// for tests and shutdown sequences that rely on the handlers' effects.
+    /// A panicking listener doesn't stop the others, the panics are logged together afterwards.
+    pub fn emit_event_blocking<E>(&self, value: &E) where
+        E: Event + Serialize
+    {
+        let key = E::get_key();
+        let event_data = match serialize_event(key, value) {
+            Some(event_data) => event_data,
+            None => return,
+        };
+        self.record_dispatch(key, &event_data);
+        self.send_raw_event_blocking(key, &event_data);
+        self.remember_and_observe(key, &event_data)
+    }
+
+    /// Listeners and observers see the request id of the emitting RPC call through `current_request_id`.
+    fn dispatch(&self, key: &str, event_data: &str) {
+        self.record_dispatch(key, event_data);
+        self.send_raw_event(key, event_data);
+        self.remember_and_observe(key, event_data)
+    }
+
+    fn record_dispatch(&self, key: &str, event_data: &str) {
+        if let Some(request_id) = current_request_id() {
+            log::debug!("Event '{}' emitted by request {}", key, request_id);
+        }
+        self.emit_counters.increment(key);
+        self.audit(key, event_data);
+    }
+
+    /// An event is either in the replay taken by `subscribe_with_replay` or reaches the observers after it.
+    fn remember_and_observe(&self, key: &str, event_data: &str) {
+        // An observer emitting an event already holds the gate on this thread
+        let nested = OBSERVING.with(|observing| observing.replace(true));
+        let _gate = if nested { None } else { Some(self.replay_gate.read().unwrap()) };
+        self.remember(key, event_data);
+        self.send_to_observers(key, event_data);
+        OBSERVING.with(|observing| observing.set(nested));
+    }
+
+    /// Keeps the last `capacity` events of every key for `replay_recent`.
+    /// The default of 0 keeps nothing, lowering the capacity drops the oldest events.
+    pub fn set_replay_capacity(&self, capacity: usize) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.capacity = capacity;
+        recent.events.retain(|_, events| {
+            while events.len() > capacity {
+                events.pop_front();
+            }
+            !events.is_empty()
+        });
+    }
+
+    /// Raw data of the last events emitted with `key`, oldest first.
+    pub fn replay_recent(&self, key: &str) -> Vec<String> {
+        self.recent_events(key, usize::MAX).into_iter()
+            .map(|event| event.payload)
+            .collect()
+    }
+
+    /// At most `limit` of the last events emitted with `key`, oldest first.
+    pub fn recent_events(&self, key: &str, limit: usize) -> Vec<RecentEvent> {
+        self.recent.lock().unwrap().events.get(key)
+            .map(|events| {
+                let skip = events.len().saturating_sub(limit);
+                events.iter().skip(skip).map(|(_, event)| event.clone()).collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Number of events emitted per key since the start, also when nothing listened.
+    pub fn emit_counts(&self) -> BTreeMap<String, u64> {
+        self.emit_counters.snapshot().into_iter()
+            .map(|metrics| (metrics.key, metrics.count))
+            .collect()
+    }
+
+    /// Keys and raw data of the last events of every key, in the order they were emitted.
+    pub fn replay_all_recent(&self) -> Vec<(String, String)> {
+        let recent = self.recent.lock().unwrap();
+        let mut events: Vec<(u64, &String, &String)> = recent.events.iter()
+            .flat_map(|(key, events)| events.iter().map(move |(sequence, event)| (*sequence, key, &event.payload)))
+            .collect();
+        events.sort_by_key(|(sequence, _, _)| *sequence);
+        events.into_iter()
+            .map(|(_, key, event_data)| (key.clone(), event_data.clone()))
+            .collect()
+    }
+
+    /// Runs `register` with `replay_all_recent` while no event is sent to the observers,
+    /// so a client registered there with an observer neither misses nor repeats an event.
+    pub fn subscribe_with_replay<F>(&self, register: F) where
+        F: FnOnce(Vec<(String, String)>)
+    {
+        let _gate = self.replay_gate.write().unwrap();
+        register(self.replay_all_recent());
+    }
+
+    fn remember(&self, key: &str, event_data: &str) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.capacity == 0 {
+            return;
+        }
+        let sequence = recent.next_sequence;
+        recent.next_sequence += 1;
+        let capacity = recent.capacity;
+        let events = recent.events.entry(key.to_string()).or_default();
+        if events.len() >= capacity {
+            events.pop_front();
+        }
+        events.push_back((sequence, RecentEvent {
+            payload: event_data.to_string(),
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0),
+        }));
+    }
+
+    /// Starts reporting every emitted event matching `filter` to `sink`.
+    pub fn enable_audit(&self, filter: EventFilter, sink: AuditSink) {
+        *self.audit.write().unwrap() = Some(EventAudit {
+            filter,
+            sink,
+        });
+        self.audit_enabled.store(true, Ordering::Release);
+    }
+
+    /// Toggles auditing without replacing the configured filter and sink.
+    /// When nothing was configured yet, all events are written to the debug log.
+    pub fn set_audit_enabled(&self, enabled: bool) {
+        if enabled {
+            let mut audit = self.audit.write().unwrap();
+            if audit.is_none() {
+                *audit = Some(EventAudit {
+                    filter: EventFilter::All,
+                    sink: Box::new(log_audit_sink),
+                });
+            }
+        }
+        self.audit_enabled.store(enabled, Ordering::Release);
+    }
+
+    pub fn is_audit_enabled(&self) -> bool {
+        self.audit_enabled.load(Ordering::Acquire)
+    }
+
+    fn audit(&self, key: &str, event_data: &str) {
+        if !self.audit_enabled.load(Ordering::Acquire) {
+            return;
+        }
+        let audit = self.audit.read().unwrap();
+        if let Some(audit) = audit.deref() {
+            if !audit.filter.matches(key) {
+                return;
+            }
+            let listener_count = self.events.read().unwrap()
+                .get(key)
+                .map_or(0, |listeners| listeners.len());
+            let record = AuditRecord {
+                sequence: self.audit_sequence.fetch_add(1, Ordering::Relaxed),
+                key: key.to_string(),
+                payload_len: event_data.len(),
+                thread_name: std::thread::current().name().unwrap_or("<unnamed>").to_string(),
+                listener_count,
+            };
+            (audit.sink)(record);
+        }
+    }
+
+    pub(crate) fn emit_counters(&self) -> &KeyedCounters {
+        &self.emit_counters
     }
 
     fn add_raw_listener(&self, key: &str, listener: Listener) {
@@ -91,9 +449,21 @@ impl EventEmitter {
         };
     }
 
+    fn remove_raw_listener(&self, key: &str, id: u64) {
+        let mut events = self.events.write().unwrap();
+        if let Some(listeners) = events.get_mut(key) {
+            listeners.retain(|listener| listener.id != id);
+            if listeners.is_empty() {
+                events.remove(key);
+            }
+        }
+    }
+
     fn send_raw_event(&self, key: &str, event_data: &str) {
         let events = self.events.read().unwrap();
         if let Some(listeners) = events.get(key) {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!("event_dispatch", key, listeners = listeners.len()).entered();
             for listener in listeners.iter() {
                 let handler = listener.handler.deref();
                 handler(event_data);
@@ -101,6 +471,25 @@ impl EventEmitter {
         }
     }
 
+    fn send_raw_event_blocking(&self, key: &str, event_data: &str) {
+        // Cloned out, so listeners may register other listeners without deadlocking
+        let handlers: Vec<_> = match self.events.read().unwrap().get(key) {
+            Some(listeners) => listeners.iter().map(|listener| listener.blocking_handler.clone()).collect(),
+            None => return,
+        };
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("event_dispatch_blocking", key, listeners = handlers.len()).entered();
+        let mut panics = Vec::new();
+        for handler in handlers.iter() {
+            if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| handler(event_data))) {
+                panics.push(panic_message(panic.as_ref()));
+            }
+        }
+        if !panics.is_empty() {
+            log::error!("{} of {} listeners of event '{}' panicked: {}", panics.len(), handlers.len(), key, panics.join("; "));
+        }
+    }
+
     fn add_raw_observer(&self, observer: Box<dyn Fn(&str, &str) + Sync + Send + 'static>) {
         let mut observers = self.observers.write().unwrap();
         observers.push(observer);
@@ -116,6 +505,37 @@ impl EventEmitter {
 
 }
 
+thread_local! {
+    /// Set while the observers of an event run on this thread.
+    static OBSERVING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// `None` if the event can't be serialized, it is logged and skipped instead of panicking in the emitter.
+fn serialize_event<T: Serialize>(key: &str, value: &T) -> Option<String> {
+    match serde_json::to_string(value) {
+        Ok(event_data) => Some(event_data),
+        Err(err) => {
+            log::error!("Can't serialize event '{}', skipping it: {}", key, err);
+            None
+        },
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "<non-string panic>".to_string()
+    }
+}
+
+fn log_audit_sink(record: AuditRecord) {
+    log::debug!("Event audit: seq={} key={} payload_len={} thread={} listeners={}",
+        record.sequence, record.key, record.payload_len, record.thread_name, record.listener_count);
+}
+
 impl ServiceApi for EventEmitter {
 
 }
@@ -134,6 +554,16 @@ impl EventEmitterGate {
         self.event_emitter.add_raw_observer(observer);
     }
 
+    pub fn replay_all_recent(&self) -> Vec<(String, String)> {
+        self.event_emitter.replay_all_recent()
+    }
+
+    pub fn subscribe_with_replay<F>(&self, register: F) where
+        F: FnOnce(Vec<(String, String)>)
+    {
+        self.event_emitter.subscribe_with_replay(register);
+    }
+
 }
 
 impl ServiceApi for EventEmitterGate {
@@ -147,11 +577,36 @@ impl ServiceInitializer for EventEmitter {
             events: RwLock::new(HashMap::new()),
             observers: RwLock::new(Vec::new()),
             task_manager,
+            audit_enabled: AtomicBool::new(false),
+            audit_sequence: AtomicU64::new(0),
+            next_listener_id: AtomicU64::new(0),
+            audit: RwLock::new(None),
+            emit_counters: KeyedCounters::default(),
+            recent: Mutex::new(RecentEvents::default()),
+            replay_gate: RwLock::new(()),
+            ordered_workers: Mutex::new(HashMap::new()),
         });
         let gate = EventEmitterGate {
             event_emitter: service.clone(),
         };
         context.add_service(gate);
+
+        if let Some(rpc) = context.try_get_service::<Rpc>() {
+            #[derive(Deserialize)]
+            struct RecentEventsReq {
+                key: String,
+                /// Every kept event when omitted.
+                limit: Option<usize>,
+            }
+            let event_emitter = service.clone();
+            rpc.on_generic_call_fn("amina.events.recent", move |req: &RecentEventsReq| {
+                event_emitter.recent_events(&req.key, req.limit.unwrap_or(usize::MAX))
+            });
+            let event_emitter = service.clone();
+            rpc.on_generic_call_fn("amina.events.stats", move |_: &EmptyData| {
+                event_emitter.emit_counts()
+            });
+        }
         return service;
     }
 }
@@ -171,12 +626,13 @@ macro_rules! register_event_handler {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Mutex, RwLock};
     use std::time::Duration;
     use serde::{Deserialize, Serialize};
     use amina_core_derive::Event;
     use crate::service::{ServiceApi, Context, ServiceInitializer};
-    use crate::events::{Event, EventEmitter};
+    use crate::events::{AuditRecord, Event, EventEmitter, EventFilter};
+    use crate::rpc::{current_request_id, Rpc, RpcGate};
     use crate::tasks::TaskManager;
 
     #[derive(Serialize, Deserialize)]
@@ -188,7 +644,7 @@ mod tests {
 
     #[derive(Serialize, Deserialize)]
     #[derive(Event)]
-    #[key = "event.second"]
+    #[key("event.second", strict)]
     struct EventSecond {
         value: String,
     }
@@ -263,10 +719,298 @@ mod tests {
         });
         assert_eq!(service.get_event_one_data(), "value 1".to_string());
 
+        assert_eq!(EventSecond::get_key(), "event.second");
         event_emitter.emit_event(&EventSecond {
             value: "value 2".to_string(),
         });
         assert_eq!(service.get_event_second_data(), "value 2".to_string());
     }
 
+    #[test]
+    fn test_emit_blocking() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+        let values = Arc::new(Mutex::new(Vec::<String>::new()));
+
+        let values_copy = values.clone();
+        event_emitter.on_event_fn(move |event: &EventOne| {
+            std::thread::sleep(Duration::from_millis(20));
+            values_copy.lock().unwrap().push(event.value.clone());
+        });
+        event_emitter.on_event_fn(|_: &EventOne| {
+            panic!("listener failed");
+        });
+        let values_copy = values.clone();
+        event_emitter.on_event_fn(move |event: &EventOne| {
+            values_copy.lock().unwrap().push(format!("{} again", event.value));
+        });
+
+        event_emitter.emit_event_blocking(&EventOne {
+            value: "value 1".to_string(),
+        });
+        assert_eq!(*values.lock().unwrap(), vec!["value 1".to_string(), "value 1 again".to_string()]);
+    }
+
+    #[test]
+    fn test_ordered_listeners() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+        let values = Arc::new(Mutex::new(Vec::<String>::new()));
+        let (done_tx, done_rx) = std::sync::mpsc::sync_channel(1);
+
+        let values_copy = values.clone();
+        event_emitter.on_event_fn_ordered(move |event: &EventOne| {
+            // The second listener must wait for the first one despite the delay
+            std::thread::sleep(Duration::from_millis(2));
+            values_copy.lock().unwrap().push(format!("first {}", event.value));
+        });
+        let values_copy = values.clone();
+        let done_tx = Mutex::new(done_tx);
+        event_emitter.on_event_fn_ordered(move |event: &EventOne| {
+            values_copy.lock().unwrap().push(format!("second {}", event.value));
+            if event.value == "4" {
+                done_tx.lock().unwrap().send(()).unwrap();
+            }
+        });
+
+        for i in 0..5 {
+            event_emitter.emit_event(&EventOne {
+                value: i.to_string(),
+            });
+        }
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let expected: Vec<String> = (0..5)
+            .flat_map(|i| vec![format!("first {}", i), format!("second {}", i)])
+            .collect();
+        assert_eq!(*values.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_replay_recent() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+        let emit_one = |value: &str| event_emitter.emit_event(&EventOne {
+            value: value.to_string(),
+        });
+        emit_one("ignored");
+        assert!(event_emitter.replay_recent(EventOne::get_key()).is_empty());
+
+        event_emitter.set_replay_capacity(2);
+        emit_one("1");
+        event_emitter.emit_event(&EventSecond {
+            value: "2".to_string(),
+        });
+        emit_one("3");
+        emit_one("4");
+        assert_eq!(event_emitter.replay_recent(EventOne::get_key()),
+            vec!["{\"value\":\"3\"}".to_string(), "{\"value\":\"4\"}".to_string()]);
+        let keys: Vec<String> = event_emitter.replay_all_recent().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["event.second", "event.one", "event.one"]);
+
+        event_emitter.set_replay_capacity(1);
+        assert_eq!(event_emitter.replay_recent(EventOne::get_key()), vec!["{\"value\":\"4\"}".to_string()]);
+        event_emitter.set_replay_capacity(0);
+        assert!(event_emitter.replay_all_recent().is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_with_replay() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+        event_emitter.set_replay_capacity(2000);
+        type Client = Arc<Mutex<Vec<String>>>;
+        let clients: Arc<RwLock<Vec<Client>>> = Arc::default();
+        let clients_copy = clients.clone();
+        event_emitter.add_raw_observer(Box::new(move |_key: &str, event_data: &str| {
+            for client in clients_copy.read().unwrap().iter() {
+                client.lock().unwrap().push(event_data.to_string());
+            }
+        }));
+
+        let emitter = event_emitter.clone();
+        let emitting = std::thread::spawn(move || {
+            for i in 0..2000 {
+                emitter.emit_event(&EventOne {
+                    value: i.to_string(),
+                });
+            }
+        });
+        let mut received = Vec::new();
+        while !emitting.is_finished() {
+            let client = Arc::new(Mutex::new(Vec::new()));
+            event_emitter.subscribe_with_replay(|replay| {
+                client.lock().unwrap().extend(replay.into_iter().map(|(_, event_data)| event_data));
+                clients.write().unwrap().push(client.clone());
+            });
+            received.push(client);
+            std::thread::yield_now();
+        }
+        emitting.join().unwrap();
+
+        let expected: Vec<String> = (0..2000).map(|i| format!("{{\"value\":\"{}\"}}", i)).collect();
+        for client in received {
+            assert_eq!(*client.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_event_inspector() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<Rpc>();
+        context.init_service::<EventEmitter>();
+        let event_emitter = context.get_service::<EventEmitter>();
+        event_emitter.set_replay_capacity(5);
+        for value in 1..=3 {
+            event_emitter.emit("test.event", &value);
+        }
+        event_emitter.emit("test.other", &0);
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        let recent: serde_json::Value = serde_json::from_str(&rpc_gate.call_raw("amina.events.recent",
+            "{\"key\":\"test.event\",\"limit\":2}")).unwrap();
+        let payloads: Vec<&str> = recent.as_array().unwrap().iter()
+            .map(|event| event["payload"].as_str().unwrap())
+            .collect();
+        assert_eq!(payloads, vec!["2", "3"]);
+        assert!(recent[0]["timestamp_ms"].as_u64().unwrap() > 0);
+        assert_eq!(rpc_gate.call_raw("amina.events.recent", "{\"key\":\"test.missing\"}"), "[]");
+
+        let stats: serde_json::Value = serde_json::from_str(&rpc_gate.call_raw("amina.events.stats", "{}")).unwrap();
+        assert_eq!(stats, serde_json::json!({ "test.event": 3, "test.other": 1 }));
+    }
+
+    #[test]
+    fn test_audit() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+        let records = Arc::new(Mutex::new(Vec::<AuditRecord>::new()));
+
+        let records_copy = records.clone();
+        event_emitter.enable_audit(EventFilter::Prefix("event.".to_string()), Box::new(move |record| {
+            records_copy.lock().unwrap().push(record);
+        }));
+
+        event_emitter.emit_event(&EventOne {
+            value: "value 1".to_string(),
+        });
+        event_emitter.emit("other.key", &EventOne {
+            value: "value 2".to_string(),
+        });
+
+        event_emitter.set_audit_enabled(false);
+        event_emitter.emit_event(&EventSecond {
+            value: "value 3".to_string(),
+        });
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key, "event.one");
+        assert_eq!(records[0].sequence, 0);
+        assert_eq!(records[0].listener_count, 0);
+    }
+
+    #[test]
+    fn test_request_id() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        event_emitter.on_event_fn(move |_: &EventOne| {
+            tx.send(current_request_id()).unwrap();
+        });
+
+        let event_emitter_copy = event_emitter.clone();
+        context.get_service::<Rpc>().on_generic_call_fn("test.emit", move |value: &String| {
+            event_emitter_copy.emit_event(&EventOne {
+                value: value.clone(),
+            });
+            current_request_id()
+        });
+
+        let response = context.get_service::<RpcGate>().call_raw_with_request_id("test.emit", "\"x\"", "req-1");
+        assert_eq!(response, "\"req-1\"");
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), Some("req-1".to_string()));
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[test]
+    fn test_wait_for_event() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+
+        let emitter = event_emitter.clone();
+        let event: Option<EventOne> = event_emitter.wait_for_event_after(Duration::from_secs(5), move || {
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                emitter.emit_event(&EventOne { value: "awaited".to_string() });
+            });
+        });
+        assert_eq!(event.unwrap().value, "awaited");
+
+        // Emitted from the trigger itself, before the wait starts
+        let event: Option<EventOne> = event_emitter.wait_for_event_after(Duration::from_secs(5), || {
+            event_emitter.emit_event_blocking(&EventOne { value: "sync".to_string() });
+        });
+        assert_eq!(event.unwrap().value, "sync");
+
+        let event: Option<EventSecond> = event_emitter.wait_for_event(Duration::from_millis(20));
+        assert!(event.is_none());
+
+        // The one-shot listeners are gone
+        assert!(event_emitter.events.read().unwrap().get(EventOne::get_key()).is_none());
+        assert!(event_emitter.events.read().unwrap().get(EventSecond::get_key()).is_none());
+    }
+
+    #[test]
+    fn test_unserializable_event() {
+        let context = Context::new();
+
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+
+        let event_emitter = context.get_service::<EventEmitter>();
+        let received = Arc::new(Mutex::new(0));
+        let received_copy = received.clone();
+        event_emitter.on_generic_event_fn("event.map", move |_: &serde_json::Value| {
+            *received_copy.lock().unwrap() += 1;
+        });
+
+        // JSON object keys must be strings
+        let mut value = std::collections::BTreeMap::new();
+        value.insert((1, 2), "pair");
+        event_emitter.emit("event.map", &value);
+        event_emitter.emit("event.map", &vec![1, 2]);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(*received.lock().unwrap(), 1);
+    }
+
 }
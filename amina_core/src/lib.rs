@@ -4,5 +4,9 @@ pub mod rpc;
 pub mod settings;
 pub mod tasks;
 pub mod cmd_manager;
+pub mod metrics;
+pub mod bootstrap;
 
 extern crate amina_core_derive;
+// Lets code generated by `amina_core_derive` refer to `::amina_core` from inside this crate too.
+extern crate self as amina_core;
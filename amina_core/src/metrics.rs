@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::events::EventEmitter;
+use crate::rpc::Rpc;
+use crate::service::{Context, Service, ServiceApi, ServiceInitializer};
+use crate::tasks::TaskManager;
+
+#[derive(Default)]
+pub(crate) struct CallCounters {
+    count: AtomicU64,
+    errors: AtomicU64,
+    total_latency_us: AtomicU64,
+}
+
+/// Counters per key, created on first use.
+#[derive(Default)]
+pub(crate) struct KeyedCounters {
+    counters: RwLock<HashMap<String, Arc<CallCounters>>>,
+}
+
+impl KeyedCounters {
+
+    pub(crate) fn get(&self, key: &str) -> Arc<CallCounters> {
+        if let Some(counters) = self.counters.read().unwrap().get(key) {
+            return counters.clone();
+        }
+        let mut counters = self.counters.write().unwrap();
+        counters.entry(key.to_string()).or_default().clone()
+    }
+
+    pub(crate) fn increment(&self, key: &str) {
+        self.get(key).count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts timing a call, it is counted when the returned recorder is dropped.
+    pub(crate) fn record_call(&self, key: &str) -> CallRecorder {
+        CallRecorder {
+            counters: self.get(key),
+            start: Instant::now(),
+            failed: false,
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<KeyMetrics> {
+        let counters = self.counters.read().unwrap();
+        let mut result: Vec<KeyMetrics> = counters.iter()
+            .map(|(key, counters)| KeyMetrics {
+                key: key.clone(),
+                count: counters.count.load(Ordering::Relaxed),
+                errors: counters.errors.load(Ordering::Relaxed),
+                total_latency_us: counters.total_latency_us.load(Ordering::Relaxed),
+            })
+            .collect();
+        result.sort_by(|a, b| a.key.cmp(&b.key));
+        result
+    }
+}
+
+/// Counts a call on drop, a panicking call is counted as an error.
+pub(crate) struct CallRecorder {
+    counters: Arc<CallCounters>,
+    start: Instant,
+    failed: bool,
+}
+
+impl CallRecorder {
+    pub(crate) fn fail(&mut self) {
+        self.failed = true;
+    }
+}
+
+impl Drop for CallRecorder {
+    fn drop(&mut self) {
+        self.counters.count.fetch_add(1, Ordering::Relaxed);
+        self.counters.total_latency_us.fetch_add(self.start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if self.failed || std::thread::panicking() {
+            self.counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TaskCounters {
+    spawned: AtomicU64,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+}
+
+impl TaskCounters {
+
+    /// Counts a spawned task, it is counted as completed or panicked when the guard is dropped.
+    pub(crate) fn spawn(self: &Arc<Self>) -> TaskGuard {
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+        TaskGuard {
+            counters: self.clone(),
+        }
+    }
+
+    fn snapshot(&self) -> TaskMetrics {
+        TaskMetrics {
+            spawned: self.spawned.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct TaskGuard {
+    counters: Arc<TaskCounters>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.counters.panicked.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.counters.completed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyMetrics {
+    pub key: String,
+    pub count: u64,
+    pub errors: u64,
+    pub total_latency_us: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskMetrics {
+    pub spawned: u64,
+    pub completed: u64,
+    pub panicked: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub rpc_calls: Vec<KeyMetrics>,
+    /// `None` without an `EventEmitter` in the context.
+    pub events: Option<Vec<KeyMetrics>>,
+    /// `None` without a `TaskManager` in the context.
+    pub tasks: Option<TaskMetrics>,
+}
+
+impl MetricsSnapshot {
+
+    /// Renders the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE amina_rpc_calls_total counter").unwrap();
+        for call in self.rpc_calls.iter() {
+            writeln!(out, "amina_rpc_calls_total{{key=\"{}\"}} {}", escape_label(&call.key), call.count).unwrap();
+        }
+        writeln!(out, "# TYPE amina_rpc_errors_total counter").unwrap();
+        for call in self.rpc_calls.iter() {
+            writeln!(out, "amina_rpc_errors_total{{key=\"{}\"}} {}", escape_label(&call.key), call.errors).unwrap();
+        }
+        writeln!(out, "# TYPE amina_rpc_latency_seconds_total counter").unwrap();
+        for call in self.rpc_calls.iter() {
+            writeln!(out, "amina_rpc_latency_seconds_total{{key=\"{}\"}} {}",
+                escape_label(&call.key), call.total_latency_us as f64 / 1_000_000.0).unwrap();
+        }
+        if let Some(events) = self.events.as_ref() {
+            writeln!(out, "# TYPE amina_events_emitted_total counter").unwrap();
+            for event in events.iter() {
+                writeln!(out, "amina_events_emitted_total{{key=\"{}\"}} {}", escape_label(&event.key), event.count).unwrap();
+            }
+        }
+        if let Some(tasks) = self.tasks.as_ref() {
+            writeln!(out, "# TYPE amina_tasks_spawned_total counter").unwrap();
+            writeln!(out, "amina_tasks_spawned_total {}", tasks.spawned).unwrap();
+            writeln!(out, "# TYPE amina_tasks_completed_total counter").unwrap();
+            writeln!(out, "amina_tasks_completed_total {}", tasks.completed).unwrap();
+            writeln!(out, "# TYPE amina_tasks_panicked_total counter").unwrap();
+            writeln!(out, "amina_tasks_panicked_total {}", tasks.panicked).unwrap();
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Read-only view over the counters kept by `Rpc`, `EventEmitter` and `TaskManager`.
+/// The families of a missing `EventEmitter` or `TaskManager` are left out.
+pub struct Metrics {
+    rpc: Service<Rpc>,
+    event_emitter: Option<Service<EventEmitter>>,
+    task_manager: Option<Service<TaskManager>>,
+}
+
+impl Metrics {
+
+    pub fn new(rpc: Service<Rpc>, event_emitter: Option<Service<EventEmitter>>, task_manager: Option<Service<TaskManager>>) -> Self {
+        Self {
+            rpc,
+            event_emitter,
+            task_manager,
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            rpc_calls: self.rpc.call_counters().snapshot(),
+            events: self.event_emitter.as_ref().map(|event_emitter| event_emitter.emit_counters().snapshot()),
+            tasks: self.task_manager.as_ref().map(|task_manager| task_manager.task_counters().snapshot()),
+        }
+    }
+
+}
+
+impl ServiceApi for Metrics {
+
+}
+
+impl ServiceInitializer for Metrics {
+    fn initialize(context: &Context) -> Arc<Self> {
+        let rpc = context.get_service::<Rpc>();
+        let event_emitter = context.try_get_service::<EventEmitter>();
+        let task_manager = context.try_get_service::<TaskManager>();
+
+        Arc::new(Self::new(rpc, event_emitter, task_manager))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::events::EventEmitter;
+    use crate::metrics::Metrics;
+    use crate::rpc::{Rpc, RpcGate};
+    use crate::service::Context;
+    use crate::tasks::TaskManager;
+
+    #[test]
+    fn test_snapshot() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<Metrics>();
+
+        context.get_service::<Rpc>().on_generic_call_fn("test.double", |value: &i64| value * 2);
+        let rpc_gate = context.get_service::<RpcGate>();
+        rpc_gate.call_raw("test.double", "2");
+        rpc_gate.call_raw("test.double", "3");
+        rpc_gate.call_raw("test.missing", "{}");
+
+        context.get_service::<EventEmitter>().emit("test.event", &1);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        context.get_service::<TaskManager>().run_instant_task(move |_| tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        let snapshot = context.get_service::<Metrics>().snapshot();
+        let double = snapshot.rpc_calls.iter().find(|call| call.key == "test.double").unwrap();
+        assert_eq!(double.count, 2);
+        assert_eq!(double.errors, 0);
+        let missing = snapshot.rpc_calls.iter().find(|call| call.key == "<unknown>").unwrap();
+        assert_eq!(missing.errors, 1);
+        assert_eq!(snapshot.events.as_ref().unwrap()[0].key, "test.event");
+        assert_eq!(snapshot.tasks.as_ref().unwrap().spawned, 1);
+
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("amina_rpc_calls_total{key=\"test.double\"} 2"));
+        assert!(text.contains("amina_events_emitted_total{key=\"test.event\"} 1"));
+    }
+
+    #[test]
+    fn test_snapshot_without_optional_services() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.init_service::<Metrics>();
+
+        let snapshot = context.get_service::<Metrics>().snapshot();
+        assert!(snapshot.events.is_none());
+        assert!(snapshot.tasks.is_none());
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("# TYPE amina_rpc_calls_total counter"));
+        assert!(!text.contains("amina_events_emitted_total"));
+        assert!(!text.contains("amina_tasks_spawned_total"));
+    }
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::RpcGate;
+use crate::service::{Context, Service};
+
+/// Calls handlers of the `Rpc` service in the same process, with the same ergonomics as `RpcTcpClient`.
+#[derive(Clone)]
+pub struct InProcessRpcClient {
+    rpc_gate: Service<RpcGate>,
+}
+
+impl InProcessRpcClient {
+
+    pub fn new(rpc_gate: Service<RpcGate>) -> Self {
+        Self {
+            rpc_gate,
+        }
+    }
+
+    pub fn from_context(context: &Context) -> Self {
+        Self::new(context.get_service::<RpcGate>())
+    }
+
+    pub fn send_request<O, I>(&self, key: &str, request: &O) -> I where
+            for<'de> I: Deserialize<'de> + Send + 'static,
+            O: Serialize + Send + 'static,
+    {
+        let request = serde_json::to_string(request).unwrap();
+        let response = self.rpc_gate.call_raw(key, &request);
+        serde_json::from_str(&response).unwrap()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::Rpc;
+    use crate::rpc::in_process_client::InProcessRpcClient;
+    use crate::service::Context;
+
+    #[test]
+    fn test_send_request() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.get_service::<Rpc>().on_generic_call_fn("test.concat", |values: &Vec<String>| values.concat());
+
+        let client = InProcessRpcClient::from_context(&context);
+        let response: String = client.send_request("test.concat", &vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(response, "ab".to_string());
+    }
+}
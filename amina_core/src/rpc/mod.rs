@@ -1,38 +1,58 @@
+pub mod in_process_client;
+mod request_id;
+mod single_flight;
+pub mod stdio;
 pub mod tcp_client;
+#[cfg(unix)]
+pub mod unix_socket;
 
-use std::cell::Cell;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::Read;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::{Receiver, SyncSender};
 
 use serde::{Deserialize, Serialize};
 
+use crate::metrics::KeyedCounters;
 use crate::service::{ServiceApi, ServiceInitializer, Context};
+use single_flight::SingleFlight;
 
+pub use request_id::{current_request_id, generate_request_id, with_request_id};
+
+/// Hands RPC requests over to a worker that polls for them.
+///
+/// Several requests may be received before they are answered. Responses are FIFO:
+/// each `send_response` answers the oldest received request that has no response yet.
 pub struct RequestAsyncReceiver<I: Send, O: Send> {
-    request_rx: Receiver<I>,
-    response_tx: SyncSender<O>,
-    pending_response: Cell<bool>,
+    request_rx: Receiver<(I, SyncSender<O>)>,
+    /// Reply channels of the received requests, oldest first.
+    pending_responses: RefCell<VecDeque<SyncSender<O>>>,
 }
 
 impl <I: Send, O: Send> RequestAsyncReceiver<I, O> {
 
     pub fn try_receive(&self) -> Option<I> {
-        let req = self.request_rx.try_recv().ok();
-        if req.is_some() {
-            self.pending_response.set(true);
-        }
-        req
+        let (req, response_tx) = self.request_rx.try_recv().ok()?;
+        self.pending_responses.borrow_mut().push_back(response_tx);
+        Some(req)
     }
 
+    /// Panics if there is no received request waiting for a response.
     pub fn send_response(&self, response: O) {
-        self.response_tx.send(response).unwrap();
-        self.pending_response.set(false);
+        let response_tx = self.pending_responses.borrow_mut().pop_front()
+            .expect("No pending request to respond to");
+        response_tx.send(response).unwrap();
     }
 
     pub fn is_pending_response(&self) -> bool {
-        self.pending_response.get()
+        self.pending_count() > 0
+    }
+
+    /// Number of received requests that haven't been answered yet.
+    pub fn pending_count(&self) -> usize {
+        self.pending_responses.borrow().len()
     }
 
 }
@@ -64,15 +84,52 @@ struct Listener {
     handler: Box<dyn Fn(&str) -> String + Sync + Send + 'static>,
 }
 
+/// Receives the key, the input and the rest of the chain, see `Rpc::add_interceptor`.
+type Interceptor = Box<dyn Fn(&str, &str, &dyn Fn(&str) -> String) -> String + Sync + Send + 'static>;
+
 struct GetFileListener {
     handler: Box<dyn Fn(&str) -> Result<Vec<u8>, std::io::Error> + Sync + Send + 'static>,
 }
 
+/// Shared, so an upload runs without holding the handlers lock.
+type PutFileHandler = Arc<dyn Fn(&str, &mut dyn Read) -> Result<(), std::io::Error> + Sync + Send + 'static>;
+
+struct PutFileListener {
+    handler: PutFileHandler,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct EmptyData {
     pub value: Option<i32>,
 }
 
+/// Response envelope of handlers registered with `on_generic_call_result_fn`,
+/// serialized as `{"ok":..}` or `{"err":..}`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RpcResult<O, E> {
+    Ok(O),
+    Err(E),
+}
+
+impl <O, E> From<Result<O, E>> for RpcResult<O, E> {
+    fn from(result: Result<O, E>) -> Self {
+        match result {
+            Ok(value) => RpcResult::Ok(value),
+            Err(err) => RpcResult::Err(err),
+        }
+    }
+}
+
+impl <O, E> From<RpcResult<O, E>> for Result<O, E> {
+    fn from(result: RpcResult<O, E>) -> Self {
+        match result {
+            RpcResult::Ok(value) => Ok(value),
+            RpcResult::Err(err) => Err(err),
+        }
+    }
+}
+
 impl EmptyData {
 
     pub fn new() -> EmptyData {
@@ -85,7 +142,14 @@ impl EmptyData {
 
 pub struct Rpc {
     calls: RwLock<HashMap<String, Listener>>,
+    versioned_calls: RwLock<HashMap<String, BTreeMap<u32, Listener>>>,
     get_file_calls: RwLock<HashMap<String, GetFileListener>>,
+    put_file_calls: RwLock<HashMap<String, PutFileListener>>,
+    interceptors: RwLock<Vec<Interceptor>>,
+    call_counters: KeyedCounters,
+    single_flight: SingleFlight,
+    #[cfg(feature = "tokio")]
+    tokio_handle: Arc<RwLock<Option<tokio::runtime::Handle>>>,
 }
 
 impl Rpc {
@@ -93,31 +157,133 @@ impl Rpc {
     pub fn new() -> Self {
         Self {
             calls: RwLock::new(HashMap::new()),
+            versioned_calls: RwLock::new(HashMap::new()),
             get_file_calls: RwLock::new(HashMap::new()),
+            put_file_calls: RwLock::new(HashMap::new()),
+            interceptors: RwLock::new(Vec::new()),
+            call_counters: KeyedCounters::default(),
+            single_flight: SingleFlight::default(),
+            #[cfg(feature = "tokio")]
+            tokio_handle: Arc::new(RwLock::new(None)),
         }
     }
 
+    /// Runtime that runs the futures of `on_generic_call_fn_async_tokio` handlers.
+    #[cfg(feature = "tokio")]
+    pub fn set_tokio_handle(&self, handle: tokio::runtime::Handle) {
+        *self.tokio_handle.write().unwrap() = Some(handle);
+    }
+
+    /// Registers a handler returning a future, e.g. for database queries or HTTP fetches.
+    ///
+    /// The future is spawned on the runtime set by `set_tokio_handle`, or the current one, and
+    /// the calling thread blocks until it resolves. Calls must therefore come from a blocking
+    /// thread such as `spawn_blocking`, never from an async task. Without any runtime the
+    /// future runs on a temporary single-threaded runtime.
+    #[cfg(feature = "tokio")]
+    pub fn on_generic_call_fn_async_tokio<I, O, F, Fut>(&self, key: &str, handler: F) where
+            for<'de> I: Deserialize<'de>,
+            O: Serialize + Send + 'static,
+            F: Fn(I) -> Fut + Send + Sync + 'static,
+            Fut: std::future::Future<Output = O> + Send + 'static,
+    {
+        let tokio_handle = self.tokio_handle.clone();
+        let key_copy = key.to_string();
+        let handler_wrapper = move |input_data: &str| {
+            let input_value: I = serde_json::from_str(input_data)
+                .unwrap_or_else(|err| panic!("Invalid input req for '{}': {}", key_copy, err));
+            let future = handler(input_value);
+            let handle = tokio_handle.read().unwrap().clone()
+                .or_else(|| tokio::runtime::Handle::try_current().ok());
+            let output_value = match handle {
+                Some(handle) => {
+                    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+                    handle.spawn(async move {
+                        let _ = response_tx.send(future.await);
+                    });
+                    response_rx.blocking_recv()
+                        .unwrap_or_else(|_| panic!("Async handler for '{}' failed", key_copy))
+                },
+                None => tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap()
+                    .block_on(future),
+            };
+            serialize_output(&key_copy, &output_value)
+        };
+
+        self.add_raw_listener(key, Listener {
+            handler: Box::new(handler_wrapper),
+        });
+    }
+
     pub fn on_generic_call_fn<I, O, F>(&self, key: &str, handler: F) where
             for<'de> I: Deserialize<'de>,
             O: Serialize,
             F: Fn(&I) -> O + Send + Sync + 'static
     {
+        self.add_raw_listener(key, Self::json_listener(key, handler));
+    }
+
+    /// Like `on_generic_call_fn`, the deserialized input is moved into the handler, so
+    /// large strings or vectors don't have to be cloned out of it.
+    pub fn on_generic_call_owned_fn<I, O, F>(&self, key: &str, handler: F) where
+            for<'de> I: Deserialize<'de>,
+            O: Serialize,
+            F: Fn(I) -> O + Send + Sync + 'static
+    {
+        self.add_raw_listener(key, Self::owned_json_listener(key, handler));
+    }
+
+    /// Like `on_generic_call_fn`, for handlers that can fail. The response is an `RpcResult` envelope.
+    pub fn on_generic_call_result_fn<I, O, E, F>(&self, key: &str, handler: F) where
+            for<'de> I: Deserialize<'de>,
+            O: Serialize,
+            E: Serialize,
+            F: Fn(&I) -> Result<O, E> + Send + Sync + 'static
+    {
+        self.on_generic_call_fn(key, move |input: &I| RpcResult::from(handler(input)));
+    }
+
+    /// Registers one version of the handler for `key`, so old clients keep working after
+    /// the request type changes. Callers that ask for an unknown version or for none get
+    /// the latest one.
+    pub fn on_generic_call_versioned<I, O, F>(&self, key: &str, version: u32, handler: F) where
+            for<'de> I: Deserialize<'de>,
+            O: Serialize,
+            F: Fn(&I) -> O + Send + Sync + 'static
+    {
+        let mut versioned_calls = self.versioned_calls.write().unwrap();
+        versioned_calls.entry(key.to_string()).or_default().insert(version, Self::json_listener(key, handler));
+    }
+
+    fn json_listener<I, O, F>(key: &str, handler: F) -> Listener where
+            for<'de> I: Deserialize<'de>,
+            O: Serialize,
+            F: Fn(&I) -> O + Send + Sync + 'static
+    {
+        Self::owned_json_listener(key, move |input_value: I| handler(&input_value))
+    }
+
+    fn owned_json_listener<I, O, F>(key: &str, handler: F) -> Listener where
+            for<'de> I: Deserialize<'de>,
+            O: Serialize,
+            F: Fn(I) -> O + Send + Sync + 'static
+    {
+        let key = key.to_string();
         let handler_wrapper = move |input_data: &str| {
             let input_value = serde_json::from_str(input_data);
             if input_value.is_err() {
                 log::error!("Invalid input req: {}", input_data);
             }
             let input_value: I = input_value.unwrap();
-            let output_value = handler(&input_value);
-            let output_data = serde_json::to_string(&output_value).unwrap();
-            return output_data;
+            let output_value = handler(input_value);
+            return serialize_output(&key, &output_value);
         };
 
-        let listener = Listener {
+        Listener {
             handler: Box::new(handler_wrapper),
-        };
-
-        self.add_raw_listener(key, listener);
+        }
     }
 
     pub fn on_generic_call_async<I, O, F>(&self, key: &str, handler: F) -> AsyncHandler<I, O> where
@@ -130,14 +296,14 @@ impl Rpc {
 
         let mpsc_mutex = Mutex::new((request_tx, response_rx));
 
+        let key_copy = key.to_string();
         let handler_wrapper = move |input_data: &str| {
             let mpsc_channel = mpsc_mutex.lock().unwrap();
             let input_value: I = serde_json::from_str(input_data).unwrap();
             let (tx, rx) = mpsc_channel.deref();
             tx.send(input_value).unwrap();
             let output_value: O = rx.recv().unwrap();
-            let output_data = serde_json::to_string(&output_value).unwrap();
-            return output_data;
+            return serialize_output(&key_copy, &output_value);
         };
 
         let listener = Listener {
@@ -157,19 +323,27 @@ impl Rpc {
             for<'de> I: Deserialize<'de> + Send + 'static,
             O: Serialize + Send + 'static,
     {
-        let (request_tx, request_rx) = std::sync::mpsc::sync_channel(1);
-        let (response_tx, response_rx) = std::sync::mpsc::sync_channel(1);
+        self.get_generic_call_async_receiver_with_depth(key, 1)
+    }
 
-        let mpsc_mutex = Mutex::new((request_tx, response_rx));
+    /// Like `get_generic_call_async_receiver`, but up to `depth` requests can queue up
+    /// before the worker receives them. Callers beyond that block until there is room.
+    pub fn get_generic_call_async_receiver_with_depth<I, O>(&self, key: &str, depth: usize) -> RequestAsyncReceiver<I, O> where
+            for<'de> I: Deserialize<'de> + Send + 'static,
+            O: Serialize + Send + 'static,
+    {
+        let (request_tx, request_rx) = std::sync::mpsc::sync_channel(depth);
+        let request_tx = Mutex::new(request_tx);
 
+        let key_copy = key.to_string();
         let handler_wrapper = move |input_data: &str| {
-            let mpsc_channel = mpsc_mutex.lock().unwrap();
             let input_value: I = serde_json::from_str(input_data).unwrap();
-            let (tx, rx) = mpsc_channel.deref();
-            tx.send(input_value).unwrap();
-            let output_value: O = rx.recv().unwrap();
-            let output_data = serde_json::to_string(&output_value).unwrap();
-            return output_data;
+            let (response_tx, response_rx) = std::sync::mpsc::sync_channel(1);
+            // Cloned so a caller blocked on a full queue doesn't hold the lock
+            let tx = request_tx.lock().unwrap().clone();
+            tx.send((input_value, response_tx)).unwrap();
+            let output_value: O = response_rx.recv().unwrap();
+            return serialize_output(&key_copy, &output_value);
         };
 
         let listener = Listener {
@@ -180,8 +354,7 @@ impl Rpc {
 
         RequestAsyncReceiver {
             request_rx,
-            response_tx,
-            pending_response: Cell::new(false),
+            pending_responses: RefCell::new(VecDeque::new()),
         }
     }
 
@@ -191,15 +364,75 @@ impl Rpc {
     }
 
     fn call_raw(&self, key: &str, input_data: &str) -> String {
+        self.call_raw_versioned(key, None, input_data)
+    }
+
+    /// Version of `key` that serves a request for `version`: the same version if it is
+    /// registered, else the latest one. `None` if `key` has no versioned handlers.
+    fn resolve_version(&self, key: &str, version: Option<u32>) -> Option<u32> {
+        resolve_version_in(&self.versioned_calls.read().unwrap(), key, version)
+    }
+
+    /// Wraps every call, e.g. for auth checks or timing. Interceptors run in registration order,
+    /// each calls `next` with the (possibly changed) input to continue the chain, or returns
+    /// its own response without calling it. Must not be added from inside a call.
+    pub fn add_interceptor(&self, interceptor: Interceptor) {
+        self.interceptors.write().unwrap().push(interceptor);
+    }
+
+    /// Concurrent calls of `key` with the same input share one handler run and get its response,
+    /// for idempotent calls only. Coalescing happens after the interceptors, so each call is
+    /// still checked. A handler must not call its own key with the same input.
+    pub fn set_single_flight(&self, key: &str) {
+        self.single_flight.enable(key);
+    }
+
+    fn call_raw_versioned(&self, key: &str, version: Option<u32>, input_data: &str) -> String {
+        let interceptors = self.interceptors.read().unwrap();
+        self.call_chain(&interceptors, key, version, input_data)
+    }
+
+    fn call_chain(&self, interceptors: &[Interceptor], key: &str, version: Option<u32>, input_data: &str) -> String {
+        match interceptors.split_first() {
+            Some((interceptor, rest)) => interceptor(key, input_data, &|input_data: &str| {
+                self.call_chain(rest, key, version, input_data)
+            }),
+            None => self.call_handler(key, version, input_data),
+        }
+    }
+
+    fn call_handler(&self, key: &str, version: Option<u32>, input_data: &str) -> String {
+        let versioned_calls = self.versioned_calls.read().unwrap();
         let calls = self.calls.read().unwrap();
-        return if let Some(listener) = calls.get(key) {
+        // Not `resolve_version`, a second read lock blocks behind a waiting writer
+        let listener = match resolve_version_in(&versioned_calls, key, version) {
+            Some(version) => versioned_calls.get(key).and_then(|versions| versions.get(&version)),
+            None => calls.get(key),
+        };
+        return if let Some(listener) = listener {
             let handler = listener.handler.deref();
-            handler(input_data)
+            let call = || {
+                #[cfg(feature = "tracing")]
+                let _span = tracing::debug_span!("rpc_call", key, request_id = current_request_id().as_deref()).entered();
+                let _recorder = self.call_counters.record_call(key);
+                handler(input_data)
+            };
+            if self.single_flight.is_enabled(key) {
+                self.single_flight.run(key, version, input_data, call)
+            } else {
+                call()
+            }
         } else {
+            // Unknown keys share one counter, so arbitrary keys can't grow the map
+            self.call_counters.record_call("<unknown>").fail();
             String::from("{ }")
         }
     }
 
+    pub(crate) fn call_counters(&self) -> &KeyedCounters {
+        &self.call_counters
+    }
+
     pub fn add_get_file_handler<F>(&self, key: &str, handler: F) where
             F: Fn(&str) -> Result<Vec<u8>, std::io::Error> + Send + Sync + 'static
     {
@@ -215,18 +448,65 @@ impl Rpc {
         get_file_calls.insert(key.to_string(), listener);
     }
 
+    /// Returns whether a handler was registered for `key`, e.g. to drop the file routes
+    /// of an unloaded plugin.
+    pub fn remove_get_file_handler(&self, key: &str) -> bool {
+        self.get_file_calls.write().unwrap().remove(key).is_some()
+    }
+
+    /// Keys with a `get_file` handler, sorted.
+    pub fn get_file_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.get_file_calls.read().unwrap().keys().cloned().collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// `NotFound` for a key without a handler.
     fn get_file(&self, key: &str, path: &str) -> Result<Vec<u8>, std::io::Error> {
         let file_calls = self.get_file_calls.read().unwrap();
         return if let Some(listener) = file_calls.get(key) {
             let handler = listener.handler.deref();
             handler(path)
         } else {
-            Ok(Vec::new())
+            Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No get_file handler for '{}'", key)))
+        }
+    }
+
+    /// Counterpart of `add_get_file_handler` for inbound data. The handler gets the path
+    /// and reads the uploaded content from the reader as it arrives.
+    pub fn add_put_file_handler<F>(&self, key: &str, handler: F) where
+            F: Fn(&str, &mut dyn Read) -> Result<(), std::io::Error> + Send + Sync + 'static
+    {
+        let listener = PutFileListener {
+            handler: Arc::new(handler),
+        };
+        self.put_file_calls.write().unwrap().insert(key.to_string(), listener);
+    }
+
+    fn put_file(&self, key: &str, path: &str, reader: &mut dyn Read) -> Result<(), std::io::Error> {
+        let handler = self.put_file_calls.read().unwrap().get(key).map(|listener| listener.handler.clone());
+        match handler {
+            Some(handler) => handler(path, reader),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No upload handler for '{}'", key))),
         }
     }
 
 }
 
+/// Falls back to an empty object, so an unserializable response doesn't take the caller down.
+fn serialize_output<O: Serialize>(key: &str, output_value: &O) -> String {
+    serde_json::to_string(output_value).unwrap_or_else(|err| {
+        log::error!("Can't serialize the response of '{}': {}", key, err);
+        String::from("{}")
+    })
+}
+
+fn resolve_version_in(versioned_calls: &HashMap<String, BTreeMap<u32, Listener>>, key: &str, version: Option<u32>) -> Option<u32> {
+    let versions = versioned_calls.get(key)?;
+    version.filter(|version| versions.contains_key(version))
+        .or_else(|| versions.keys().next_back().copied())
+}
+
 impl ServiceApi for Rpc {
 
 }
@@ -241,10 +521,33 @@ impl RpcGate {
         return self.rpc.call_raw(key, input_data);
     }
 
+    /// Like `call_raw`, picking the handler registered for `version` by `on_generic_call_versioned`.
+    /// Falls back to the latest version, and to the unversioned handler if there are no versions.
+    pub fn call_raw_versioned(&self, key: &str, version: Option<u32>, input_data: &str) -> String {
+        self.rpc.call_raw_versioned(key, version, input_data)
+    }
+
+    /// Version of `key` that `call_raw_versioned` would use for `version`,
+    /// `None` if `key` is not versioned.
+    pub fn resolve_version(&self, key: &str, version: Option<u32>) -> Option<u32> {
+        self.rpc.resolve_version(key, version)
+    }
+
+    /// Like `call_raw`, with `request_id` returned by `current_request_id` while the handler runs.
+    pub fn call_raw_with_request_id(&self, key: &str, input_data: &str, request_id: &str) -> String {
+        with_request_id(Some(request_id.to_string()), || self.rpc.call_raw(key, input_data))
+    }
+
+    /// `NotFound` if no handler is registered for `key`.
     pub fn get_file(&self, key: &str, path: &str) -> Result<Vec<u8>, std::io::Error> {
         return self.rpc.get_file(key, path)
     }
 
+    /// Fails with `NotFound` if no upload handler is registered for `key`.
+    pub fn put_file(&self, key: &str, path: &str, reader: &mut dyn Read) -> Result<(), std::io::Error> {
+        self.rpc.put_file(key, path, reader)
+    }
+
 }
 
 impl ServiceApi for RpcGate {
@@ -258,6 +561,24 @@ impl ServiceInitializer for Rpc {
             rpc: service.clone(),
         };
         context.add_service(gate);
+
+        let services_view = context.services_view();
+        let names_view = services_view.clone();
+        service.on_generic_call_fn("amina.context.list_services", move |_: &EmptyData| {
+            names_view.names()
+        });
+        let timings_view = services_view.clone();
+        service.on_generic_call_fn("amina.context.services", move |_: &EmptyData| {
+            services_view.states()
+        });
+        service.on_generic_call_fn("amina.context.startup_report", move |_: &EmptyData| {
+            timings_view.timings()
+        });
+        // Weak, the handler is stored inside the service
+        let rpc = Arc::downgrade(&service);
+        service.on_generic_call_fn("amina.rpc.list_file_keys", move |_: &EmptyData| {
+            rpc.upgrade().map(|rpc| rpc.get_file_keys()).unwrap_or_default()
+        });
         return service;
     }
 }
@@ -282,3 +603,307 @@ macro_rules! register_rpc_handler {
         }
     };
 }
+
+/// Like `register_rpc_handler!`, for methods returning `Result`. The response is an `RpcResult` envelope.
+#[macro_export]
+macro_rules! register_rpc_handler_result {
+    ($rpc:expr, $service:expr, $key:expr, $method:ident ($($arg_name:ident : $arg_type:ty),*)) => {
+        #[allow(unused_variables)]
+        {
+            let service_copy = $service.clone();
+
+            #[derive(serde::Deserialize)]
+            struct Args {
+                #[allow(dead_code)]
+                pub value: Option<i32>,
+                $($arg_name : $arg_type),*
+            }
+
+            $rpc.on_generic_call_result_fn($key, move |args: &Args| {
+                service_copy.$method($(args.$arg_name.clone()),*)
+            });
+        }
+    };
+}
+
+/// Like `register_rpc_handler!`, the arguments are moved into the method instead of cloned.
+#[macro_export]
+macro_rules! register_rpc_handler_owned {
+    ($rpc:expr, $service:expr, $key:expr, $method:ident ($($arg_name:ident : $arg_type:ty),*)) => {
+        #[allow(unused_variables)]
+        {
+            let service_copy = $service.clone();
+
+            #[derive(serde::Deserialize)]
+            struct Args {
+                #[allow(dead_code)]
+                pub value: Option<i32>,
+                $($arg_name : $arg_type),*
+            }
+
+            $rpc.on_generic_call_owned_fn($key, move |args: Args| {
+                service_copy.$method($(args.$arg_name),*)
+            });
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::rpc::{Rpc, RpcGate, RpcResult};
+    use crate::service::Context;
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_tokio_calls() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        rpc.on_generic_call_fn_async_tokio("test.delayed_double", |value: i64| async move {
+            tokio::task::yield_now().await;
+            value * 2
+        });
+        let rpc_gate = context.get_service::<RpcGate>();
+
+        // Without a runtime the future runs on a temporary one
+        assert_eq!(rpc_gate.call_raw("test.delayed_double", "4"), "8");
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rpc.set_tokio_handle(runtime.handle().clone());
+        let response = runtime.block_on(async move {
+            tokio::task::spawn_blocking(move || rpc_gate.call_raw("test.delayed_double", "21")).await.unwrap()
+        });
+        assert_eq!(response, "42");
+    }
+
+    #[test]
+    fn test_async_receiver_pipelining() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        let receiver = rpc.get_generic_call_async_receiver_with_depth::<i64, i64>("test.double", 2);
+
+        let callers: Vec<_> = (1..=2).map(|value| {
+            let rpc_gate = context.get_service::<RpcGate>();
+            std::thread::spawn(move || (value, rpc_gate.call_raw("test.double", &value.to_string())))
+        }).collect();
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match receiver.try_receive() {
+                Some(value) => received.push(value),
+                None => std::thread::yield_now(),
+            }
+        }
+        assert_eq!(receiver.pending_count(), 2);
+        for value in received {
+            receiver.send_response(value * 2);
+        }
+        assert!(!receiver.is_pending_response());
+
+        for caller in callers {
+            let (value, response) = caller.join().unwrap();
+            assert_eq!(response, (value * 2).to_string());
+        }
+    }
+
+    #[test]
+    fn test_list_services() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let response = context.get_service::<RpcGate>().call_raw("amina.context.list_services", "{}");
+        assert_eq!(response, "[\"amina_core::rpc::Rpc\",\"amina_core::rpc::RpcGate\"]");
+        let response = context.get_service::<RpcGate>().call_raw("amina.context.services", "{}");
+        assert_eq!(response, "[{\"name\":\"amina_core::rpc::RpcGate\",\"state\":\"initialized\"},\
+            {\"name\":\"amina_core::rpc::Rpc\",\"state\":\"initialized\"}]");
+    }
+
+    #[test]
+    fn test_remove_get_file_handler() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        rpc.add_get_file_handler("plugin.files", |path| Ok(path.as_bytes().to_vec()));
+        rpc.add_get_file_handler("core.files", |_| Ok(Vec::new()));
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        assert_eq!(rpc_gate.call_raw("amina.rpc.list_file_keys", "{}"), "[\"core.files\",\"plugin.files\"]");
+        assert_eq!(rpc_gate.get_file("plugin.files", "a.txt").unwrap(), b"a.txt".to_vec());
+
+        assert!(rpc.remove_get_file_handler("plugin.files"));
+        assert!(!rpc.remove_get_file_handler("plugin.files"));
+        assert_eq!(rpc.get_file_keys(), vec!["core.files".to_string()]);
+        assert_eq!(rpc_gate.get_file("plugin.files", "a.txt").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    struct Playlists {
+        names: Mutex<Vec<String>>,
+    }
+
+    impl Playlists {
+        fn add(&self, name: String, tracks: Vec<String>) -> usize {
+            self.names.lock().unwrap().push(name);
+            tracks.len()
+        }
+    }
+
+    #[test]
+    fn test_unserializable_output() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        // JSON object keys must be strings
+        rpc.on_generic_call_fn("test.pairs", |value: &i32| {
+            let mut pairs = BTreeMap::new();
+            pairs.insert((*value, *value), "pair");
+            pairs
+        });
+        rpc.on_generic_call_fn("test.double", |value: &i32| value * 2);
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        assert_eq!(rpc_gate.call_raw("test.pairs", "1"), "{}");
+        assert_eq!(rpc_gate.call_raw("test.double", "2"), "4");
+    }
+
+    #[test]
+    fn test_owned_calls() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        rpc.on_generic_call_owned_fn("test.take", |value: Vec<String>| value.into_iter().rev().collect::<Vec<_>>());
+        let playlists = Arc::new(Playlists { names: Mutex::new(Vec::new()) });
+        crate::register_rpc_handler_owned!(rpc, playlists, "test.playlists.add", add(name: String, tracks: Vec<String>));
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        assert_eq!(rpc_gate.call_raw("test.take", "[\"a\",\"b\"]"), "[\"b\",\"a\"]");
+        assert_eq!(rpc_gate.call_raw("test.playlists.add", "{\"name\":\"Road\",\"tracks\":[\"x\",\"y\"]}"), "2");
+        assert_eq!(*playlists.names.lock().unwrap(), vec!["Road".to_string()]);
+    }
+
+    #[test]
+    fn test_put_file() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let uploads_copy = uploads.clone();
+        let rpc = context.get_service::<Rpc>();
+        context.get_service::<Rpc>().add_put_file_handler("test.import", move |path, reader| {
+            let mut content = String::new();
+            reader.read_to_string(&mut content)?;
+            uploads_copy.lock().unwrap().push((path.to_string(), content));
+            // Uploads don't hold the handlers lock
+            rpc.add_put_file_handler("test.import.next", |_, _| Ok(()));
+            Ok(())
+        });
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        rpc_gate.put_file("test.import", "a/b.txt", &mut "content".as_bytes()).unwrap();
+        assert_eq!(*uploads.lock().unwrap(), vec![("a/b.txt".to_string(), "content".to_string())]);
+        rpc_gate.put_file("test.import.next", "", &mut "".as_bytes()).unwrap();
+        let err = rpc_gate.put_file("test.missing", "", &mut "".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        rpc.on_generic_call_fn("test.double", |value: &i64| value * 2);
+        rpc.on_generic_call_fn("admin.double", |value: &i64| value * 2);
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let order_copy = order.clone();
+        rpc.add_interceptor(Box::new(move |key, input, next| {
+            order_copy.lock().unwrap().push(format!("outer:{}", key));
+            if key.starts_with("admin.") {
+                return "\"denied\"".to_string();
+            }
+            next(input)
+        }));
+        let order_copy = order.clone();
+        rpc.add_interceptor(Box::new(move |key, input, next| {
+            order_copy.lock().unwrap().push(format!("inner:{}", key));
+            let input = (input.parse::<i64>().unwrap() + 1).to_string();
+            format!("[{}]", next(&input))
+        }));
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        assert_eq!(rpc_gate.call_raw("test.double", "1"), "[4]");
+        assert_eq!(rpc_gate.call_raw("admin.double", "1"), "\"denied\"");
+        assert_eq!(*order.lock().unwrap(), vec!["outer:test.double", "inner:test.double", "outer:admin.double"]);
+    }
+
+    #[test]
+    fn test_single_flight() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        let runs = Arc::new(Mutex::new(0));
+        let runs_copy = runs.clone();
+        rpc.on_generic_call_fn("library.rescan", move |value: &i64| {
+            *runs_copy.lock().unwrap() += 1;
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            value * 2
+        });
+        rpc.set_single_flight("library.rescan");
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        let calls: Vec<_> = ["1", "1", "1", "2"].iter().map(|input| {
+            let rpc_gate = rpc_gate.clone();
+            std::thread::spawn(move || rpc_gate.call_raw("library.rescan", input))
+        }).collect();
+        let responses: Vec<String> = calls.into_iter().map(|call| call.join().unwrap()).collect();
+        assert_eq!(responses, vec!["2", "2", "2", "4"]);
+        assert_eq!(*runs.lock().unwrap(), 2);
+
+        // Finished calls aren't cached
+        assert_eq!(rpc_gate.call_raw("library.rescan", "1"), "2");
+        assert_eq!(*runs.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_result_calls() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.get_service::<Rpc>().on_generic_call_result_fn("test.sqrt", |value: &f64| {
+            if *value < 0.0 {
+                Err(format!("{} is negative", value))
+            } else {
+                Ok(value.sqrt())
+            }
+        });
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        assert_eq!(rpc_gate.call_raw("test.sqrt", "4.0"), "{\"ok\":2.0}");
+        let response = rpc_gate.call_raw("test.sqrt", "-1.0");
+        assert_eq!(response, "{\"err\":\"-1 is negative\"}");
+        let response: RpcResult<f64, String> = serde_json::from_str(&response).unwrap();
+        assert_eq!(Result::from(response), Err::<f64, _>("-1 is negative".to_string()));
+    }
+
+    #[test]
+    fn test_versioned_calls() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        rpc.on_generic_call_fn("test.plain", |value: &i64| value + 1);
+        rpc.on_generic_call_versioned("test.value", 1, |value: &i64| value * 10);
+        rpc.on_generic_call_versioned("test.value", 2, |value: &String| format!("v2:{}", value));
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        assert_eq!(rpc_gate.call_raw_versioned("test.value", Some(1), "4"), "40");
+        assert_eq!(rpc_gate.call_raw_versioned("test.value", Some(2), "\"a\""), "\"v2:a\"");
+        // Unknown or missing versions fall back to the latest one
+        assert_eq!(rpc_gate.call_raw_versioned("test.value", Some(7), "\"b\""), "\"v2:b\"");
+        assert_eq!(rpc_gate.call_raw("test.value", "\"c\""), "\"v2:c\"");
+        assert_eq!(rpc_gate.resolve_version("test.value", Some(1)), Some(1));
+        assert_eq!(rpc_gate.resolve_version("test.value", None), Some(2));
+
+        assert_eq!(rpc_gate.call_raw_versioned("test.plain", Some(3), "1"), "2");
+        assert_eq!(rpc_gate.resolve_version("test.plain", Some(3)), None);
+    }
+}
@@ -0,0 +1,37 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+thread_local! {
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Id of the RPC request being handled on this thread, it is also carried into
+/// tasks spawned by the handler and into listeners of events it emits.
+pub fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// Runs `f` with `request_id` as the current request id, restoring the previous one afterwards.
+pub fn with_request_id<R, F: FnOnce() -> R>(request_id: Option<String>, f: F) -> R {
+    struct Restore(Option<String>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+
+    let previous = CURRENT_REQUEST_ID.with(|current| current.replace(request_id));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Unique within the process and unlikely to repeat across restarts.
+pub fn generate_request_id() -> String {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    format!("{:x}-{:x}", started, NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+}
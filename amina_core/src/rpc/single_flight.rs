@@ -0,0 +1,85 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+type FlightKey = (String, Option<u32>, String);
+
+/// Response of a call in progress. Holds `Some(None)` when the call panicked.
+#[derive(Default)]
+struct Flight {
+    response: Mutex<Option<Option<String>>>,
+    done: Condvar,
+}
+
+/// Coalesces identical concurrent calls of the keys marked with `Rpc::set_single_flight`.
+#[derive(Default)]
+pub(crate) struct SingleFlight {
+    keys: RwLock<HashSet<String>>,
+    in_flight: Mutex<HashMap<FlightKey, Arc<Flight>>>,
+}
+
+impl SingleFlight {
+
+    pub fn enable(&self, key: &str) {
+        self.keys.write().unwrap().insert(key.to_string());
+    }
+
+    pub fn is_enabled(&self, key: &str) -> bool {
+        self.keys.read().unwrap().contains(key)
+    }
+
+    /// Runs `call`, or waits for the call with the same key, version and input that is
+    /// already running and returns its response. If that call panics the waiters run their own.
+    pub fn run<F: Fn() -> String>(&self, key: &str, version: Option<u32>, input_data: &str, call: F) -> String {
+        let flight_key = (key.to_string(), version, input_data.to_string());
+        loop {
+            let (flight, leader) = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(&flight_key) {
+                    Some(flight) => (flight.clone(), false),
+                    None => {
+                        let flight = Arc::new(Flight::default());
+                        in_flight.insert(flight_key.clone(), flight.clone());
+                        (flight, true)
+                    },
+                }
+            };
+
+            if leader {
+                let mut landing = Landing {
+                    single_flight: self,
+                    flight_key: &flight_key,
+                    flight,
+                    response: None,
+                };
+                let response = call();
+                landing.response = Some(response.clone());
+                return response;
+            }
+
+            let mut response = flight.response.lock().unwrap();
+            while response.is_none() {
+                response = flight.done.wait(response).unwrap();
+            }
+            if let Some(response) = response.clone().unwrap() {
+                return response;
+            }
+        }
+    }
+
+}
+
+/// Publishes the response of the leading call to the waiters, also when the call panics.
+struct Landing<'a> {
+    single_flight: &'a SingleFlight,
+    flight_key: &'a FlightKey,
+    flight: Arc<Flight>,
+    response: Option<String>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.single_flight.in_flight.lock().unwrap().remove(self.flight_key);
+        *self.flight.response.lock().unwrap() = Some(self.response.take());
+        self.flight.done.notify_all();
+    }
+}
@@ -0,0 +1,272 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::panic_message;
+use crate::rpc::{current_request_id, RpcGate};
+use crate::service::{Context, Service};
+
+/// One line of the stdio protocol, used for both requests and responses.
+#[derive(Serialize, Deserialize)]
+struct StdioMessage {
+    key: String,
+    data: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// Set instead of `data` when the request couldn't be answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// How long a dropped `StdioRpcClient` waits for the child to exit before killing it.
+const CHILD_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+const CHILD_EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Serves RPC calls over stdin and stdout, so a module can run as a child process.
+///
+/// Every request is a `{"key":..,"data":..}` line, answered by a line of the same shape
+/// with the handler response as `data`. An optional `request_id` is echoed back.
+/// Malformed requests and panicking handlers are answered with an `error` line.
+/// Stdout carries only the protocol, so logs must go to stderr, which is where
+/// `env_logger` writes by default.
+pub struct StdioRpcServer {
+    thread: JoinHandle<()>,
+}
+
+impl StdioRpcServer {
+
+    pub fn run(context: &Context) -> Self {
+        let rpc_gate = context.get_service::<RpcGate>();
+        let thread = std::thread::spawn(move || {
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            if let Err(err) = Self::serve(stdin.lock(), stdout.lock(), &rpc_gate) {
+                log::error!("Stdio RPC failed: {}", err);
+            }
+            log::info!("Stdin closed, stdio RPC stopped");
+        });
+        Self {
+            thread,
+        }
+    }
+
+    /// Blocks until stdin is closed by the parent process.
+    pub fn join(self) {
+        let _ = self.thread.join();
+    }
+
+    fn serve(reader: impl BufRead, mut writer: impl Write, rpc_gate: &Service<RpcGate>) -> io::Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<StdioMessage>(&line) {
+                Ok(request) => Self::answer(request, rpc_gate),
+                Err(err) => {
+                    log::warn!("Malformed stdio RPC request: {}", err);
+                    StdioMessage::error(String::new(), None, format!("Malformed request: {}", err))
+                },
+            };
+            writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn answer(request: StdioMessage, rpc_gate: &Service<RpcGate>) -> StdioMessage {
+        let input_data = request.data.to_string();
+        let response = panic::catch_unwind(AssertUnwindSafe(|| match &request.request_id {
+            Some(request_id) => rpc_gate.call_raw_with_request_id(&request.key, &input_data, request_id),
+            None => rpc_gate.call_raw(&request.key, &input_data),
+        }));
+        let response = match response {
+            Ok(response) => response,
+            Err(panic) => {
+                let message = panic_message(panic.as_ref());
+                log::error!("Stdio RPC handler for '{}' panicked: {}", request.key, message);
+                return StdioMessage::error(request.key, request.request_id, format!("Handler panicked: {}", message));
+            },
+        };
+        match serde_json::from_str(&response) {
+            Ok(data) => StdioMessage {
+                key: request.key,
+                data,
+                request_id: request.request_id,
+                error: None,
+            },
+            Err(err) => StdioMessage::error(request.key, request.request_id, format!("Handler returned invalid JSON: {}", err)),
+        }
+    }
+
+}
+
+impl StdioMessage {
+    fn error(key: String, request_id: Option<String>, message: String) -> Self {
+        Self {
+            key,
+            data: serde_json::Value::Null,
+            request_id,
+            error: Some(message),
+        }
+    }
+}
+
+struct StdioPipes {
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// Spawns a module with `StdioRpcServer` and calls it over the child's stdin and stdout.
+/// Stderr of the child is inherited, so its logs show up in ours.
+pub struct StdioRpcClient {
+    child: Child,
+    pipes: Mutex<Option<StdioPipes>>,
+}
+
+impl StdioRpcClient {
+
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let pipes = StdioPipes {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+        };
+        Ok(Self {
+            child,
+            pipes: Mutex::new(Some(pipes)),
+        })
+    }
+
+    pub fn call_raw(&self, key: &str, request: &str) -> io::Result<String> {
+        let request = StdioMessage {
+            key: key.to_string(),
+            data: serde_json::from_str(request)?,
+            request_id: current_request_id(),
+            error: None,
+        };
+        let mut pipes = self.pipes.lock().unwrap();
+        let pipes = pipes.as_mut().unwrap();
+        writeln!(pipes.stdin, "{}", serde_json::to_string(&request)?)?;
+        pipes.stdin.flush()?;
+
+        let mut line = String::new();
+        if pipes.stdout.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Child process closed stdout"));
+        }
+        let response: StdioMessage = serde_json::from_str(&line)?;
+        match response.error {
+            Some(message) => Err(io::Error::other(message)),
+            None => Ok(response.data.to_string()),
+        }
+    }
+
+    pub fn send_request<O, I>(&self, key: &str, request: &O) -> I where
+            for<'de> I: Deserialize<'de> + Send + 'static,
+            O: Serialize + Send + 'static,
+    {
+        let request = serde_json::to_string(request).unwrap();
+        let response = self.call_raw(key, &request).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+}
+
+impl Drop for StdioRpcClient {
+    fn drop(&mut self) {
+        // Closing stdin lets the child shut down on its own, it is killed if it doesn't
+        self.pipes.lock().unwrap().take();
+        let deadline = Instant::now() + CHILD_EXIT_TIMEOUT;
+        while Instant::now() < deadline {
+            match self.child.try_wait() {
+                Ok(Some(_)) => return,
+                Ok(None) => thread::sleep(CHILD_EXIT_POLL_INTERVAL),
+                Err(err) => {
+                    log::warn!("Unable to check stdio RPC child: {}", err);
+                    break;
+                },
+            }
+        }
+        log::warn!("Stdio RPC child didn't exit after stdin was closed, killing it");
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::rpc::{Rpc, RpcGate};
+    use crate::rpc::stdio::StdioRpcServer;
+    use crate::service::Context;
+
+    #[test]
+    fn test_serve() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.get_service::<Rpc>().on_generic_call_fn("test.double", |value: &i64| value * 2);
+
+        let input = "{\"key\":\"test.double\",\"data\":21}\n\n{\"key\":\"test.double\",\"data\":5,\"request_id\":\"r1\"}\n";
+        let mut output = Vec::new();
+        StdioRpcServer::serve(Cursor::new(input), &mut output, &context.get_service::<RpcGate>()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(),
+            "{\"key\":\"test.double\",\"data\":42}\n{\"key\":\"test.double\",\"data\":10,\"request_id\":\"r1\"}\n");
+    }
+
+    #[test]
+    fn test_serve_errors() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        rpc.on_generic_call_fn("test.double", |value: &i64| value * 2);
+        rpc.on_generic_call_fn("test.panic", |_: &i64| -> i64 { panic!("boom") });
+
+        let input = "not json\n{\"key\":\"test.panic\",\"data\":1,\"request_id\":\"r1\"}\n{\"key\":\"test.double\",\"data\":2}\n";
+        let mut output = Vec::new();
+        StdioRpcServer::serve(Cursor::new(input), &mut output, &context.get_service::<RpcGate>()).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(output).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0]["error"].as_str().unwrap().starts_with("Malformed request"));
+        assert_eq!(lines[1]["request_id"], "r1");
+        assert_eq!(lines[1]["error"], "Handler panicked: boom");
+        assert_eq!(lines[2]["data"], 4);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_client_framing() {
+        use std::process::Command;
+        use crate::rpc::stdio::StdioRpcClient;
+
+        // `cat` echoes every request back, which is a valid response line
+        let client = StdioRpcClient::spawn(&mut Command::new("cat")).unwrap();
+        let response: Vec<String> = client.send_request("test.echo", &vec!["a".to_string()]);
+        assert_eq!(response, vec!["a".to_string()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_client_drop_kills_child() {
+        use std::process::Command;
+        use std::time::{Duration, Instant};
+        use crate::rpc::stdio::StdioRpcClient;
+
+        // Ignores stdin, so closing it doesn't make it exit
+        let client = StdioRpcClient::spawn(Command::new("sleep").arg("30")).unwrap();
+        let started = Instant::now();
+        drop(client);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -1,6 +1,24 @@
 use serde::{Deserialize, Serialize};
 use reqwest::blocking::Client;
 use reqwest::blocking::RequestBuilder;
+use reqwest::Url;
+
+use crate::rpc::RpcResult;
+
+const BASE_URL: &str = "http://127.0.0.1:8090";
+
+#[derive(Debug, thiserror::Error)]
+pub enum RpcClientError {
+    #[error("File '{path}' of '{key}' not found")]
+    NotFound {
+        key: String,
+        path: String,
+    },
+    #[error("Server answered with status {0}")]
+    Status(u16),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
 
 #[derive(Clone)]
 pub struct RpcTcpClient {
@@ -25,8 +43,53 @@ impl RpcTcpClient {
             .json().unwrap()
     }
 
+    /// Calls a handler registered with `on_generic_call_result_fn`.
+    pub fn send_request_result<O, I, E>(&self, key: &str, request: &O) -> Result<I, E> where
+            for<'de> I: Deserialize<'de> + Send + 'static,
+            for<'de> E: Deserialize<'de> + Send + 'static,
+            O: Serialize + Send + 'static,
+    {
+        let response: RpcResult<I, E> = self.send_request(key, request);
+        response.into()
+    }
+
+    /// Downloads a file served by the `RpcGate::get_file` handler registered for `key`.
+    pub fn get_file(&self, key: &str, path: &str) -> Result<Vec<u8>, RpcClientError> {
+        let response = self.client.get(file_url(key, path)).send()?;
+        match response.status() {
+            status if status.is_success() => Ok(response.bytes()?.to_vec()),
+            reqwest::StatusCode::NOT_FOUND => Err(RpcClientError::NotFound {
+                key: key.to_string(),
+                path: path.to_string(),
+            }),
+            status => Err(RpcClientError::Status(status.as_u16())),
+        }
+    }
+
     fn request_builder(&self, key: &str) -> RequestBuilder {
-        self.client.post("http://127.0.0.1:8090/api/rpc_call").query(&[("key", key)])
+        self.client.post(format!("{}/api/rpc_call", BASE_URL)).query(&[("key", key)])
     }
 
 }
+
+/// Percent-encodes `key` and every segment of `path`, the `/` between the segments are kept.
+fn file_url(key: &str, path: &str) -> Url {
+    let mut url = Url::parse(BASE_URL).unwrap();
+    url.path_segments_mut().unwrap()
+        .push("get_file")
+        .push(key)
+        .extend(path.split('/'));
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::tcp_client::file_url;
+
+    #[test]
+    fn test_file_url() {
+        assert_eq!(file_url("media", "covers/a b#1?.png").as_str(),
+            "http://127.0.0.1:8090/get_file/media/covers/a%20b%231%3F.png");
+        assert_eq!(file_url("my key", "100%").as_str(), "http://127.0.0.1:8090/get_file/my%20key/100%25");
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::RpcGate;
+use crate::service::{Context, Service};
+
+/// Frames larger than this are rejected, so a broken peer can't make us allocate gigabytes.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Frames are a big endian `u32` length followed by that many bytes of UTF-8.
+fn write_frame(stream: &mut impl Write, data: &str) -> io::Result<()> {
+    if data.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Frame of {} bytes is too large", data.len())));
+    }
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data.as_bytes())
+}
+
+/// Returns `None` on a clean end of stream before the frame starts.
+fn read_frame(stream: &mut impl Read) -> io::Result<Option<String>> {
+    let mut len_bytes = [0u8; 4];
+    match stream.read_exact(&mut len_bytes) {
+        Ok(()) => {},
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame of {} bytes is too large", len)));
+    }
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data)?;
+    String::from_utf8(data)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Removes `path` only if it is a socket nothing accepts connections on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and isn't a socket", path.display())));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("A server is already listening on {}", path.display())));
+    }
+    std::fs::remove_file(path)
+}
+
+/// Serves RPC calls over a unix domain socket, for local front-ends that don't need HTTP.
+///
+/// A request is a `key` frame followed by a JSON frame, the response is a single JSON frame.
+/// A connection may carry any number of requests one after another.
+pub struct UnixSocketRpcServer {
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+}
+
+impl UnixSocketRpcServer {
+
+    /// Binds the socket, replacing a stale socket file left by a previous run. Fails with
+    /// `AddrInUse` if a server still listens on `path`, and `AlreadyExists` if it isn't a socket.
+    pub fn bind(context: &Context, path: &Path) -> io::Result<Self> {
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        let rpc_gate = context.get_service::<RpcGate>();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let stopped_copy = stopped.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped_copy.load(Ordering::Relaxed) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let rpc_gate = rpc_gate.clone();
+                        std::thread::spawn(move || Self::serve_connection(stream, rpc_gate));
+                    },
+                    Err(err) => log::warn!("Unix socket accept failed: {}", err),
+                }
+            }
+        });
+
+        log::info!("Serving RPC on unix socket {:?}", path);
+        Ok(Self {
+            path: path.to_path_buf(),
+            stopped,
+        })
+    }
+
+    fn serve_connection(mut stream: UnixStream, rpc_gate: Service<RpcGate>) {
+        loop {
+            let key = match read_frame(&mut stream) {
+                Ok(Some(key)) => key,
+                Ok(None) => break,
+                Err(err) => {
+                    log::warn!("Unix socket read failed: {}", err);
+                    break;
+                }
+            };
+            let request = match read_frame(&mut stream) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(err) => {
+                    log::warn!("Unix socket read failed: {}", err);
+                    break;
+                }
+            };
+            let response = rpc_gate.call_raw(&key, &request);
+            if let Err(err) = write_frame(&mut stream, &response) {
+                log::warn!("Unix socket write failed: {}", err);
+                break;
+            }
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Stops accepting connections and removes the socket file.
+    /// Connections that are already open are served until the client closes them.
+    pub fn stop(&self) {
+        if self.stopped.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        // Wake the accept loop up so it sees the flag
+        let _ = UnixStream::connect(&self.path);
+        let _ = std::fs::remove_file(&self.path);
+    }
+
+}
+
+impl Drop for UnixSocketRpcServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub struct UnixSocketRpcClient {
+    stream: Mutex<UnixStream>,
+}
+
+impl UnixSocketRpcClient {
+
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            stream: Mutex::new(UnixStream::connect(path)?),
+        })
+    }
+
+    pub fn call_raw(&self, key: &str, request: &str) -> io::Result<String> {
+        let mut stream = self.stream.lock().unwrap();
+        write_frame(&mut *stream, key)?;
+        write_frame(&mut *stream, request)?;
+        read_frame(&mut *stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the connection"))
+    }
+
+    pub fn send_request<O, I>(&self, key: &str, request: &O) -> I where
+            for<'de> I: Deserialize<'de> + Send + 'static,
+            O: Serialize + Send + 'static,
+    {
+        let request = serde_json::to_string(request).unwrap();
+        let response = self.call_raw(key, &request).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::os::unix::net::UnixListener;
+
+    use crate::rpc::Rpc;
+    use crate::rpc::unix_socket::{UnixSocketRpcClient, UnixSocketRpcServer};
+    use crate::service::Context;
+
+    #[test]
+    fn test_call() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.get_service::<Rpc>().on_generic_call_fn("test.double", |value: &i64| value * 2);
+
+        let path = std::env::temp_dir().join(format!("amina_rpc_test_{}.sock", std::process::id()));
+        let server = UnixSocketRpcServer::bind(&context, path.as_path()).unwrap();
+
+        let client = UnixSocketRpcClient::connect(path.as_path()).unwrap();
+        let response: i64 = client.send_request("test.double", &21i64);
+        assert_eq!(response, 42);
+        assert_eq!(client.call_raw("test.double", "5").unwrap(), "10".to_string());
+        assert_eq!(client.call_raw("test.missing", "{}").unwrap(), "{ }".to_string());
+
+        server.stop();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bind_existing_path() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let path = std::env::temp_dir().join(format!("amina_rpc_bind_test_{}.sock", std::process::id()));
+
+        std::fs::write(&path, "data").unwrap();
+        let err = UnixSocketRpcServer::bind(&context, path.as_path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        std::fs::remove_file(&path).unwrap();
+
+        // Dropping a listener leaves its socket file behind
+        drop(UnixListener::bind(&path).unwrap());
+        let server = UnixSocketRpcServer::bind(&context, path.as_path()).unwrap();
+        let err = UnixSocketRpcServer::bind(&context, path.as_path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(UnixSocketRpcClient::connect(path.as_path()).is_ok());
+
+        server.stop();
+    }
+}
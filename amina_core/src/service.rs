@@ -1,11 +1,55 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::any::{TypeId, Any};
-use std::sync::{Arc, RwLock};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
 use std::ops::Deref;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
+use amina_core_derive::Event;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::events::{panic_message, Event, EventEmitter};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ServiceError {
+    /// Returned by `ServiceApi::try_start` implementations.
+    #[error("{0}")]
+    Failed(String),
+    #[error("Service {service} failed to start: {reason}")]
+    StartFailed {
+        service: String,
+        reason: String,
+    },
+    #[error("Service {0} isn't initialized in this context")]
+    NotInitialized(String),
+    #[error("Context isn't started")]
+    NotStarted,
+}
+
+/// Emitted by `Context::restart_service` once the service and its dependents are running again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Event)]
+#[key = "amina_core.context.service_restarted"]
+pub struct ServiceRestartedEvent {
+    pub name: String,
+    /// Restarted along with the service, in start order.
+    pub dependents: Vec<String>,
+}
+
+/// `Context::start`, `stop` and `restart_service` call `try_start` and `stop` on a helper
+/// thread to enforce the service timeout. Thread-locals of the calling thread and a tokio
+/// runtime entered there aren't available in them.
 pub trait ServiceApi: Send + Sync + 'static {
     fn start(&self) { }
     fn stop(&self) { }
+
+    /// Called by `Context::start` instead of `start`, override it for services that can fail
+    /// to start. The default calls `start` and succeeds.
+    fn try_start(&self) -> Result<(), ServiceError> {
+        self.start();
+        Ok(())
+    }
 }
 
 pub trait ServiceInitializer: ServiceApi {
@@ -14,6 +58,217 @@ pub trait ServiceInitializer: ServiceApi {
 
 struct ServiceWrapper {
     entry: Arc<dyn Any + Send + Sync>,
+    /// `type_name` of the service, `TypeId` can't be turned back into a name.
+    name: &'static str,
+}
+
+type ServicesMap = Arc<RwLock<HashMap<TypeId, ServiceWrapper>>>;
+
+/// Service recorded by `Context::register`, initialized on demand.
+struct PendingService {
+    type_id: TypeId,
+    name: &'static str,
+    init: fn(&Context),
+}
+
+/// State of a single service of a context, see `Context::get_service_states`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "state", content = "reason", rename_all = "snake_case")]
+pub enum ServiceState {
+    /// Recorded by `Context::register`, not initialized yet.
+    Registered,
+    Initialized,
+    Started,
+    /// `try_start` failed with the given reason.
+    Failed(String),
+    Stopped,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ServiceStatus {
+    pub name: String,
+    #[serde(flatten)]
+    pub state: ServiceState,
+}
+
+/// Initialized service in the order `Context::start` starts them.
+struct ServiceEntry {
+    type_id: TypeId,
+    name: &'static str,
+    service: Arc<dyn ServiceApi>,
+    state: ServiceState,
+    /// How long the last `start` and `stop` took, see `Context::startup_report`.
+    start_time: Option<Duration>,
+    stop_time: Option<Duration>,
+}
+
+/// Time a service took to start and stop, `None` until it did.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ServiceTiming {
+    pub name: String,
+    pub start_ms: Option<u64>,
+    pub stop_ms: Option<u64>,
+}
+
+/// Default of `Context::set_service_timeout`.
+pub const DEFAULT_SERVICE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Result of a call made by `call_with_timeout`, or that its caller stopped waiting for it.
+#[derive(Default)]
+struct CallSlot {
+    result: Option<Result<(), String>>,
+    abandoned: bool,
+}
+
+/// Runs `call` on a helper thread and waits for it at most `timeout`. A call that times out
+/// is left running on its thread: `on_timeout` runs when the wait gives up, and `on_late`
+/// gets the result once the call returns, always after `on_timeout`. Returns how long the call took.
+fn call_with_timeout<F, T, L>(action: &str, timeout: Duration, call: F, on_timeout: T, on_late: L) -> Result<Duration, String> where
+    F: FnOnce() -> Result<(), ServiceError> + Send + 'static,
+    T: FnOnce(),
+    L: FnOnce(Result<(), String>) + Send + 'static,
+{
+    let started = Instant::now();
+    let slot = Arc::new((Mutex::new(CallSlot::default()), Condvar::new()));
+    let slot_copy = slot.clone();
+    let action_copy = action.to_string();
+    thread::Builder::new()
+        .name(format!("service-{}", action))
+        .spawn(move || {
+            let result = match panic::catch_unwind(AssertUnwindSafe(call)) {
+                Ok(result) => result.map_err(|err| err.to_string()),
+                Err(panic) => Err(format!("panicked during {}: {}", action_copy, panic_message(panic.as_ref()))),
+            };
+            let (lock, condvar) = &*slot_copy;
+            let mut slot = lock.lock().unwrap();
+            if slot.abandoned {
+                drop(slot);
+                on_late(result);
+            } else {
+                slot.result = Some(result);
+                condvar.notify_one();
+            }
+        })
+        .map_err(|err| format!("can't spawn a thread to {}: {}", action, err))?;
+
+    let (lock, condvar) = &*slot;
+    let (mut slot, _) = condvar.wait_timeout_while(lock.lock().unwrap(), timeout, |slot| slot.result.is_none()).unwrap();
+    match slot.result.take() {
+        Some(result) => result.map(|()| started.elapsed()),
+        None => {
+            slot.abandoned = true;
+            on_timeout();
+            Err(format!("didn't {} within {:?}", action, timeout))
+        },
+    }
+}
+
+fn init_pending<S>(context: &Context) where S: ServiceInitializer {
+    context.init_service::<S>();
+}
+
+/// Services whose initializers are running, outermost first, per initializing thread.
+type ResolvingChains = Mutex<HashMap<ThreadId, Vec<(TypeId, &'static str)>>>;
+
+/// Pops the service from the chain of initializing services once its initializer returns.
+struct ResolvingGuard<'a> {
+    resolving: &'a ResolvingChains,
+    thread_id: ThreadId,
+}
+
+impl Drop for ResolvingGuard<'_> {
+    fn drop(&mut self) {
+        let mut resolving = self.resolving.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(chain) = resolving.get_mut(&self.thread_id) {
+            chain.pop();
+            if chain.is_empty() {
+                resolving.remove(&self.thread_id);
+            }
+        }
+    }
+}
+
+fn sorted_names(services: &HashMap<TypeId, ServiceWrapper>) -> Vec<&'static str> {
+    let mut names: Vec<&'static str> = services.values().map(|wrapper| wrapper.name).collect();
+    names.sort_unstable();
+    names
+}
+
+/// Searches `services`, then the `parents`, nearest first.
+fn find_entry(services: &ServicesMap, parents: &[ServicesMap], type_id: TypeId) -> Option<Arc<dyn Any + Send + Sync>> {
+    std::iter::once(services)
+        .chain(parents.iter())
+        .find_map(|services| services.read().unwrap().get(&type_id).map(|wrapper| wrapper.entry.clone()))
+}
+
+fn visible_names(services: &ServicesMap, parents: &[ServicesMap]) -> Vec<&'static str> {
+    let mut names: Vec<&'static str> = std::iter::once(services)
+        .chain(parents.iter())
+        .flat_map(|services| sorted_names(&services.read().unwrap()))
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+/// Lists the services of a context without borrowing it, for handlers registered during initialization
+/// or serving requests, e.g. a health check. The maps are held weakly, since those handlers are
+/// stored in services of the same context, so a view of a dropped context lists nothing.
+#[derive(Clone)]
+pub struct ServicesView {
+    services: Weak<RwLock<HashMap<TypeId, ServiceWrapper>>>,
+    services_order: Weak<RwLock<Vec<ServiceEntry>>>,
+    pending: Weak<Mutex<Vec<PendingService>>>,
+}
+
+impl ServicesView {
+    /// Like `Context::services_count`, read at the time of the call.
+    pub fn count(&self) -> usize {
+        self.services.upgrade()
+            .map(|services| services.read().unwrap().len())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn names(&self) -> Vec<&'static str> {
+        self.services.upgrade()
+            .map(|services| sorted_names(&services.read().unwrap()))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn timings(&self) -> Vec<ServiceTiming> {
+        let services_order = match self.services_order.upgrade() {
+            Some(services_order) => services_order,
+            None => return Vec::new(),
+        };
+        let timings = services_order.read().unwrap().iter()
+            .map(|entry| ServiceTiming {
+                name: entry.name.to_string(),
+                start_ms: entry.start_time.map(|time| time.as_millis() as u64),
+                stop_ms: entry.stop_time.map(|time| time.as_millis() as u64),
+            })
+            .collect();
+        timings
+    }
+
+    /// Initialized services in initialization order, then the registered ones.
+    pub(crate) fn states(&self) -> Vec<ServiceStatus> {
+        let mut states: Vec<ServiceStatus> = match self.services_order.upgrade() {
+            Some(services_order) => services_order.read().unwrap().iter()
+                .map(|entry| ServiceStatus {
+                    name: entry.name.to_string(),
+                    state: entry.state.clone(),
+                })
+                .collect(),
+            None => return Vec::new(),
+        };
+        if let Some(pending) = self.pending.upgrade() {
+            states.extend(pending.lock().unwrap().iter().map(|pending| ServiceStatus {
+                name: pending.name.to_string(),
+                state: ServiceState::Registered,
+            }));
+        }
+        states
+    }
 }
 
 pub struct Service<S: ServiceApi> {
@@ -44,52 +299,509 @@ impl<S: ServiceApi> Clone for Service<S> {
     }
 }
 
+/// Where a context is in its lifecycle, see `Context::start` and `Context::stop`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LifecycleState {
+    Created,
+    Started,
+    Stopped,
+}
+
 pub struct Context {
-    services: RwLock<HashMap<TypeId, ServiceWrapper>>,
-    services_order: RwLock<Vec<Arc<dyn ServiceApi>>>,
+    services: ServicesMap,
+    state: Mutex<LifecycleState>,
+    services_order: Arc<RwLock<Vec<ServiceEntry>>>,
+    /// Registered services that aren't initialized yet, in registration order.
+    pending: Arc<Mutex<Vec<PendingService>>>,
+    /// Services whose initializers are running, outermost first. Kept per thread, so a lookup
+    /// from another thread isn't taken for a cycle or a dependency.
+    resolving: ResolvingChains,
+    /// Services each service asked for while initializing, used by `restart_service`.
+    dependencies: Mutex<HashMap<TypeId, Vec<TypeId>>>,
+    /// Held by `restart_service` and `stop`, so they don't interleave.
+    restart_lock: Mutex<()>,
+    /// Limit for a single `start` or `stop` call of a service.
+    service_timeout: RwLock<Duration>,
+    /// Services whose `start` timed out and is still running, see `orphaned_services`.
+    orphans: Arc<Mutex<Vec<&'static str>>>,
+    /// Services of the ancestors, nearest first, searched when a service isn't registered here.
+    parents: Vec<ServicesMap>,
+    /// Services added with `add_service_as`, kept apart so a trait entry can't replace a
+    /// concrete service of the same `TypeId`.
+    trait_services: ServicesMap,
+    trait_parents: Vec<ServicesMap>,
 }
 
 impl Context {
 
     pub fn new() -> Self {
         Context {
-            services: RwLock::new(HashMap::new()),
-            services_order: RwLock::new(Vec::new()),
+            services: Arc::new(RwLock::new(HashMap::new())),
+            state: Mutex::new(LifecycleState::Created),
+            services_order: Arc::default(),
+            pending: Arc::default(),
+            resolving: Mutex::new(HashMap::new()),
+            dependencies: Mutex::new(HashMap::new()),
+            restart_lock: Mutex::new(()),
+            service_timeout: RwLock::new(DEFAULT_SERVICE_TIMEOUT),
+            orphans: Arc::default(),
+            parents: Vec::new(),
+            trait_services: Arc::default(),
+            trait_parents: Vec::new(),
         }
     }
 
+    /// Context for a plugin: it sees the services of this context, including ones added later,
+    /// but its own registrations, `start` and `stop` stay in the child. A service added to the
+    /// child shadows the parent's service of the same type only for the child.
+    pub fn child(&self) -> Context {
+        let mut parents = vec![self.services.clone()];
+        parents.extend(self.parents.iter().cloned());
+        let mut trait_parents = vec![self.trait_services.clone()];
+        trait_parents.extend(self.trait_parents.iter().cloned());
+        Context {
+            services: Arc::new(RwLock::new(HashMap::new())),
+            state: Mutex::new(LifecycleState::Created),
+            services_order: Arc::default(),
+            pending: Arc::default(),
+            resolving: Mutex::new(HashMap::new()),
+            dependencies: Mutex::new(HashMap::new()),
+            restart_lock: Mutex::new(()),
+            service_timeout: RwLock::new(DEFAULT_SERVICE_TIMEOUT),
+            orphans: Arc::default(),
+            parents,
+            trait_services: Arc::default(),
+            trait_parents,
+        }
+    }
+
+    /// Panics if the context is stopped.
     pub fn init_service<S>(&self) where S: ServiceInitializer {
         let name = std::any::type_name::<S>();
+        self.assert_not_stopped(name);
+        let type_id = TypeId::of::<S>();
+        self.pending.lock().unwrap().retain(|pending| pending.type_id != type_id);
+        let _guard = self.enter_resolving(type_id, name);
         log::debug!("Initializing service: {}", name);
         let service = S::initialize(self);
         self.add_service_internal::<S>(service);
     }
 
+    /// Records the service without initializing it. It is initialized by `build`, or earlier
+    /// when an initializer asks for it, so services can be registered in any order.
+    pub fn register<S>(&self) where S: ServiceInitializer {
+        let type_id = TypeId::of::<S>();
+        if self.services.read().unwrap().contains_key(&type_id) {
+            log::debug!("Service {} is already initialized", std::any::type_name::<S>());
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if !pending.iter().any(|pending| pending.type_id == type_id) {
+            pending.push(PendingService {
+                type_id,
+                name: std::any::type_name::<S>(),
+                init: init_pending::<S>,
+            });
+        }
+    }
+
+    /// Initializes the registered services, each after the services its initializer asks for.
+    /// Panics on a dependency cycle, naming every service in it.
+    pub fn build(&self) {
+        loop {
+            let next = {
+                let mut pending = self.pending.lock().unwrap();
+                if pending.is_empty() {
+                    break;
+                }
+                pending.remove(0)
+            };
+            (next.init)(self);
+        }
+    }
+
+    /// Panics if the context is stopped.
     pub fn add_service<S>(&self, service: S) where S: ServiceApi {
         let name = std::any::type_name::<S>();
+        self.assert_not_stopped(name);
         log::debug!("Adding service: {}", name);
         self.add_service_internal::<S>(Arc::new(service));
     }
 
+    /// Panics if the service isn't registered here or in an ancestor, see `try_get_service`.
     pub fn get_service<S>(&self) -> Service<S> where S: ServiceApi  {
-        let services = self.services.read().unwrap();
-        let wrapper = services.get(&TypeId::of::<S>()).unwrap();
-        let service_any = wrapper.entry.clone();
-        Service {
-            entry: service_any,
+        match self.try_get_service::<S>() {
+            Some(service) => service,
+            None => panic!("Service {} isn't registered, registered services: [{}]",
+                std::any::type_name::<S>(), self.visible_service_names().join(", ")),
+        }
+    }
+
+    /// Searches this context, then its ancestors.
+    pub fn try_get_service<S>(&self) -> Option<Service<S>> where S: ServiceApi {
+        let type_id = TypeId::of::<S>();
+        if !self.services.read().unwrap().contains_key(&type_id) {
+            self.resolve_pending(type_id, std::any::type_name::<S>());
+        }
+        let entry = self.find_entry(type_id)?;
+        self.record_dependency(type_id);
+        Some(Service {
+            entry,
             _ptr: Arc::new(None),
+        })
+    }
+
+    /// Registers `service` under a trait, so dependents can be tested with a mock, e.g.
+    /// `context.add_service_as::<dyn PlayerApi>(Arc::new(MockPlayer::new()))`. Adding another
+    /// implementation replaces it for later lookups, `Arc`s handed out before keep the old one.
+    /// The context doesn't start or stop it, register the implementation with `add_service`
+    /// as well if it needs that. Trait services aren't counted by `services_count` and
+    /// `service_names`. Panics if the context is stopped.
+    pub fn add_service_as<T>(&self, service: Arc<T>) where T: ?Sized + Send + Sync + 'static {
+        let name = std::any::type_name::<T>();
+        self.assert_not_stopped(name);
+        log::debug!("Adding service: {}", name);
+        let wrapper = ServiceWrapper {
+            entry: Arc::new(service),
+            name,
+        };
+        self.trait_services.write().unwrap().insert(TypeId::of::<T>(), wrapper);
+    }
+
+    /// Panics if nothing is registered under the trait, see `add_service_as`.
+    pub fn get_service_dyn<T>(&self) -> Arc<T> where T: ?Sized + Send + Sync + 'static {
+        match self.try_get_service_dyn::<T>() {
+            Some(service) => service,
+            None => panic!("Service {} isn't registered, registered trait services: [{}]",
+                std::any::type_name::<T>(), visible_names(&self.trait_services, &self.trait_parents).join(", ")),
         }
     }
 
-    pub fn start(&self) {
-        for service in self.services_order.read().unwrap().iter() {
-            service.start();
+    /// Searches this context, then its ancestors.
+    pub fn try_get_service_dyn<T>(&self) -> Option<Arc<T>> where T: ?Sized + Send + Sync + 'static {
+        find_entry(&self.trait_services, &self.trait_parents, TypeId::of::<T>())
+            .and_then(|entry| entry.downcast_ref::<Arc<T>>().cloned())
+    }
+
+    fn find_entry(&self, type_id: TypeId) -> Option<Arc<dyn Any + Send + Sync>> {
+        find_entry(&self.services, &self.parents, type_id)
+    }
+
+    /// For optional integrations, e.g. using the `EventEmitter` only if there is one.
+    pub fn has_service<S>(&self) -> bool where S: ServiceApi {
+        let type_id = TypeId::of::<S>();
+        self.pending.lock().unwrap().iter().any(|pending| pending.type_id == type_id) ||
+        std::iter::once(&self.services)
+            .chain(self.parents.iter())
+            .any(|services| services.read().unwrap().contains_key(&type_id))
+    }
+
+    /// Names of the services of this context and its ancestors, sorted.
+    fn visible_service_names(&self) -> Vec<&'static str> {
+        visible_names(&self.services, &self.parents)
+    }
+
+    /// Services registered in this context, not counting the parents'.
+    pub fn services_count(&self) -> usize {
+        self.services.read().unwrap().len()
+    }
+
+    /// `type_name` of every service registered in this context, sorted.
+    pub fn service_names(&self) -> Vec<&'static str> {
+        sorted_names(&self.services.read().unwrap())
+    }
+
+    pub fn services_view(&self) -> ServicesView {
+        ServicesView {
+            services: Arc::downgrade(&self.services),
+            services_order: Arc::downgrade(&self.services_order),
+            pending: Arc::downgrade(&self.pending),
         }
     }
 
-    pub fn stop(&self) {
-        for service in self.services_order.read().unwrap().iter().rev() {
+    /// How long each service took to start and stop, in initialization order.
+    pub fn startup_report(&self) -> Vec<ServiceTiming> {
+        self.services_view().timings()
+    }
+
+    /// Limit for each service's `start` and `stop`. `start` fails when a service exceeds it,
+    /// `stop` logs the service and moves on to the next one.
+    pub fn set_service_timeout(&self, timeout: Duration) {
+        *self.service_timeout.write().unwrap() = timeout;
+    }
+
+    /// Services whose `start` exceeded the service timeout and hasn't returned yet. Each is
+    /// stopped as soon as its `start` returns, and removed from the list.
+    pub fn orphaned_services(&self) -> Vec<&'static str> {
+        self.orphans.lock().unwrap().clone()
+    }
+
+    /// State of every service of this context, in initialization order, registered ones last.
+    pub fn get_service_states(&self) -> Vec<ServiceStatus> {
+        self.services_view().states()
+    }
+
+    pub fn state(&self) -> LifecycleState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Builds the registered services, then starts every service in initialization order, so
+    /// dependencies start first. Does nothing if the context is already started, panics if it
+    /// is stopped, a stopped context can't be started again.
+    ///
+    /// On the first service that fails to start, the services started before it are stopped in
+    /// reverse order and the context ends up stopped.
+    pub fn start(&self) -> Result<(), ServiceError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            match *state {
+                LifecycleState::Created => *state = LifecycleState::Started,
+                LifecycleState::Started => {
+                    log::warn!("Context is already started");
+                    return Ok(());
+                },
+                LifecycleState::Stopped => panic!("Context can't be started after it was stopped"),
+            }
+        }
+        self.build();
+        // Cloned out, so services may use the context while starting
+        let services: Vec<(&'static str, Arc<dyn ServiceApi>)> = self.services_order.read().unwrap().iter()
+            .map(|entry| (entry.name, entry.service.clone()))
+            .collect();
+        let started = Instant::now();
+        for (index, (name, service)) in services.iter().enumerate() {
+            if let Err(reason) = self.start_entry(index, name, service) {
+                for (started_index, (started_name, started_service)) in services[..index].iter().enumerate().rev() {
+                    self.stop_entry(started_index, started_name, started_service);
+                }
+                *self.state.lock().unwrap() = LifecycleState::Stopped;
+                return Err(ServiceError::StartFailed {
+                    service: name.to_string(),
+                    reason,
+                });
+            }
+        }
+        log::info!("Started {} services in {:?}", services.len(), started.elapsed());
+        Ok(())
+    }
+
+    /// Starts the service on a helper thread within the service timeout, records the time and state.
+    /// A start that times out is recorded as an orphan, and the service is stopped once it returns.
+    fn start_entry(&self, index: usize, name: &'static str, service: &Arc<dyn ServiceApi>) -> Result<(), String> {
+        let timeout = *self.service_timeout.read().unwrap();
+        let service_copy = service.clone();
+        let late_service = service.clone();
+        let orphans = self.orphans.clone();
+        let on_timeout = || orphans.lock().unwrap().push(name);
+        let late_orphans = self.orphans.clone();
+        let on_late = move |result: Result<(), String>| {
+            if result.is_ok() {
+                log::warn!("Service {} finished starting after its timeout, stopping it", name);
+                late_service.stop();
+            }
+            let mut orphans = late_orphans.lock().unwrap();
+            if let Some(position) = orphans.iter().position(|orphan| *orphan == name) {
+                orphans.remove(position);
+            }
+        };
+        match call_with_timeout("start", timeout, move || service_copy.try_start(), on_timeout, on_late) {
+            Ok(elapsed) => {
+                log::debug!("Service {} started in {:?}", name, elapsed);
+                self.update_entry(index, |entry| {
+                    entry.state = ServiceState::Started;
+                    entry.start_time = Some(elapsed);
+                });
+                Ok(())
+            },
+            Err(reason) => {
+                log::error!("Service {} failed to start: {}", name, reason);
+                self.set_service_state(index, ServiceState::Failed(reason.clone()));
+                Err(reason)
+            },
+        }
+    }
+
+    /// Like `start_entry`, a service that fails to stop is logged and counts as stopped.
+    fn stop_entry(&self, index: usize, name: &'static str, service: &Arc<dyn ServiceApi>) {
+        let timeout = *self.service_timeout.read().unwrap();
+        let service = service.clone();
+        let result = call_with_timeout("stop", timeout, move || {
             service.stop();
+            Ok(())
+        }, || {}, move |_| log::warn!("Service {} finished stopping after its timeout", name));
+        match result {
+            Ok(elapsed) => {
+                log::debug!("Service {} stopped in {:?}", name, elapsed);
+                self.update_entry(index, |entry| entry.stop_time = Some(elapsed));
+            },
+            Err(reason) => log::error!("Service {} failed to stop: {}", name, reason),
+        }
+        self.set_service_state(index, ServiceState::Stopped);
+    }
+
+    /// Stops the service and the services that asked for it while initializing, directly or
+    /// through others, then starts them again in initialization order, e.g. after the listen
+    /// port changed. The instances are kept, so `get_service` returns the same service during
+    /// and after the restart. Emits `ServiceRestartedEvent` if there is an `EventEmitter`.
+    ///
+    /// If one of them fails to start, the ones after it stay stopped.
+    pub fn restart_service<S>(&self) -> Result<(), ServiceError> where S: ServiceApi {
+        let _restart = self.restart_lock.lock().unwrap();
+        if self.state() != LifecycleState::Started {
+            return Err(ServiceError::NotStarted);
+        }
+        let affected = self.restart_set(TypeId::of::<S>());
+        if affected.is_empty() {
+            return Err(ServiceError::NotInitialized(std::any::type_name::<S>().to_string()));
+        }
+        let services: Vec<(usize, &'static str, Arc<dyn ServiceApi>)> = {
+            let services_order = self.services_order.read().unwrap();
+            affected.iter()
+                .map(|index| (*index, services_order[*index].name, services_order[*index].service.clone()))
+                .collect()
+        };
+
+        log::info!("Restarting service {}", services[0].1);
+        for (index, name, service) in services.iter().rev() {
+            self.stop_entry(*index, name, service);
+        }
+        for (index, name, service) in services.iter() {
+            if let Err(reason) = self.start_entry(*index, name, service) {
+                return Err(ServiceError::StartFailed {
+                    service: name.to_string(),
+                    reason,
+                });
+            }
+        }
+
+        let event_emitter = self.find_entry(TypeId::of::<EventEmitter>())
+            .and_then(|entry| entry.downcast::<EventEmitter>().ok());
+        if let Some(event_emitter) = event_emitter {
+            event_emitter.emit_event(&ServiceRestartedEvent {
+                name: services[0].1.to_string(),
+                dependents: services[1..].iter().map(|(_, name, _)| name.to_string()).collect(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Indexes in `services_order` of the service and its dependents, ascending.
+    fn restart_set(&self, type_id: TypeId) -> Vec<usize> {
+        let services_order = self.services_order.read().unwrap();
+        let dependencies = self.dependencies.lock().unwrap();
+        // Dependencies are initialized before their dependents, so one pass finds every dependent
+        let mut affected = HashSet::new();
+        let mut indexes = Vec::new();
+        for (index, entry) in services_order.iter().enumerate() {
+            let is_dependent = !affected.is_empty() && dependencies.get(&entry.type_id)
+                .is_some_and(|dependencies| dependencies.iter().any(|dependency| affected.contains(dependency)));
+            if entry.type_id == type_id || is_dependent {
+                affected.insert(entry.type_id);
+                indexes.push(index);
+            }
+        }
+        indexes
+    }
+
+    /// Records that the service being initialized asked for `type_id`.
+    fn record_dependency(&self, type_id: TypeId) {
+        let dependent = match self.resolving.lock().unwrap().get(&thread::current().id()).and_then(|chain| chain.last()) {
+            Some((dependent, _)) if *dependent != type_id => *dependent,
+            _ => return,
+        };
+        let mut dependencies = self.dependencies.lock().unwrap();
+        let dependencies = dependencies.entry(dependent).or_default();
+        if !dependencies.contains(&type_id) {
+            dependencies.push(type_id);
+        }
+    }
+
+    /// Stops the services in reverse initialization order. Does nothing if the context
+    /// isn't started or is already stopped.
+    pub fn stop(&self) {
+        let _restart = self.restart_lock.lock().unwrap();
+        {
+            let mut state = self.state.lock().unwrap();
+            match *state {
+                LifecycleState::Started => *state = LifecycleState::Stopped,
+                LifecycleState::Created => {
+                    log::warn!("Context isn't started, not stopping it");
+                    return;
+                },
+                LifecycleState::Stopped => {
+                    log::warn!("Context is already stopped");
+                    return;
+                },
+            }
+        }
+        let services: Vec<(&'static str, Arc<dyn ServiceApi>)> = self.services_order.read().unwrap().iter()
+            .map(|entry| (entry.name, entry.service.clone()))
+            .collect();
+        let started = Instant::now();
+        for (index, (name, service)) in services.iter().enumerate().rev() {
+            self.stop_entry(index, name, service);
+        }
+        log::info!("Stopped {} services in {:?}", services.len(), started.elapsed());
+    }
+
+    fn set_service_state(&self, index: usize, state: ServiceState) {
+        self.update_entry(index, |entry| entry.state = state);
+    }
+
+    fn update_entry<F: FnOnce(&mut ServiceEntry)>(&self, index: usize, update: F) {
+        if let Some(entry) = self.services_order.write().unwrap().get_mut(index) {
+            update(entry);
+        }
+    }
+
+    /// Initializes the service if it is registered here and not initialized yet.
+    fn resolve_pending(&self, type_id: TypeId, name: &'static str) {
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.iter().position(|pending| pending.type_id == type_id) {
+                Some(index) => pending.remove(index),
+                None => {
+                    drop(pending);
+                    // Taken out of the pending list, but its initializer hasn't returned yet
+                    self.check_cycle(type_id, name);
+                    return;
+                },
+            }
+        };
+        (pending.init)(self);
+    }
+
+    fn enter_resolving(&self, type_id: TypeId, name: &'static str) -> ResolvingGuard<'_> {
+        self.check_cycle(type_id, name);
+        let thread_id = thread::current().id();
+        self.resolving.lock().unwrap().entry(thread_id).or_default().push((type_id, name));
+        ResolvingGuard {
+            resolving: &self.resolving,
+            thread_id,
+        }
+    }
+
+    fn check_cycle(&self, type_id: TypeId, name: &'static str) {
+        let chain = {
+            let resolving = self.resolving.lock().unwrap();
+            let chain = match resolving.get(&thread::current().id()) {
+                Some(chain) => chain,
+                None => return,
+            };
+            match chain.iter().position(|(id, _)| *id == type_id) {
+                Some(start) => chain[start..].iter()
+                    .map(|(_, name)| *name)
+                    .chain(std::iter::once(name))
+                    .collect::<Vec<_>>(),
+                None => return,
+            }
+        };
+        panic!("Dependency cycle between services: {}", chain.join(" -> "));
+    }
+
+    fn assert_not_stopped(&self, name: &str) {
+        if self.state() == LifecycleState::Stopped {
+            panic!("Can't add service {} to a stopped context", name);
         }
     }
 
@@ -97,17 +809,30 @@ impl Context {
         let type_id = TypeId::of::<S>();
         let wrapper = ServiceWrapper {
             entry: service_arc.clone(),
+            name: std::any::type_name::<S>(),
         };
         let mut services = self.services.write().unwrap();
         services.insert(type_id, wrapper);
-        self.services_order.write().unwrap().push(service_arc);
+        self.services_order.write().unwrap().push(ServiceEntry {
+            type_id,
+            name: std::any::type_name::<S>(),
+            service: service_arc,
+            state: ServiceState::Initialized,
+            start_time: None,
+            stop_time: None,
+        });
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
-    use crate::service::{ServiceApi, Context, Service, ServiceInitializer};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use crate::events::EventEmitter;
+    use crate::rpc::Rpc;
+    use crate::service::{ServiceApi, Context, LifecycleState, Service, ServiceError, ServiceInitializer, ServiceRestartedEvent, ServiceState};
+    use crate::tasks::TaskManager;
 
     struct ServiceOne {}
 
@@ -164,7 +889,506 @@ mod tests {
         let context = Context::new();
         context.init_service::<ServiceOne>();
         context.init_service::<ServiceTwo>();
-        context.start();
+        context.start().unwrap();
+        context.stop();
+    }
+
+    struct PluginService {
+        name: &'static str,
+        stopped: AtomicBool,
+    }
+
+    impl PluginService {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                stopped: AtomicBool::new(false),
+            }
+        }
+    }
+
+    impl ServiceApi for PluginService {
+        fn stop(&self) {
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_child() {
+        let context = Context::new();
+        context.init_service::<ServiceOne>();
+        let plugin_a = context.child();
+        let plugin_b = context.child();
+        plugin_a.init_service::<ServiceTwo>();
+        plugin_a.add_service(PluginService::new("a"));
+        plugin_b.add_service(PluginService::new("b"));
+
+        assert_eq!(plugin_a.get_service::<PluginService>().name, "a");
+        assert_eq!(plugin_b.get_service::<PluginService>().name, "b");
+        assert_eq!(context.services_count(), 1);
+        assert_eq!(plugin_a.service_names(), vec![
+            "amina_core::service::tests::PluginService",
+            "amina_core::service::tests::ServiceTwo",
+        ]);
+
+        // Services added to the parent later are visible, nested children see every ancestor
+        context.add_service(PluginService::new("core"));
+        assert_eq!(plugin_a.get_service::<PluginService>().name, "a");
+        assert_eq!(context.get_service::<PluginService>().name, "core");
+        let nested = plugin_a.child();
+        nested.get_service::<ServiceOne>().say_hello();
+        assert_eq!(nested.get_service::<PluginService>().name, "a");
+        assert_eq!(nested.services_count(), 0);
+
+        plugin_b.start().unwrap();
+        plugin_b.stop();
+        assert!(plugin_b.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+        assert!(!plugin_a.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+        assert!(!context.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let context = Context::new();
+        context.add_service(PluginService::new("a"));
+        context.stop();
+        assert_eq!(context.state(), LifecycleState::Created);
+        assert!(!context.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+
+        context.start().unwrap();
+        context.start().unwrap();
+        assert_eq!(context.state(), LifecycleState::Started);
+        context.stop();
+        assert_eq!(context.state(), LifecycleState::Stopped);
+        assert!(context.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+
+        context.get_service::<PluginService>().stopped.store(false, Ordering::Relaxed);
+        context.stop();
+        assert!(!context.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+
+        let add = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| context.init_service::<ServiceOne>()));
+        assert!(add.is_err());
+        let start = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| context.start()));
+        assert!(start.is_err());
+    }
+
+    #[test]
+    fn test_missing_service() {
+        let context = Context::new();
+        context.init_service::<ServiceOne>();
+        let child = context.child();
+        assert!(child.has_service::<ServiceOne>());
+        assert!(child.try_get_service::<ServiceOne>().is_some());
+        assert!(!child.has_service::<ServiceTwo>());
+        assert!(child.try_get_service::<ServiceTwo>().is_none());
+
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| { child.get_service::<ServiceTwo>(); })).unwrap_err();
+        assert_eq!(panic.downcast_ref::<String>().unwrap(), "Service amina_core::service::tests::ServiceTwo isn't registered, \
+            registered services: [amina_core::service::tests::ServiceOne]");
+    }
+
+    #[test]
+    fn test_service_names() {
+        let context = Context::new();
+        context.init_service::<ServiceOne>();
+        context.init_service::<ServiceTwo>();
+        assert_eq!(context.service_names(), vec![
+            "amina_core::service::tests::ServiceOne",
+            "amina_core::service::tests::ServiceTwo",
+        ]);
+    }
+
+    struct Journal {
+        entries: Mutex<Vec<&'static str>>,
+    }
+
+    impl ServiceApi for Journal { }
+
+    impl Journal {
+        fn write(&self, entry: &'static str) {
+            self.entries.lock().unwrap().push(entry);
+        }
+    }
+
+    struct Storage {
+        journal: Service<Journal>,
+    }
+
+    impl ServiceApi for Storage {
+        fn start(&self) {
+            self.journal.write("storage started");
+        }
+
+        fn stop(&self) {
+            self.journal.write("storage stopped");
+        }
+    }
+
+    impl ServiceInitializer for Storage {
+        fn initialize(context: &Context) -> Arc<Self> {
+            Arc::new(Self {
+                journal: context.get_service::<Journal>(),
+            })
+        }
+    }
+
+    struct Api {
+        journal: Service<Journal>,
+        _storage: Service<Storage>,
+    }
+
+    impl ServiceApi for Api {
+        fn start(&self) {
+            self.journal.write("api started");
+        }
+
+        fn stop(&self) {
+            self.journal.write("api stopped");
+        }
+    }
+
+    impl ServiceInitializer for Api {
+        fn initialize(context: &Context) -> Arc<Self> {
+            Arc::new(Self {
+                _storage: context.get_service::<Storage>(),
+                journal: context.get_service::<Journal>(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let context = Context::new();
+        context.add_service(Journal { entries: Mutex::new(Vec::new()) });
+        context.register::<Api>();
+        context.register::<Storage>();
+        assert!(context.has_service::<Storage>());
+        assert_eq!(context.services_count(), 1);
+        let services_view = context.services_view();
+
+        context.build();
+        assert_eq!(context.services_count(), 3);
+        assert_eq!(services_view.count(), 3);
+        context.start().unwrap();
         context.stop();
+        assert_eq!(*context.get_service::<Journal>().entries.lock().unwrap(), vec![
+            "storage started",
+            "api started",
+            "api stopped",
+            "storage stopped",
+        ]);
+
+        // Initialized services aren't registered again
+        let context = Context::new();
+        context.add_service(Journal { entries: Mutex::new(Vec::new()) });
+        context.init_service::<Storage>();
+        context.register::<Storage>();
+        context.register::<Api>();
+        context.start().unwrap();
+        assert_eq!(context.services_count(), 3);
+    }
+
+    #[test]
+    fn test_services_view_is_weak() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let services_view = context.services_view();
+        let services = Arc::downgrade(&context.services);
+        assert_eq!(services_view.count(), 2);
+
+        // The `amina.context.*` handlers stored in `Rpc` hold a view, the maps are freed anyway
+        drop(context);
+        assert!(services.upgrade().is_none());
+        assert_eq!(services_view.count(), 0);
+        assert!(services_view.states().is_empty());
+    }
+
+    struct CycleA {}
+
+    impl ServiceApi for CycleA { }
+
+    impl ServiceInitializer for CycleA {
+        fn initialize(context: &Context) -> Arc<Self> {
+            context.get_service::<CycleB>();
+            Arc::new(Self {})
+        }
+    }
+
+    struct CycleB {}
+
+    impl ServiceApi for CycleB { }
+
+    impl ServiceInitializer for CycleB {
+        fn initialize(context: &Context) -> Arc<Self> {
+            context.get_service::<CycleA>();
+            Arc::new(Self {})
+        }
+    }
+
+    #[test]
+    fn test_dependency_cycle() {
+        let context = Context::new();
+        context.init_service::<ServiceOne>();
+        context.register::<CycleA>();
+        context.register::<CycleB>();
+
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| context.build())).unwrap_err();
+        assert_eq!(panic.downcast_ref::<String>().unwrap(), "Dependency cycle between services: \
+            amina_core::service::tests::CycleA -> amina_core::service::tests::CycleB -> amina_core::service::tests::CycleA");
+        assert!(context.resolving.lock().unwrap().is_empty());
+    }
+
+    struct Spawning {}
+
+    impl ServiceApi for Spawning { }
+
+    impl ServiceInitializer for Spawning {
+        fn initialize(context: &Context) -> Arc<Self> {
+            // Lookups from another thread while this initializer runs
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    assert!(context.try_get_service::<Spawning>().is_none());
+                    assert!(context.try_get_service::<Journal>().is_some());
+                }).join().unwrap();
+            });
+            Arc::new(Self {})
+        }
+    }
+
+    #[test]
+    fn test_resolving_per_thread() {
+        let context = Context::new();
+        context.add_service(Journal { entries: Mutex::new(Vec::new()) });
+        context.init_service::<Spawning>();
+        assert!(context.resolving.lock().unwrap().is_empty());
+        assert!(!context.dependencies.lock().unwrap().contains_key(&std::any::TypeId::of::<Spawning>()));
+    }
+
+    trait PlayerApi: Send + Sync {
+        fn track(&self) -> String;
+    }
+
+    struct MockPlayer {
+        track: &'static str,
+    }
+
+    impl PlayerApi for MockPlayer {
+        fn track(&self) -> String {
+            self.track.to_string()
+        }
+    }
+
+    struct NowPlaying {
+        player: Arc<dyn PlayerApi>,
+    }
+
+    impl ServiceApi for NowPlaying { }
+
+    impl ServiceInitializer for NowPlaying {
+        fn initialize(context: &Context) -> Arc<Self> {
+            Arc::new(Self {
+                player: context.get_service_dyn::<dyn PlayerApi>(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_service_as_trait() {
+        let context = Context::new();
+        assert!(context.try_get_service_dyn::<dyn PlayerApi>().is_none());
+        context.add_service_as::<dyn PlayerApi>(Arc::new(MockPlayer { track: "first" }));
+        context.init_service::<NowPlaying>();
+        assert_eq!(context.get_service::<NowPlaying>().player.track(), "first");
+
+        // Seen by later lookups, including children, but not by services that already have the old one
+        let taken_before = context.get_service_dyn::<dyn PlayerApi>();
+        context.add_service_as::<dyn PlayerApi>(Arc::new(MockPlayer { track: "second" }));
+        assert_eq!(context.child().get_service_dyn::<dyn PlayerApi>().track(), "second");
+        assert_eq!(taken_before.track(), "first");
+        assert_eq!(context.get_service::<NowPlaying>().player.track(), "first");
+        assert_eq!(context.services_count(), 1);
+        assert_eq!(context.service_names(), vec!["amina_core::service::tests::NowPlaying"]);
+
+        // A concrete service and a trait entry of the same type don't replace each other
+        context.add_service(Journal { entries: Mutex::new(Vec::new()) });
+        context.add_service_as::<Journal>(Arc::new(Journal { entries: Mutex::new(vec!["mock"]) }));
+        assert!(context.get_service::<Journal>().entries.lock().unwrap().is_empty());
+        assert_eq!(context.get_service_dyn::<Journal>().entries.lock().unwrap().clone(), vec!["mock"]);
+        assert!(context.try_get_service_dyn::<NowPlaying>().is_none());
+    }
+
+    struct Broken {
+        journal: Service<Journal>,
+    }
+
+    impl ServiceApi for Broken {
+        fn stop(&self) {
+            self.journal.write("broken stopped");
+        }
+
+        fn try_start(&self) -> Result<(), ServiceError> {
+            Err(ServiceError::Failed("port is taken".to_string()))
+        }
+    }
+
+    impl ServiceInitializer for Broken {
+        fn initialize(context: &Context) -> Arc<Self> {
+            Arc::new(Self {
+                journal: context.get_service::<Journal>(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_service_states() {
+        let context = Context::new();
+        context.add_service(Journal { entries: Mutex::new(Vec::new()) });
+        context.init_service::<Storage>();
+        context.init_service::<Broken>();
+        context.register::<Api>();
+        assert_eq!(context.get_service_states()[1].state, ServiceState::Initialized);
+        assert_eq!(context.get_service_states()[3].state, ServiceState::Registered);
+
+        let err = context.start().unwrap_err();
+        assert_eq!(err.to_string(), "Service amina_core::service::tests::Broken failed to start: port is taken");
+        assert_eq!(context.state(), LifecycleState::Stopped);
+        // Started ones are rolled back, the ones after the failure never start
+        assert_eq!(*context.get_service::<Journal>().entries.lock().unwrap(), vec!["storage started", "storage stopped"]);
+        let states: Vec<ServiceState> = context.get_service_states().into_iter().map(|status| status.state).collect();
+        assert_eq!(states, vec![
+            ServiceState::Stopped,
+            ServiceState::Stopped,
+            ServiceState::Failed("port is taken".to_string()),
+            ServiceState::Initialized,
+        ]);
+        assert_eq!(serde_json::to_string(&context.get_service_states()[2]).unwrap(),
+            "{\"name\":\"amina_core::service::tests::Broken\",\"state\":\"failed\",\"reason\":\"port is taken\"}");
+        assert_eq!(serde_json::to_string(&context.get_service_states()[0]).unwrap(),
+            "{\"name\":\"amina_core::service::tests::Journal\",\"state\":\"stopped\"}");
+    }
+
+    #[test]
+    fn test_restart_service() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.add_service(Journal { entries: Mutex::new(Vec::new()) });
+        context.register::<Api>();
+        context.register::<Storage>();
+        context.init_service::<ServiceOne>();
+        assert_eq!(context.restart_service::<Storage>(), Err(ServiceError::NotStarted));
+        context.start().unwrap();
+
+        let journal = context.get_service::<Journal>();
+        journal.entries.lock().unwrap().clear();
+        let storage = context.get_service::<Storage>();
+        let event_emitter = context.get_service::<EventEmitter>();
+        let event: Option<ServiceRestartedEvent> = event_emitter.wait_for_event_after(Duration::from_secs(5), || {
+            context.restart_service::<Storage>().unwrap();
+        });
+        assert_eq!(event.unwrap(), ServiceRestartedEvent {
+            name: "amina_core::service::tests::Storage".to_string(),
+            dependents: vec!["amina_core::service::tests::Api".to_string()],
+        });
+        assert_eq!(*journal.entries.lock().unwrap(), vec![
+            "api stopped",
+            "storage stopped",
+            "storage started",
+            "api started",
+        ]);
+        // Same instance, no torn state for holders of the service
+        assert!(Arc::ptr_eq(&storage.entry, &context.get_service::<Storage>().entry));
+        assert!(context.get_service_states().iter().all(|status| status.state == ServiceState::Started));
+
+        journal.entries.lock().unwrap().clear();
+        context.restart_service::<Api>().unwrap();
+        assert_eq!(*journal.entries.lock().unwrap(), vec!["api stopped", "api started"]);
+        assert_eq!(context.restart_service::<ServiceTwo>(),
+            Err(ServiceError::NotInitialized("amina_core::service::tests::ServiceTwo".to_string())));
+    }
+
+    struct Slow {
+        start_delay: Duration,
+        stop_delay: Duration,
+        stopped: AtomicBool,
+    }
+
+    impl Slow {
+        fn new(start_delay: Duration, stop_delay: Duration) -> Self {
+            Self {
+                start_delay,
+                stop_delay,
+                stopped: AtomicBool::new(false),
+            }
+        }
+    }
+
+    impl ServiceApi for Slow {
+        fn start(&self) {
+            std::thread::sleep(self.start_delay);
+        }
+
+        fn stop(&self) {
+            std::thread::sleep(self.stop_delay);
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    struct Panicking;
+
+    impl ServiceApi for Panicking {
+        fn start(&self) {
+            panic!("config is missing");
+        }
+    }
+
+    #[test]
+    fn test_service_timeout() {
+        // A slow stop is logged and the remaining services are still stopped
+        let context = Context::new();
+        context.set_service_timeout(Duration::from_millis(200));
+        context.add_service(PluginService::new("a"));
+        context.add_service(Slow::new(Duration::from_millis(20), Duration::from_secs(2)));
+        context.start().unwrap();
+        let started = std::time::Instant::now();
+        context.stop();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(context.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+        let report = context.startup_report();
+        assert!(report[1].start_ms.unwrap() >= 20);
+        assert!(report[1].stop_ms.is_none());
+        assert!(report[0].stop_ms.is_some());
+
+        // A start that hangs aborts the startup
+        let context = Context::new();
+        context.set_service_timeout(Duration::from_millis(100));
+        context.add_service(PluginService::new("a"));
+        context.add_service(Slow::new(Duration::from_millis(400), Duration::ZERO));
+        let err = context.start().unwrap_err();
+        assert_eq!(err, ServiceError::StartFailed {
+            service: "amina_core::service::tests::Slow".to_string(),
+            reason: "didn't start within 100ms".to_string(),
+        });
+        assert!(context.get_service::<PluginService>().stopped.load(Ordering::Relaxed));
+        assert_eq!(context.state(), LifecycleState::Stopped);
+
+        // The orphaned start is stopped once it returns
+        assert_eq!(context.orphaned_services(), vec!["amina_core::service::tests::Slow"]);
+        assert!(!context.get_service::<Slow>().stopped.load(Ordering::Relaxed));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !context.orphaned_services().is_empty() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(context.orphaned_services().is_empty());
+        assert!(context.get_service::<Slow>().stopped.load(Ordering::Relaxed));
+
+        // A panic is reported with its message
+        let context = Context::new();
+        context.add_service(Panicking);
+        let err = context.start().unwrap_err();
+        assert_eq!(err, ServiceError::StartFailed {
+            service: "amina_core::service::tests::Panicking".to_string(),
+            reason: "panicked during start: config is missing".to_string(),
+        });
     }
 }
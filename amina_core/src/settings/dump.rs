@@ -0,0 +1,30 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Values of every registered settings instance, nested like in a JSON settings file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SettingsDump {
+    pub instances: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportMode {
+    /// Keys missing from the dump are kept.
+    Merge,
+    /// Keys missing from the dump are removed from the instances it contains.
+    Replace,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImportFailure {
+    pub key: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub applied: Vec<String>,
+    pub removed: Vec<String>,
+    pub failures: Vec<ImportFailure>,
+}
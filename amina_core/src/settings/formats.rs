@@ -0,0 +1,550 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use yaml_rust::{YamlLoader, Yaml, YamlEmitter};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+use yaml_rust::yaml::Hash;
+
+use crate::settings::SettingsLoadError;
+
+/// File format of a settings instance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SettingsFormat {
+    #[default]
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl SettingsFormat {
+
+    /// Picks the format from the file extension, unknown extensions are YAML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => SettingsFormat::Json,
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => SettingsFormat::Toml,
+            _ => SettingsFormat::Yaml,
+        }
+    }
+
+}
+
+/// Format independent value of a single property.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SettingsValue {
+    String(String),
+    I64(i64),
+    Bool(bool),
+    F64(f64),
+    StringList(Vec<String>),
+    I64List(Vec<i64>),
+    /// Also read from lists that mix ints and floats.
+    F64List(Vec<f64>),
+}
+
+impl From<&str> for SettingsValue {
+    fn from(value: &str) -> Self {
+        SettingsValue::String(value.to_string())
+    }
+}
+
+impl From<String> for SettingsValue {
+    fn from(value: String) -> Self {
+        SettingsValue::String(value)
+    }
+}
+
+impl From<i64> for SettingsValue {
+    fn from(value: i64) -> Self {
+        SettingsValue::I64(value)
+    }
+}
+
+impl From<bool> for SettingsValue {
+    fn from(value: bool) -> Self {
+        SettingsValue::Bool(value)
+    }
+}
+
+impl From<f64> for SettingsValue {
+    fn from(value: f64) -> Self {
+        SettingsValue::F64(value)
+    }
+}
+
+impl From<Vec<String>> for SettingsValue {
+    fn from(value: Vec<String>) -> Self {
+        SettingsValue::StringList(value)
+    }
+}
+
+impl From<Vec<i64>> for SettingsValue {
+    fn from(value: Vec<i64>) -> Self {
+        SettingsValue::I64List(value)
+    }
+}
+
+impl From<Vec<f64>> for SettingsValue {
+    fn from(value: Vec<f64>) -> Self {
+        SettingsValue::F64List(value)
+    }
+}
+
+/// Pushes a list of strings, of ints or of numbers, the items are read with the scalar
+/// conversion of the format. Empty lists are string lists, lists with other items or several
+/// kinds of items are skipped.
+fn push_list<I>(values: &mut Vec<(String, SettingsValue)>, key: String, items: I) where
+    I: Iterator<Item = Option<SettingsValue>>
+{
+    if let Some(list) = items.collect::<Option<Vec<SettingsValue>>>().and_then(list_value) {
+        values.push((key, list));
+    }
+}
+
+fn list_value(items: Vec<SettingsValue>) -> Option<SettingsValue> {
+    if items.iter().all(|item| matches!(item, SettingsValue::String(_))) {
+        return Some(SettingsValue::StringList(items.into_iter().filter_map(|item| match item {
+            SettingsValue::String(value) => Some(value),
+            _ => None,
+        }).collect()));
+    }
+    if items.iter().all(|item| matches!(item, SettingsValue::I64(_))) {
+        return Some(SettingsValue::I64List(items.into_iter().filter_map(|item| match item {
+            SettingsValue::I64(value) => Some(value),
+            _ => None,
+        }).collect()));
+    }
+    items.into_iter()
+        .map(|item| match item {
+            SettingsValue::I64(value) => Some(value as f64),
+            SettingsValue::F64(value) => Some(value),
+            _ => None,
+        })
+        .collect::<Option<Vec<f64>>>()
+        .map(SettingsValue::F64List)
+}
+
+/// Why a settings text couldn't be parsed, turned into `SettingsLoadError` once the file is known.
+/// Lines and columns start at 1.
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    Syntax {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    WrongRoot {
+        found: &'static str,
+    },
+    DuplicateKey {
+        key: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+impl ParseError {
+
+    pub(crate) fn with_path(self, path: &Path) -> SettingsLoadError {
+        let path = path.to_path_buf();
+        match self {
+            ParseError::Syntax { line, column, message } => SettingsLoadError::Syntax { path, line, column, message },
+            ParseError::WrongRoot { found } => SettingsLoadError::WrongRoot { path, found },
+            ParseError::DuplicateKey { key, line, column } => SettingsLoadError::DuplicateKey { path, key, line, column },
+        }
+    }
+
+}
+
+/// Parses `text` into flattened dotted keys. Unsupported values are skipped.
+pub(crate) fn parse(format: SettingsFormat, text: &str) -> Result<Vec<(String, SettingsValue)>, ParseError> {
+    let mut values = Vec::new();
+    match format {
+        SettingsFormat::Yaml => {
+            let docs = YamlLoader::load_from_str(text).map_err(|err| ParseError::Syntax {
+                line: err.marker().line(),
+                column: err.marker().col() + 1,
+                // The message of `ScanError` already ends with the location
+                message: err.to_string().split(" at line ").next().unwrap_or_default().to_string(),
+            })?;
+            match docs.first() {
+                Some(Yaml::Hash(hash)) => {
+                    check_yaml_duplicates(text)?;
+                    parse_yaml(hash, "", &mut values);
+                },
+                Some(other) => return Err(ParseError::WrongRoot { found: yaml_type_name(other) }),
+                None => {},
+            }
+        },
+        SettingsFormat::Json => {
+            let value: serde_json::Value = serde_json::from_str(text).map_err(|err| ParseError::Syntax {
+                line: err.line(),
+                column: err.column(),
+                message: err.to_string().split(" at line ").next().unwrap_or_default().to_string(),
+            })?;
+            match &value {
+                serde_json::Value::Object(object) => parse_json(object, "", &mut values),
+                other => return Err(ParseError::WrongRoot { found: json_type_name(other) }),
+            }
+        },
+        SettingsFormat::Toml => {
+            let table: toml::Table = text.parse().map_err(|err: toml::de::Error| {
+                let (line, column) = line_column(text, err.span().map(|span| span.start).unwrap_or_default());
+                ParseError::Syntax { line, column, message: err.message().to_string() }
+            })?;
+            parse_toml(&table, "", &mut values);
+        },
+    }
+    Ok(values)
+}
+
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = text.get(..offset).unwrap_or(text);
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+fn yaml_type_name(value: &Yaml) -> &'static str {
+    match value {
+        Yaml::Array(_) => "a list",
+        Yaml::Hash(_) => "a mapping",
+        Yaml::Null => "empty",
+        _ => "a single value",
+    }
+}
+
+fn json_type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Array(_) => "a list",
+        serde_json::Value::Object(_) => "a mapping",
+        serde_json::Value::Null => "null",
+        _ => "a single value",
+    }
+}
+
+enum YamlFrame {
+    /// `path` is the dotted key of the mapping, `None` inside lists where keys aren't flattened.
+    Mapping {
+        path: Option<String>,
+        keys: HashSet<String>,
+        pending_key: Option<(String, Marker)>,
+    },
+    Sequence,
+}
+
+/// Finds keys that yaml-rust would silently overwrite: a key repeated in one mapping,
+/// or two spellings of the same dotted key such as `a.b` next to `a: { b: .. }`.
+#[derive(Default)]
+struct YamlDuplicateFinder {
+    frames: Vec<YamlFrame>,
+    leaf_keys: HashSet<String>,
+    duplicate: Option<(String, Marker)>,
+}
+
+impl YamlDuplicateFinder {
+
+    /// Called when a value starts, returns the dotted key it is stored under.
+    fn value_key(&mut self) -> Option<(String, Marker)> {
+        match self.frames.last_mut() {
+            Some(YamlFrame::Mapping { path, pending_key, .. }) => {
+                let (key, mark) = pending_key.take()?;
+                path.as_ref().map(|path| (join_key(path, &key), mark))
+            },
+            _ => None,
+        }
+    }
+
+    fn add_leaf(&mut self) {
+        if let Some((key, mark)) = self.value_key() {
+            if !self.leaf_keys.insert(key.clone()) {
+                self.duplicate = Some((key, mark));
+            }
+        }
+    }
+
+}
+
+impl MarkedEventReceiver for YamlDuplicateFinder {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        if self.duplicate.is_some() {
+            return;
+        }
+        match event {
+            Event::MappingStart(_) => {
+                let path = if self.frames.is_empty() {
+                    Some(String::new())
+                } else {
+                    self.value_key().map(|(key, _)| key)
+                };
+                self.frames.push(YamlFrame::Mapping { path, keys: HashSet::new(), pending_key: None });
+            },
+            Event::SequenceStart(_) => {
+                self.add_leaf();
+                self.frames.push(YamlFrame::Sequence);
+            },
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.frames.pop();
+            },
+            Event::Scalar(value, ..) => {
+                if let Some(YamlFrame::Mapping { path, keys, pending_key: pending_key @ None }) = self.frames.last_mut() {
+                    if !keys.insert(value.clone()) {
+                        let key = join_key(path.as_deref().unwrap_or_default(), &value);
+                        self.duplicate = Some((key, mark));
+                    } else {
+                        *pending_key = Some((value, mark));
+                    }
+                } else {
+                    self.add_leaf();
+                }
+            },
+            Event::Alias(_) => self.add_leaf(),
+            _ => {},
+        }
+    }
+}
+
+fn check_yaml_duplicates(text: &str) -> Result<(), ParseError> {
+    let mut finder = YamlDuplicateFinder::default();
+    // Syntax errors are already reported by the loader
+    let _ = Parser::new(text.chars()).load(&mut finder, false);
+    match finder.duplicate {
+        Some((key, mark)) => Err(ParseError::DuplicateKey { key, line: mark.line(), column: mark.col() + 1 }),
+        None => Ok(()),
+    }
+}
+
+/// Writes flattened dotted keys as nested tables, sorted by key.
+pub(crate) fn dump(format: SettingsFormat, mut values: Vec<(String, SettingsValue)>) -> String {
+    values.sort_by(|a, b| a.0.cmp(&b.0));
+    match format {
+        SettingsFormat::Yaml => {
+            let mut root = Hash::new();
+            for (key, value) in values.iter() {
+                let mut key: Vec<&str> = key.split('.').collect();
+                dump_yaml(&mut root, &mut key, value);
+            }
+            let mut out_str = String::new();
+            YamlEmitter::new(&mut out_str).dump(&Yaml::Hash(root)).unwrap();
+            out_str
+        },
+        SettingsFormat::Json => serde_json::to_string_pretty(&to_json_value(values)).unwrap(),
+        SettingsFormat::Toml => {
+            let mut root = toml::Table::new();
+            for (key, value) in values {
+                let toml_value = match value {
+                    SettingsValue::String(value) => toml::Value::String(value),
+                    SettingsValue::I64(value) => toml::Value::Integer(value),
+                    SettingsValue::Bool(value) => toml::Value::Boolean(value),
+                    SettingsValue::F64(value) => toml::Value::Float(value),
+                    SettingsValue::StringList(value) => toml::Value::Array(value.into_iter().map(toml::Value::String).collect()),
+                    SettingsValue::I64List(value) => toml::Value::Array(value.into_iter().map(toml::Value::Integer).collect()),
+                    SettingsValue::F64List(value) => toml::Value::Array(value.into_iter().map(toml::Value::Float).collect()),
+                };
+                insert_nested(&mut root, &key, toml_value, |table, name| {
+                    table.entry(name).or_insert_with(|| toml::Value::Table(toml::Table::new())).as_table_mut()
+                });
+            }
+            toml::to_string(&root).unwrap()
+        },
+    }
+}
+
+/// Flattens a JSON object into dotted keys.
+pub(crate) fn from_json_value(value: &serde_json::Value) -> Result<Vec<(String, SettingsValue)>, String> {
+    let mut values = Vec::new();
+    match value {
+        serde_json::Value::Object(object) => parse_json(object, "", &mut values),
+        _ => return Err("Root element must be an object".to_string()),
+    }
+    Ok(values)
+}
+
+/// Nests dotted keys into a JSON object.
+pub(crate) fn to_json_value(values: Vec<(String, SettingsValue)>) -> serde_json::Value {
+    let mut root = serde_json::Map::new();
+    for (key, value) in values {
+        insert_nested(&mut root, &key, value_to_json(value), |table, name| {
+            table.entry(name).or_insert_with(|| serde_json::Value::Object(serde_json::Map::new())).as_object_mut()
+        });
+    }
+    serde_json::Value::Object(root)
+}
+
+pub(crate) fn value_to_json(value: SettingsValue) -> serde_json::Value {
+    match value {
+        SettingsValue::String(value) => serde_json::Value::from(value),
+        SettingsValue::I64(value) => serde_json::Value::from(value),
+        SettingsValue::Bool(value) => serde_json::Value::from(value),
+        SettingsValue::F64(value) => serde_json::Value::from(value),
+        SettingsValue::StringList(value) => serde_json::Value::from(value),
+        SettingsValue::I64List(value) => serde_json::Value::from(value),
+        SettingsValue::F64List(value) => serde_json::Value::from(value),
+    }
+}
+
+/// Inserts `value` under a dotted `key`, `child` returns the nested table for a name.
+fn insert_nested<T, V, F>(root: &mut T, key: &str, value: V, child: F) where
+    T: Extend<(String, V)>,
+    F: Fn(&mut T, String) -> Option<&mut T>,
+{
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let name = parts.pop().unwrap();
+    let mut table = root;
+    for part in parts {
+        table = match child(table, part.to_string()) {
+            Some(next) => next,
+            None => {
+                log::error!("Unable to save '{}': '{}' is not a table", key, part);
+                return;
+            }
+        };
+    }
+    table.extend(std::iter::once((name.to_string(), value)));
+}
+
+fn join_key(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        prefix.to_string() + "." + name
+    }
+}
+
+fn parse_yaml(hash: &Hash, key: &str, values: &mut Vec<(String, SettingsValue)>) {
+    for element in hash {
+        let name = match element.0.as_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let next_key = join_key(key, name);
+        match element.1 {
+            Yaml::Hash(next_hash) => parse_yaml(next_hash, &next_key, values),
+            Yaml::Array(array) => push_list(values, next_key, array.iter().map(yaml_scalar)),
+            value => {
+                if let Some(value) = yaml_scalar(value) {
+                    values.push((next_key, value));
+                }
+            },
+        }
+    }
+}
+
+fn yaml_scalar(value: &Yaml) -> Option<SettingsValue> {
+    match value {
+        Yaml::String(string_value) => Some(SettingsValue::String(string_value.clone())),
+        Yaml::Integer(int_value) => Some(SettingsValue::I64(*int_value)),
+        Yaml::Boolean(bool_value) => Some(SettingsValue::Bool(*bool_value)),
+        Yaml::Real(_) => value.as_f64().map(SettingsValue::F64),
+        _ => None,
+    }
+}
+
+fn dump_yaml(root: &mut Hash, key: &mut Vec<&str>, value: &SettingsValue) {
+    let key_part = key[0];
+    let node_key = Yaml::String(key_part.to_string());
+    key.remove(0);
+    if !key.is_empty() {
+        match root.get_mut(&node_key) {
+            Some(node) => {
+                match node {
+                    Yaml::Hash(hash_node) => {
+                        dump_yaml(hash_node, key, value);
+                    },
+                    _ => panic!("Root element must be 'Hash'")
+                }
+            },
+            None => {
+                let mut hash_node = Hash::new();
+                dump_yaml(&mut hash_node, key, value);
+                root.insert(node_key, Yaml::Hash(hash_node));
+            }
+        }
+    } else {
+        let value = match value {
+            SettingsValue::String(value) => Yaml::String(value.clone()),
+            SettingsValue::I64(value) => Yaml::Integer(*value),
+            SettingsValue::Bool(value) => Yaml::Boolean(*value),
+            SettingsValue::F64(value) => Yaml::Real(format_f64(*value)),
+            SettingsValue::StringList(value) => Yaml::Array(
+                value.iter().cloned().map(Yaml::String).collect()
+            ),
+            SettingsValue::I64List(value) => Yaml::Array(
+                value.iter().cloned().map(Yaml::Integer).collect()
+            ),
+            SettingsValue::F64List(value) => Yaml::Array(
+                value.iter().map(|value| Yaml::Real(format_f64(*value))).collect()
+            ),
+        };
+        root.insert(node_key, value);
+    }
+}
+
+/// Formats a float so that YAML reads it back as a real, not an integer.
+pub(crate) fn format_f64(value: f64) -> String {
+    if value.is_nan() {
+        ".nan".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { ".inf".to_string() } else { "-.inf".to_string() }
+    } else {
+        let text = value.to_string();
+        if text.contains('.') || text.contains('e') {
+            text
+        } else {
+            text + ".0"
+        }
+    }
+}
+
+fn parse_json(object: &serde_json::Map<String, serde_json::Value>, key: &str, values: &mut Vec<(String, SettingsValue)>) {
+    for (name, value) in object {
+        let next_key = join_key(key, name);
+        match value {
+            serde_json::Value::Object(next_object) => parse_json(next_object, &next_key, values),
+            serde_json::Value::Array(array) => push_list(values, next_key, array.iter().map(json_scalar)),
+            value => {
+                if let Some(value) = json_scalar(value) {
+                    values.push((next_key, value));
+                }
+            },
+        }
+    }
+}
+
+fn json_scalar(value: &serde_json::Value) -> Option<SettingsValue> {
+    match value {
+        serde_json::Value::String(string_value) => Some(SettingsValue::String(string_value.clone())),
+        serde_json::Value::Bool(bool_value) => Some(SettingsValue::Bool(*bool_value)),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(int_value) => Some(SettingsValue::I64(int_value)),
+            None => number.as_f64().map(SettingsValue::F64),
+        },
+        _ => None,
+    }
+}
+
+fn parse_toml(table: &toml::Table, key: &str, values: &mut Vec<(String, SettingsValue)>) {
+    for (name, value) in table {
+        let next_key = join_key(key, name);
+        match value {
+            toml::Value::Table(next_table) => parse_toml(next_table, &next_key, values),
+            toml::Value::Array(array) => push_list(values, next_key, array.iter().map(toml_scalar)),
+            value => {
+                if let Some(value) = toml_scalar(value) {
+                    values.push((next_key, value));
+                }
+            },
+        }
+    }
+}
+
+fn toml_scalar(value: &toml::Value) -> Option<SettingsValue> {
+    match value {
+        toml::Value::String(string_value) => Some(SettingsValue::String(string_value.clone())),
+        toml::Value::Integer(int_value) => Some(SettingsValue::I64(*int_value)),
+        toml::Value::Boolean(bool_value) => Some(SettingsValue::Bool(*bool_value)),
+        toml::Value::Float(float_value) => Some(SettingsValue::F64(*float_value)),
+        _ => None,
+    }
+}
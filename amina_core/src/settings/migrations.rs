@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use super::formats::SettingsValue;
+
+/// Reserved property holding the schema version of a settings file.
+pub const META_VERSION_KEY: &str = "meta.version";
+/// Version of files that have no `meta.version` yet.
+const INITIAL_VERSION: i64 = 1;
+
+pub(crate) type MigrationFn = Box<dyn Fn(&mut SettingsSnapshot) + Send + Sync + 'static>;
+
+pub(crate) struct Migration {
+    pub(crate) from_version: i64,
+    pub(crate) to_version: i64,
+    pub(crate) migration: MigrationFn,
+}
+
+/// Raw values of a settings file as they are seen by migrations.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SettingsSnapshot {
+    values: BTreeMap<String, SettingsValue>,
+}
+
+impl SettingsSnapshot {
+
+    pub(crate) fn from_values(values: Vec<(String, SettingsValue)>) -> Self {
+        Self {
+            values: values.into_iter().collect(),
+        }
+    }
+
+    pub(crate) fn into_values(self) -> Vec<(String, SettingsValue)> {
+        self.values.into_iter().collect()
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.values.keys().cloned().collect()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&SettingsValue> {
+        self.values.get(key)
+    }
+
+    pub fn set<V: Into<SettingsValue>>(&mut self, key: &str, value: V) {
+        self.values.insert(key.to_string(), value.into());
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<SettingsValue> {
+        self.values.remove(key)
+    }
+
+    /// Moves a value to a new key, returns `false` if `from` does not exist.
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        match self.values.remove(from) {
+            Some(value) => {
+                self.values.insert(to.to_string(), value);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn get_version(&self) -> i64 {
+        match self.values.get(META_VERSION_KEY) {
+            Some(SettingsValue::I64(version)) => *version,
+            _ => INITIAL_VERSION,
+        }
+    }
+
+}
+
+pub(crate) fn latest_version(migrations: &[Migration]) -> Option<i64> {
+    migrations.iter().map(|migration| migration.to_version).max()
+}
+
+/// Runs migrations one after another until the snapshot reaches the latest version.
+/// Returns `false` if the snapshot already was at the latest version.
+pub(crate) fn apply(migrations: &[Migration], snapshot: &mut SettingsSnapshot) -> Result<bool, String> {
+    let latest = match latest_version(migrations) {
+        Some(latest) => latest,
+        None => return Ok(false),
+    };
+    let mut version = snapshot.get_version();
+    if version > latest {
+        return Err(format!("Settings version {} is newer than the supported version {}", version, latest));
+    }
+    if version == latest {
+        return Ok(false);
+    }
+    while version < latest {
+        let migration = migrations.iter()
+            .find(|migration| migration.from_version == version)
+            .ok_or_else(|| format!("No migration from settings version {}", version))?;
+        log::info!("Migrating settings from version {} to {}", migration.from_version, migration.to_version);
+        (migration.migration)(snapshot);
+        version = migration.to_version;
+        snapshot.set(META_VERSION_KEY, version);
+    }
+    Ok(true)
+}
@@ -0,0 +1,3853 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{DerefMut, Deref};
+use std::any::Any;
+use std::sync::{Mutex, RwLock, Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::fmt::{self, Debug};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use amina_core_derive::{rpc_service, Event};
+
+use crate::cmd_manager::{current_cmd_caller, CmdBuilder, CmdManager, CmdResult, PermissionLevel, DEFAULT_HISTORY_SIZE, HISTORY_SIZE_KEY};
+use crate::events::{Event, EventEmitter};
+use crate::rpc::Rpc;
+use crate::service::{Context, Service, ServiceApi, ServiceInitializer};
+use crate::tasks::TaskManager;
+
+mod dump;
+mod formats;
+mod migrations;
+mod profiles;
+mod scope;
+mod secrets;
+mod template;
+mod transaction;
+
+use migrations::Migration;
+use profiles::Profiles;
+use secrets::SecretCipher;
+use template::DeclaredProperty;
+
+pub use dump::{ImportFailure, ImportMode, ImportReport, SettingsDump};
+pub use formats::{SettingsFormat, SettingsValue};
+pub use migrations::{SettingsSnapshot, META_VERSION_KEY};
+pub use profiles::{ProfileSwitchedEvent, ACTIVE_PROFILE_KEY};
+pub use scope::SettingsScope;
+pub use transaction::SettingsTransaction;
+
+/// Returned over RPC instead of the value of a secret property.
+pub const SECRET_MASK: &str = "********";
+
+type ChangeCallback<T> = Arc<dyn Fn(&T) + Send + Sync + 'static>;
+
+/// Called with each property added after loading, and whether it was created by assigning
+/// a value, e.g. by an import, rather than by a getter or a declared default.
+type CreatedCallback = Arc<dyn Fn(&PropertyWrapper, bool) + Send + Sync + 'static>;
+
+/// Keys changed since the last save, shared by all properties of one settings instance.
+pub type DirtyKeys = Arc<Mutex<HashSet<String>>>;
+
+struct ChangeCallbacks<T> {
+    next_id: AtomicU64,
+    callbacks: RwLock<Vec<(u64, ChangeCallback<T>)>>,
+}
+
+impl <T> Debug for ChangeCallbacks<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChangeCallbacks({})", self.callbacks.read().unwrap().len())
+    }
+}
+
+/// Handle returned by `Property::on_change`. Dropping it keeps the callback registered.
+pub struct PropertySubscription {
+    unsubscribe: Box<dyn FnOnce() + Send + Sync + 'static>,
+}
+
+impl PropertySubscription {
+    pub fn unsubscribe(self) {
+        (self.unsubscribe)();
+    }
+}
+
+#[derive(Clone)]
+pub struct Property<T: Clone + Debug> {
+    value: Arc<RwLock<T>>,
+    default_value: Arc<RwLock<Option<T>>>,
+    change_callbacks: Arc<ChangeCallbacks<T>>,
+    /// Shared by clones, so a rename is seen by every holder of the property.
+    key: Arc<RwLock<String>>,
+    dirty_keys: DirtyKeys,
+    /// Masks the value in `Debug` and `Display`, set for secrets.
+    secret: Arc<AtomicBool>,
+}
+
+impl <T: Clone + Debug> Debug for Property<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Property");
+        debug.field("key", &*self.key.read().unwrap());
+        if self.secret.load(Ordering::Relaxed) {
+            debug.field("value", &SECRET_MASK).field("default_value", &SECRET_MASK);
+        } else {
+            debug.field("value", &*self.value.read().unwrap()).field("default_value", &*self.default_value.read().unwrap());
+        }
+        debug.field("change_callbacks", &self.change_callbacks).finish()
+    }
+}
+
+/// The value, or `SECRET_MASK` for secrets.
+impl <T: Clone + Debug + fmt::Display> fmt::Display for Property<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.secret.load(Ordering::Relaxed) {
+            f.write_str(SECRET_MASK)
+        } else {
+            fmt::Display::fmt(&*self.value.read().unwrap(), f)
+        }
+    }
+}
+
+impl <T: Clone + Debug + 'static> Property<T> {
+
+    /// `key` is recorded in `dirty_keys` whenever the value is set.
+    pub fn new(key: &str, value: T, dirty_keys: DirtyKeys) -> Self {
+        Self {
+            value: Arc::new(RwLock::new(value)),
+            default_value: Arc::new(RwLock::new(None)),
+            change_callbacks: Arc::new(ChangeCallbacks {
+                next_id: AtomicU64::new(0),
+                callbacks: RwLock::new(Vec::new()),
+            }),
+            key: Arc::new(RwLock::new(key.to_string())),
+            dirty_keys,
+            secret: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn is_secret(&self) -> bool {
+        self.secret.load(Ordering::Relaxed)
+    }
+
+    fn mark_secret(&self) {
+        self.secret.store(true, Ordering::Relaxed);
+    }
+
+    pub fn get_key(&self) -> String {
+        self.key.read().unwrap().clone()
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty_keys.lock().unwrap().insert(self.get_key());
+    }
+
+    /// Runs `f` under the value lock only, so it may use other properties of the same
+    /// settings. The key is marked dirty after it returns.
+    fn write_value<R, F: FnOnce(&mut T) -> Option<R>>(&self, f: F) -> Option<R> {
+        let result = f(self.value.write().unwrap().deref_mut())?;
+        self.mark_dirty();
+        Some(result)
+    }
+
+    /// Registers a callback invoked with the new value after every `set`.
+    /// Callbacks run on the setting thread, outside of the property lock.
+    pub fn on_change<F>(&self, callback: F) -> PropertySubscription where
+        F: Fn(&T) + Send + Sync + 'static
+    {
+        let id = self.change_callbacks.next_id.fetch_add(1, Ordering::Relaxed);
+        self.change_callbacks.callbacks.write().unwrap().push((id, Arc::new(callback)));
+
+        let change_callbacks = Arc::downgrade(&self.change_callbacks);
+        PropertySubscription {
+            unsubscribe: Box::new(move || {
+                if let Some(change_callbacks) = change_callbacks.upgrade() {
+                    change_callbacks.callbacks.write().unwrap().retain(|(callback_id, _)| *callback_id != id);
+                }
+            }),
+        }
+    }
+
+    /// Like `on_change`, with the current key and the value as `SettingsValue`, secrets as `SECRET_MASK`.
+    fn on_change_value<F>(&self, callback: F) -> PropertySubscription where
+        T: Into<SettingsValue>,
+        F: Fn(&str, SettingsValue) + Send + Sync + 'static
+    {
+        let key = self.key.clone();
+        let secret = self.secret.clone();
+        self.on_change(move |value: &T| {
+            let value = if secret.load(Ordering::Relaxed) {
+                SettingsValue::String(SECRET_MASK.to_string())
+            } else {
+                value.clone().into()
+            };
+            callback(&key.read().unwrap(), value);
+        })
+    }
+
+    fn notify_changed(&self) {
+        let callbacks: Vec<ChangeCallback<T>> = self.change_callbacks.callbacks.read().unwrap()
+            .iter()
+            .map(|(_, callback)| callback.clone())
+            .collect();
+        if callbacks.is_empty() {
+            return;
+        }
+        let value = self.get();
+        for callback in callbacks {
+            callback(&value);
+        }
+    }
+
+    fn set_default(&self, default_value: T) {
+        *self.default_value.write().unwrap() = Some(default_value);
+    }
+
+    /// Default value registered through one of the `Settings::get_*_or` accessors.
+    pub fn get_default(&self) -> Option<T> {
+        self.default_value.read().unwrap().clone()
+    }
+
+    /// Restores the registered default. Returns `false` if the property has no default.
+    pub fn reset_to_default(&mut self) -> bool {
+        match self.get_default() {
+            Some(default_value) => {
+                self.set(default_value);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn set(&mut self, value: T) {
+        self.write_value(|current| {
+            *current = value;
+            Some(())
+        });
+        self.notify_changed();
+    }
+
+    pub fn get(&self) -> T {
+        self.value.read().unwrap().clone()
+    }
+
+    /// Modifies the value in place under a single lock, so concurrent updates aren't lost
+    /// the way they are with `get` followed by `set`.
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        self.write_value(|current| {
+            f(current);
+            Some(())
+        });
+        self.notify_changed();
+    }
+
+    /// Sets `value` only if `condition` holds for the current value, checked under the same lock.
+    /// Returns whether the value was set.
+    pub fn set_if<F: FnOnce(&T) -> bool>(&self, condition: F, value: T) -> bool {
+        let set = self.write_value(|current| {
+            if !condition(current) {
+                return None;
+            }
+            *current = value;
+            Some(())
+        });
+        if set.is_none() {
+            return false;
+        }
+        self.notify_changed();
+        true
+    }
+
+}
+
+impl <T: Clone + Debug + PartialEq + 'static> Property<T> {
+
+    /// Sets the value without marking it dirty or notifying, the caller holds the dirty keys lock.
+    /// Returns whether the value changed.
+    fn store(&self, value: T) -> bool {
+        let mut guard = self.value.write().unwrap();
+        if *guard == value {
+            return false;
+        }
+        *guard = value;
+        true
+    }
+
+    /// Replaces the value with one read from the settings file. Change callbacks are
+    /// invoked, but the property isn't marked as changed since the file already has it.
+    fn reload(&self, value: T) -> bool {
+        {
+            let _writes = self.dirty_keys.lock().unwrap();
+            let mut guard = self.value.write().unwrap();
+            if *guard == value {
+                return false;
+            }
+            *guard = value;
+        }
+        self.notify_changed();
+        true
+    }
+
+}
+
+#[derive(Debug, Serialize, thiserror::Error)]
+pub enum SettingsError {
+    #[error("Property '{key}' has type '{actual}', but '{expected}' was requested")]
+    TypeMismatch {
+        key: String,
+        expected: &'static str,
+        actual: &'static str,
+    },
+    #[error("No registered settings can serve property '{key}'")]
+    UnknownKey {
+        key: String,
+    },
+    #[error("Invalid value for property '{key}': {message}")]
+    InvalidValue {
+        key: String,
+        message: String,
+    },
+    #[error("Property '{key}' already exists")]
+    KeyExists {
+        key: String,
+    },
+    #[error("Property '{key}' is computed and can't be changed")]
+    ReadOnly {
+        key: String,
+    },
+    #[error("Property '{key}' is overridden by the environment or command line, a change wouldn't be saved")]
+    Overridden {
+        key: String,
+    },
+    #[error("No settings tab named '{name}'")]
+    UnknownTab {
+        name: String,
+    },
+    #[error("Profiles are not enabled")]
+    ProfilesDisabled,
+    #[error("No profile named '{name}'")]
+    UnknownProfile {
+        name: String,
+    },
+    #[error("Profile '{name}' already exists")]
+    ProfileExists {
+        name: String,
+    },
+    #[error("Invalid profile name '{name}', only letters, digits, '-' and '_' are allowed")]
+    InvalidProfileName {
+        name: String,
+    },
+}
+
+/// Why a settings file couldn't be loaded. Lines and columns start at 1.
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsLoadError {
+    #[error("Unable to read settings file '{}': {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Syntax error in settings file '{}' at line {line}, column {column}: {message}", path.display())]
+    Syntax {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("Root element of settings file '{}' must be a mapping, but it is {found}", path.display())]
+    WrongRoot {
+        path: PathBuf,
+        found: &'static str,
+    },
+    #[error("Duplicate key '{key}' in settings file '{}' at line {line}, column {column}", path.display())]
+    DuplicateKey {
+        path: PathBuf,
+        key: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+impl From<SettingsLoadError> for io::Error {
+    fn from(err: SettingsLoadError) -> Self {
+        let kind = match &err {
+            SettingsLoadError::Io { source, .. } => source.kind(),
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+/// Clones share the property.
+#[derive(Clone, Debug)]
+enum PropertyWrapper {
+    String(Property<String>),
+    I64(Property<i64>),
+    Bool(Property<bool>),
+    F64(Property<f64>),
+    StringList(Property<Vec<String>>),
+    I64List(Property<Vec<i64>>),
+    F64List(Property<Vec<f64>>),
+}
+
+impl PropertyWrapper {
+    fn value_eq(&self, other: &PropertyWrapper) -> bool {
+        match (self, other) {
+            (PropertyWrapper::String(a), PropertyWrapper::String(b)) => a.get() == b.get(),
+            (PropertyWrapper::I64(a), PropertyWrapper::I64(b)) => a.get() == b.get(),
+            (PropertyWrapper::Bool(a), PropertyWrapper::Bool(b)) => a.get() == b.get(),
+            (PropertyWrapper::F64(a), PropertyWrapper::F64(b)) => a.get() == b.get(),
+            (PropertyWrapper::StringList(a), PropertyWrapper::StringList(b)) => a.get() == b.get(),
+            (PropertyWrapper::I64List(a), PropertyWrapper::I64List(b)) => a.get() == b.get(),
+            (PropertyWrapper::F64List(a), PropertyWrapper::F64List(b)) => a.get() == b.get(),
+            _ => false,
+        }
+    }
+
+    /// Copies the value of `other` into this property. Returns `None` on type mismatch.
+    fn reload_from(&self, other: &PropertyWrapper) -> Option<bool> {
+        match (self, other) {
+            (PropertyWrapper::String(a), PropertyWrapper::String(b)) => Some(a.reload(b.get())),
+            (PropertyWrapper::I64(a), PropertyWrapper::I64(b)) => Some(a.reload(b.get())),
+            (PropertyWrapper::Bool(a), PropertyWrapper::Bool(b)) => Some(a.reload(b.get())),
+            (PropertyWrapper::F64(a), PropertyWrapper::F64(b)) => Some(a.reload(b.get())),
+            (PropertyWrapper::StringList(a), PropertyWrapper::StringList(b)) => Some(a.reload(b.get())),
+            (PropertyWrapper::I64List(a), PropertyWrapper::I64List(b)) => Some(a.reload(b.get())),
+            (PropertyWrapper::F64List(a), PropertyWrapper::F64List(b)) => Some(a.reload(b.get())),
+            _ => None,
+        }
+    }
+
+    fn default_as_string(&self) -> Option<String> {
+        match self {
+            PropertyWrapper::String(prop) => prop.get_default(),
+            PropertyWrapper::I64(prop) => prop.get_default().map(|value| value.to_string()),
+            PropertyWrapper::Bool(prop) => prop.get_default().map(|value| value.to_string()),
+            PropertyWrapper::F64(prop) => prop.get_default().map(|value| value.to_string()),
+            PropertyWrapper::StringList(prop) => prop.get_default().map(|value| serde_json::to_string(&value).unwrap()),
+            PropertyWrapper::I64List(prop) => prop.get_default().map(|value| serde_json::to_string(&value).unwrap()),
+            PropertyWrapper::F64List(prop) => prop.get_default().map(|value| serde_json::to_string(&value).unwrap()),
+        }
+    }
+
+    /// Detached copy of the current value, not linked to any settings.
+    fn snapshot(&self) -> PropertyWrapper {
+        match self {
+            PropertyWrapper::String(prop) => PropertyWrapper::String(Property::new(&prop.get_key(), prop.get(), DirtyKeys::default())),
+            PropertyWrapper::I64(prop) => PropertyWrapper::I64(Property::new(&prop.get_key(), prop.get(), DirtyKeys::default())),
+            PropertyWrapper::Bool(prop) => PropertyWrapper::Bool(Property::new(&prop.get_key(), prop.get(), DirtyKeys::default())),
+            PropertyWrapper::F64(prop) => PropertyWrapper::F64(Property::new(&prop.get_key(), prop.get(), DirtyKeys::default())),
+            PropertyWrapper::StringList(prop) => PropertyWrapper::StringList(Property::new(&prop.get_key(), prop.get(), DirtyKeys::default())),
+            PropertyWrapper::I64List(prop) => PropertyWrapper::I64List(Property::new(&prop.get_key(), prop.get(), DirtyKeys::default())),
+            PropertyWrapper::F64List(prop) => PropertyWrapper::F64List(Property::new(&prop.get_key(), prop.get(), DirtyKeys::default())),
+        }
+    }
+
+    /// Moves every clone of the property to `key`.
+    fn set_key(&self, key: &str) {
+        let key_lock = match self {
+            PropertyWrapper::String(prop) => &prop.key,
+            PropertyWrapper::I64(prop) => &prop.key,
+            PropertyWrapper::Bool(prop) => &prop.key,
+            PropertyWrapper::F64(prop) => &prop.key,
+            PropertyWrapper::StringList(prop) => &prop.key,
+            PropertyWrapper::I64List(prop) => &prop.key,
+            PropertyWrapper::F64List(prop) => &prop.key,
+        };
+        *key_lock.write().unwrap() = key.to_string();
+    }
+
+    /// Parses `text` into a detached property of the same type, lists are comma separated.
+    fn parse_same_type(&self, text: &str) -> Option<PropertyWrapper> {
+        let detached = DirtyKeys::default();
+        match self {
+            PropertyWrapper::String(_) => Some(PropertyWrapper::String(Property::new("", text.to_string(), detached))),
+            PropertyWrapper::I64(_) => text.trim().parse().ok().map(|value| PropertyWrapper::I64(Property::new("", value, detached))),
+            PropertyWrapper::Bool(_) => text.trim().parse().ok().map(|value| PropertyWrapper::Bool(Property::new("", value, detached))),
+            PropertyWrapper::F64(_) => text.trim().parse().ok().map(|value| PropertyWrapper::F64(Property::new("", value, detached))),
+            PropertyWrapper::StringList(_) => {
+                let list = text.split(',')
+                    .map(|item| item.trim())
+                    .filter(|item| !item.is_empty())
+                    .map(|item| item.to_string())
+                    .collect();
+                Some(PropertyWrapper::StringList(Property::new("", list, detached)))
+            },
+            PropertyWrapper::I64List(_) => parse_list(text).map(|list| PropertyWrapper::I64List(Property::new("", list, detached))),
+            PropertyWrapper::F64List(_) => parse_list(text).map(|list| PropertyWrapper::F64List(Property::new("", list, detached))),
+        }
+    }
+
+    fn to_value(&self) -> SettingsValue {
+        match self {
+            PropertyWrapper::String(prop) => SettingsValue::String(prop.get()),
+            PropertyWrapper::I64(prop) => SettingsValue::I64(prop.get()),
+            PropertyWrapper::Bool(prop) => SettingsValue::Bool(prop.get()),
+            PropertyWrapper::F64(prop) => SettingsValue::F64(prop.get()),
+            PropertyWrapper::StringList(prop) => SettingsValue::StringList(prop.get()),
+            PropertyWrapper::I64List(prop) => SettingsValue::I64List(prop.get()),
+            PropertyWrapper::F64List(prop) => SettingsValue::F64List(prop.get()),
+        }
+    }
+
+    /// Sets the value of this property, ints are accepted for floats.
+    fn set_value(&self, value: SettingsValue) -> Option<()> {
+        match (self, value) {
+            (PropertyWrapper::String(prop), SettingsValue::String(value)) => prop.clone().set(value),
+            (PropertyWrapper::I64(prop), SettingsValue::I64(value)) => prop.clone().set(value),
+            (PropertyWrapper::Bool(prop), SettingsValue::Bool(value)) => prop.clone().set(value),
+            (PropertyWrapper::F64(prop), SettingsValue::F64(value)) => prop.clone().set(value),
+            (PropertyWrapper::F64(prop), SettingsValue::I64(value)) => prop.clone().set(value as f64),
+            (PropertyWrapper::StringList(prop), SettingsValue::StringList(value)) => prop.clone().set(value),
+            (PropertyWrapper::I64List(prop), SettingsValue::I64List(value)) => prop.clone().set(value),
+            (PropertyWrapper::F64List(prop), SettingsValue::F64List(value)) => prop.clone().set(value),
+            (PropertyWrapper::F64List(prop), SettingsValue::I64List(value)) => prop.clone().set(to_f64_list(value)),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Whether `set_value` accepts `value`.
+    fn accepts(&self, value: &SettingsValue) -> bool {
+        matches!((self, value),
+            (PropertyWrapper::String(_), SettingsValue::String(_))
+            | (PropertyWrapper::I64(_), SettingsValue::I64(_))
+            | (PropertyWrapper::Bool(_), SettingsValue::Bool(_))
+            | (PropertyWrapper::F64(_), SettingsValue::F64(_) | SettingsValue::I64(_))
+            | (PropertyWrapper::StringList(_), SettingsValue::StringList(_))
+            | (PropertyWrapper::I64List(_), SettingsValue::I64List(_))
+            | (PropertyWrapper::F64List(_), SettingsValue::F64List(_) | SettingsValue::I64List(_)))
+    }
+
+    /// Like `set_value` for `Settings::transaction`: the dirty keys lock is held by the caller,
+    /// who notifies once the lock is released. Returns whether the value changed.
+    fn store_value(&self, value: SettingsValue) -> Option<bool> {
+        let changed = match (self, value) {
+            (PropertyWrapper::String(prop), SettingsValue::String(value)) => prop.store(value),
+            (PropertyWrapper::I64(prop), SettingsValue::I64(value)) => prop.store(value),
+            (PropertyWrapper::Bool(prop), SettingsValue::Bool(value)) => prop.store(value),
+            (PropertyWrapper::F64(prop), SettingsValue::F64(value)) => prop.store(value),
+            (PropertyWrapper::F64(prop), SettingsValue::I64(value)) => prop.store(value as f64),
+            (PropertyWrapper::StringList(prop), SettingsValue::StringList(value)) => prop.store(value),
+            (PropertyWrapper::I64List(prop), SettingsValue::I64List(value)) => prop.store(value),
+            (PropertyWrapper::F64List(prop), SettingsValue::F64List(value)) => prop.store(value),
+            (PropertyWrapper::F64List(prop), SettingsValue::I64List(value)) => prop.store(to_f64_list(value)),
+            _ => return None,
+        };
+        Some(changed)
+    }
+
+    fn on_change_value<F>(&self, callback: F) -> PropertySubscription where
+        F: Fn(&str, SettingsValue) + Send + Sync + 'static
+    {
+        match self {
+            PropertyWrapper::String(prop) => prop.on_change_value(callback),
+            PropertyWrapper::I64(prop) => prop.on_change_value(callback),
+            PropertyWrapper::Bool(prop) => prop.on_change_value(callback),
+            PropertyWrapper::F64(prop) => prop.on_change_value(callback),
+            PropertyWrapper::StringList(prop) => prop.on_change_value(callback),
+            PropertyWrapper::I64List(prop) => prop.on_change_value(callback),
+            PropertyWrapper::F64List(prop) => prop.on_change_value(callback),
+        }
+    }
+
+    /// Identifies the property across clones and renames. While the weak handle is kept,
+    /// the address can't be reused by another property.
+    fn identity(&self) -> (usize, Weak<dyn Any + Send + Sync>) {
+        let value: Arc<dyn Any + Send + Sync> = match self {
+            PropertyWrapper::String(prop) => prop.value.clone(),
+            PropertyWrapper::I64(prop) => prop.value.clone(),
+            PropertyWrapper::Bool(prop) => prop.value.clone(),
+            PropertyWrapper::F64(prop) => prop.value.clone(),
+            PropertyWrapper::StringList(prop) => prop.value.clone(),
+            PropertyWrapper::I64List(prop) => prop.value.clone(),
+            PropertyWrapper::F64List(prop) => prop.value.clone(),
+        };
+        (Arc::as_ptr(&value) as *const () as usize, Arc::downgrade(&value))
+    }
+
+    fn notify_changed(&self) {
+        match self {
+            PropertyWrapper::String(prop) => prop.notify_changed(),
+            PropertyWrapper::I64(prop) => prop.notify_changed(),
+            PropertyWrapper::Bool(prop) => prop.notify_changed(),
+            PropertyWrapper::F64(prop) => prop.notify_changed(),
+            PropertyWrapper::StringList(prop) => prop.notify_changed(),
+            PropertyWrapper::I64List(prop) => prop.notify_changed(),
+            PropertyWrapper::F64List(prop) => prop.notify_changed(),
+        }
+    }
+
+    fn set_default_value(&self, value: SettingsValue) -> Option<()> {
+        match (self, value) {
+            (PropertyWrapper::String(prop), SettingsValue::String(value)) => prop.set_default(value),
+            (PropertyWrapper::I64(prop), SettingsValue::I64(value)) => prop.set_default(value),
+            (PropertyWrapper::Bool(prop), SettingsValue::Bool(value)) => prop.set_default(value),
+            (PropertyWrapper::F64(prop), SettingsValue::F64(value)) => prop.set_default(value),
+            (PropertyWrapper::F64(prop), SettingsValue::I64(value)) => prop.set_default(value as f64),
+            (PropertyWrapper::StringList(prop), SettingsValue::StringList(value)) => prop.set_default(value),
+            (PropertyWrapper::I64List(prop), SettingsValue::I64List(value)) => prop.set_default(value),
+            (PropertyWrapper::F64List(prop), SettingsValue::F64List(value)) => prop.set_default(value),
+            (PropertyWrapper::F64List(prop), SettingsValue::I64List(value)) => prop.set_default(to_f64_list(value)),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn from_value(key: &str, value: SettingsValue, dirty_keys: DirtyKeys) -> Self {
+        match value {
+            SettingsValue::String(value) => PropertyWrapper::String(Property::new(key, value, dirty_keys)),
+            SettingsValue::I64(value) => PropertyWrapper::I64(Property::new(key, value, dirty_keys)),
+            SettingsValue::Bool(value) => PropertyWrapper::Bool(Property::new(key, value, dirty_keys)),
+            SettingsValue::F64(value) => PropertyWrapper::F64(Property::new(key, value, dirty_keys)),
+            SettingsValue::StringList(value) => PropertyWrapper::StringList(Property::new(key, value, dirty_keys)),
+            SettingsValue::I64List(value) => PropertyWrapper::I64List(Property::new(key, value, dirty_keys)),
+            SettingsValue::F64List(value) => PropertyWrapper::F64List(Property::new(key, value, dirty_keys)),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            PropertyWrapper::String(_) => String::TYPE_NAME,
+            PropertyWrapper::I64(_) => i64::TYPE_NAME,
+            PropertyWrapper::Bool(_) => bool::TYPE_NAME,
+            PropertyWrapper::F64(_) => f64::TYPE_NAME,
+            PropertyWrapper::StringList(_) => Vec::<String>::TYPE_NAME,
+            PropertyWrapper::I64List(_) => Vec::<i64>::TYPE_NAME,
+            PropertyWrapper::F64List(_) => Vec::<f64>::TYPE_NAME,
+        }
+    }
+}
+
+/// Comma separated items, `None` if one of them doesn't parse.
+fn parse_list<T: std::str::FromStr>(text: &str) -> Option<Vec<T>> {
+    text.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().ok())
+        .collect()
+}
+
+fn to_f64_list(list: Vec<i64>) -> Vec<f64> {
+    list.into_iter().map(|value| value as f64).collect()
+}
+
+/// Maps a Rust value type to the matching `PropertyWrapper` variant.
+trait PropertyValue: Clone + Debug + Default + Sized + 'static {
+    const TYPE_NAME: &'static str;
+    fn wrap(prop: Property<Self>) -> PropertyWrapper;
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>>;
+
+    /// Value of a property of another type that converts without loss, e.g. ints for floats.
+    fn coerce(_wrapper: &PropertyWrapper) -> Option<Self> {
+        None
+    }
+}
+
+impl PropertyValue for String {
+    const TYPE_NAME: &'static str = "string";
+
+    fn wrap(prop: Property<Self>) -> PropertyWrapper {
+        PropertyWrapper::String(prop)
+    }
+
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>> {
+        match wrapper {
+            PropertyWrapper::String(prop) => Some(prop),
+            _ => None,
+        }
+    }
+}
+
+impl PropertyValue for i64 {
+    const TYPE_NAME: &'static str = "i64";
+
+    fn wrap(prop: Property<Self>) -> PropertyWrapper {
+        PropertyWrapper::I64(prop)
+    }
+
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>> {
+        match wrapper {
+            PropertyWrapper::I64(prop) => Some(prop),
+            _ => None,
+        }
+    }
+}
+
+impl PropertyValue for bool {
+    const TYPE_NAME: &'static str = "bool";
+
+    fn wrap(prop: Property<Self>) -> PropertyWrapper {
+        PropertyWrapper::Bool(prop)
+    }
+
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>> {
+        match wrapper {
+            PropertyWrapper::Bool(prop) => Some(prop),
+            _ => None,
+        }
+    }
+}
+
+impl PropertyValue for f64 {
+    const TYPE_NAME: &'static str = "f64";
+
+    fn wrap(prop: Property<Self>) -> PropertyWrapper {
+        PropertyWrapper::F64(prop)
+    }
+
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>> {
+        match wrapper {
+            PropertyWrapper::F64(prop) => Some(prop),
+            _ => None,
+        }
+    }
+
+    fn coerce(wrapper: &PropertyWrapper) -> Option<Self> {
+        match wrapper {
+            PropertyWrapper::I64(prop) => Some(prop.get() as f64),
+            _ => None,
+        }
+    }
+}
+
+impl PropertyValue for Vec<String> {
+    const TYPE_NAME: &'static str = "string_list";
+
+    fn wrap(prop: Property<Self>) -> PropertyWrapper {
+        PropertyWrapper::StringList(prop)
+    }
+
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>> {
+        match wrapper {
+            PropertyWrapper::StringList(prop) => Some(prop),
+            _ => None,
+        }
+    }
+}
+
+impl PropertyValue for Vec<i64> {
+    const TYPE_NAME: &'static str = "i64_list";
+
+    fn wrap(prop: Property<Self>) -> PropertyWrapper {
+        PropertyWrapper::I64List(prop)
+    }
+
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>> {
+        match wrapper {
+            PropertyWrapper::I64List(prop) => Some(prop),
+            _ => None,
+        }
+    }
+}
+
+impl PropertyValue for Vec<f64> {
+    const TYPE_NAME: &'static str = "f64_list";
+
+    fn wrap(prop: Property<Self>) -> PropertyWrapper {
+        PropertyWrapper::F64List(prop)
+    }
+
+    fn unwrap(wrapper: &PropertyWrapper) -> Option<&Property<Self>> {
+        match wrapper {
+            PropertyWrapper::F64List(prop) => Some(prop),
+            _ => None,
+        }
+    }
+
+    /// A list of whole numbers in the file, e.g. `[1, 2]`, loads as an int list.
+    fn coerce(wrapper: &PropertyWrapper) -> Option<Self> {
+        match wrapper {
+            PropertyWrapper::I64List(prop) => Some(to_f64_list(prop.get())),
+            _ => None,
+        }
+    }
+}
+
+fn settings_value_type_name(value: &SettingsValue) -> &'static str {
+    match value {
+        SettingsValue::String(_) => String::TYPE_NAME,
+        SettingsValue::I64(_) => i64::TYPE_NAME,
+        SettingsValue::Bool(_) => bool::TYPE_NAME,
+        SettingsValue::F64(_) => f64::TYPE_NAME,
+        SettingsValue::StringList(_) => Vec::<String>::TYPE_NAME,
+        SettingsValue::I64List(_) => Vec::<i64>::TYPE_NAME,
+        SettingsValue::F64List(_) => Vec::<f64>::TYPE_NAME,
+    }
+}
+
+struct SettingsServiceEntry {
+    properties: Mutex<HashMap<String, PropertyWrapper>>,
+    /// File values of overridden properties, `None` if the file has no such key.
+    overridden: Mutex<HashMap<String, Option<PropertyWrapper>>>,
+    secret_keys: Mutex<HashSet<String>>,
+    /// Secrets that were stored encrypted in the file, others get encrypted on the next save.
+    encrypted_keys: Mutex<HashSet<String>>,
+    secret_cipher: RwLock<Option<SecretCipher>>,
+    dirty_keys: DirtyKeys,
+    path: PathBuf,
+    format: SettingsFormat,
+    migrations: Mutex<Vec<Migration>>,
+    save_lock: Mutex<()>,
+    created_callbacks: RwLock<Vec<CreatedCallback>>,
+}
+
+#[derive(Clone)]
+pub struct Settings {
+    entry: Arc<SettingsServiceEntry>,
+}
+
+impl Settings {
+
+    fn create(properties: HashMap<String, PropertyWrapper>, path: &Path, format: SettingsFormat, dirty_keys: DirtyKeys) -> Self {
+        Self {
+            entry: Arc::new(SettingsServiceEntry {
+                properties: Mutex::new(properties),
+                overridden: Mutex::new(HashMap::new()),
+                secret_keys: Mutex::new(HashSet::new()),
+                encrypted_keys: Mutex::new(HashSet::new()),
+                secret_cipher: RwLock::new(None),
+                dirty_keys,
+                path: path.to_path_buf(),
+                format,
+                migrations: Mutex::new(Vec::new()),
+                save_lock: Mutex::new(()),
+                created_callbacks: RwLock::new(Vec::new()),
+            })
+        }
+    }
+
+    /// The file format is picked from the extension of `path`.
+    pub fn create_empty(path: &Path) -> Self {
+        Self::create(HashMap::new(), path, SettingsFormat::from_path(path), DirtyKeys::default())
+    }
+
+    /// Parses YAML `text`, panics if it is malformed.
+    pub fn init_from_string(text: &str, path: &Path) -> Self {
+        Self::from_string(text, path, SettingsFormat::Yaml)
+    }
+
+    /// Parses YAML `text`, `path` is where the settings are saved and names the file in errors.
+    pub fn try_init_from_string(text: &str, path: &Path) -> Result<Self, SettingsLoadError> {
+        Self::try_from_string(text, path, SettingsFormat::Yaml)
+    }
+
+    /// Parses `text` in the given format, panics if it is malformed.
+    pub fn from_string(text: &str, path: &Path, format: SettingsFormat) -> Self {
+        Self::try_from_string(text, path, format).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_from_string(text: &str, path: &Path, format: SettingsFormat) -> Result<Self, SettingsLoadError> {
+        let dirty_keys = DirtyKeys::default();
+        let values = formats::parse(format, text).map_err(|err| err.with_path(path))?;
+        let properties = Self::wrap_values(values, &dirty_keys);
+        Ok(Self::create(properties, path, format, dirty_keys))
+    }
+
+    /// Reads a settings file, the format is picked from the extension and YAML is the default.
+    /// Use `create_empty`, `register_migration` and `reload_from_file` for files that need migrations.
+    pub fn load_from_file(path: &Path) -> io::Result<Self> {
+        let settings = Self::create_empty(path);
+        settings.reload_from_file()?;
+        Ok(settings)
+    }
+
+    /// Like `load_from_file`, but never fails: a missing file gives empty settings, and so does
+    /// a broken one after it is copied to `<file>.broken`, since the next save overwrites it.
+    pub fn load_from_file_lenient(path: &Path) -> Self {
+        let settings = Self::create_empty(path);
+        match settings.reload_from_file() {
+            Ok(_) => {},
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::info!("Settings file '{}' doesn't exist, starting with empty settings", path.display());
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                let mut backup_file_name = path.file_name().unwrap_or_default().to_os_string();
+                backup_file_name.push(".broken");
+                let backup_path = path.with_file_name(backup_file_name);
+                match std::fs::copy(path, &backup_path) {
+                    Ok(_) => log::warn!("Starting with empty settings, the broken file is kept as '{}'", backup_path.display()),
+                    Err(err) => log::error!("Unable to back up broken settings file '{}': {}", path.display(), err),
+                }
+            },
+        }
+        settings
+    }
+
+    fn wrap_values(values: Vec<(String, SettingsValue)>, dirty_keys: &DirtyKeys) -> HashMap<String, PropertyWrapper> {
+        values.into_iter()
+            .map(|(key, value)| {
+                let wrapper = PropertyWrapper::from_value(&key, value, dirty_keys.clone());
+                (key, wrapper)
+            })
+            .collect()
+    }
+
+    /// Lets `SettingsManager` watch properties created after registration, see `CreatedCallback`.
+    /// Callbacks run after the properties lock is released.
+    fn on_property_created<F>(&self, callback: F) where
+        F: Fn(&PropertyWrapper, bool) + Send + Sync + 'static
+    {
+        self.entry.created_callbacks.write().unwrap().push(Arc::new(callback));
+    }
+
+    fn notify_created(&self, created: Vec<(PropertyWrapper, bool)>) {
+        if created.is_empty() {
+            return;
+        }
+        let callbacks = self.entry.created_callbacks.read().unwrap().clone();
+        for (wrapper, assigned) in created {
+            for callback in callbacks.iter() {
+                callback(&wrapper, assigned);
+            }
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        self.entry.path.as_path()
+    }
+
+    pub fn get_format(&self) -> SettingsFormat {
+        self.entry.format
+    }
+
+    /// Registers an upgrade of the file schema from one `meta.version` to the next.
+    /// Files without a version are version 1. Migrations run on `reload_from_file`,
+    /// the file is then saved at the latest version after taking a `.bak` copy.
+    pub fn register_migration<F>(&self, from_version: i64, to_version: i64, migration: F) where
+        F: Fn(&mut SettingsSnapshot) + Send + Sync + 'static
+    {
+        assert!(from_version < to_version, "Migration must raise the version");
+        self.entry.migrations.lock().unwrap().push(Migration {
+            from_version,
+            to_version,
+            migration: Box::new(migration),
+        });
+    }
+
+    /// Applies pending migrations and saves the upgraded file.
+    fn migrate_file(&self, values: Vec<(String, SettingsValue)>) -> io::Result<Vec<(String, SettingsValue)>> {
+        let path = self.get_path();
+        let mut snapshot = SettingsSnapshot::from_values(values);
+        let migrated = migrations::apply(&self.entry.migrations.lock().unwrap(), &mut snapshot)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Unable to migrate {:?}: {}", path, err)))?;
+        let values = snapshot.into_values();
+        if migrated {
+            let mut backup_file_name = path.file_name().unwrap_or_default().to_os_string();
+            backup_file_name.push(".bak");
+            std::fs::copy(path, path.with_file_name(backup_file_name))?;
+            let _save_guard = self.entry.save_lock.lock().unwrap();
+            Self::write_atomically(path, formats::dump(self.entry.format, values.clone()))?;
+        }
+        Ok(values)
+    }
+
+    /// Replaces values in memory without touching the file, e.g. for container deployments.
+    /// Values are parsed as the type of the existing property, unknown keys become strings.
+    /// Saving keeps the file value of overridden properties.
+    pub fn apply_overrides(&self, overrides: &[(String, String)]) {
+        let mut created = Vec::new();
+        let mut properties = self.entry.properties.lock().unwrap();
+        let mut overridden = self.entry.overridden.lock().unwrap();
+        for (key, text) in overrides {
+            match properties.get(key) {
+                Some(wrapper) => {
+                    match wrapper.parse_same_type(text) {
+                        Some(value) => {
+                            overridden.entry(key.clone()).or_insert_with(|| Some(wrapper.snapshot()));
+                            wrapper.reload_from(&value);
+                        },
+                        None => {
+                            log::error!("Unable to override '{}' with '{}', expected {}", key, text, wrapper.type_name());
+                            continue;
+                        },
+                    }
+                },
+                None => {
+                    let wrapper = PropertyWrapper::String(Property::new(key, text.clone(), self.entry.dirty_keys.clone()));
+                    properties.insert(key.clone(), wrapper.clone());
+                    created.push((wrapper, true));
+                    overridden.entry(key.clone()).or_insert(None);
+                }
+            }
+            log::info!("Property '{}' is overridden", key);
+        }
+        drop(overridden);
+        drop(properties);
+        self.notify_created(created);
+    }
+
+    /// Collects overrides from environment variables named `<prefix>__<SECTION>__<NAME>`,
+    /// e.g. `AMINA__MAIN__COLLECTION_DIR` overrides `main.collection_dir`.
+    pub fn overrides_from_env(prefix: &str) -> Vec<(String, String)> {
+        Self::overrides_from_vars(prefix, std::env::vars())
+    }
+
+    fn overrides_from_vars<I: IntoIterator<Item = (String, String)>>(prefix: &str, vars: I) -> Vec<(String, String)> {
+        let env_prefix = format!("{}__", prefix);
+        let mut overrides: Vec<(String, String)> = vars.into_iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(&env_prefix)?
+                    .split("__")
+                    .map(|part| part.to_lowercase())
+                    .collect::<Vec<String>>()
+                    .join(".");
+                Some((key, value))
+            })
+            .collect();
+        overrides.sort();
+        overrides
+    }
+
+    pub fn is_overridden(&self, key: &str) -> bool {
+        self.entry.overridden.lock().unwrap().contains_key(key)
+    }
+
+    /// Sets the keyfile whose contents derive the key for encrypting secrets on disk.
+    /// Without a usable keyfile secrets are stored as plain text.
+    pub fn set_secret_keyfile(&self, path: &Path) {
+        let cipher = match SecretCipher::from_keyfile(path) {
+            Ok(cipher) => Some(cipher),
+            Err(err) => {
+                log::warn!("Unable to read secrets keyfile {:?}: {}. SECRETS IN {:?} WILL BE STORED AS PLAIN TEXT",
+                    path, err, self.entry.path);
+                None
+            }
+        };
+        *self.entry.secret_cipher.write().unwrap() = cipher;
+        let properties = self.entry.properties.lock().unwrap();
+        self.decrypt_values(&properties);
+        self.mark_plain_secrets_changed();
+    }
+
+    /// Returns a string property whose value is masked over RPC and encrypted on disk.
+    pub fn get_secret(&self, key: &str) -> Property<String> {
+        self.entry.secret_keys.lock().unwrap().insert(key.to_string());
+        let property = self.get_string(key);
+        property.mark_secret();
+        self.mark_plain_secrets_changed();
+        property
+    }
+
+    pub fn is_secret(&self, key: &str) -> bool {
+        self.entry.secret_keys.lock().unwrap().contains(key)
+    }
+
+    /// Encrypted values are secrets even before `get_secret` is called for them.
+    fn decrypt_values(&self, properties: &HashMap<String, PropertyWrapper>) {
+        let cipher = self.entry.secret_cipher.read().unwrap();
+        let mut secret_keys = self.entry.secret_keys.lock().unwrap();
+        let mut encrypted_keys = self.entry.encrypted_keys.lock().unwrap();
+        for (key, wrapper) in properties.iter() {
+            let prop = match wrapper {
+                PropertyWrapper::String(prop) if secrets::is_encrypted(&prop.get()) => prop,
+                _ => continue,
+            };
+            secret_keys.insert(key.clone());
+            prop.mark_secret();
+            if let Some(cipher) = cipher.as_ref() {
+                match cipher.decrypt(&prop.get()) {
+                    Some(plaintext) => {
+                        prop.reload(plaintext);
+                        encrypted_keys.insert(key.clone());
+                    },
+                    None => log::error!("Unable to decrypt secret '{}' in {:?}, wrong keyfile?", key, self.entry.path),
+                }
+            }
+        }
+    }
+
+    /// Marks the settings changed if a secret is still stored as plain text,
+    /// so the next save migrates it to the encrypted form.
+    fn mark_plain_secrets_changed(&self) {
+        if self.entry.secret_cipher.read().unwrap().is_none() {
+            return;
+        }
+        let secret_keys = self.entry.secret_keys.lock().unwrap();
+        let encrypted_keys = self.entry.encrypted_keys.lock().unwrap();
+        let plain_keys = secret_keys.iter().filter(|key| !encrypted_keys.contains(*key)).cloned();
+        self.entry.dirty_keys.lock().unwrap().extend(plain_keys);
+    }
+
+    /// Value written to the file for a secret: encrypted if a keyfile is set.
+    fn secret_for_file(&self, key: &str, wrapper: &PropertyWrapper) -> Option<PropertyWrapper> {
+        let value = match wrapper {
+            PropertyWrapper::String(prop) => prop.get(),
+            _ => return None,
+        };
+        if secrets::is_encrypted(&value) {
+            // Could not be decrypted, keep it untouched
+            return None;
+        }
+        let value = match self.entry.secret_cipher.read().unwrap().as_ref() {
+            Some(cipher) => {
+                self.entry.encrypted_keys.lock().unwrap().insert(key.to_string());
+                cipher.encrypt(&value)
+            },
+            None => {
+                log::warn!("No secrets keyfile set, saving secret '{}' to {:?} AS PLAIN TEXT", key, self.entry.path);
+                return None;
+            }
+        };
+        Some(PropertyWrapper::String(Property::new(key, value, DirtyKeys::default())))
+    }
+
+    /// Re-reads the settings file and updates existing properties in place, so clones
+    /// held by services see the new values. Returns the keys whose values changed.
+    ///
+    /// While there are unsaved changes, differing values are kept from memory.
+    ///
+    /// Pending migrations are applied first, a file newer than the latest migration is an error.
+    pub fn reload_from_file(&self) -> io::Result<Vec<String>> {
+        let path = self.get_path();
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| SettingsLoadError::Io { path: path.to_path_buf(), source })?;
+        let values = formats::parse(self.entry.format, &text).map_err(|err| err.with_path(path))?;
+        let values = self.migrate_file(values)?;
+        Ok(self.reload_values(values))
+    }
+
+    #[cfg(test)]
+    fn reload_from_string(&self, text: &str) -> Result<Vec<String>, SettingsLoadError> {
+        let values = formats::parse(self.entry.format, text).map_err(|err| err.with_path(self.get_path()))?;
+        Ok(self.reload_values(values))
+    }
+
+    fn reload_values(&self, values: Vec<(String, SettingsValue)>) -> Vec<String> {
+        let loaded = Self::wrap_values(values, &self.entry.dirty_keys);
+        self.decrypt_values(&loaded);
+        let has_unsaved_changes = self.is_changed();
+        let mut created = Vec::new();
+        let mut properties = self.entry.properties.lock().unwrap();
+        let mut overridden = self.entry.overridden.lock().unwrap();
+        let mut changed_keys = Vec::new();
+        for (key, loaded_wrapper) in loaded {
+            if let Some(original) = overridden.get_mut(&key) {
+                // The override keeps winning, only remember the new file value
+                *original = Some(loaded_wrapper);
+                continue;
+            }
+            match properties.get(&key) {
+                Some(wrapper) => {
+                    if has_unsaved_changes && !wrapper.value_eq(&loaded_wrapper) {
+                        log::warn!("Settings '{}' has unsaved changes, keeping in-memory value of '{}'", self.entry.path.display(), key);
+                        continue;
+                    }
+                    match wrapper.reload_from(&loaded_wrapper) {
+                        Some(true) => changed_keys.push(key),
+                        Some(false) => {},
+                        None => log::warn!("Type of '{}' changed from '{}' to '{}' in the file, keeping in-memory value",
+                            key, wrapper.type_name(), loaded_wrapper.type_name()),
+                    }
+                },
+                None => {
+                    properties.insert(key.clone(), loaded_wrapper.clone());
+                    created.push((loaded_wrapper, true));
+                    changed_keys.push(key);
+                }
+            }
+        }
+        drop(overridden);
+        drop(properties);
+        self.notify_created(created);
+        changed_keys.sort();
+        changed_keys
+    }
+
+    pub fn is_changed(&self) -> bool {
+        !self.entry.dirty_keys.lock().unwrap().is_empty()
+    }
+
+    /// Keys changed, added or removed since the last save, sorted.
+    pub fn dirty_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.entry.dirty_keys.lock().unwrap().iter().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    /// Like `dirty_keys`, clears the set so the next `save_if_changed` skips the file.
+    /// Only for tests, saving relies on the set.
+    #[cfg(test)]
+    pub(crate) fn take_dirty_keys(&self) -> Vec<String> {
+        let keys = self.dirty_keys();
+        self.entry.dirty_keys.lock().unwrap().clear();
+        keys
+    }
+
+    /// Saves the file if some property changed since the last save, panics on write errors.
+    pub fn save_to_file(&self) {
+        self.save_if_changed().expect("Unable to write file");
+    }
+
+    /// Writes the settings even if nothing changed, to a temporary file next to the target
+    /// and renames it over the target, so a crash mid-write never leaves a truncated file behind.
+    pub fn try_save_to_file(&self) -> io::Result<()> {
+        let _save_guard = self.entry.save_lock.lock().unwrap();
+        let (data, dirty_keys) = self.snapshot(true);
+        let result = Self::write_atomically(self.entry.path.as_path(), data);
+        if result.is_err() {
+            // Keep the keys so the next attempt retries the save
+            self.entry.dirty_keys.lock().unwrap().extend(dirty_keys);
+        }
+        result
+    }
+
+    fn write_atomically(path: &Path, data: String) -> io::Result<()> {
+        let file_name = path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid settings path: {:?}", path)))?;
+        let mut tmp_file_name = file_name.to_os_string();
+        tmp_file_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_file_name);
+
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Saves the file only if some property was changed since the last save.
+    /// Returns `true` if the file was written.
+    pub fn save_if_changed(&self) -> io::Result<bool> {
+        if !self.is_changed() {
+            return Ok(false);
+        }
+        self.try_save_to_file()?;
+        Ok(true)
+    }
+
+    #[cfg(test)]
+    fn save_to_string(&self) -> String {
+        self.snapshot(false).0
+    }
+
+    /// Renders the file from a single point in time. Property writes are blocked on the
+    /// dirty keys lock while values are collected, and with `take_dirty` the dirty keys
+    /// are cleared in the same step, so a concurrent set is either saved or stays dirty.
+    fn snapshot(&self, take_dirty: bool) -> (String, Vec<String>) {
+        let properties = self.entry.properties.lock().unwrap();
+        let overridden = self.entry.overridden.lock().unwrap();
+        let secret_keys = self.entry.secret_keys.lock().unwrap().clone();
+        // In-memory values always follow the latest schema
+        let latest_version = migrations::latest_version(&self.entry.migrations.lock().unwrap());
+        let mut dirty_keys = self.entry.dirty_keys.lock().unwrap();
+        let mut values = Vec::with_capacity(properties.len());
+        if let Some(latest_version) = latest_version {
+            values.push((META_VERSION_KEY.to_string(), SettingsValue::I64(latest_version)));
+        }
+        for prop in properties.deref() {
+            if latest_version.is_some() && prop.0 == META_VERSION_KEY {
+                continue;
+            }
+            let prop_wrapper = match overridden.get(prop.0) {
+                Some(Some(original)) => original,
+                Some(None) => continue,
+                None => prop.1,
+            };
+            let encrypted = if secret_keys.contains(prop.0) {
+                self.secret_for_file(prop.0, prop_wrapper)
+            } else {
+                None
+            };
+            values.push((prop.0.clone(), encrypted.as_ref().unwrap_or(prop_wrapper).to_value()));
+        }
+        let mut taken_keys = Vec::new();
+        if take_dirty {
+            taken_keys.extend(dirty_keys.drain());
+            taken_keys.sort();
+        }
+        drop(dirty_keys);
+        drop(overridden);
+        drop(properties);
+        (formats::dump(self.entry.format, values), taken_keys)
+    }
+
+    /// Returns the property stored under `key`, creating it when missing.
+    /// With an explicit `default_value` a new property is marked dirty, so the
+    /// default gets persisted on the next save. A property of a type that converts
+    /// to `T`, e.g. an int list read for a float list, is replaced by a converted one.
+    fn try_get_typed<T: PropertyValue>(&self, key: &str, default_value: Option<T>) -> Result<Property<T>, SettingsError> {
+        let (prop, assigned) = {
+            let mut properties = self.entry.properties.lock().unwrap();
+            match properties.get(key) {
+                Some(wrapper) => {
+                    if let Some(prop) = T::unwrap(wrapper) {
+                        if let Some(default_value) = default_value {
+                            prop.set_default(default_value);
+                        }
+                        return Ok(prop.clone());
+                    }
+                    let value = T::coerce(wrapper).ok_or_else(|| SettingsError::TypeMismatch {
+                        key: key.to_string(),
+                        expected: T::TYPE_NAME,
+                        actual: wrapper.type_name(),
+                    })?;
+                    let prop = Property::new(key, value, self.entry.dirty_keys.clone());
+                    if let Some(default_value) = default_value {
+                        prop.set_default(default_value);
+                    }
+                    properties.insert(key.to_string(), T::wrap(prop.clone()));
+                    (prop, false)
+                },
+                None => {
+                    let prop = match default_value {
+                        Some(default_value) => {
+                            let prop = Property::new(key, default_value.clone(), self.entry.dirty_keys.clone());
+                            prop.set_default(default_value);
+                            prop.mark_dirty();
+                            prop
+                        },
+                        None => Property::new(key, T::default(), self.entry.dirty_keys.clone()),
+                    };
+                    properties.insert(key.to_string(), T::wrap(prop.clone()));
+                    (prop, false)
+                }
+            }
+        };
+        self.notify_created(vec![(T::wrap(prop.clone()), assigned)]);
+        Ok(prop)
+    }
+
+    /// On type mismatch the error is logged and a detached property holding
+    /// the default value is returned, so writes to it are not persisted.
+    fn get_typed<T: PropertyValue>(&self, key: &str, default_value: Option<T>) -> Property<T> {
+        match self.try_get_typed(key, default_value.clone()) {
+            Ok(prop) => prop,
+            Err(err) => {
+                log::error!("{}", err);
+                Property::new(key, default_value.unwrap_or_default(), DirtyKeys::default())
+            }
+        }
+    }
+
+    pub fn try_get_string(&self, key: &str) -> Result<Property<String>, SettingsError> {
+        self.try_get_typed(key, None)
+    }
+
+    pub fn get_string(&self, key: &str) -> Property<String> {
+        self.get_typed(key, None)
+    }
+
+    pub fn get_string_or(&self, key: &str, default_value: &str) -> Property<String> {
+        self.get_typed(key, Some(default_value.to_string()))
+    }
+
+    pub fn try_get_i64(&self, key: &str) -> Result<Property<i64>, SettingsError> {
+        self.try_get_typed(key, None)
+    }
+
+    pub fn get_i64(&self, key: &str) -> Property<i64> {
+        self.get_typed(key, None)
+    }
+
+    pub fn get_i64_or(&self, key: &str, default_value: i64) -> Property<i64> {
+        self.get_typed(key, Some(default_value))
+    }
+
+    pub fn try_get_bool(&self, key: &str) -> Result<Property<bool>, SettingsError> {
+        self.try_get_typed(key, None)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Property<bool> {
+        self.get_typed(key, None)
+    }
+
+    pub fn get_bool_or(&self, key: &str, default_value: bool) -> Property<bool> {
+        self.get_typed(key, Some(default_value))
+    }
+
+    pub fn try_get_f64(&self, key: &str) -> Result<Property<f64>, SettingsError> {
+        self.try_get_typed(key, None)
+    }
+
+    pub fn get_f64(&self, key: &str) -> Property<f64> {
+        self.get_typed(key, None)
+    }
+
+    pub fn get_f64_or(&self, key: &str, default_value: f64) -> Property<f64> {
+        self.get_typed(key, Some(default_value))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.entry.properties.lock().unwrap().contains_key(key)
+    }
+
+    /// Current values of all properties, secrets are masked.
+    fn values_masked(&self) -> Vec<(String, SettingsValue)> {
+        let properties = self.entry.properties.lock().unwrap();
+        let secret_keys = self.entry.secret_keys.lock().unwrap();
+        properties.iter()
+            .map(|(key, wrapper)| {
+                let value = if secret_keys.contains(key) {
+                    SettingsValue::String(SECRET_MASK.to_string())
+                } else {
+                    wrapper.to_value()
+                };
+                (key.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Sets a property of any type, creating it when missing.
+    /// The value is set after the properties lock is released, so change callbacks may read other keys.
+    fn set_value(&self, key: &str, value: SettingsValue) -> Result<(), SettingsError> {
+        let wrapper = {
+            let mut properties = self.entry.properties.lock().unwrap();
+            match properties.get(key) {
+                Some(wrapper) => wrapper.clone(),
+                None => {
+                    let wrapper = PropertyWrapper::from_value(key, value, self.entry.dirty_keys.clone());
+                    properties.insert(key.to_string(), wrapper.clone());
+                    self.entry.dirty_keys.lock().unwrap().insert(key.to_string());
+                    drop(properties);
+                    self.notify_created(vec![(wrapper, true)]);
+                    return Ok(());
+                },
+            }
+        };
+        let actual = settings_value_type_name(&value);
+        wrapper.set_value(value).ok_or_else(|| SettingsError::TypeMismatch {
+            key: key.to_string(),
+            expected: wrapper.type_name(),
+            actual,
+        })
+    }
+
+    /// Applies every change made by `f` at once, missing keys are created. A concurrent save sees
+    /// all of the changes or none, and every changed property notifies its callbacks once, after
+    /// all values are set. Properties set to their current value are left untouched. Nothing is applied if a value doesn't match the type of its property.
+    pub fn transaction<F: FnOnce(&mut SettingsTransaction)>(&self, f: F) -> Result<(), SettingsError> {
+        let mut transaction = SettingsTransaction::default();
+        f(&mut transaction);
+        let (changed, created) = {
+            let mut properties = self.entry.properties.lock().unwrap();
+            for (key, value) in transaction.changes.iter() {
+                if let Some(wrapper) = properties.get(key) {
+                    if !wrapper.accepts(value) {
+                        return Err(SettingsError::TypeMismatch {
+                            key: key.clone(),
+                            expected: wrapper.type_name(),
+                            actual: settings_value_type_name(value),
+                        });
+                    }
+                }
+            }
+            // Same lock as `Property::set`, so writes and saves wait for the whole transaction
+            let mut dirty_keys = self.entry.dirty_keys.lock().unwrap();
+            let mut changed = Vec::new();
+            let mut created = Vec::new();
+            for (key, value) in transaction.changes {
+                match properties.get(&key) {
+                    Some(wrapper) => {
+                        if wrapper.store_value(value) != Some(true) {
+                            continue;
+                        }
+                        changed.push(wrapper.clone());
+                    },
+                    None => {
+                        let wrapper = PropertyWrapper::from_value(&key, value, self.entry.dirty_keys.clone());
+                        properties.insert(key.clone(), wrapper.clone());
+                        created.push((wrapper, true));
+                    },
+                }
+                dirty_keys.insert(key);
+            }
+            (changed, created)
+        };
+        self.notify_created(created);
+        for wrapper in changed {
+            wrapper.notify_changed();
+        }
+        Ok(())
+    }
+
+    /// Registers `default_value` as the default of `key`, creating the key with it when missing.
+    fn declare_default(&self, key: &str, default_value: SettingsValue) -> Result<(), SettingsError> {
+        let wrapper = {
+            let mut properties = self.entry.properties.lock().unwrap();
+            if let Some(wrapper) = properties.get(key) {
+                let actual = settings_value_type_name(&default_value);
+                return wrapper.set_default_value(default_value).ok_or_else(|| SettingsError::TypeMismatch {
+                    key: key.to_string(),
+                    expected: wrapper.type_name(),
+                    actual,
+                });
+            }
+            let wrapper = PropertyWrapper::from_value(key, default_value.clone(), self.entry.dirty_keys.clone());
+            wrapper.set_default_value(default_value);
+            properties.insert(key.to_string(), wrapper.clone());
+            self.entry.dirty_keys.lock().unwrap().insert(key.to_string());
+            wrapper
+        };
+        self.notify_created(vec![(wrapper, false)]);
+        Ok(())
+    }
+
+    fn get_value(&self, key: &str) -> Option<SettingsValue> {
+        self.entry.properties.lock().unwrap().get(key).map(|wrapper| wrapper.to_value())
+    }
+
+    fn same_instance(&self, other: &Settings) -> bool {
+        Arc::ptr_eq(&self.entry, &other.entry)
+    }
+
+    /// Deletes a property, so it is no longer written to the file.
+    /// Existing clones of the property are detached and keep their last value.
+    pub fn remove(&self, key: &str) -> bool {
+        let removed = self.entry.properties.lock().unwrap().remove(key).is_some();
+        self.entry.overridden.lock().unwrap().remove(key);
+        self.entry.encrypted_keys.lock().unwrap().remove(key);
+        if removed {
+            self.entry.dirty_keys.lock().unwrap().insert(key.to_string());
+        }
+        removed
+    }
+
+    /// Moves a property to a new key. Existing clones of the property stay
+    /// attached to it and see later changes made through the new key.
+    pub fn rename(&self, old_key: &str, new_key: &str) -> Result<(), SettingsError> {
+        {
+            let mut properties = self.entry.properties.lock().unwrap();
+            if properties.contains_key(new_key) {
+                return Err(SettingsError::KeyExists { key: new_key.to_string() });
+            }
+            let wrapper = properties.remove(old_key)
+                .ok_or_else(|| SettingsError::UnknownKey { key: old_key.to_string() })?;
+            wrapper.set_key(new_key);
+            properties.insert(new_key.to_string(), wrapper);
+        }
+        let mut overridden = self.entry.overridden.lock().unwrap();
+        if let Some(original) = overridden.remove(old_key) {
+            overridden.insert(new_key.to_string(), original);
+        }
+        for keys in [&self.entry.secret_keys, &self.entry.encrypted_keys] {
+            let mut keys = keys.lock().unwrap();
+            if keys.remove(old_key) {
+                keys.insert(new_key.to_string());
+            }
+        }
+        self.entry.dirty_keys.lock().unwrap().extend([old_key.to_string(), new_key.to_string()]);
+        Ok(())
+    }
+
+    pub fn try_get_string_list(&self, key: &str) -> Result<Property<Vec<String>>, SettingsError> {
+        self.try_get_typed(key, None)
+    }
+
+    pub fn get_string_list(&self, key: &str) -> Property<Vec<String>> {
+        self.get_typed(key, None)
+    }
+
+    pub fn get_string_list_or(&self, key: &str, default_value: &[&str]) -> Property<Vec<String>> {
+        self.get_typed(key, Some(default_value.iter().map(|value| value.to_string()).collect()))
+    }
+
+    pub fn try_get_i64_list(&self, key: &str) -> Result<Property<Vec<i64>>, SettingsError> {
+        self.try_get_typed(key, None)
+    }
+
+    pub fn get_i64_list(&self, key: &str) -> Property<Vec<i64>> {
+        self.get_typed(key, None)
+    }
+
+    pub fn get_i64_list_or(&self, key: &str, default_value: &[i64]) -> Property<Vec<i64>> {
+        self.get_typed(key, Some(default_value.to_vec()))
+    }
+
+    pub fn try_get_f64_list(&self, key: &str) -> Result<Property<Vec<f64>>, SettingsError> {
+        self.try_get_typed(key, None)
+    }
+
+    pub fn get_f64_list(&self, key: &str) -> Property<Vec<f64>> {
+        self.get_typed(key, None)
+    }
+
+    pub fn get_f64_list_or(&self, key: &str, default_value: &[f64]) -> Property<Vec<f64>> {
+        self.get_typed(key, Some(default_value.to_vec()))
+    }
+
+    pub fn get_default_as_string(&self, key: &str) -> Option<String> {
+        let properties = self.entry.properties.lock().unwrap();
+        properties.get(key).and_then(|wrapper| wrapper.default_as_string())
+    }
+
+    /// View of the properties under `prefix`, see `SettingsScope`.
+    pub fn scope(&self, prefix: &str) -> SettingsScope {
+        SettingsScope::new(self.clone(), prefix)
+    }
+
+    pub fn get_properties(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        let properties = self.entry.properties.lock().unwrap();
+        for prop in properties.deref() {
+            result.push(prop.0.clone());
+        }
+        return result;
+    }
+
+}
+
+/// How the settings UI should render and edit a property.
+#[derive(Clone, Debug, Serialize)]
+pub enum PropertyKind {
+    Text,
+    Password,
+    Path,
+    Toggle,
+    Number {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    Enum {
+        options: Vec<String>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PropertyUiMeta {
+    pub label: String,
+    pub description: Option<String>,
+    pub kind: PropertyKind,
+}
+
+impl PropertyUiMeta {
+
+    pub fn new(label: &str, kind: PropertyKind) -> Self {
+        Self {
+            label: label.to_string(),
+            description: None,
+            kind,
+        }
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    fn validate(&self, key: &str, value: &str) -> Result<(), SettingsError> {
+        match &self.kind {
+            PropertyKind::Enum { options } if !options.iter().any(|option| option == value) => {
+                Err(SettingsError::InvalidValue {
+                    key: key.to_string(),
+                    message: format!("'{}' is not one of {:?}", value, options),
+                })
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Describes a validator so the UI can check values before sending them.
+#[derive(Clone, Debug, Serialize)]
+pub enum ValidatorKind {
+    IntegerRange {
+        min: i64,
+        max: i64,
+    },
+    NonEmpty,
+    ExistingPath,
+    Custom,
+}
+
+type ValidatorFn = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync + 'static>;
+
+/// Check run by `SettingsManager::set_string_value` before a value is written.
+#[derive(Clone)]
+pub struct SettingsValidator {
+    kind: ValidatorKind,
+    check: ValidatorFn,
+}
+
+impl SettingsValidator {
+
+    pub fn integer_range(min: i64, max: i64) -> Self {
+        Self {
+            kind: ValidatorKind::IntegerRange { min, max },
+            check: Arc::new(move |value| {
+                match value.trim().parse::<i64>() {
+                    Ok(number) if number >= min && number <= max => Ok(()),
+                    Ok(number) => Err(format!("{} is out of range {}..={}", number, min, max)),
+                    Err(_) => Err(format!("'{}' is not an integer", value)),
+                }
+            }),
+        }
+    }
+
+    pub fn non_empty() -> Self {
+        Self {
+            kind: ValidatorKind::NonEmpty,
+            check: Arc::new(|value| {
+                if value.trim().is_empty() {
+                    Err("Value must not be empty".to_string())
+                } else {
+                    Ok(())
+                }
+            }),
+        }
+    }
+
+    pub fn existing_path() -> Self {
+        Self {
+            kind: ValidatorKind::ExistingPath,
+            check: Arc::new(|value| {
+                if Path::new(value).exists() {
+                    Ok(())
+                } else {
+                    Err(format!("Path '{}' does not exist", value))
+                }
+            }),
+        }
+    }
+
+    pub fn custom<F>(check: F) -> Self where
+        F: Fn(&str) -> Result<(), String> + Send + Sync + 'static
+    {
+        Self {
+            kind: ValidatorKind::Custom,
+            check: Arc::new(check),
+        }
+    }
+
+    pub fn get_kind(&self) -> &ValidatorKind {
+        &self.kind
+    }
+
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        (self.check)(value)
+    }
+}
+
+impl <F> From<F> for SettingsValidator where
+    F: Fn(&str) -> Result<(), String> + Send + Sync + 'static
+{
+    fn from(check: F) -> Self {
+        Self::custom(check)
+    }
+}
+
+/// Returns `true` if `key` is `key_or_prefix` itself or lies below it.
+fn key_matches(key_or_prefix: &str, key: &str) -> bool {
+    key.strip_prefix(key_or_prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PropertyDescription {
+    pub name: String,
+    pub default_value: Option<String>,
+    pub meta: Option<PropertyUiMeta>,
+    pub validators: Vec<ValidatorKind>,
+    /// Set when the value comes from an override instead of the settings file.
+    pub overridden: bool,
+    /// Set for computed properties, which are shown but can't be changed.
+    pub read_only: bool,
+    /// The default value is masked, the UI should show a password field.
+    pub secret: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SectionDescription {
+    pub name: String,
+    pub properties: Vec<PropertyDescription>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TabDescription {
+    pub name: String,
+    pub sections: Vec<SectionDescription>,
+}
+
+impl TabDescription {
+
+    pub fn get_section(&mut self, section_name: &str) -> Option<&mut SectionDescription> {
+          self.sections.iter_mut().find(|section| section.name == section_name)
+    }
+
+    fn get_or_add_section(&mut self, section_name: &str) -> &mut SectionDescription {
+        return if let Some(index) = self.sections.iter().position(|section| section.name == section_name) {
+            self.sections.get_mut(index).unwrap()
+        } else {
+            let section_description = SectionDescription {
+                name: section_name.to_string(),
+                properties: Vec::new(),
+            };
+            self.sections.push(section_description);
+            self.sections.last_mut().unwrap()
+        }
+    }
+}
+
+/// Tab of keys without a dot, e.g. `debug`.
+pub const DEFAULT_TAB: &str = "general";
+/// Section of keys with fewer than three segments, e.g. `main.collection_dir`.
+pub const DEFAULT_SECTION: &str = "general";
+
+pub struct SettingsDescription {
+    tabs: Vec<TabDescription>,
+}
+
+impl SettingsDescription {
+
+    fn empty() -> Self {
+        Self {
+            tabs: Vec::new(),
+        }
+    }
+
+    fn get_tab(&mut self, tab_name: &str) -> Option<&mut TabDescription> {
+        self.tabs.iter_mut().find(|tab| tab.name == tab_name)
+    }
+
+    fn get_or_add_tab(&mut self, tab_name: &str) -> &mut TabDescription {
+        return if let Some(index) = self.tabs.iter().position(|tab| tab.name == tab_name) {
+            self.tabs.get_mut(index).unwrap()
+        } else {
+            let tab_description = TabDescription {
+                name: tab_name.to_string(),
+                sections: Vec::new(),
+            };
+            self.tabs.push(tab_description);
+            self.tabs.last_mut().unwrap()
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add_property(&mut self, property_path: &str, default_value: Option<String>, meta: Option<PropertyUiMeta>,
+                    validators: Vec<ValidatorKind>, overridden: bool, read_only: bool, secret: bool) {
+        let parts: Vec<&str> = property_path.splitn(3, '.').collect();
+        let (tab_name, section_name) = match parts.as_slice() {
+            [_] => (DEFAULT_TAB, DEFAULT_SECTION),
+            [tab_name, _] => (*tab_name, DEFAULT_SECTION),
+            [tab_name, section_name, ..] => (*tab_name, *section_name),
+            [] => unreachable!("splitn yields at least one part"),
+        };
+        let tab_description = self.get_or_add_tab(tab_name);
+        let section_description = tab_description.get_or_add_section(section_name);
+        if !section_description.properties.iter().any(|prop| prop.name == property_path) {
+            section_description.properties.push(PropertyDescription {
+                name: property_path.to_string(),
+                default_value,
+                meta,
+                validators,
+                overridden,
+                read_only,
+                secret,
+            });
+        }
+    }
+
+    fn add_properties(&mut self, settings: &Settings, property_meta: &HashMap<String, PropertyUiMeta>,
+                      validators: &[(String, SettingsValidator)]) {
+        for property in settings.get_properties() {
+            let secret = settings.is_secret(&property);
+            let default_value = settings.get_default_as_string(&property)
+                .map(|default_value| if secret { SECRET_MASK.to_string() } else { default_value });
+            let meta = property_meta.get(&property).cloned();
+            let validator_kinds = validators.iter()
+                .filter(|(key_or_prefix, _)| key_matches(key_or_prefix, &property))
+                .map(|(_, validator)| validator.get_kind().clone())
+                .collect();
+            let overridden = settings.is_overridden(&property);
+            self.add_property(&property, default_value, meta, validator_kinds, overridden, false, secret);
+        }
+    }
+
+    fn add_computed_properties(&mut self, computed: &BTreeMap<String, ComputedValue>, property_meta: &HashMap<String, PropertyUiMeta>) {
+        for key in computed.keys() {
+            self.add_property(key, None, property_meta.get(key).cloned(), Vec::new(), false, true, false);
+        }
+    }
+}
+
+/// Emitted when settings files were re-read, lists the keys whose values changed.
+#[derive(Clone, Debug, Serialize, Deserialize, Event)]
+#[key = "amina_core.settings.reloaded"]
+pub struct SettingsReloadedEvent {
+    pub keys: Vec<String>,
+}
+
+/// Emitted when a property of registered settings changed, whether it was set through
+/// `SettingsManager` or on the `Property`, or was created with a value, e.g. by an import.
+/// Secrets carry `SECRET_MASK` as the value.
+#[derive(Clone, Debug, Serialize, Deserialize, Event)]
+#[key = "amina.settings.changed"]
+pub struct SettingsChangedEvent {
+    pub key: String,
+    pub value: serde_json::Value,
+}
+
+/// Payload of the `settings_save` command.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SettingsSaveReport {
+    pub files: usize,
+    pub properties: usize,
+    /// Files that couldn't be written, with the reason.
+    pub errors: Vec<String>,
+}
+
+const SETTINGS_SAVE_CMD: &str = "settings_save";
+const SETTINGS_RELOAD_CMD: &str = "settings_reload";
+const SETTINGS_CATEGORY: &str = "Settings";
+
+type ComputedValue = Arc<dyn Fn() -> String + Send + Sync + 'static>;
+
+const DEFAULT_AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+const AUTOSAVE_POLL_STEP: Duration = Duration::from_millis(100);
+
+pub struct SettingsManager {
+    settings_list: Arc<Mutex<Vec<Arc<Settings>>>>,
+    prefixed_settings: Mutex<Vec<(String, Arc<Settings>)>>,
+    default_settings: Mutex<Option<Arc<Settings>>>,
+    settings_description: Mutex<SettingsDescription>,
+    property_meta: Mutex<HashMap<String, PropertyUiMeta>>,
+    validators: Mutex<Vec<(String, SettingsValidator)>>,
+    computed: Mutex<BTreeMap<String, ComputedValue>>,
+    declared: Mutex<BTreeMap<String, DeclaredProperty>>,
+    profiles: Mutex<Option<Profiles>>,
+    /// Runs autosave and file watching, both are off without it.
+    task_manager: Option<Service<TaskManager>>,
+    /// Changes, reloads and profile switches are only logged without it.
+    event_emitter: Option<Service<EventEmitter>>,
+    /// Present when the context has one, its history size follows `HISTORY_SIZE_KEY`.
+    cmd_manager: Option<Service<CmdManager>>,
+    history_size: Mutex<Option<PropertySubscription>>,
+    autosave_interval_ms: Arc<AtomicU64>,
+    file_watch_interval_ms: Arc<AtomicU64>,
+    /// Properties that emit `SettingsChangedEvent`, see `PropertyWrapper::identity`.
+    watched: Arc<Mutex<HashMap<usize, Weak<dyn Any + Send + Sync>>>>,
+}
+
+#[rpc_service]
+impl SettingsManager {
+
+    #[rpc("amina_core.settings_manager.get_tabs")]
+    pub fn get_tabs(&self) -> Vec<String> {
+        let settings_description = self.settings_description.lock().unwrap();
+        let mut result = Vec::new();
+        for tab in settings_description.tabs.deref() {
+            result.push(tab.name.clone());
+        }
+        return result;
+    }
+
+    #[rpc("amina_core.settings_manager.get_tab")]
+    pub fn get_tab(&self, tab_name: String) -> Result<TabDescription, SettingsError> {
+        let mut settings_description = self.settings_description.lock().unwrap();
+        match settings_description.get_tab(&tab_name) {
+            Some(tab) => Ok(tab.clone()),
+            None => Err(SettingsError::UnknownTab { name: tab_name }),
+        }
+    }
+
+    /// Registers settings that serve only the keys they already contain.
+    pub fn register_settings(&self, settings: Arc<Settings>) {
+        self.watch_changes(&settings);
+        let mut settings_list = self.settings_list.lock().unwrap();
+        settings_list.push(settings);
+    }
+
+    /// Registers settings that own every key starting with `prefix.`,
+    /// missing keys under the prefix are created there.
+    pub fn register_prefixed_settings(&self, prefix: &str, settings: Arc<Settings>) {
+        self.prefixed_settings.lock().unwrap().push((prefix.to_string(), settings.clone()));
+        self.register_settings(settings);
+    }
+
+    /// Registers settings that receive keys no other instance can serve.
+    pub fn register_default_settings(&self, settings: Arc<Settings>) {
+        *self.default_settings.lock().unwrap() = Some(settings.clone());
+        self.register_settings(settings);
+    }
+
+    /// Enables profiles with `base` as the default settings. Profile overlays are loaded from
+    /// the files next to the base file and hold only the keys that differ between profiles.
+    pub fn register_profile_base(&self, base: Arc<Settings>) {
+        let profiles = Profiles::load(base.clone());
+        self.register_default_settings(base);
+        for overlay in profiles.overlays.values() {
+            self.register_settings(overlay.clone());
+        }
+        let event_emitter = self.event_emitter.clone();
+        let _ = profiles.active.on_change(move |name| {
+            let profile = Some(name.clone()).filter(|name| !name.is_empty());
+            log::info!("Switched to settings profile {:?}", profile);
+            if let Some(event_emitter) = &event_emitter {
+                event_emitter.emit_event(&ProfileSwitchedEvent {
+                    profile,
+                });
+            }
+        });
+        *self.profiles.lock().unwrap() = Some(profiles);
+        self.regenerate_settings_description();
+    }
+
+    #[rpc("amina_core.settings_manager.get_profiles")]
+    pub fn get_profiles(&self) -> Vec<String> {
+        match self.profiles.lock().unwrap().as_ref() {
+            Some(profiles) => profiles.overlays.keys().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    #[rpc("amina_core.settings_manager.get_active_profile")]
+    pub fn get_active_profile(&self) -> Option<String> {
+        self.profiles.lock().unwrap().as_ref().and_then(|profiles| profiles.active_name())
+    }
+
+    /// Creates an empty profile, its file is written with the next save.
+    #[rpc("amina_core.settings_manager.create_profile")]
+    pub fn create_profile(&self, name: String) -> Result<(), SettingsError> {
+        profiles::check_profile_name(&name)?;
+        let overlay = {
+            let mut profiles = self.profiles.lock().unwrap();
+            let profiles = profiles.as_mut().ok_or(SettingsError::ProfilesDisabled)?;
+            if profiles.overlays.contains_key(&name) {
+                return Err(SettingsError::ProfileExists { name });
+            }
+            let overlay = Arc::new(Settings::create_empty(&profiles.overlay_path(&name)));
+            if let Err(err) = overlay.try_save_to_file() {
+                log::error!("Unable to save profile file {:?}: {}", overlay.get_path(), err);
+            }
+            profiles.overlays.insert(name, overlay.clone());
+            overlay
+        };
+        self.register_settings(overlay);
+        Ok(())
+    }
+
+    /// Deletes a profile with its file, the base settings are used if it was active.
+    #[rpc("amina_core.settings_manager.delete_profile")]
+    pub fn delete_profile(&self, name: String) -> Result<(), SettingsError> {
+        let (overlay, mut active) = {
+            let mut profiles = self.profiles.lock().unwrap();
+            let profiles = profiles.as_mut().ok_or(SettingsError::ProfilesDisabled)?;
+            let overlay = profiles.overlays.remove(&name)
+                .ok_or_else(|| SettingsError::UnknownProfile { name: name.clone() })?;
+            (overlay, profiles.active.clone())
+        };
+        self.settings_list.lock().unwrap().retain(|settings| !settings.same_instance(&overlay));
+        match std::fs::remove_file(overlay.get_path()) {
+            Ok(()) => {},
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => log::error!("Unable to delete profile file {:?}: {}", overlay.get_path(), err),
+        }
+        if active.get() == name {
+            active.set(String::new());
+        }
+        self.regenerate_settings_description();
+        Ok(())
+    }
+
+    /// Makes `name` the active profile, `None` switches to the base settings.
+    /// Emits `ProfileSwitchedEvent`.
+    #[rpc("amina_core.settings_manager.switch_profile")]
+    pub fn switch_profile(&self, name: Option<String>) -> Result<(), SettingsError> {
+        let mut active = {
+            let profiles = self.profiles.lock().unwrap();
+            let profiles = profiles.as_ref().ok_or(SettingsError::ProfilesDisabled)?;
+            if let Some(name) = &name {
+                if !profiles.overlays.contains_key(name) {
+                    return Err(SettingsError::UnknownProfile { name: name.clone() });
+                }
+            }
+            profiles.active.clone()
+        };
+        let name = name.unwrap_or_default();
+        if active.get() != name {
+            active.set(name);
+        }
+        Ok(())
+    }
+
+    /// Makes `key` differ in profile `name`, starting with the value of the base settings.
+    /// Writes to `key` go to the overlay while the profile is active, `remove_property` undoes it.
+    #[rpc("amina_core.settings_manager.add_profile_property")]
+    pub fn add_profile_property(&self, name: String, key: String) -> Result<(), SettingsError> {
+        self.check_writable(&key)?;
+        if key == ACTIVE_PROFILE_KEY {
+            return Err(SettingsError::InvalidValue {
+                key,
+                message: "The active profile is shared by all profiles".to_string(),
+            });
+        }
+        let profiles = self.profiles.lock().unwrap();
+        let profiles = profiles.as_ref().ok_or(SettingsError::ProfilesDisabled)?;
+        let overlay = profiles.overlays.get(&name)
+            .ok_or_else(|| SettingsError::UnknownProfile { name: name.clone() })?;
+        if overlay.contains(&key) {
+            return Err(SettingsError::KeyExists { key });
+        }
+        let value = profiles.base.get_value(&key)
+            .ok_or_else(|| SettingsError::UnknownKey { key: key.clone() })?;
+        overlay.set_value(&key, value)
+    }
+
+    /// Finds the instance owning `key`: the active profile if it overlays the key, then the
+    /// longest matching prefix, then any other instance that already contains the key,
+    /// then the default instance.
+    fn find_settings(&self, key: &str) -> Result<Arc<Settings>, SettingsError> {
+        let mut overlays = Vec::new();
+        if let Some(profiles) = self.profiles.lock().unwrap().as_ref() {
+            if let Some(overlay) = profiles.active_overlay().filter(|overlay| overlay.contains(key)) {
+                return Ok(overlay);
+            }
+            overlays.extend(profiles.overlays.values().cloned());
+        }
+
+        let prefixed_settings = self.prefixed_settings.lock().unwrap();
+        let prefixed = prefixed_settings.iter()
+            .filter(|(prefix, _)| key != prefix && key_matches(prefix, key))
+            .max_by_key(|(prefix, _)| prefix.len());
+        if let Some((_, settings)) = prefixed {
+            return Ok(settings.clone());
+        }
+
+        let settings_list = self.settings_list.lock().unwrap();
+        let found = settings_list.iter()
+            .filter(|settings| !overlays.iter().any(|overlay| overlay.same_instance(settings)))
+            .find(|settings| settings.contains(key));
+        if let Some(settings) = found {
+            return Ok(settings.clone());
+        }
+
+        self.default_settings.lock().unwrap().clone()
+            .ok_or_else(|| SettingsError::UnknownKey { key: key.to_string() })
+    }
+
+    /// Attaches UI metadata to `key`, it is returned by `get_tab` with the property.
+    pub fn describe_property(&self, key: &str, meta: PropertyUiMeta) {
+        self.property_meta.lock().unwrap().insert(key.to_string(), meta);
+        self.regenerate_settings_description();
+    }
+
+    /// Registers a read-only property whose value is produced by `value` on every read,
+    /// e.g. the app version. It is listed in the tabs but never stored in a settings file.
+    pub fn register_computed<F>(&self, key: &str, value: F) where
+        F: Fn() -> String + Send + Sync + 'static
+    {
+        self.computed.lock().unwrap().insert(key.to_string(), Arc::new(value));
+        self.regenerate_settings_description();
+    }
+
+    /// Declares a key up front with its default and UI metadata, typically from a service's
+    /// `initialize`. Missing declared keys are added with their defaults on `start`, so the
+    /// saved file is complete after the first run, and `write_template` lists them all.
+    pub fn declare<V: Into<SettingsValue>>(&self, key: &str, default_value: V, meta: PropertyUiMeta) {
+        self.property_meta.lock().unwrap().insert(key.to_string(), meta.clone());
+        self.declared.lock().unwrap().insert(key.to_string(), DeclaredProperty {
+            default_value: default_value.into(),
+            meta,
+        });
+    }
+
+    /// Writes a YAML file with every declared key at its default, commented with its description.
+    pub fn write_template(&self, path: &Path) -> io::Result<()> {
+        let text = template::render(&self.declared.lock().unwrap());
+        Settings::write_atomically(path, text)
+    }
+
+    fn populate_declared(&self) {
+        let declared: Vec<(String, SettingsValue)> = self.declared.lock().unwrap().iter()
+            .map(|(key, property)| (key.clone(), property.default_value.clone()))
+            .collect();
+        for (key, default_value) in declared {
+            let result = self.find_settings(&key)
+                .and_then(|settings| settings.declare_default(&key, default_value));
+            if let Err(err) = result {
+                log::error!("Unable to add declared property: {}", err);
+            }
+        }
+    }
+
+    /// Registered settings are known by now, unlike when the `CmdManager` is initialized.
+    fn bind_history_size(&self) {
+        let cmd_manager = match &self.cmd_manager {
+            Some(cmd_manager) => cmd_manager,
+            None => return,
+        };
+        match self.find_settings(HISTORY_SIZE_KEY) {
+            Ok(settings) => {
+                let property = settings.get_i64_or(HISTORY_SIZE_KEY, DEFAULT_HISTORY_SIZE as i64);
+                *self.history_size.lock().unwrap() = Some(cmd_manager.bind_history_size(&property));
+            },
+            Err(err) => log::debug!("Command history size isn't read from the settings: {}", err),
+        }
+    }
+
+    /// Computed properties can't be written, overridden ones would keep the override on save.
+    fn check_writable(&self, key: &str) -> Result<(), SettingsError> {
+        if self.computed.lock().unwrap().contains_key(key) {
+            return Err(SettingsError::ReadOnly { key: key.to_string() });
+        }
+        if self.find_settings(key).map(|settings| settings.is_overridden(key)).unwrap_or(false) {
+            return Err(SettingsError::Overridden { key: key.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Adds a check for `key_or_prefix` and every key below it, run by `set_string_value`
+    /// before the value is written. Accepts built-in validators and plain closures.
+    pub fn add_validator<V: Into<SettingsValidator>>(&self, key_or_prefix: &str, validator: V) {
+        self.validators.lock().unwrap().push((key_or_prefix.to_string(), validator.into()));
+        self.regenerate_settings_description();
+    }
+
+    fn validate(&self, key: &str, value: &str) -> Result<(), SettingsError> {
+        if let Some(meta) = self.property_meta.lock().unwrap().get(key) {
+            meta.validate(key, value)?;
+        }
+        let validators = self.validators.lock().unwrap();
+        for (_, validator) in validators.iter().filter(|(key_or_prefix, _)| key_matches(key_or_prefix, key)) {
+            validator.validate(value).map_err(|message| SettingsError::InvalidValue {
+                key: key.to_string(),
+                message,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Secrets are masked unless `reveal` is `Some(true)` and the current caller is at least `Admin`.
+    #[rpc("amina_core.settings_manager.get_string_value")]
+    pub fn get_string_value(&self, key: String, reveal: Option<bool>) -> Result<String, SettingsError> {
+        let computed = self.computed.lock().unwrap().get(&key).cloned();
+        if let Some(computed) = computed {
+            return Ok(computed());
+        }
+        let settings = self.find_settings(&key)?;
+        let property = settings.try_get_string(&key)?;
+        let may_reveal = reveal == Some(true)
+            && current_cmd_caller().is_some_and(|caller| caller.level >= PermissionLevel::Admin);
+        if settings.is_secret(&key) && !may_reveal {
+            return Ok(SECRET_MASK.to_string());
+        }
+        Ok(property.get())
+    }
+
+    #[rpc("amina_core.settings_manager.set_string_value")]
+    pub fn set_string_value(&self, key: String, data: String) -> Result<(), SettingsError> {
+        self.check_writable(&key)?;
+        self.validate(&key, &data)?;
+        let settings = self.find_settings(&key)?;
+        let mut property = settings.try_get_string(&key)?;
+        property.set(data);
+        Ok(())
+    }
+
+    #[rpc("amina_core.settings_manager.get_string_list_value")]
+    pub fn get_string_list_value(&self, key: String) -> Result<Vec<String>, SettingsError> {
+        let settings = self.find_settings(&key)?;
+        Ok(settings.try_get_string_list(&key)?.get())
+    }
+
+    /// Replaces the whole list, every item is checked by the key's validators.
+    #[rpc("amina_core.settings_manager.set_string_list_value")]
+    pub fn set_string_list_value(&self, key: String, data: Vec<String>) -> Result<(), SettingsError> {
+        self.check_writable(&key)?;
+        for item in data.iter() {
+            self.validate(&key, item)?;
+        }
+        let settings = self.find_settings(&key)?;
+        let mut property = settings.try_get_string_list(&key)?;
+        property.set(data);
+        Ok(())
+    }
+
+    #[rpc("amina_core.settings_manager.append_to_string_list")]
+    pub fn append_to_string_list(&self, key: String, data: String) -> Result<(), SettingsError> {
+        self.check_writable(&key)?;
+        self.validate(&key, &data)?;
+        let settings = self.find_settings(&key)?;
+        let mut property = settings.try_get_string_list(&key)?;
+        let mut list = property.get();
+        list.push(data);
+        property.set(list);
+        Ok(())
+    }
+
+    /// Returns `false` if the settings serving `key` have no such property.
+    #[rpc("amina_core.settings_manager.remove_property")]
+    pub fn remove_property(&self, key: String) -> Result<bool, SettingsError> {
+        self.check_writable(&key)?;
+        let settings = self.find_settings(&key)?;
+        let removed = settings.remove(&key);
+        if removed {
+            self.regenerate_settings_description();
+        }
+        Ok(removed)
+    }
+
+    /// Renames within the settings holding `old_key`, whichever settings would serve `new_key`.
+    #[rpc("amina_core.settings_manager.rename_property")]
+    pub fn rename_property(&self, old_key: String, new_key: String) -> Result<(), SettingsError> {
+        self.check_writable(&old_key)?;
+        self.check_writable(&new_key)?;
+        let settings = self.find_settings(&old_key)?;
+        settings.rename(&old_key, &new_key)?;
+        self.regenerate_settings_description();
+        Ok(())
+    }
+
+    /// Values of every registered settings instance, for a "download my settings" button.
+    /// Secrets are masked.
+    #[rpc("amina_core.settings_manager.export")]
+    pub fn export_all(&self) -> SettingsDump {
+        let mut dump = SettingsDump::default();
+        for (name, settings) in self.named_instances() {
+            dump.instances.insert(name, formats::to_json_value(settings.values_masked()));
+        }
+        dump
+    }
+
+    /// Applies a dump made by `export_all`. Every key is validated like in `set_string_value`,
+    /// failed keys are reported and the rest of the dump is still applied.
+    /// Masked secrets are skipped, so a restore never overwrites them with the mask.
+    #[rpc("amina_core.settings_manager.import")]
+    pub fn import(&self, dump: SettingsDump, mode: ImportMode) -> ImportReport {
+        let instances = self.named_instances();
+        let mut report = ImportReport::default();
+        for (name, value) in dump.instances {
+            let settings = match instances.iter().find(|(instance_name, _)| *instance_name == name) {
+                Some((_, settings)) => settings,
+                None => {
+                    report.failures.push(ImportFailure {
+                        key: name,
+                        message: "No registered settings with this name".to_string(),
+                    });
+                    continue;
+                }
+            };
+            let values = match formats::from_json_value(&value) {
+                Ok(values) => values,
+                Err(message) => {
+                    report.failures.push(ImportFailure {
+                        key: name,
+                        message,
+                    });
+                    continue;
+                }
+            };
+
+            let mut imported_keys = HashSet::new();
+            for (key, value) in values {
+                imported_keys.insert(key.clone());
+                if settings.is_secret(&key) && value == SettingsValue::String(SECRET_MASK.to_string()) {
+                    continue;
+                }
+                match self.import_value(settings, &key, value) {
+                    Ok(()) => report.applied.push(key),
+                    Err(err) => report.failures.push(ImportFailure {
+                        key,
+                        message: err.to_string(),
+                    }),
+                }
+            }
+            if mode == ImportMode::Replace {
+                let mut keys = settings.get_properties();
+                keys.sort();
+                for key in keys {
+                    if !imported_keys.contains(&key) && settings.remove(&key) {
+                        report.removed.push(key);
+                    }
+                }
+            }
+        }
+        self.regenerate_settings_description();
+        report
+    }
+
+    fn import_value(&self, settings: &Settings, key: &str, value: SettingsValue) -> Result<(), SettingsError> {
+        self.check_writable(key)?;
+        match &value {
+            SettingsValue::String(text) => self.validate(key, text)?,
+            SettingsValue::I64(number) => self.validate(key, &number.to_string())?,
+            SettingsValue::Bool(flag) => self.validate(key, &flag.to_string())?,
+            SettingsValue::F64(number) => self.validate(key, &number.to_string())?,
+            SettingsValue::StringList(items) => {
+                for item in items.iter() {
+                    self.validate(key, item)?;
+                }
+            },
+            SettingsValue::I64List(items) => {
+                for item in items.iter() {
+                    self.validate(key, &item.to_string())?;
+                }
+            },
+            SettingsValue::F64List(items) => {
+                for item in items.iter() {
+                    self.validate(key, &item.to_string())?;
+                }
+            },
+        }
+        settings.set_value(key, value)
+    }
+
+    /// Every registered instance once, named by its prefix or its file name.
+    fn named_instances(&self) -> Vec<(String, Arc<Settings>)> {
+        let mut instances: Vec<(String, Arc<Settings>)> = Vec::new();
+        let mut add = |name: String, settings: &Arc<Settings>| {
+            if instances.iter().any(|(_, added)| added.same_instance(settings)) {
+                return;
+            }
+            let mut unique_name = name.clone();
+            let mut index = 2;
+            while instances.iter().any(|(added_name, _)| *added_name == unique_name) {
+                unique_name = format!("{}_{}", name, index);
+                index += 1;
+            }
+            instances.push((unique_name, settings.clone()));
+        };
+        let file_name = |settings: &Arc<Settings>| settings.get_path().file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "settings".to_string());
+
+        // Prefixed and default instances are in the list too, they are named first
+        for (prefix, settings) in self.prefixed_settings.lock().unwrap().iter() {
+            add(prefix.clone(), settings);
+        }
+        if let Some(settings) = self.default_settings.lock().unwrap().as_ref() {
+            add(file_name(settings), settings);
+        }
+        for settings in self.settings_list.lock().unwrap().iter() {
+            add(file_name(settings), settings);
+        }
+        instances
+    }
+
+    /// Sets how often changed settings are saved in background.
+    /// A zero interval disables autosave, changes are then only flushed on `stop`.
+    pub fn set_autosave_interval(&self, interval: Duration) {
+        self.autosave_interval_ms.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Immediately saves every registered settings instance that has unsaved changes.
+    /// Returns the number of written files.
+    #[rpc("amina_core.settings_manager.flush")]
+    pub fn flush(&self) -> usize {
+        Self::flush_settings(&self.settings_list)
+    }
+
+    fn flush_settings(settings_list: &Mutex<Vec<Arc<Settings>>>) -> usize {
+        let settings_list = settings_list.lock().unwrap().clone();
+        let mut saved = 0;
+        for settings in settings_list {
+            match settings.save_if_changed() {
+                Ok(true) => saved += 1,
+                Ok(false) => {},
+                Err(err) => log::error!("Unable to save settings to {:?}: {}", settings.entry.path, err),
+            }
+        }
+        saved
+    }
+
+    /// Sets how often settings files are checked for external modifications.
+    /// Zero (the default) disables watching. Takes effect on `start`.
+    pub fn set_file_watch_interval(&self, interval: Duration) {
+        self.file_watch_interval_ms.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Re-reads all registered settings files and emits `SettingsReloadedEvent`
+    /// if any value changed. Returns the changed keys.
+    pub fn reload(&self) -> Vec<String> {
+        let settings_list = self.settings_list.lock().unwrap().clone();
+        let mut changed_keys = Vec::new();
+        for settings in settings_list {
+            changed_keys.extend(Self::reload_settings(&settings));
+        }
+        Self::notify_reloaded(&self.event_emitter, changed_keys.clone());
+        changed_keys
+    }
+
+    /// Writes every registered settings instance, whether it changed or not.
+    pub fn save_all(&self) -> SettingsSaveReport {
+        let settings_list = self.settings_list.lock().unwrap().clone();
+        let mut report = SettingsSaveReport {
+            files: 0,
+            properties: 0,
+            errors: Vec::new(),
+        };
+        for settings in settings_list {
+            match settings.try_save_to_file() {
+                Ok(()) => {
+                    report.files += 1;
+                    report.properties += settings.entry.properties.lock().unwrap().len();
+                },
+                Err(err) => report.errors.push(format!("{:?}: {}", settings.entry.path, err)),
+            }
+        }
+        report
+    }
+
+    fn register_commands(settings_manager: &Arc<Self>, cmd_manager: &CmdManager) {
+        let save_cmd = CmdBuilder::new(SETTINGS_SAVE_CMD)
+            .category(SETTINGS_CATEGORY)
+            .set_permission(PermissionLevel::Admin)
+            .add_description("Write all settings to their files")
+            .build();
+        // Weak, so the command doesn't keep the settings manager alive
+        let settings_manager_weak = Arc::downgrade(settings_manager);
+        cmd_manager.add_command(save_cmd, move |_| {
+            let settings_manager = match settings_manager_weak.upgrade() {
+                Some(settings_manager) => settings_manager,
+                None => return CmdResult::error("Settings manager is gone"),
+            };
+            let report = settings_manager.save_all();
+            let message = format!("Saved {} properties to {} files", report.properties, report.files);
+            let result = if report.errors.is_empty() {
+                CmdResult::ok(&message)
+            } else {
+                CmdResult::error(&format!("{}, failed: {}", message, report.errors.join("; ")))
+            };
+            result.with_payload(&report)
+        }).unwrap();
+
+        let reload_cmd = CmdBuilder::new(SETTINGS_RELOAD_CMD)
+            .category(SETTINGS_CATEGORY)
+            .set_permission(PermissionLevel::Admin)
+            .add_description("Re-read all settings from their files")
+            .build();
+        let settings_manager_weak = Arc::downgrade(settings_manager);
+        cmd_manager.add_command(reload_cmd, move |_| {
+            match settings_manager_weak.upgrade() {
+                Some(settings_manager) => {
+                    let changed_keys = settings_manager.reload();
+                    CmdResult::ok(&format!("Reloaded settings, {} properties changed", changed_keys.len()))
+                        .with_payload(&changed_keys)
+                },
+                None => CmdResult::error("Settings manager is gone"),
+            }
+        }).unwrap();
+    }
+
+    fn reload_settings(settings: &Settings) -> Vec<String> {
+        match settings.reload_from_file() {
+            Ok(changed_keys) => changed_keys,
+            Err(err) => {
+                log::error!("Unable to reload settings from {:?}: {}", settings.get_path(), err);
+                Vec::new()
+            }
+        }
+    }
+
+    fn notify_reloaded(event_emitter: &Option<Service<EventEmitter>>, keys: Vec<String>) {
+        if !keys.is_empty() {
+            log::info!("Settings reloaded, changed keys: {:?}", keys);
+            if let Some(event_emitter) = event_emitter {
+                event_emitter.emit_event(&SettingsReloadedEvent {
+                    keys,
+                });
+            }
+        }
+    }
+
+    fn start_file_watch(&self) {
+        let interval = Duration::from_millis(self.file_watch_interval_ms.load(Ordering::Relaxed));
+        if interval.is_zero() {
+            return;
+        }
+        let task_manager = match &self.task_manager {
+            Some(task_manager) => task_manager,
+            None => {
+                log::warn!("Settings files aren't watched, there is no task manager");
+                return;
+            },
+        };
+        let settings_list = self.settings_list.clone();
+        let event_emitter = self.event_emitter.clone();
+        task_manager.run(move |task_context| {
+            let modified_time = |settings: &Settings| {
+                std::fs::metadata(settings.get_path()).and_then(|metadata| metadata.modified()).ok()
+            };
+            let mut known_times = HashMap::new();
+            for settings in settings_list.lock().unwrap().iter() {
+                known_times.insert(settings.get_path().to_path_buf(), modified_time(settings));
+            }
+
+            let mut elapsed = Duration::ZERO;
+            while !task_context.is_interrupted() {
+                std::thread::sleep(AUTOSAVE_POLL_STEP);
+                elapsed += AUTOSAVE_POLL_STEP;
+                if elapsed < interval {
+                    continue;
+                }
+                elapsed = Duration::ZERO;
+
+                let settings_list = settings_list.lock().unwrap().clone();
+                let mut changed_keys = Vec::new();
+                for settings in settings_list {
+                    let time = modified_time(&settings);
+                    let known_time = known_times.insert(settings.get_path().to_path_buf(), time);
+                    if time.is_some() && known_time != Some(time) {
+                        changed_keys.extend(Self::reload_settings(&settings));
+                    }
+                }
+                Self::notify_reloaded(&event_emitter, changed_keys);
+            }
+        });
+    }
+
+    fn start_autosave(&self) {
+        if self.autosave_interval_ms.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let task_manager = match &self.task_manager {
+            Some(task_manager) => task_manager,
+            None => {
+                log::warn!("Settings are only saved on stop, there is no task manager for autosave");
+                return;
+            },
+        };
+        let settings_list = self.settings_list.clone();
+        let autosave_interval_ms = self.autosave_interval_ms.clone();
+        task_manager.run(move |task_context| {
+            let mut elapsed = Duration::ZERO;
+            while !task_context.is_interrupted() {
+                std::thread::sleep(AUTOSAVE_POLL_STEP);
+                elapsed += AUTOSAVE_POLL_STEP;
+                let interval = Duration::from_millis(autosave_interval_ms.load(Ordering::Relaxed));
+                if !interval.is_zero() && elapsed >= interval {
+                    elapsed = Duration::ZERO;
+                    Self::flush_settings(&settings_list);
+                }
+            }
+        });
+    }
+
+    fn regenerate_settings_description(&self) {
+        let mut settings_description = self.settings_description.lock().unwrap();
+        settings_description.tabs.clear();
+        let property_meta = self.property_meta.lock().unwrap();
+        let validators = self.validators.lock().unwrap();
+        let settings_list = self.settings_list.lock().unwrap();
+        for settings in settings_list.deref() {
+            settings_description.add_properties(settings, &property_meta, &validators);
+        }
+        settings_description.add_computed_properties(&self.computed.lock().unwrap(), &property_meta);
+    }
+
+    /// Makes the properties of `settings` emit `SettingsChangedEvent`, including the ones
+    /// created later. Creating a property by assigning it, e.g. by an import, emits it as well.
+    fn watch_changes(&self, settings: &Settings) {
+        let event_emitter = match &self.event_emitter {
+            Some(event_emitter) => event_emitter.clone(),
+            None => return,
+        };
+        let properties: Vec<PropertyWrapper> = settings.entry.properties.lock().unwrap().values().cloned().collect();
+        for property in properties {
+            Self::watch_property(&self.watched, &event_emitter, &property);
+        }
+        let watched = self.watched.clone();
+        settings.on_property_created(move |property, assigned| {
+            Self::watch_property(&watched, &event_emitter, property);
+            if assigned {
+                property.notify_changed();
+            }
+        });
+    }
+
+    fn watch_property(watched: &Mutex<HashMap<usize, Weak<dyn Any + Send + Sync>>>, event_emitter: &Service<EventEmitter>, property: &PropertyWrapper) {
+        let mut watched = watched.lock().unwrap();
+        watched.retain(|_, property| property.strong_count() > 0);
+        let (id, handle) = property.identity();
+        if watched.contains_key(&id) {
+            return;
+        }
+        watched.insert(id, handle);
+        let event_emitter = event_emitter.clone();
+        let _ = property.on_change_value(move |key, value| {
+            event_emitter.emit_event(&SettingsChangedEvent {
+                key: key.to_string(),
+                value: formats::value_to_json(value),
+            });
+        });
+    }
+
+}
+
+impl ServiceApi for SettingsManager {
+    fn start(&self) {
+        self.populate_declared();
+        self.bind_history_size();
+        self.regenerate_settings_description();
+        self.start_autosave();
+        self.start_file_watch();
+    }
+
+    fn stop(&self) {
+        self.flush();
+    }
+}
+
+impl ServiceInitializer for SettingsManager {
+    fn initialize(context: &Context) -> Arc<Self> {
+        let rpc = context.get_service::<Rpc>();
+        let task_manager = context.try_get_service::<TaskManager>();
+        let event_emitter = context.try_get_service::<EventEmitter>();
+        let cmd_manager = context.try_get_service::<CmdManager>();
+
+        let settings_manager = Arc::new(Self {
+            settings_list: Arc::new(Mutex::new(Vec::new())),
+            prefixed_settings: Mutex::new(Vec::new()),
+            default_settings: Mutex::new(None),
+            settings_description: Mutex::new(SettingsDescription::empty()),
+            property_meta: Mutex::new(HashMap::new()),
+            validators: Mutex::new(Vec::new()),
+            computed: Mutex::new(BTreeMap::new()),
+            declared: Mutex::new(BTreeMap::new()),
+            profiles: Mutex::new(None),
+            task_manager,
+            event_emitter,
+            cmd_manager: cmd_manager.clone(),
+            history_size: Mutex::new(None),
+            autosave_interval_ms: Arc::new(AtomicU64::new(DEFAULT_AUTOSAVE_INTERVAL.as_millis() as u64)),
+            file_watch_interval_ms: Arc::new(AtomicU64::new(0)),
+            watched: Arc::new(Mutex::new(HashMap::new())),
+        });
+
+        Self::register_rpc_handlers(&settings_manager, &rpc);
+        if let Some(cmd_manager) = &cmd_manager {
+            Self::register_commands(&settings_manager, cmd_manager);
+        }
+
+        // Lets the browser download the export as a file, the path part is ignored
+        let settings_manager_copy = Arc::downgrade(&settings_manager);
+        rpc.add_get_file_handler("amina_core.settings_manager.export", move |_path| {
+            let settings_manager = settings_manager_copy.upgrade()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Settings manager is gone"))?;
+            serde_json::to_vec_pretty(&settings_manager.export_all())
+                .map_err(io::Error::other)
+        });
+
+        return settings_manager;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd_manager::{with_cmd_caller, ArgsList, CmdCaller, CmdManager, PermissionLevel, HISTORY_SIZE_KEY};
+    use crate::events::EventEmitter;
+    use crate::rpc::{Rpc, RpcGate};
+    use crate::service::Context;
+    use crate::settings::{ImportMode, ProfileSwitchedEvent, SettingsChangedEvent, PropertyKind, PropertyUiMeta, Settings, SettingsDump, SettingsError, SettingsFormat, SettingsLoadError, SettingsManager, SettingsSnapshot, SettingsValue, META_VERSION_KEY, SettingsValidator, SECRET_MASK, DEFAULT_SECTION, DEFAULT_TAB};
+    use crate::tasks::TaskManager;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_init() {
+        let text =
+            "
+            main:
+                collection_dir: \"some_dir\"
+            bar:
+                - 1
+                - 2.0
+            ";
+        let service = Settings::init_from_string(text, PathBuf::new().as_path());
+
+        assert_eq!(service.get_string("main.collection_dir").get(), "some_dir".to_string());
+        assert_eq!(service.get_f64_list("bar").get(), vec![1.0, 2.0]);
+
+        // Nothing of the document is lost on save
+        let service = Settings::init_from_string(&service.save_to_string(), PathBuf::new().as_path());
+        assert_eq!(service.get_string("main.collection_dir").get(), "some_dir".to_string());
+        assert_eq!(service.get_f64_list("bar").get(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let service = Settings::init_from_string("net:\n  host: \"a\"\n  port: 80", PathBuf::new().as_path());
+        let host = service.get_string("net.host");
+        let port = service.get_i64("net.port");
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen_copy = seen.clone();
+        let port_copy = port.clone();
+        let _subscription = host.on_change(move |host| {
+            // Every value of the transaction is already set when the first callback runs
+            seen_copy.lock().unwrap().push(format!("{}:{}", host, port_copy.get()));
+        });
+
+        service.transaction(|tx| {
+            tx.set_string("net.host", "b");
+            tx.set_i64("net.port", 8080);
+            tx.set_string("net.host", "c");
+            tx.set_bool("net.secure", true);
+        }).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec!["c:8080".to_string()]);
+        assert!(service.get_bool("net.secure").get());
+
+        // Unchanged values don't notify
+        service.transaction(|tx| {
+            tx.set_string("net.host", "c");
+            tx.set_i64("net.port", 8081);
+        }).unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert_eq!(port.get(), 8081);
+
+        let err = service.transaction(|tx| {
+            tx.set_i64("net.port", 9090);
+            tx.set_i64("net.host", 1);
+        }).unwrap_err();
+        assert!(matches!(err, SettingsError::TypeMismatch { .. }));
+        assert_eq!(port.get(), 8081);
+
+        let service = Settings::init_from_string(&service.save_to_string(), PathBuf::new().as_path());
+        assert_eq!(service.get_string("net.host").get(), "c".to_string());
+        assert_eq!(service.get_i64("net.port").get(), 8081);
+    }
+
+    #[test]
+    fn test_number_lists() {
+        let text =
+            "
+            player:
+                bands: [60, 250, 1000]
+                gains: [0.5, -1.5]
+                paths: [/music, /podcasts]
+                empty: []
+                nested: [[1], [2]]
+            ";
+        let service = Settings::init_from_string(text, PathBuf::new().as_path());
+        assert_eq!(service.get_i64_list("player.bands").get(), vec![60, 250, 1000]);
+        assert_eq!(service.get_f64_list("player.gains").get(), vec![0.5, -1.5]);
+        assert_eq!(service.get_string_list("player.paths").get(), vec!["/music".to_string(), "/podcasts".to_string()]);
+        assert!(service.get_string_list("player.empty").get().is_empty());
+        assert!(!service.contains("player.nested"));
+        assert!(matches!(service.try_get_i64_list("player.gains"), Err(SettingsError::TypeMismatch { .. })));
+
+        // Whole numbers load as ints, reading them as floats converts the property for good
+        let floats = Settings::init_from_string(text, PathBuf::new().as_path());
+        let mut bands = floats.get_f64_list("player.bands");
+        assert_eq!(bands.get(), vec![60.0, 250.0, 1000.0]);
+        bands.set(vec![60.0, 250.0, 1000.0, 4000.5]);
+        assert_eq!(floats.get_f64_list("player.bands").get(), vec![60.0, 250.0, 1000.0, 4000.5]);
+        assert!(floats.save_to_string().contains("4000.5"));
+
+        service.get_i64_list("player.bands").set(vec![60, 1000]);
+        service.get_f64_list_or("player.balance", &[0.0, 1.0]);
+        let text = service.save_to_string();
+        let service = Settings::init_from_string(&text, PathBuf::new().as_path());
+        assert_eq!(service.get_i64_list("player.bands").get(), vec![60, 1000]);
+        assert_eq!(service.get_f64_list("player.gains").get(), vec![0.5, -1.5]);
+        assert_eq!(service.get_f64_list("player.balance").get(), vec![0.0, 1.0]);
+
+        for format in [SettingsFormat::Json, SettingsFormat::Toml] {
+            let text = crate::settings::formats::dump(format, vec![
+                ("bands".to_string(), SettingsValue::I64List(vec![60, 250])),
+                ("gains".to_string(), SettingsValue::F64List(vec![0.5, 2.0])),
+            ]);
+            let mut values = crate::settings::formats::parse(format, &text).unwrap();
+            values.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(values, vec![
+                ("bands".to_string(), SettingsValue::I64List(vec![60, 250])),
+                ("gains".to_string(), SettingsValue::F64List(vec![0.5, 2.0])),
+            ], "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_save() {
+        let service = Settings::create_empty(PathBuf::new().as_path());
+        service.get_string("main.collection_dir").set("some_dir".to_string());
+        let text = service.save_to_string();
+
+        println!("{}", &text);
+
+        let service = Settings::init_from_string(&text, PathBuf::new().as_path());
+        assert_eq!(service.get_string("main.collection_dir").get(), "some_dir".to_string());
+    }
+
+    #[test]
+    fn test_typed_round_trip() {
+        let text =
+            "
+            server:
+                port: 8090
+                enabled: true
+                volume: 0.5
+                gain: 2.0
+            ";
+        let service = Settings::init_from_string(text, PathBuf::new().as_path());
+        assert_eq!(service.get_i64("server.port").get(), 8090);
+        assert!(service.get_bool("server.enabled").get());
+        assert_eq!(service.get_f64("server.volume").get(), 0.5);
+        assert_eq!(service.get_f64("server.gain").get(), 2.0);
+        assert!(service.try_get_string("server.port").is_err());
+
+        service.get_i64("server.retries").set(-3);
+        let text = service.save_to_string();
+
+        let service = Settings::init_from_string(&text, PathBuf::new().as_path());
+        assert_eq!(service.get_i64("server.port").get(), 8090);
+        assert_eq!(service.get_i64("server.retries").get(), -3);
+        assert!(service.get_bool("server.enabled").get());
+        assert_eq!(service.get_f64("server.gain").get(), 2.0);
+    }
+
+    #[test]
+    fn test_string_list() {
+        let text =
+            "
+            main:
+                watched_dirs:
+                    - \"/music\"
+                    - \"/podcasts\"
+                excluded: []
+            ";
+        let service = Settings::init_from_string(text, PathBuf::new().as_path());
+        assert_eq!(service.get_string_list("main.watched_dirs").get(), vec!["/music".to_string(), "/podcasts".to_string()]);
+        assert!(service.get_string_list("main.excluded").get().is_empty());
+        assert!(service.try_get_string("main.watched_dirs").is_err());
+
+        service.get_string_list("main.tags").set(Vec::new());
+        let text = service.save_to_string();
+        let service = Settings::init_from_string(&text, PathBuf::new().as_path());
+        assert!(service.contains("main.excluded"));
+        assert!(service.contains("main.tags"));
+        assert_eq!(service.get_string_list("main.watched_dirs").get().len(), 2);
+
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        context.get_service::<SettingsManager>().register_default_settings(Arc::new(service.clone()));
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        rpc_gate.call_raw("amina_core.settings_manager.append_to_string_list", "{\"key\":\"main.tags\",\"data\":\"rock\"}");
+        let response = rpc_gate.call_raw("amina_core.settings_manager.get_string_list_value", "{\"key\":\"main.tags\"}");
+        assert_eq!(response, "{\"ok\":[\"rock\"]}");
+        rpc_gate.call_raw("amina_core.settings_manager.set_string_list_value", "{\"key\":\"main.tags\",\"data\":[]}");
+        assert!(service.get_string_list("main.tags").get().is_empty());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let vars = vec![
+            ("AMINA__MAIN__PORT".to_string(), "9000".to_string()),
+            ("AMINA__MAIN__COLLECTION_DIR".to_string(), "/data".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let overrides = Settings::overrides_from_vars("AMINA", vars);
+        assert_eq!(overrides, vec![
+            ("main.collection_dir".to_string(), "/data".to_string()),
+            ("main.port".to_string(), "9000".to_string()),
+        ]);
+
+        let service = Settings::init_from_string("main:\n  port: 8090\n  name: \"x\"", PathBuf::new().as_path());
+        let port = service.get_i64("main.port");
+        service.apply_overrides(&overrides);
+        service.apply_overrides(&[("main.name".to_string(), "y".to_string())]);
+        assert_eq!(port.get(), 9000);
+        assert_eq!(service.get_string("main.collection_dir").get(), "/data".to_string());
+        assert!(service.is_overridden("main.port"));
+        assert!(!service.is_changed());
+
+        // File values are kept on save, keys that exist only as overrides are not written
+        let text = service.save_to_string();
+        let saved = Settings::init_from_string(&text, PathBuf::new().as_path());
+        assert_eq!(saved.get_i64("main.port").get(), 8090);
+        assert_eq!(saved.get_string("main.name").get(), "x".to_string());
+        assert!(!saved.contains("main.collection_dir"));
+
+        let changed_keys = service.reload_from_string("main:\n  port: 8091\n  name: \"x\"").unwrap();
+        assert!(changed_keys.is_empty());
+        assert_eq!(port.get(), 9000);
+        let saved = Settings::init_from_string(&service.save_to_string(), PathBuf::new().as_path());
+        assert_eq!(saved.get_i64("main.port").get(), 8091);
+
+        // Writes through the manager are refused instead of being dropped on save
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        settings_manager.register_settings(Arc::new(service.clone()));
+        assert!(matches!(settings_manager.set_string_value("main.name".to_string(), "z".to_string()),
+            Err(SettingsError::Overridden { .. })));
+        assert_eq!(service.get_string("main.name").get(), "y".to_string());
+    }
+
+    #[test]
+    fn test_secrets() {
+        let keyfile = std::env::temp_dir().join(format!("amina_settings_test_{}.key", std::process::id()));
+        std::fs::write(&keyfile, "machine-local key material").unwrap();
+
+        let service = Settings::init_from_string("api:\n  token: \"abc123\"\n  host: \"x\"", PathBuf::new().as_path());
+        service.set_secret_keyfile(&keyfile);
+        assert!(!service.is_changed());
+        let token = service.get_secret("api.token");
+        assert_eq!(token.get(), "abc123".to_string());
+        // Plain text secret from the file is migrated on the next save
+        assert!(service.is_changed());
+
+        let text = service.save_to_string();
+        assert!(!text.contains("abc123"));
+        assert!(text.contains("enc:v1:"));
+
+        let service = Settings::init_from_string(&text, PathBuf::new().as_path());
+        service.set_secret_keyfile(&keyfile);
+        assert_eq!(service.get_secret("api.token").get(), "abc123".to_string());
+        assert!(service.is_secret("api.token"));
+        assert!(!service.is_changed());
+
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        settings_manager.register_default_settings(Arc::new(service.clone()));
+        assert_eq!(settings_manager.get_string_value("api.token".to_string(), None).unwrap(), SECRET_MASK.to_string());
+        assert_eq!(settings_manager.get_string_value("api.token".to_string(), Some(true)).unwrap(), SECRET_MASK.to_string());
+        let admin = Some(CmdCaller::new("web", PermissionLevel::Admin));
+        assert_eq!(with_cmd_caller(admin.clone(), || settings_manager.get_string_value("api.token".to_string(), Some(true))).unwrap(),
+            "abc123".to_string());
+        let rpc_gate = context.get_service::<RpcGate>();
+        let request = "{\"key\":\"api.token\",\"reveal\":true}";
+        let response = with_cmd_caller(Some(CmdCaller::new("web", PermissionLevel::User)),
+            || rpc_gate.call_raw("amina_core.settings_manager.get_string_value", request));
+        assert_eq!(response, format!("{{\"ok\":\"{}\"}}", SECRET_MASK));
+        let response = with_cmd_caller(admin, || rpc_gate.call_raw("amina_core.settings_manager.get_string_value", request));
+        assert_eq!(response, "{\"ok\":\"abc123\"}");
+        settings_manager.set_string_value("api.token".to_string(), "def456".to_string()).unwrap();
+        let token = service.get_secret("api.token");
+        assert_eq!(token.get(), "def456".to_string());
+        assert_eq!(token.to_string(), SECRET_MASK);
+        assert!(!format!("{:?}", token).contains("def456"));
+        assert!(format!("{:?}", service.get_string("api.token")).contains(SECRET_MASK));
+
+        let net = Settings::create_empty(PathBuf::new().as_path());
+        net.get_string_or("net.api.key", "default-key");
+        net.get_secret("net.api.key");
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        settings_manager.register_settings(Arc::new(net));
+        settings_manager.regenerate_settings_description();
+        let property = settings_manager.get_tab("net".to_string()).unwrap().sections[0].properties[0].clone();
+        assert!(property.secret);
+        assert_eq!(property.default_value, Some(SECRET_MASK.to_string()));
+
+        // Without the keyfile the value stays encrypted and is written back untouched
+        std::fs::remove_file(&keyfile).unwrap();
+        let service = Settings::init_from_string(&text, PathBuf::new().as_path());
+        service.set_secret_keyfile(&keyfile);
+        assert!(service.is_secret("api.token"));
+        assert!(service.save_to_string().contains("enc:v1:"));
+    }
+
+    #[test]
+    fn test_manager_rpc() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+
+        let settings = Settings::create_empty(PathBuf::new().as_path());
+        settings.get_string("main.collection_dir").set("some_dir".to_string());
+        context.get_service::<SettingsManager>().register_settings(Arc::new(settings.clone()));
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        let response = rpc_gate.call_raw("amina_core.settings_manager.get_string_value", "{\"key\":\"main.collection_dir\"}");
+        assert_eq!(response, "{\"ok\":\"some_dir\"}");
+
+        rpc_gate.call_raw("amina_core.settings_manager.set_string_value", "{\"key\":\"main.collection_dir\",\"data\":\"other_dir\"}");
+        assert_eq!(settings.get_string("main.collection_dir").get(), "other_dir".to_string());
+
+        // Without a default instance unknown keys are reported instead of created
+        let response = rpc_gate.call_raw("amina_core.settings_manager.get_string_value", "{\"key\":\"main.unknown\"}");
+        assert_eq!(response, "{\"err\":{\"UnknownKey\":{\"key\":\"main.unknown\"}}}");
+        assert!(!settings.contains("main.unknown"));
+    }
+
+    #[test]
+    fn test_manager_without_optional_services() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        settings_manager.set_file_watch_interval(std::time::Duration::from_millis(10));
+        let settings = Settings::init_from_string("main:\n  name: \"a\"", PathBuf::new().as_path());
+        settings_manager.register_default_settings(Arc::new(settings.clone()));
+        context.start().unwrap();
+
+        settings_manager.set_string_value("main.name".to_string(), "b".to_string()).unwrap();
+        assert_eq!(settings.get_string("main.name").get(), "b".to_string());
+        context.stop();
+    }
+
+    #[test]
+    fn test_history_size() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        context.init_service::<SettingsManager>();
+        let settings = Settings::init_from_string("cmd_manager:\n  history_size: 2", PathBuf::new().as_path());
+        context.get_service::<SettingsManager>().register_default_settings(Arc::new(settings.clone()));
+        context.start().unwrap();
+
+        let cmd_manager = context.get_service::<CmdManager>();
+        for _ in 0..3 {
+            cmd_manager.handle("help", &ArgsList::new(), &CmdCaller::local("test")).unwrap();
+        }
+        assert_eq!(cmd_manager.get_history().len(), 2);
+
+        settings.get_i64(HISTORY_SIZE_KEY).set(1);
+        assert_eq!(cmd_manager.get_history().len(), 1);
+        context.stop();
+    }
+
+    #[test]
+    fn test_remove_and_rename() {
+        let service = Settings::init_from_string("main:\n  collection_dir: \"some_dir\"\n  stale: 1", PathBuf::new().as_path());
+        let collection_dir = service.get_string("main.collection_dir");
+
+        assert!(service.remove("main.stale"));
+        assert!(!service.remove("main.stale"));
+        assert!(service.is_changed());
+
+        service.rename("main.collection_dir", "library.root_dir").unwrap();
+        service.get_string("library.root_dir").set("other_dir".to_string());
+        assert_eq!(collection_dir.get(), "other_dir".to_string());
+        assert!(matches!(service.rename("main.collection_dir", "library.x"), Err(SettingsError::UnknownKey { .. })));
+        service.get_string("main.name");
+        assert!(matches!(service.rename("main.name", "library.root_dir"), Err(SettingsError::KeyExists { .. })));
+
+        let saved = service.save_to_string();
+        assert!(!saved.contains("stale"));
+        assert!(!saved.contains("collection_dir"));
+        assert!(saved.contains("root_dir"));
+
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        let settings = Settings::init_from_string("main:\n  ui:\n    theme: \"dark\"\n  library:\n    dir: \"x\"", PathBuf::new().as_path());
+        settings_manager.register_settings(Arc::new(settings.clone()));
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        let response = rpc_gate.call_raw("amina_core.settings_manager.rename_property", "{\"old_key\":\"main.library.dir\",\"new_key\":\"library.paths.root_dir\"}");
+        assert_eq!(response, "{\"ok\":null}");
+        let response = rpc_gate.call_raw("amina_core.settings_manager.remove_property", "{\"key\":\"main.ui.theme\"}");
+        assert_eq!(response, "{\"ok\":true}");
+        assert_eq!(settings_manager.get_tabs(), vec!["library".to_string()]);
+        assert_eq!(settings_manager.get_tab("library".to_string()).unwrap().sections[0].properties[0].name, "library.paths.root_dir".to_string());
+        let response = rpc_gate.call_raw("amina_core.settings_manager.get_tab", "{\"tab_name\":\"main\"}");
+        assert_eq!(response, "{\"err\":{\"UnknownTab\":{\"name\":\"main\"}}}");
+        assert_eq!(settings.get_string("library.paths.root_dir").get(), "x".to_string());
+    }
+
+    #[test]
+    fn test_computed() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let settings = Settings::init_from_string("about:\n  app:\n    name: \"amina\"", PathBuf::new().as_path());
+        settings_manager.register_settings(Arc::new(settings.clone()));
+        settings_manager.register_computed("about.app.version", || "1.2.3".to_string());
+
+        let rpc_gate = context.get_service::<RpcGate>();
+        let response = rpc_gate.call_raw("amina_core.settings_manager.get_string_value", "{\"key\":\"about.app.version\"}");
+        assert_eq!(response, "{\"ok\":\"1.2.3\"}");
+        let response = rpc_gate.call_raw("amina_core.settings_manager.set_string_value", "{\"key\":\"about.app.version\",\"data\":\"2\"}");
+        assert_eq!(response, "{\"err\":{\"ReadOnly\":{\"key\":\"about.app.version\"}}}");
+
+        let tab = settings_manager.get_tab("about".to_string()).unwrap();
+        let properties = &tab.sections[0].properties;
+        assert_eq!(properties.len(), 2);
+        assert!(properties.iter().any(|prop| prop.name == "about.app.version" && prop.read_only));
+        assert!(properties.iter().any(|prop| prop.name == "about.app.name" && !prop.read_only));
+
+        assert!(!settings.contains("about.app.version"));
+        assert!(!settings.save_to_string().contains("version"));
+    }
+
+    #[test]
+    fn test_export_import() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let app_settings = Settings::init_from_string("main:\n  net:\n    port: 8090\n    token: \"abc123\"\n    stale: true", PathBuf::from("/etc/amina/app.yaml").as_path());
+        app_settings.get_secret("main.net.token");
+        let user_settings = Settings::init_from_string("user:\n  profile:\n    name: \"amina\"", PathBuf::new().as_path());
+        settings_manager.register_default_settings(Arc::new(app_settings.clone()));
+        settings_manager.register_prefixed_settings("user", Arc::new(user_settings.clone()));
+        settings_manager.add_validator("main.net.port", SettingsValidator::integer_range(1, 65535));
+
+        let dump = settings_manager.export_all();
+        assert_eq!(dump.instances["app"]["main"]["net"]["port"], serde_json::json!(8090));
+        assert_eq!(dump.instances["app"]["main"]["net"]["token"], serde_json::json!(SECRET_MASK));
+        assert_eq!(dump.instances["user"]["user"]["profile"]["name"], serde_json::json!("amina"));
+
+        let file = context.get_service::<RpcGate>().get_file("amina_core.settings_manager.export", "settings.json").unwrap();
+        assert_eq!(serde_json::from_slice::<SettingsDump>(&file).unwrap(), dump);
+
+        let mut dump = dump;
+        dump.instances.insert("app".to_string(), serde_json::json!({
+            "main": { "net": { "port": 70000, "token": SECRET_MASK, "theme": "dark" } },
+        }));
+        dump.instances.insert("plugin".to_string(), serde_json::json!({}));
+        dump.instances.get_mut("user").unwrap()["user"]["profile"]["name"] = serde_json::json!("other");
+
+        let report = settings_manager.import(dump, ImportMode::Replace);
+        assert_eq!(report.applied, vec!["main.net.theme".to_string(), "user.profile.name".to_string()]);
+        assert_eq!(report.removed, vec!["main.net.stale".to_string()]);
+        let failed_keys: Vec<&str> = report.failures.iter().map(|failure| failure.key.as_str()).collect();
+        assert_eq!(failed_keys, vec!["main.net.port", "plugin"]);
+
+        assert_eq!(app_settings.get_i64("main.net.port").get(), 8090);
+        assert_eq!(app_settings.get_string("main.net.token").get(), "abc123".to_string());
+        assert_eq!(app_settings.get_string("main.net.theme").get(), "dark".to_string());
+        assert!(!app_settings.contains("main.net.stale"));
+        assert_eq!(user_settings.get_string("user.profile.name").get(), "other".to_string());
+    }
+
+    #[test]
+    fn test_import_callback_reads_settings() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let settings = Arc::new(Settings::init_from_string("main:\n  port: 8090\n  host: \"localhost\"", PathBuf::from("/etc/amina/app.yaml").as_path()));
+        settings_manager.register_default_settings(settings.clone());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_copy = seen.clone();
+        let settings_copy = settings.clone();
+        let _subscription = settings.get_i64("main.port").on_change(move |port| {
+            seen_copy.lock().unwrap().push(format!("{}:{}", settings_copy.get_string("main.host").get(), port));
+        });
+
+        let mut dump = settings_manager.export_all();
+        dump.instances.get_mut("app").unwrap()["main"]["port"] = serde_json::json!(9000);
+        let report = settings_manager.import(dump, ImportMode::Merge);
+        assert!(report.failures.is_empty());
+        assert_eq!(*seen.lock().unwrap(), vec!["localhost:9000".to_string()]);
+    }
+
+    #[test]
+    fn test_manager_routing() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let app_settings = Arc::new(Settings::init_from_string("main:\n  collection_dir: \"some_dir\"", PathBuf::new().as_path()));
+        let plugin_settings = Arc::new(Settings::init_from_string("player:\n  volume: \"10\"", PathBuf::new().as_path()));
+        let user_settings = Arc::new(Settings::create_empty(PathBuf::new().as_path()));
+        settings_manager.register_default_settings(app_settings.clone());
+        settings_manager.register_settings(plugin_settings.clone());
+        settings_manager.register_prefixed_settings("user", user_settings.clone());
+
+        assert_eq!(settings_manager.get_string_value("player.volume".to_string(), None).unwrap(), "10".to_string());
+        settings_manager.set_string_value("player.volume".to_string(), "20".to_string()).unwrap();
+        assert_eq!(plugin_settings.get_string("player.volume").get(), "20".to_string());
+
+        settings_manager.set_string_value("user.name".to_string(), "amina".to_string()).unwrap();
+        assert_eq!(user_settings.get_string("user.name").get(), "amina".to_string());
+        assert!(!app_settings.contains("user.name"));
+
+        settings_manager.set_string_value("main.theme".to_string(), "dark".to_string()).unwrap();
+        assert!(app_settings.contains("main.theme"));
+        assert!(!plugin_settings.contains("main.theme"));
+    }
+
+    #[test]
+    fn test_changed_event() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_copy = changes.clone();
+        context.get_service::<EventEmitter>().on_event_fn(move |event: &SettingsChangedEvent| {
+            changes_copy.lock().unwrap().push((event.key.clone(), event.value.clone()));
+        });
+
+        let settings = Arc::new(Settings::init_from_string("server:\n  port: 80\n  host: \"a\"", PathBuf::new().as_path()));
+        let token = settings.get_secret("server.token");
+        settings_manager.register_settings(settings.clone());
+        // Created after registration by a getter, watched without going through the manager
+        let aliases = settings.get_string_list_or("server.aliases", &[]);
+
+        settings_manager.set_string_value("server.host".to_string(), "b".to_string()).unwrap();
+        settings.get_i64("server.port").set(81);
+        token.clone().set("secret".to_string());
+        aliases.clone().set(vec!["c".to_string()]);
+        // Keys created by an import are emitted with their value
+        let name = settings_manager.export_all().instances.keys().next().unwrap().clone();
+        let mut dump = SettingsDump::default();
+        dump.instances.insert(name, serde_json::json!({"server": {"timeout": 5}}));
+        settings_manager.import(dump, ImportMode::Merge);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let mut changes = changes.lock().unwrap().clone();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(changes, vec![
+            ("server.aliases".to_string(), serde_json::json!(["c"])),
+            ("server.host".to_string(), serde_json::json!("b")),
+            ("server.port".to_string(), serde_json::json!(81)),
+            ("server.timeout".to_string(), serde_json::json!(5)),
+            ("server.token".to_string(), serde_json::json!(SECRET_MASK)),
+        ]);
+    }
+
+    #[test]
+    fn test_save_reload_commands() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<CmdManager>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        let cmd_manager = context.get_service::<CmdManager>();
+        let admin = CmdCaller::new("test", PermissionLevel::Admin);
+
+        let path = std::env::temp_dir().join(format!("amina_settings_commands_{}.yaml", std::process::id()));
+        let settings = Arc::new(Settings::create_empty(path.as_path()));
+        settings.get_string("server.host").set("a".to_string());
+        settings.get_i64("server.port").set(80);
+        settings_manager.register_settings(settings.clone());
+
+        let result = cmd_manager.handle("settings_save", &ArgsList::new(), &admin).unwrap();
+        assert!(result.success);
+        assert_eq!(result.message, "Saved 2 properties to 1 files");
+        assert_eq!(result.payload.unwrap()["properties"], 2);
+
+        std::fs::write(&path, "server:\n  host: \"b\"\n  port: 80\n").unwrap();
+        let result = cmd_manager.handle("settings_reload", &ArgsList::new(), &admin).unwrap();
+        assert_eq!(result.message, "Reloaded settings, 1 properties changed");
+        assert_eq!(result.payload.unwrap(), serde_json::json!(["server.host"]));
+        assert_eq!(settings.get_string("server.host").get(), "b");
+
+        let user = CmdCaller::new("test", PermissionLevel::User);
+        assert!(cmd_manager.handle("settings_save", &ArgsList::new(), &user).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_profiles() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        let switched = Arc::new(Mutex::new(Vec::new()));
+        let switched_copy = switched.clone();
+        context.get_service::<EventEmitter>().on_event_fn(move |event: &ProfileSwitchedEvent| {
+            switched_copy.lock().unwrap().push(event.profile.clone());
+        });
+
+        let dir = std::env::temp_dir().join(format!("amina_settings_profiles_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base_path = dir.join("settings.yaml");
+        std::fs::write(&base_path, "library:\n  main:\n    path: \"/music\"\n  scan:\n    depth: 3\n").unwrap();
+        std::fs::write(dir.join("settings.home.yaml"), "library:\n  main:\n    path: \"/home/music\"\n").unwrap();
+        let base = Arc::new(Settings::load_from_file(&base_path).unwrap());
+        settings_manager.register_profile_base(base.clone());
+        assert_eq!(settings_manager.get_profiles(), vec!["home".to_string()]);
+        assert_eq!(settings_manager.get_active_profile(), None);
+
+        settings_manager.create_profile("work".to_string()).unwrap();
+        assert!(matches!(settings_manager.create_profile("work".to_string()), Err(SettingsError::ProfileExists { .. })));
+        assert!(matches!(settings_manager.create_profile("../x".to_string()), Err(SettingsError::InvalidProfileName { .. })));
+        settings_manager.add_profile_property("work".to_string(), "library.main.path".to_string()).unwrap();
+
+        settings_manager.switch_profile(Some("work".to_string())).unwrap();
+        settings_manager.set_string_value("library.main.path".to_string(), "/work/music".to_string()).unwrap();
+        settings_manager.set_string_value("library.main.name".to_string(), "shared".to_string()).unwrap();
+        assert_eq!(base.get_string("library.main.path").get(), "/music");
+        assert!(base.contains("library.main.name"));
+
+        settings_manager.switch_profile(Some("home".to_string())).unwrap();
+        assert_eq!(settings_manager.get_string_value("library.main.path".to_string(), None).unwrap(), "/home/music");
+        settings_manager.switch_profile(None).unwrap();
+        assert_eq!(settings_manager.get_string_value("library.main.path".to_string(), None).unwrap(), "/music");
+        settings_manager.switch_profile(Some("work".to_string())).unwrap();
+        assert_eq!(settings_manager.get_string_value("library.main.path".to_string(), None).unwrap(), "/work/music");
+        assert!(matches!(settings_manager.switch_profile(Some("gym".to_string())), Err(SettingsError::UnknownProfile { .. })));
+
+        settings_manager.flush();
+        assert!(dir.join("settings.work.yaml").exists());
+        settings_manager.delete_profile("work".to_string()).unwrap();
+        assert!(!dir.join("settings.work.yaml").exists());
+        assert_eq!(settings_manager.get_active_profile(), None);
+        assert_eq!(settings_manager.get_string_value("library.main.path".to_string(), None).unwrap(), "/music");
+
+        assert_eq!(*switched.lock().unwrap(), vec![Some("work".to_string()), Some("home".to_string()), None, Some("work".to_string()), None]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_save() {
+        let settings = Arc::new(Settings::init_from_string("counter:\n  a: 0\n  b: 0", PathBuf::new().as_path()));
+        let mut a = settings.get_i64("counter.a");
+        let mut b = settings.get_i64("counter.b");
+
+        let writer = std::thread::spawn(move || {
+            for i in 1..=20000 {
+                a.set(i);
+                b.set(i);
+            }
+        });
+        let saver_settings = settings.clone();
+        let saver = std::thread::spawn(move || {
+            let mut saves = 0;
+            loop {
+                let (text, _) = saver_settings.snapshot(true);
+                let saved = Settings::init_from_string(&text, PathBuf::new().as_path());
+                let a = saved.get_i64("counter.a").get();
+                let b = saved.get_i64("counter.b").get();
+                // `a` is always set first, so a consistent snapshot is at most one step ahead
+                assert!(a == b || a == b + 1, "inconsistent snapshot: a = {}, b = {}", a, b);
+                saves += 1;
+                if b == 20000 {
+                    return saves;
+                }
+            }
+        });
+        writer.join().unwrap();
+        assert!(saver.join().unwrap() > 0);
+        assert!(!settings.is_changed());
+    }
+
+    #[test]
+    fn test_declare() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+        settings_manager.set_autosave_interval(std::time::Duration::ZERO);
+
+        let settings = Arc::new(Settings::init_from_string("player:\n  output:\n    volume: 30", PathBuf::new().as_path()));
+        settings_manager.register_default_settings(settings.clone());
+        settings_manager.declare("player.output.volume", 50, PropertyUiMeta::new("Volume", PropertyKind::Number { min: Some(0.0), max: Some(100.0) }));
+        settings_manager.declare("player.output.device", "default", PropertyUiMeta::new("Output device", PropertyKind::Text)
+            .with_description("Name of the audio device\nLeave 'default' for the system one"));
+        settings_manager.declare("library.main.mode", "scan", PropertyUiMeta::new("Mode", PropertyKind::Enum {
+            options: vec!["scan".to_string(), "watch".to_string()],
+        }));
+        settings_manager.declare("library.main.paths", vec!["/music".to_string()], PropertyUiMeta::new("Paths", PropertyKind::Path));
+
+        let path = std::env::temp_dir().join(format!("amina_settings_template_{}.yaml", std::process::id()));
+        settings_manager.write_template(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(text, "# Settings template, every value is the default\n\
+            library:\n  main:\n    # Mode\n    # One of: scan, watch\n    mode: \"scan\"\n    # Paths\n    paths: [\"/music\"]\n\
+            player:\n  output:\n    # Output device\n    # Name of the audio device\n    # Leave 'default' for the system one\n    device: \"default\"\n\
+            \x20   # Volume\n    volume: 50\n");
+        let template = Settings::init_from_string(&text, PathBuf::new().as_path());
+        assert_eq!(template.get_string_list("library.main.paths").get(), vec!["/music".to_string()]);
+
+        settings_manager.populate_declared();
+        assert_eq!(settings.get_i64("player.output.volume").get(), 30);
+        assert_eq!(settings.get_i64("player.output.volume").get_default(), Some(50));
+        assert_eq!(settings.get_string("player.output.device").get(), "default");
+        assert_eq!(settings.take_dirty_keys(), vec!["library.main.mode", "library.main.paths", "player.output.device"]);
+    }
+
+    #[test]
+    fn test_property_meta() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let settings = Settings::create_empty(PathBuf::new().as_path());
+        settings.get_string_or("main.ui.theme", "dark");
+        settings.get_string("main.ui.font");
+        settings_manager.register_default_settings(Arc::new(settings.clone()));
+
+        let options = vec!["dark".to_string(), "light".to_string()];
+        settings_manager.describe_property("main.ui.theme", PropertyUiMeta::new("Theme", PropertyKind::Enum { options })
+            .with_description("Color scheme of the UI"));
+
+        let tab = serde_json::to_value(settings_manager.get_tab("main".to_string()).unwrap()).unwrap();
+        let properties = &tab["sections"][0]["properties"];
+        let theme = properties.as_array().unwrap().iter().find(|p| p["name"] == "main.ui.theme").unwrap();
+        assert_eq!(theme["meta"]["label"], "Theme");
+        assert_eq!(theme["meta"]["kind"]["Enum"]["options"][1], "light");
+        let font = properties.as_array().unwrap().iter().find(|p| p["name"] == "main.ui.font").unwrap();
+        assert!(font["meta"].is_null());
+
+        settings_manager.set_string_value("main.ui.theme".to_string(), "light".to_string()).unwrap();
+        let result = settings_manager.set_string_value("main.ui.theme".to_string(), "blue".to_string());
+        assert!(matches!(result, Err(SettingsError::InvalidValue { .. })));
+        assert_eq!(settings.get_string("main.ui.theme").get(), "light".to_string());
+    }
+
+    #[test]
+    fn test_short_keys() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let settings = Settings::create_empty(PathBuf::new().as_path());
+        settings.get_string_or("main.collection_dir", "/music");
+        settings.get_bool_or("debug", false);
+        settings_manager.register_default_settings(Arc::new(settings.clone()));
+
+        settings_manager.describe_property("main.collection_dir", PropertyUiMeta::new("Collection", PropertyKind::Text));
+        settings_manager.add_validator("main.collection_dir", SettingsValidator::non_empty());
+        settings_manager.register_computed("about.version", || "1.2.3".to_string());
+
+        let main = settings_manager.get_tab("main".to_string()).unwrap();
+        assert_eq!(main.sections[0].name, DEFAULT_SECTION);
+        let collection_dir = &main.sections[0].properties[0];
+        assert_eq!(collection_dir.name, "main.collection_dir");
+        assert_eq!(collection_dir.meta.as_ref().unwrap().label, "Collection");
+        assert_eq!(collection_dir.validators.len(), 1);
+
+        let about = settings_manager.get_tab("about".to_string()).unwrap();
+        assert!(about.sections[0].properties[0].read_only);
+        let general = settings_manager.get_tab(DEFAULT_TAB.to_string()).unwrap();
+        assert_eq!(general.sections[0].properties[0].name, "debug");
+
+        assert!(settings_manager.set_string_value("main.collection_dir".to_string(), "".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_validators() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let settings = Settings::init_from_string("server:\n  net:\n    port: \"8090\"\n    host: \"localhost\"", PathBuf::new().as_path());
+        settings_manager.register_default_settings(Arc::new(settings.clone()));
+        settings_manager.add_validator("server.net.port", SettingsValidator::integer_range(1, 65535));
+        settings_manager.add_validator("server", SettingsValidator::non_empty());
+        settings_manager.add_validator("server.net.host", |value: &str| {
+            if value.contains(' ') { Err("Host must not contain spaces".to_string()) } else { Ok(()) }
+        });
+
+        settings_manager.set_string_value("server.net.port".to_string(), "8091".to_string()).unwrap();
+        match settings_manager.set_string_value("server.net.port".to_string(), "abc".to_string()) {
+            Err(SettingsError::InvalidValue { key, message }) => {
+                assert_eq!(key, "server.net.port");
+                assert_eq!(message, "'abc' is not an integer");
+            },
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(settings_manager.set_string_value("server.net.host".to_string(), "".to_string()).is_err());
+        assert!(settings_manager.set_string_value("server.net.host".to_string(), "my host".to_string()).is_err());
+        assert_eq!(settings.get_string("server.net.port").get(), "8091".to_string());
+        assert_eq!(settings.get_string("server.net.host").get(), "localhost".to_string());
+
+        let tab = serde_json::to_value(settings_manager.get_tab("server".to_string()).unwrap()).unwrap();
+        let properties = tab["sections"][0]["properties"].as_array().unwrap();
+        let port = properties.iter().find(|p| p["name"] == "server.net.port").unwrap();
+        assert_eq!(port["validators"][0]["IntegerRange"]["max"], 65535);
+        assert_eq!(port["validators"][1], "NonEmpty");
+    }
+
+    #[test]
+    fn test_defaults() {
+        let service = Settings::create_empty(PathBuf::new().as_path());
+        let mut collection_dir = service.get_string_or("main.collection_dir", "some_dir");
+        assert_eq!(collection_dir.get(), "some_dir".to_string());
+        assert_eq!(service.get_i64_or("main.port", 8090).get(), 8090);
+        assert!(service.is_changed());
+
+        collection_dir.set("other_dir".to_string());
+        assert!(collection_dir.reset_to_default());
+        assert_eq!(service.get_default_as_string("main.port"), Some("8090".to_string()));
+
+        let text = service.save_to_string();
+        let service = Settings::init_from_string(&text, PathBuf::new().as_path());
+        assert_eq!(service.get_string("main.collection_dir").get(), "some_dir".to_string());
+        assert_eq!(service.get_i64_or("main.port", 1).get(), 8090);
+        assert!(!service.is_changed());
+    }
+
+    #[test]
+    fn test_change_callbacks() {
+        let service = Settings::create_empty(PathBuf::new().as_path());
+        let mut prop = service.get_string("main.collection_dir");
+
+        let changes = Arc::new(Mutex::new(Vec::<String>::new()));
+        let changes_copy = changes.clone();
+        let first = prop.on_change(move |value| changes_copy.lock().unwrap().push(format!("first:{}", value)));
+        let changes_copy = changes.clone();
+        let prop_copy = prop.clone();
+        let _second = prop.on_change(move |value| {
+            // Reading the property from a callback must not deadlock
+            assert_eq!(&prop_copy.get(), value);
+            changes_copy.lock().unwrap().push(format!("second:{}", value));
+        });
+
+        prop.set("a".to_string());
+        first.unsubscribe();
+        service.get_string("main.collection_dir").set("b".to_string());
+
+        assert_eq!(*changes.lock().unwrap(), vec!["first:a", "second:a", "second:b"]);
+    }
+
+    #[test]
+    fn test_update() {
+        let service = Settings::create_empty(PathBuf::new().as_path());
+        let prop = service.get_i64("main.counter");
+
+        let threads: Vec<_> = (0..100).map(|_| {
+            let prop = prop.clone();
+            std::thread::spawn(move || prop.update(|value| *value += 1))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(service.get_i64("main.counter").get(), 100);
+        assert!(service.is_changed());
+
+        assert!(!prop.set_if(|value| *value == 0, 5));
+        assert!(prop.set_if(|value| *value == 100, 5));
+        assert_eq!(prop.get(), 5);
+
+        // The closure may use other properties of the same settings
+        let mut total = service.get_i64("main.total");
+        prop.update(|value| {
+            *value += 1;
+            total.set(*value);
+        });
+        assert_eq!(service.get_i64("main.total").get(), 6);
+        assert_eq!(service.take_dirty_keys(), vec!["main.counter", "main.total"]);
+    }
+
+    #[test]
+    fn test_scope() {
+        let service = Settings::init_from_string("player:\n  output:\n    device: hw0\n", PathBuf::new().as_path());
+        let player = service.scope("player");
+        let output = player.scope("output");
+        assert_eq!(output.get_prefix(), "player.output");
+        assert_eq!(output.get_string("device").get(), "hw0");
+
+        output.get_string("device").set("hw1".to_string());
+        assert_eq!(service.get_string("player.output.device").get(), "hw1");
+        assert!(service.is_changed());
+
+        player.get_i64_or("output.volume", 50);
+        assert!(service.contains("player.output.volume"));
+        let mut keys = output.get_properties();
+        keys.sort();
+        assert_eq!(keys, vec!["device", "volume"]);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let path = PathBuf::from("config.yaml");
+        let err = Settings::try_init_from_string("main:\n  port: 1\n  name: \"x\n", &path).err().unwrap();
+        assert_eq!(err.to_string(), "Syntax error in settings file 'config.yaml' at line 3, column 9: \
+            while scanning a quoted scalar, found unexpected end of stream");
+
+        let err = Settings::try_init_from_string("- a\n- b\n", &path).err().unwrap();
+        assert_eq!(err.to_string(), "Root element of settings file 'config.yaml' must be a mapping, but it is a list");
+
+        let err = Settings::try_init_from_string("main:\n  port: 1\n  port: 2\n", &path).err().unwrap();
+        assert_eq!(err.to_string(), "Duplicate key 'main.port' in settings file 'config.yaml' at line 3, column 3");
+        let err = Settings::try_init_from_string("main.port: 1\nmain:\n  port: 2\n", &path).err().unwrap();
+        assert!(matches!(err, SettingsLoadError::DuplicateKey { line: 3, .. }), "{:?}", err);
+
+        let err = Settings::try_from_string("{\"a\": }", &path, SettingsFormat::Json).err().unwrap();
+        assert!(matches!(err, SettingsLoadError::Syntax { line: 1, column: 7, .. }), "{:?}", err);
+        let err = Settings::try_from_string("a = 1\nb = \n", &path, SettingsFormat::Toml).err().unwrap();
+        assert!(matches!(err, SettingsLoadError::Syntax { line: 2, .. }), "{:?}", err);
+
+        let dir = std::env::temp_dir().join(format!("amina_settings_broken_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("settings.yaml");
+        std::fs::write(&file, "main: [").unwrap();
+        let settings = Settings::load_from_file_lenient(&file);
+        assert!(settings.get_properties().is_empty());
+        assert_eq!(std::fs::read_to_string(dir.join("settings.yaml.broken")).unwrap(), "main: [");
+        assert!(Settings::load_from_file(&file).is_err());
+        assert!(Settings::load_from_file_lenient(&dir.join("missing.yaml")).get_properties().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_if_changed() {
+        let path = std::env::temp_dir().join(format!("amina_settings_test_{}.yaml", std::process::id()));
+        let service = Settings::create_empty(path.as_path());
+        assert!(!service.save_if_changed().unwrap());
+
+        service.get_string("main.collection_dir").set("some_dir".to_string());
+        assert!(service.save_if_changed().unwrap());
+        assert!(!service.save_if_changed().unwrap());
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let service = Settings::init_from_string(&text, path.as_path());
+        assert_eq!(service.get_string("main.collection_dir").get(), "some_dir".to_string());
+    }
+
+    #[test]
+    fn test_dirty_keys() {
+        let path = std::env::temp_dir().join(format!("amina_settings_dirty_{}.yaml", std::process::id()));
+        let service = Settings::init_from_string("main:\n  port: 80\n  name: \"x\"\n  old: 1", path.as_path());
+        assert!(service.take_dirty_keys().is_empty());
+
+        let mut port = service.get_i64("main.port");
+        port.update(|port| *port += 1);
+        service.get_string("main.name").set("y".to_string());
+        service.get_bool_or("main.enabled", true);
+        assert_eq!(service.dirty_keys(), vec!["main.enabled", "main.name", "main.port"]);
+        assert!(service.is_changed());
+        assert_eq!(service.take_dirty_keys(), vec!["main.enabled", "main.name", "main.port"]);
+        assert!(!service.is_changed());
+
+        // Nothing changed, so the file isn't written at all
+        service.save_to_file();
+        assert!(!path.exists());
+
+        service.rename("main.port", "main.http_port").unwrap();
+        assert_eq!(port.get_key(), "main.http_port");
+        port.set(82);
+        service.remove("main.old");
+        assert_eq!(service.take_dirty_keys(), vec!["main.http_port", "main.old", "main.port"]);
+
+        port.set(83);
+        service.save_to_file();
+        assert!(!service.is_changed());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let service = Settings::init_from_string("main:\n  collection_dir: \"some_dir\"\n  port: 80", PathBuf::new().as_path());
+        let collection_dir = service.get_string("main.collection_dir");
+
+        let changed_keys = service.reload_from_string("main:\n  collection_dir: \"other_dir\"\n  port: 80\n  name: \"x\"").unwrap();
+        assert_eq!(changed_keys, vec!["main.collection_dir".to_string(), "main.name".to_string()]);
+        assert_eq!(collection_dir.get(), "other_dir".to_string());
+        assert!(!service.is_changed());
+
+        // Unsaved in-memory changes win over the file
+        service.get_i64("main.port").set(81);
+        let changed_keys = service.reload_from_string("main:\n  collection_dir: \"third_dir\"\n  port: 82").unwrap();
+        assert!(changed_keys.is_empty());
+        assert_eq!(service.get_i64("main.port").get(), 81);
+        assert_eq!(collection_dir.get(), "other_dir".to_string());
+    }
+
+    #[test]
+    fn test_formats() {
+        for extension in ["yaml", "json", "toml"] {
+            let path = std::env::temp_dir().join(format!("amina_settings_format_{}.{}", std::process::id(), extension));
+            let service = Settings::create_empty(path.as_path());
+            service.get_string("main.collection_dir").set("some_dir".to_string());
+            service.get_i64("main.net.port").set(8090);
+            service.get_bool("main.net.enabled").set(true);
+            service.get_string_list("main.paths").set(vec!["a".to_string(), "b".to_string()]);
+            service.save_to_file();
+
+            let loaded = Settings::load_from_file(path.as_path()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.get_format(), SettingsFormat::from_path(path.as_path()));
+            assert_eq!(loaded.get_string("main.collection_dir").get(), "some_dir".to_string());
+            assert_eq!(loaded.try_get_i64("main.net.port").unwrap().get(), 8090);
+            assert!(loaded.try_get_bool("main.net.enabled").unwrap().get());
+            assert_eq!(loaded.get_string_list("main.paths").get(), vec!["a".to_string(), "b".to_string()]);
+        }
+
+        let settings = Settings::from_string("{\"main\": {\"port\": 80}}", PathBuf::new().as_path(), SettingsFormat::Json);
+        assert_eq!(settings.get_i64("main.port").get(), 80);
+        let settings = Settings::from_string("[main]\nport = 80", PathBuf::new().as_path(), SettingsFormat::Toml);
+        assert_eq!(settings.get_i64("main.port").get(), 80);
+    }
+
+    #[test]
+    fn test_mixed_formats() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        context.init_service::<EventEmitter>();
+        context.init_service::<Rpc>();
+        context.init_service::<SettingsManager>();
+        let settings_manager = context.get_service::<SettingsManager>();
+
+        let yaml_settings = Arc::new(Settings::init_from_string("main:\n  collection_dir: \"some_dir\"", PathBuf::new().as_path()));
+        let json_settings = Arc::new(Settings::from_string("{\"player\": {\"volume\": \"10\"}}", PathBuf::new().as_path(), SettingsFormat::Json));
+        let toml_settings = Arc::new(Settings::from_string("[server]\nhost = \"localhost\"", PathBuf::new().as_path(), SettingsFormat::Toml));
+        settings_manager.register_default_settings(yaml_settings);
+        settings_manager.register_settings(json_settings.clone());
+        settings_manager.register_settings(toml_settings.clone());
+
+        assert_eq!(settings_manager.get_string_value("main.collection_dir".to_string(), None).unwrap(), "some_dir".to_string());
+        assert_eq!(settings_manager.get_string_value("player.volume".to_string(), None).unwrap(), "10".to_string());
+        settings_manager.set_string_value("server.host".to_string(), "example.org".to_string()).unwrap();
+        assert!(json_settings.save_to_string().contains("\"volume\": \"10\""));
+        assert!(toml_settings.save_to_string().contains("host = \"example.org\""));
+    }
+
+    #[test]
+    fn test_migrations() {
+        fn register_migrations(settings: &Settings) {
+            settings.register_migration(1, 2, |snapshot: &mut SettingsSnapshot| {
+                snapshot.rename("main.collection_dir", "library.root_dir");
+            });
+            settings.register_migration(2, 3, |snapshot: &mut SettingsSnapshot| {
+                let scan = snapshot.remove("main.scan_on_start") == Some(SettingsValue::String("yes".to_string()));
+                snapshot.set("library.scan_on_start", scan);
+            });
+        }
+
+        let path = std::env::temp_dir().join(format!("amina_settings_migrations_{}.yaml", std::process::id()));
+        let backup_path = path.with_file_name(format!("amina_settings_migrations_{}.yaml.bak", std::process::id()));
+        let original = "main:\n  collection_dir: \"some_dir\"\n  scan_on_start: \"yes\"\n";
+        std::fs::write(&path, original).unwrap();
+
+        let service = Settings::create_empty(path.as_path());
+        register_migrations(&service);
+        service.reload_from_file().unwrap();
+        assert_eq!(service.get_string("library.root_dir").get(), "some_dir".to_string());
+        assert!(service.get_bool("library.scan_on_start").get());
+        assert!(!service.contains("main.collection_dir"));
+        assert_eq!(service.get_i64(META_VERSION_KEY).get(), 3);
+        assert_eq!(std::fs::read_to_string(&backup_path).unwrap(), original.to_string());
+
+        // The upgraded file is already at the latest version
+        let reloaded = Settings::create_empty(path.as_path());
+        register_migrations(&reloaded);
+        reloaded.reload_from_file().unwrap();
+        assert_eq!(reloaded.get_string("library.root_dir").get(), "some_dir".to_string());
+
+        std::fs::write(&path, "meta:\n  version: 4\n").unwrap();
+        let newer = Settings::create_empty(path.as_path());
+        register_migrations(&newer);
+        assert_eq!(newer.reload_from_file().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup_path).unwrap();
+    }
+
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use amina_core_derive::Event;
+
+use crate::events::Event;
+use crate::settings::{Property, Settings, SettingsError};
+
+/// Property of the base settings holding the active profile, empty when no profile is active.
+pub const ACTIVE_PROFILE_KEY: &str = "settings.profile.active";
+
+/// Emitted when the active profile changes, `profile` is `None` when only the base settings are used.
+#[derive(Clone, Debug, Serialize, Deserialize, Event)]
+#[key = "amina_core.settings.profile_switched"]
+pub struct ProfileSwitchedEvent {
+    pub profile: Option<String>,
+}
+
+/// Base settings and the overlays of the named profiles, each stored in its own file
+/// next to the base file: `settings.yaml` has profiles in `settings.<profile>.yaml`.
+pub(crate) struct Profiles {
+    pub(crate) base: Arc<Settings>,
+    pub(crate) active: Property<String>,
+    pub(crate) overlays: BTreeMap<String, Arc<Settings>>,
+}
+
+impl Profiles {
+
+    /// Loads the overlay files found next to `base`, broken ones start empty.
+    pub(crate) fn load(base: Arc<Settings>) -> Self {
+        let active = base.get_string_or(ACTIVE_PROFILE_KEY, "");
+        let mut overlays = BTreeMap::new();
+        let (stem, extension) = file_name_parts(base.get_path());
+        let dir = base.get_path().parent().unwrap_or_else(|| Path::new(""));
+        let entries = std::fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir });
+        for entry in entries.into_iter().flatten().flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let name = file_name.strip_prefix(&format!("{}.", stem))
+                .and_then(|rest| match &extension {
+                    Some(extension) => rest.strip_suffix(&format!(".{}", extension)),
+                    None => Some(rest),
+                });
+            if let Some(name) = name.filter(|name| check_profile_name(name).is_ok()) {
+                let overlay = Settings::load_from_file_lenient(&entry.path());
+                overlays.insert(name.to_string(), Arc::new(overlay));
+            }
+        }
+        Self {
+            base,
+            active,
+            overlays,
+        }
+    }
+
+    pub(crate) fn active_name(&self) -> Option<String> {
+        Some(self.active.get()).filter(|name| !name.is_empty())
+    }
+
+    pub(crate) fn active_overlay(&self) -> Option<Arc<Settings>> {
+        self.active_name().and_then(|name| self.overlays.get(&name).cloned())
+    }
+
+    pub(crate) fn overlay_path(&self, name: &str) -> PathBuf {
+        let (stem, extension) = file_name_parts(self.base.get_path());
+        let file_name = match extension {
+            Some(extension) => format!("{}.{}.{}", stem, name, extension),
+            None => format!("{}.{}", stem, name),
+        };
+        self.base.get_path().with_file_name(file_name)
+    }
+
+}
+
+fn file_name_parts(path: &Path) -> (String, Option<String>) {
+    let stem = path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "settings".to_string());
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_string());
+    (stem, extension)
+}
+
+/// Profile names become part of a file name, so only letters, digits, `-` and `_` are allowed.
+pub(crate) fn check_profile_name(name: &str) -> Result<(), SettingsError> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(SettingsError::InvalidProfileName { name: name.to_string() })
+    }
+}
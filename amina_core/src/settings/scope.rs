@@ -0,0 +1,123 @@
+use crate::settings::{Property, Settings, SettingsError};
+
+/// View of `Settings` that prepends a dotted prefix to every key.
+///
+/// Properties are looked up in the underlying settings with the full key, so scoped and
+/// unscoped access share the same `Property` and the same dirty flag.
+#[derive(Clone)]
+pub struct SettingsScope {
+    settings: Settings,
+    prefix: String,
+}
+
+impl SettingsScope {
+
+    pub(crate) fn new(settings: Settings, prefix: &str) -> Self {
+        Self {
+            settings,
+            prefix: prefix.trim_matches('.').to_string(),
+        }
+    }
+
+    pub fn get_prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn get_settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Narrows the scope further, `scope("player").scope("output")` is the same as `scope("player.output")`.
+    pub fn scope(&self, prefix: &str) -> SettingsScope {
+        SettingsScope::new(self.settings.clone(), &self.full_key(prefix))
+    }
+
+    /// Fully-qualified key for `key` relative to this scope.
+    pub fn full_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.prefix, key)
+        }
+    }
+
+    pub fn try_get_string(&self, key: &str) -> Result<Property<String>, SettingsError> {
+        self.settings.try_get_string(&self.full_key(key))
+    }
+
+    pub fn get_string(&self, key: &str) -> Property<String> {
+        self.settings.get_string(&self.full_key(key))
+    }
+
+    pub fn get_string_or(&self, key: &str, default_value: &str) -> Property<String> {
+        self.settings.get_string_or(&self.full_key(key), default_value)
+    }
+
+    pub fn try_get_i64(&self, key: &str) -> Result<Property<i64>, SettingsError> {
+        self.settings.try_get_i64(&self.full_key(key))
+    }
+
+    pub fn get_i64(&self, key: &str) -> Property<i64> {
+        self.settings.get_i64(&self.full_key(key))
+    }
+
+    pub fn get_i64_or(&self, key: &str, default_value: i64) -> Property<i64> {
+        self.settings.get_i64_or(&self.full_key(key), default_value)
+    }
+
+    pub fn try_get_bool(&self, key: &str) -> Result<Property<bool>, SettingsError> {
+        self.settings.try_get_bool(&self.full_key(key))
+    }
+
+    pub fn get_bool(&self, key: &str) -> Property<bool> {
+        self.settings.get_bool(&self.full_key(key))
+    }
+
+    pub fn get_bool_or(&self, key: &str, default_value: bool) -> Property<bool> {
+        self.settings.get_bool_or(&self.full_key(key), default_value)
+    }
+
+    pub fn try_get_f64(&self, key: &str) -> Result<Property<f64>, SettingsError> {
+        self.settings.try_get_f64(&self.full_key(key))
+    }
+
+    pub fn get_f64(&self, key: &str) -> Property<f64> {
+        self.settings.get_f64(&self.full_key(key))
+    }
+
+    pub fn get_f64_or(&self, key: &str, default_value: f64) -> Property<f64> {
+        self.settings.get_f64_or(&self.full_key(key), default_value)
+    }
+
+    pub fn try_get_string_list(&self, key: &str) -> Result<Property<Vec<String>>, SettingsError> {
+        self.settings.try_get_string_list(&self.full_key(key))
+    }
+
+    pub fn get_string_list(&self, key: &str) -> Property<Vec<String>> {
+        self.settings.get_string_list(&self.full_key(key))
+    }
+
+    pub fn get_string_list_or(&self, key: &str, default_value: &[&str]) -> Property<Vec<String>> {
+        self.settings.get_string_list_or(&self.full_key(key), default_value)
+    }
+
+    pub fn get_secret(&self, key: &str) -> Property<String> {
+        self.settings.get_secret(&self.full_key(key))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.settings.contains(&self.full_key(key))
+    }
+
+    /// Keys of the properties under this scope, relative to it.
+    pub fn get_properties(&self) -> Vec<String> {
+        if self.prefix.is_empty() {
+            return self.settings.get_properties();
+        }
+        let prefix = format!("{}.", self.prefix);
+        self.settings.get_properties().into_iter()
+            .filter_map(|key| key.strip_prefix(&prefix).map(|key| key.to_string()))
+            .collect()
+    }
+
+}
@@ -0,0 +1,56 @@
+use std::io;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng};
+use sha2::{Digest, Sha256};
+
+/// Marks an encrypted value in the settings file, followed by base64 of nonce and ciphertext.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+pub(crate) fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// Encrypts secret settings values with a key derived from the contents of a keyfile.
+pub(crate) struct SecretCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretCipher {
+
+    pub(crate) fn from_keyfile(path: &Path) -> io::Result<Self> {
+        let key_material = std::fs::read(path)?;
+        if key_material.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Keyfile {:?} is empty", path)));
+        }
+        let key = Sha256::digest(&key_material);
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(&key),
+        })
+    }
+
+    pub(crate) fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext.as_bytes())
+            .expect("Encryption of a settings value failed");
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(data))
+    }
+
+    /// Returns `None` if the value is malformed or was encrypted with another key.
+    pub(crate) fn decrypt(&self, value: &str) -> Option<String> {
+        let data = BASE64.decode(value.strip_prefix(ENCRYPTED_PREFIX)?).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+}
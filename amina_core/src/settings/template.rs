@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+use crate::settings::{PropertyKind, PropertyUiMeta, SettingsValue};
+use crate::settings::formats::format_f64;
+
+/// Key declared by a service with `SettingsManager::declare`.
+pub(crate) struct DeclaredProperty {
+    pub(crate) default_value: SettingsValue,
+    pub(crate) meta: PropertyUiMeta,
+}
+
+/// YAML with every declared key at its default, preceded by its label and description as comments.
+pub(crate) fn render(declared: &BTreeMap<String, DeclaredProperty>) -> String {
+    let mut entries: Vec<(Vec<&str>, &DeclaredProperty)> = declared.iter()
+        .map(|(key, property)| (key.split('.').collect(), property))
+        .collect();
+    // Sorting by parts keeps every mapping in one contiguous block
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut text = String::from("# Settings template, every value is the default\n");
+    let mut previous: Vec<&str> = Vec::new();
+    for (parts, property) in entries {
+        let (name, parents) = parts.split_last().unwrap();
+        let common = previous.iter().zip(parents.iter()).take_while(|(a, b)| a == b).count();
+        for (depth, parent) in parents.iter().enumerate().skip(common) {
+            text += &format!("{}{}:\n", indent(depth), parent);
+        }
+        let indent = indent(parents.len());
+        let meta = &property.meta;
+        text += &format!("{}# {}\n", indent, meta.label);
+        if let Some(description) = &meta.description {
+            for line in description.lines() {
+                text += &format!("{}# {}\n", indent, line);
+            }
+        }
+        if let PropertyKind::Enum { options } = &meta.kind {
+            text += &format!("{}# One of: {}\n", indent, options.join(", "));
+        }
+        text += &format!("{}{}: {}\n", indent, name, render_value(&property.default_value));
+        previous = parents.to_vec();
+    }
+    text
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+/// JSON strings and arrays are valid YAML flow scalars and sequences.
+fn render_value(value: &SettingsValue) -> String {
+    match value {
+        SettingsValue::String(value) => serde_json::to_string(value).unwrap(),
+        SettingsValue::I64(value) => value.to_string(),
+        SettingsValue::Bool(value) => value.to_string(),
+        SettingsValue::F64(value) => format_f64(*value),
+        SettingsValue::StringList(value) => serde_json::to_string(value).unwrap(),
+        SettingsValue::I64List(value) => serde_json::to_string(value).unwrap(),
+        SettingsValue::F64List(value) => {
+            let items: Vec<String> = value.iter().map(|value| format_f64(*value)).collect();
+            format!("[{}]", items.join(", "))
+        },
+    }
+}
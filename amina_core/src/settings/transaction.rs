@@ -0,0 +1,40 @@
+use crate::settings::SettingsValue;
+
+/// Changes collected by `Settings::transaction`, applied together once the closure returns.
+#[derive(Default)]
+pub struct SettingsTransaction {
+    pub(crate) changes: Vec<(String, SettingsValue)>,
+}
+
+impl SettingsTransaction {
+
+    /// Setting a key again replaces the earlier value.
+    pub fn set_value<V: Into<SettingsValue>>(&mut self, key: &str, value: V) {
+        let value = value.into();
+        match self.changes.iter_mut().find(|(changed_key, _)| changed_key == key) {
+            Some(change) => change.1 = value,
+            None => self.changes.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn set_string(&mut self, key: &str, value: &str) {
+        self.set_value(key, value);
+    }
+
+    pub fn set_i64(&mut self, key: &str, value: i64) {
+        self.set_value(key, value);
+    }
+
+    pub fn set_bool(&mut self, key: &str, value: bool) {
+        self.set_value(key, value);
+    }
+
+    pub fn set_f64(&mut self, key: &str, value: f64) {
+        self.set_value(key, value);
+    }
+
+    pub fn set_string_list(&mut self, key: &str, value: Vec<String>) {
+        self.set_value(key, value);
+    }
+
+}
@@ -1,9 +1,12 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use threadpool::ThreadPool;
 
+use crate::metrics::TaskCounters;
+use crate::rpc::{current_request_id, with_request_id};
 use crate::service::{ServiceApi, ServiceInitializer, Context};
 
 pub struct TaskContext {
@@ -17,7 +20,7 @@ impl TaskContext {
         }
     }
     
-    fn stop(&self) {
+    pub(crate) fn stop(&self) {
         self.is_interrupted.store(true, Ordering::Relaxed);
     }
 
@@ -26,17 +29,63 @@ impl TaskContext {
     }
 }
 
+/// Default of `TaskManager::set_shutdown_timeout`.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often `stop` checks whether the tasks have exited.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+struct RunningTask {
+    context: Arc<TaskContext>,
+    handle: JoinHandle<()>,
+}
+
 pub struct TaskManager {
     pool: Mutex<ThreadPool>,
-    tasks: RwLock<Vec<Arc<TaskContext>>>,
+    tasks: Mutex<Vec<RunningTask>>,
+    task_counters: Arc<TaskCounters>,
+    shutdown_timeout: RwLock<Duration>,
 }
 
 impl ServiceApi for TaskManager {
+    /// Interrupts the tasks, then waits for them and for the pool to drain within the shutdown
+    /// timeout. Tasks still running after it are logged and left behind.
     fn stop(&self) {
-        let tasks = self.tasks.read().unwrap();
+        let deadline = Instant::now() + *self.shutdown_timeout.read().unwrap();
+        let tasks: Vec<RunningTask> = self.tasks.lock().unwrap().drain(..).collect();
         for task in tasks.iter() {
-            task.stop();
+            task.context.stop();
+        }
+
+        let mut pending = tasks;
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) = pending.into_iter()
+                .partition(|task| task.handle.is_finished());
+            for task in finished {
+                if task.handle.join().is_err() {
+                    log::warn!("Task panicked before shutdown");
+                }
+            }
+            pending = running;
+            if pending.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        for task in pending.iter() {
+            log::warn!("Task on thread {:?} didn't exit within the shutdown timeout", task.handle.thread().id());
         }
+
+        // Cloned, so jobs can still submit to the pool while it drains
+        let pool = self.pool.lock().unwrap().clone();
+        while pool.active_count() + pool.queued_count() > 0 {
+            if Instant::now() >= deadline {
+                log::warn!("{} pool tasks didn't exit within the shutdown timeout", pool.active_count() + pool.queued_count());
+                return;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        pool.join();
     }
 }
 
@@ -44,18 +93,40 @@ impl ServiceInitializer for TaskManager {
     fn initialize(_: &Context) -> Arc<Self> {
         Arc::new(TaskManager {
             pool: Mutex::new(ThreadPool::new(4)),
-            tasks: RwLock::default(),
+            tasks: Mutex::default(),
+            task_counters: Arc::default(),
+            shutdown_timeout: RwLock::new(DEFAULT_SHUTDOWN_TIMEOUT),
         })
     }
 }
 
 impl TaskManager {
+
+    /// How long `stop` waits for the tasks to exit.
+    pub fn set_shutdown_timeout(&self, timeout: Duration) {
+        *self.shutdown_timeout.write().unwrap() = timeout;
+    }
+
     pub fn run_instant_task<F>(&self, job: F) where
         F: Fn(&TaskContext) + Send + Sync + 'static
     {
+        self.run_instant_once(job);
+    }
+
+    /// Like `run_instant_task`, for one-shot jobs that move their data in.
+    pub fn run_instant_once<F>(&self, job: F) where
+        F: FnOnce(&TaskContext) + Send + 'static
+    {
+        let task_guard = self.task_counters.spawn();
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("instant_task");
+        let request_id = current_request_id();
         self.pool.lock().unwrap().execute(move || {
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+            let _task_guard = task_guard;
             let task_context = TaskContext::new();
-            job(&task_context);
+            with_request_id(request_id, || job(&task_context));
         });
     }
 
@@ -64,11 +135,84 @@ impl TaskManager {
     {
         let task_context = Arc::new(TaskContext::new());
 
-        let mut tasks = self.tasks.write().unwrap();
-        tasks.push(task_context.clone());
+        let task_guard = self.task_counters.spawn();
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("task");
+        let request_id = current_request_id();
+        let context = task_context.clone();
+        let handle = thread::spawn(move || {
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+            let _task_guard = task_guard;
+            with_request_id(request_id, || job(task_context));
+        });
+
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.handle.is_finished());
+        tasks.push(RunningTask { context, handle });
+    }
+
+    pub(crate) fn task_counters(&self) -> &TaskCounters {
+        &self.task_counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::service::{Context, ServiceApi};
+    use crate::tasks::TaskManager;
 
-        thread::spawn(move || {
-            job(task_context);
+    #[test]
+    fn test_run_instant_once() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        let task_manager = context.get_service::<TaskManager>();
+
+        // `Cell` isn't `Sync`, so this job couldn't go to `run_instant_task`
+        let value = Cell::new(20);
+        let (sender, receiver) = mpsc::channel();
+        task_manager.run_instant_once(move |_| {
+            value.set(value.get() + 1);
+            sender.send(value.get()).unwrap();
         });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), 21);
+    }
+
+    #[test]
+    fn test_stop_joins_tasks() {
+        let context = Context::new();
+        context.init_service::<TaskManager>();
+        let task_manager = context.get_service::<TaskManager>();
+        task_manager.set_shutdown_timeout(Duration::from_millis(300));
+
+        let cleaned_up = Arc::new(AtomicBool::new(false));
+        let cleaned_up_copy = cleaned_up.clone();
+        task_manager.run(move |task| {
+            while !task.is_interrupted() {
+                thread::sleep(Duration::from_millis(5));
+            }
+            thread::sleep(Duration::from_millis(50));
+            cleaned_up_copy.store(true, Ordering::Relaxed);
+        });
+        let drained = Arc::new(AtomicBool::new(false));
+        let drained_copy = drained.clone();
+        task_manager.run_instant_task(move |_| {
+            thread::sleep(Duration::from_millis(50));
+            drained_copy.store(true, Ordering::Relaxed);
+        });
+        // Ignores the interrupt, so it's abandoned after the timeout
+        task_manager.run(|_| thread::sleep(Duration::from_secs(10)));
+
+        let started = Instant::now();
+        task_manager.stop();
+        assert!(cleaned_up.load(Ordering::Relaxed));
+        assert!(drained.load(Ordering::Relaxed));
+        assert!(started.elapsed() < Duration::from_secs(5));
     }
 }
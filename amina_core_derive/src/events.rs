@@ -1,40 +1,15 @@
 use proc_macro::TokenStream;
 use quote::quote;
 use syn;
-use syn::Meta;
 use syn::Lit;
+use syn::parse::{Parser, ParseStream};
 
 pub fn impl_event(ast: &syn::DeriveInput) -> TokenStream {
     let name = &ast.ident;
-    let attr = ast
-        .attrs
-        .iter()
-        .find_map(|a| {
-            let a = a.parse_meta();
-            match a {
-                Ok(meta) => {
-                    if meta.path().is_ident("key") {
-                        Some(meta)
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
-            }
-        })
-        .unwrap();
-
-    let key = match attr {
-        Meta::NameValue(value) => {
-            //println!("{}", value.path.get_ident().unwrap());
-            match value.lit {
-                Lit::Str(str_value) => {
-                    str_value.value()
-                }
-                _ => {panic!()}
-            }
-        }
-        _ => {panic!()}
+
+    let key = match parse_key(ast) {
+        Ok(key) => key,
+        Err(err) => return err.to_compile_error().into(),
     };
 
     let a = quote! {
@@ -46,3 +21,154 @@ pub fn impl_event(ast: &syn::DeriveInput) -> TokenStream {
     };
     a.into()
 }
+
+fn parse_key(ast: &syn::DeriveInput) -> syn::Result<String> {
+    let attr = ast
+        .attrs
+        .iter()
+        .find(|a| a.path.is_ident("key"));
+
+    match attr {
+        Some(attr) => parse_key_tokens.parse2(attr.tokens.clone()),
+        None => Err(syn::Error::new_spanned(
+            &ast.ident,
+            format!("#[derive(Event)] requires a #[key = \"...\"] attribute on `{}`", ast.ident),
+        )),
+    }
+}
+
+/// Parses the tail of `#[key = "some.event"]` or `#[key("some.event", strict)]`.
+fn parse_key_tokens(input: ParseStream) -> syn::Result<String> {
+    if input.peek(syn::Token![=]) {
+        input.parse::<syn::Token![=]>()?;
+        let lit: Lit = input.parse()?;
+        if !input.is_empty() {
+            return Err(input.error("unexpected tokens after event key, use #[key(\"some.event\", strict)] for options"));
+        }
+        return parse_key_lit(lit, false);
+    }
+
+    if input.peek(syn::token::Paren) {
+        let content;
+        syn::parenthesized!(content in input);
+        let lit: Lit = content.parse()?;
+        let mut strict = false;
+        if content.parse::<Option<syn::Token![,]>>()?.is_some() {
+            let flag: syn::Ident = content.parse()?;
+            if flag != "strict" {
+                return Err(syn::Error::new_spanned(flag, "unknown event key option, expected `strict`"));
+            }
+            strict = true;
+        }
+        if !content.is_empty() {
+            return Err(content.error("unexpected tokens after event key options"));
+        }
+        return parse_key_lit(lit, strict);
+    }
+
+    Err(input.error("expected event key in the form #[key = \"some.event\"]"))
+}
+
+fn parse_key_lit(lit: Lit, strict: bool) -> syn::Result<String> {
+    let key = match &lit {
+        Lit::Str(str_value) => str_value.value(),
+        other => return Err(syn::Error::new_spanned(
+            other,
+            "event key must be a string literal: #[key = \"some.event\"]",
+        )),
+    };
+
+    if strict && !is_valid_key(&key) {
+        return Err(syn::Error::new_spanned(
+            lit,
+            format!("invalid event key \"{}\": expected dot separated segments of [a-z0-9_]+", key),
+        ));
+    }
+
+    Ok(key)
+}
+
+fn is_valid_key(key: &str) -> bool {
+    key.split('.').all(|segment| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+    use super::{is_valid_key, parse_key};
+
+    fn key_error(ast: syn::DeriveInput) -> String {
+        parse_key(&ast).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let ast: syn::DeriveInput = parse_quote! {
+            #[key = "event.one"]
+            struct EventOne;
+        };
+        assert_eq!(parse_key(&ast).unwrap(), "event.one".to_string());
+    }
+
+    #[test]
+    fn test_parse_key_errors() {
+        assert_eq!(key_error(parse_quote! {
+            struct EventOne;
+        }), "#[derive(Event)] requires a #[key = \"...\"] attribute on `EventOne`");
+        assert_eq!(key_error(parse_quote! {
+            #[key = 1]
+            struct EventOne;
+        }), "event key must be a string literal: #[key = \"some.event\"]");
+        assert_eq!(key_error(parse_quote! {
+            #[key]
+            struct EventOne;
+        }), "unexpected end of input, expected event key in the form #[key = \"some.event\"]");
+        assert_eq!(key_error(parse_quote! {
+            #[key = "event.one" "event.two"]
+            struct EventOne;
+        }), "unexpected tokens after event key, use #[key(\"some.event\", strict)] for options");
+    }
+
+    #[test]
+    fn test_is_valid_key() {
+        assert!(is_valid_key("event"));
+        assert!(is_valid_key("amina_core.cmd_manager.command_executed"));
+        assert!(is_valid_key("event.v2"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("event..one"));
+        assert!(!is_valid_key(".event"));
+        assert!(!is_valid_key("event."));
+        assert!(!is_valid_key("Event.one"));
+        assert!(!is_valid_key("event-one"));
+        assert!(!is_valid_key("event one"));
+    }
+
+    #[test]
+    fn test_parse_strict_key() {
+        let ast: syn::DeriveInput = parse_quote! {
+            #[key("event.one", strict)]
+            struct EventOne;
+        };
+        assert_eq!(parse_key(&ast).unwrap(), "event.one".to_string());
+        let ast: syn::DeriveInput = parse_quote! {
+            #[key("Event.One")]
+            struct EventOne;
+        };
+        assert_eq!(parse_key(&ast).unwrap(), "Event.One".to_string());
+
+        assert_eq!(key_error(parse_quote! {
+            #[key("Event.One", strict)]
+            struct EventOne;
+        }), "invalid event key \"Event.One\": expected dot separated segments of [a-z0-9_]+");
+        assert_eq!(key_error(parse_quote! {
+            #[key("event.one", lenient)]
+            struct EventOne;
+        }), "unknown event key option, expected `strict`");
+        assert_eq!(key_error(parse_quote! {
+            #[key("event.one", strict, strict)]
+            struct EventOne;
+        }), "unexpected tokens after event key options");
+    }
+}
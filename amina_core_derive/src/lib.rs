@@ -1,4 +1,5 @@
 mod events;
+mod rpc_service;
 
 use proc_macro::TokenStream;
 use syn;
@@ -10,3 +11,10 @@ pub fn event_macro_derive(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).unwrap();
     events::impl_event(&ast)
 }
+
+/// Generates `register_rpc_handlers(&Arc<Self>, &Rpc)` for every `#[rpc("key")]` method of the impl block.
+#[proc_macro_attribute]
+pub fn rpc_service(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item_impl = syn::parse_macro_input!(item as syn::ItemImpl);
+    rpc_service::impl_rpc_service(item_impl)
+}
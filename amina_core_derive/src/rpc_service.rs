@@ -0,0 +1,93 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{FnArg, ImplItem, LitStr, Pat, ReturnType, Type};
+
+pub fn impl_rpc_service(mut item_impl: syn::ItemImpl) -> TokenStream {
+    let mut registrations = Vec::new();
+
+    for impl_item in item_impl.items.iter_mut() {
+        if let ImplItem::Method(method) = impl_item {
+            let position = method.attrs.iter().position(|a| a.path.is_ident("rpc"));
+            if let Some(position) = position {
+                let attr = method.attrs.remove(position);
+                match rpc_registration(&attr, &method.sig) {
+                    Ok(registration) => registrations.push(registration),
+                    Err(err) => return err.to_compile_error().into(),
+                }
+            }
+        }
+    }
+
+    let self_ty = &item_impl.self_ty;
+    let (impl_generics, _, where_clause) = item_impl.generics.split_for_impl();
+
+    let a = quote! {
+        #item_impl
+
+        impl #impl_generics #self_ty #where_clause {
+            pub fn register_rpc_handlers(service: &::std::sync::Arc<Self>, rpc: &::amina_core::rpc::Rpc) {
+                #(#registrations)*
+            }
+        }
+    };
+    a.into()
+}
+
+fn rpc_registration(attr: &syn::Attribute, sig: &syn::Signature) -> syn::Result<proc_macro2::TokenStream> {
+    let key: LitStr = attr.parse_args()
+        .map_err(|err| syn::Error::new(err.span(), "expected RPC key in the form #[rpc(\"some.key\")]"))?;
+    let method_name = &sig.ident;
+
+    if sig.asyncness.is_some() {
+        return Err(syn::Error::new_spanned(sig, "RPC handler methods can't be async"));
+    }
+
+    let mut arg_names = Vec::new();
+    let mut arg_types = Vec::new();
+    let mut has_receiver = false;
+    for input in sig.inputs.iter() {
+        match input {
+            FnArg::Receiver(receiver) => {
+                if receiver.reference.is_none() || receiver.mutability.is_some() {
+                    return Err(syn::Error::new_spanned(receiver, "RPC handler methods must take `&self`"));
+                }
+                has_receiver = true;
+            },
+            FnArg::Typed(pat_type) => {
+                match pat_type.pat.as_ref() {
+                    Pat::Ident(pat_ident) => {
+                        arg_names.push(pat_ident.ident.clone());
+                        arg_types.push(pat_type.ty.clone());
+                    },
+                    other => return Err(syn::Error::new_spanned(other, "RPC handler arguments must be plain identifiers")),
+                }
+            },
+        }
+    }
+
+    if !has_receiver {
+        return Err(syn::Error::new_spanned(sig, "RPC handler methods must take `&self`"));
+    }
+
+    // Fallible methods answer with the `RpcResult` envelope, like `on_generic_call_result_fn`
+    if returns_result(&sig.output) {
+        Ok(quote! {
+            ::amina_core::register_rpc_handler_result!(rpc, service, #key, #method_name(#(#arg_names: #arg_types),*));
+        })
+    } else {
+        Ok(quote! {
+            ::amina_core::register_rpc_handler!(rpc, service, #key, #method_name(#(#arg_names: #arg_types),*));
+        })
+    }
+}
+
+fn returns_result(output: &ReturnType) -> bool {
+    match output {
+        ReturnType::Type(_, ty) => match ty.as_ref() {
+            Type::Path(type_path) => type_path.path.segments.last()
+                .is_some_and(|segment| segment.ident == "Result"),
+            _ => false,
+        },
+        ReturnType::Default => false,
+    }
+}
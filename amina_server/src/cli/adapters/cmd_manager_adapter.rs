@@ -1,173 +1,116 @@
-use std::collections::HashMap;
-use std::iter::FromIterator;
-use amina_core::cmd_manager::{ArgDescription, ArgType, ArgsList, CmdManager};
+use std::cell::RefCell;
+use amina_core::cmd_manager::args_parser::{parse_command_line, split_command};
+use amina_core::cmd_manager::{CmdCaller, CmdError, CmdManager, CmdResult, WizardPrompt};
 use amina_core::service::Service;
 
 use crate::cli::InputHandler;
 
-#[derive(PartialEq)]
-enum ArgsParserState {
-    WaitForArgNameStart,
-    ReadingArgName,
-    WaitForArgValue,
-    ReadingStringValue,
-    ReadingNonStringValue,
-}
+/// Typed instead of an answer to leave a wizard.
+const WIZARD_CANCEL: &str = ":cancel";
 
 pub struct CmdManagerAdapter {
     cmd_manager: Service<CmdManager>,
+    /// Step of a running wizard, the next line answers it.
+    wizard: RefCell<Option<WizardPrompt>>,
 }
 
 impl CmdManagerAdapter {
     pub fn new(cmd_manager: Service<CmdManager>) -> Self {
         Self {
-            cmd_manager
+            cmd_manager,
+            wizard: RefCell::new(None),
         }
     }
-}
-
-impl InputHandler for CmdManagerAdapter {
-    fn handle(&self, input_line: &str) {
-        let cmd_line = input_line.replace("\n", "");
 
-        let args_start_option = cmd_line.find(" ");
-        let (cmd_name, args_str) = match args_start_option {
-            Some(args_start) => {
-                (&cmd_line[..args_start], &cmd_line[(args_start + 1)..])
-            },
-            None => {
-                (&cmd_line[..], "")
+    fn answer_wizard(&self, prompt: WizardPrompt, answer: &str) {
+        let caller = CmdCaller::local("cli");
+        if answer == WIZARD_CANCEL {
+            if let Err(err) = self.cmd_manager.wizard_cancel(&prompt.token, &caller) {
+                eprintln!("Error: {}", err);
+            }
+            return;
+        }
+        match self.cmd_manager.wizard_answer(&prompt.token, answer, &caller) {
+            Ok(result) => self.print_result(result),
+            Err(err @ CmdError::InvalidWizardAnswer { .. }) => {
+                // The session stays on the step, ask again
+                eprintln!("Error: {}", err);
+                self.print_result(CmdResult::ok(&prompt.prompt).with_payload(&prompt));
             },
-        };
-
-        log::debug!("CLI cmd: {:?}, args: {:?}", cmd_name, args_str);
+            Err(err) => eprintln!("Error: {}", err),
+        }
+    }
 
-        let cmd_list = self.cmd_manager.get_cmd_description().read().unwrap();
-        match cmd_list.get(cmd_name) {
-            Some(cmd_wrapper) => {
-                let args = parse(args_str, &cmd_wrapper.description.args);
-                if let Some(args) = args {
-                    log::debug!("Cmd args: {:?}", &args);
-                    (cmd_wrapper.handler)(&args);
-                }
-            },
-            None => {
-                log::error!("Unknown command '{}'", cmd_name);
+    fn print_result(&self, result: CmdResult) {
+        if let Some(prompt) = result.wizard_prompt() {
+            let options = if prompt.options.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", prompt.options.join(", "))
+            };
+            println!("{}{}, or {} to stop", prompt.prompt, options, WIZARD_CANCEL);
+            *self.wizard.borrow_mut() = Some(prompt);
+            return;
+        }
+        if !result.message.is_empty() {
+            if result.success {
+                println!("{}", result.message);
+            } else {
+                eprintln!("Error: {}", result.message);
             }
         }
+        if let Some(output) = result.output() {
+            println!("{}", output.render(terminal_width()));
+        } else if let Some(payload) = result.payload {
+            println!("{}", serde_json::to_string_pretty(&payload).unwrap());
+        }
     }
 }
 
-fn parse_raw(args_str: &str) -> HashMap<String, String> {
-    let mut result = HashMap::new();
-    let mut state = ArgsParserState::WaitForArgNameStart;
-
-    let mut name_vec = Vec::<char>::new();
-    let mut value_vec = Vec::<char>::new();
-
-    for c in args_str.chars() {
-        match state {
-            ArgsParserState::WaitForArgNameStart => {
-                if c != ' ' {
-                    name_vec.push(c);
-                    state = ArgsParserState::ReadingArgName;
-                }
-            },
-            ArgsParserState::ReadingArgName => {
-                if c == ':' {
-                    state = ArgsParserState::WaitForArgValue;
-                } else {
-                    name_vec.push(c);
-                }
-            },
-            ArgsParserState::WaitForArgValue => {
-                if c == '\'' {
-                    state = ArgsParserState::ReadingStringValue;
-                } else if c == ' ' {
+impl InputHandler for CmdManagerAdapter {
+    fn handle(&self, input_line: &str) {
+        let cmd_line = input_line.replace("\n", "");
 
-                } else {
-                    value_vec.push(c);
-                    state = ArgsParserState::ReadingNonStringValue;
-                }
-            },
-            ArgsParserState::ReadingStringValue => {
-                if c == '\'' {
-                    let name = String::from_iter(&name_vec);
-                    let value = String::from_iter(&value_vec);
-                    result.insert(name, value);
-                    name_vec.clear();
-                    value_vec.clear();
-                    state = ArgsParserState::WaitForArgNameStart;
-                } else {
-                    value_vec.push(c);
-                }
-            },
-            ArgsParserState::ReadingNonStringValue => {
-                if c == ' ' {
-                    let name = String::from_iter(&name_vec);
-                    let value = String::from_iter(&value_vec);
-                    result.insert(name, value);
-                    name_vec.clear();
-                    value_vec.clear();
-                    state = ArgsParserState::WaitForArgNameStart;
-                } else {
-                    value_vec.push(c);
-                }
-            },
+        let wizard = self.wizard.borrow_mut().take();
+        if let Some(prompt) = wizard {
+            self.answer_wizard(prompt, &cmd_line);
+            return;
         }
 
-    }
-
-    if state == ArgsParserState::ReadingNonStringValue {
-        let name = String::from_iter(&name_vec);
-        let value = String::from_iter(&value_vec);
-        result.insert(name, value);
-        name_vec.clear();
-        value_vec.clear();
-    }
-
-    return result;
-}
+        let (cmd_name, args_str) = split_command(&cmd_line);
 
-fn parse(args_str: &str, args_description: &HashMap<String, ArgDescription>) -> Option<ArgsList> {
-    let mut args_list = ArgsList::new();
-
-    let raw_args = parse_raw(args_str);
+        log::debug!("CLI cmd: {:?}, args: {:?}", cmd_name, args_str);
 
-    for (arg_name, description) in args_description {
-        match raw_args.get(arg_name) {
-            Some(arg_value_raw) => {
-                match description.arg_type {
-                    ArgType::U64 => {
-                        match arg_value_raw.parse::<u64>() {
-                            Ok(value) => args_list.put_u64(arg_name, value),
-                            Err(_) => {
-                                log::error!("Invalid int arg '{}': '{}'", arg_name, arg_value_raw);
-                                return None;
-                            }
-                        }
-                    },
-                    ArgType::BOOL => {
-                        if arg_value_raw.eq("y") {
-                            args_list.put_bool(arg_name, true);
-                        } else if arg_value_raw.eq("n") {
-                            args_list.put_bool(arg_name, false);
-                        } else {
-                            log::error!("Invalid bool arg '{}', expected 'y' or 'n' but '{}' found", arg_name, arg_value_raw);
-                            return None;
-                        }
-                    },
-                    ArgType::STRING => {
-                        args_list.put_string(arg_name, arg_value_raw.clone());
-                    }
-                }
-            },
-            None => {
-                log::error!("Argument '{}' not found", arg_name);
-                return None;
+        let description = match self.cmd_manager.get_command_description(cmd_name) {
+            Ok(description) => description,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return;
+            }
+        };
+        let args = match parse_command_line(&cmd_line, &description) {
+            Ok(args) => args,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return;
             }
+        };
+        log::debug!("Cmd args: {:?}", &args);
+        match self.cmd_manager.handle(cmd_name, &args, &CmdCaller::local("cli")) {
+            Ok(result) => self.print_result(result),
+            Err(err) => eprintln!("Error: {}", err),
         }
     }
 
-    return Some(args_list);
+    fn prompt(&self) -> String {
+        match &*self.wizard.borrow() {
+            Some(prompt) => format!("{}>", prompt.step),
+            None => ">".to_string(),
+        }
+    }
+}
+
+/// Falls back to 80 columns when stdout isn't a terminal.
+fn terminal_width() -> usize {
+    termion::terminal_size().map(|(width, _)| width as usize).unwrap_or(80)
 }
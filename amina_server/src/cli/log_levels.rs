@@ -0,0 +1,146 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use env_logger::{Builder, Logger};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use amina_core::cmd_manager::{ArgBuilder, ArgType, CmdBuilder, CmdError, CmdManager, CmdResult};
+
+/// Creates a builder with the output format set up, the filters are added by `LogLevels`.
+pub(crate) type BuilderFactory = Box<dyn Fn() -> Builder + Send + Sync>;
+
+struct Levels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+struct ReloadableLogger {
+    logger: RwLock<Logger>,
+    levels: Mutex<Levels>,
+    builder_factory: BuilderFactory,
+}
+
+impl ReloadableLogger {
+
+    fn rebuild(&self, levels: &Levels) {
+        let mut builder = (self.builder_factory)();
+        builder.filter(None, levels.default);
+        for (module, level) in levels.modules.iter() {
+            builder.filter(Some(module), *level);
+        }
+        let logger = builder.build();
+        log::set_max_level(logger.filter());
+        *self.logger.write().unwrap() = logger;
+    }
+
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.logger.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.logger.read().unwrap().flush()
+    }
+}
+
+/// Handle to the installed logger that changes log levels at runtime,
+/// e.g. to trace a single module while debugging without a restart.
+#[derive(Clone)]
+pub struct LogLevels {
+    logger: Arc<ReloadableLogger>,
+}
+
+struct ForwardingLogger(Arc<ReloadableLogger>);
+
+impl Log for ForwardingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.log(record)
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+impl LogLevels {
+
+    /// Installs the global logger, panics if one is already set.
+    pub(crate) fn init(builder_factory: BuilderFactory, default: LevelFilter, modules: Vec<(String, LevelFilter)>) -> Self {
+        let logger = Arc::new(ReloadableLogger {
+            logger: RwLock::new(builder_factory().build()),
+            levels: Mutex::new(Levels {
+                default,
+                modules,
+            }),
+            builder_factory,
+        });
+        logger.rebuild(&logger.levels.lock().unwrap());
+        log::set_boxed_logger(Box::new(ForwardingLogger(logger.clone())))
+            .expect("Logger is already initialized");
+        Self {
+            logger,
+        }
+    }
+
+    /// Sets the level of `module` and everything below it, replacing an earlier level for the same module.
+    pub fn set_level(&self, module: &str, level: LevelFilter) {
+        let mut levels = self.logger.levels.lock().unwrap();
+        levels.modules.retain(|(name, _)| name != module);
+        levels.modules.push((module.to_string(), level));
+        self.logger.rebuild(&levels);
+    }
+
+    /// Level of modules without a level of their own.
+    pub fn set_default_level(&self, level: LevelFilter) {
+        let mut levels = self.logger.levels.lock().unwrap();
+        levels.default = level;
+        self.logger.rebuild(&levels);
+    }
+
+    /// Adds the `log.set_level` command, for example `log.set_level module:amina_core::rpc level:trace`.
+    /// Without `module` the default level is changed. Fails if the command name is taken.
+    pub fn register_command(&self, cmd_manager: &CmdManager) -> Result<(), CmdError> {
+        let cmd = CmdBuilder::new("log.set_level")
+            .add_description("Change a log level at runtime")
+            .add_arg(ArgBuilder::new("module", ArgType::STRING)
+                .add_description("Module path, the default level is changed if omitted")
+                .optional()
+                .build())
+            .add_arg(ArgBuilder::new("level", ArgType::STRING)
+                .add_description("One of 'off', 'error', 'warn', 'info', 'debug' or 'trace'")
+                .build())
+            .build();
+        let log_levels = self.clone();
+        cmd_manager.add_command(cmd, move |args| {
+            let level = match args.get_string("level") {
+                Ok(level) => level,
+                Err(err) => return CmdResult::error(&err.to_string()),
+            };
+            let level = match LevelFilter::from_str(&level) {
+                Ok(level) => level,
+                Err(_) => return CmdResult::error(&format!("Unknown log level '{}'", level)),
+            };
+            match args.try_get_string("module") {
+                Some(module) => {
+                    log_levels.set_level(&module, level);
+                    CmdResult::ok(&format!("Log level of '{}' set to {}", module, level))
+                },
+                None => {
+                    log_levels.set_default_level(level);
+                    CmdResult::ok(&format!("Default log level set to {}", level))
+                },
+            }
+        })
+    }
+
+}
@@ -1,4 +1,7 @@
 pub mod adapters;
+mod log_levels;
+
+pub use log_levels::LogLevels;
 
 use std::{io::Write, path::Path};
 use log::LevelFilter;
@@ -14,29 +17,52 @@ impl Completer for EmptyCompleter {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Human,
+    Json,
+}
+
 pub trait InputHandler {
     fn handle(&self, input_line: &str);
+
+    /// Shown before the next input line.
+    fn prompt(&self) -> String {
+        ">".to_string()
+    }
 }
 
 pub struct CliContext {
     liner_ctx: Context,
     input_handler: Box<dyn InputHandler>,
+    log_levels: LogLevels,
 }
 
 impl CliContext {
-    pub fn create(input_handler: Box<dyn InputHandler>, filters: Vec<(String, log::LevelFilter)>, history_file: &Path) -> Self {
-        let mut builder = Builder::from_default_env();
-
-        builder.format(|buf, record| {
-                write!(buf, "[{}][{}][{}] {}\r\n", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.target(), record.args())
-        });
-        builder.filter(None, LevelFilter::Debug);
-
-        for (module, level) in filters {
-            builder.filter(Some(&module), level);
-        }
-
-        builder.init();
+    pub fn create(input_handler: Box<dyn InputHandler>, filters: Vec<(String, log::LevelFilter)>, history_file: &Path, log_format: LogFormat) -> Self {
+        let log_levels = LogLevels::init(Box::new(move || {
+            let mut builder = Builder::from_default_env();
+            match log_format {
+                LogFormat::Human => {
+                    builder.format(|buf, record| {
+                            write!(buf, "[{}][{}][{}] {}\r\n", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.target(), record.args())
+                    });
+                },
+                LogFormat::Json => {
+                    builder.format(|buf, record| {
+                        let line = serde_json::json!({
+                            "timestamp": Local::now().to_rfc3339(),
+                            "level": record.level().as_str(),
+                            "target": record.target(),
+                            "message": record.args().to_string(),
+                        });
+                        write!(buf, "{}\r\n", line)
+                    });
+                },
+            }
+            builder
+        }), LevelFilter::Debug, filters);
 
         let mut liner_ctx = Context::new();
 
@@ -46,13 +72,19 @@ impl CliContext {
 
         Self {
             liner_ctx,
-            input_handler
+            input_handler,
+            log_levels,
         }
     }
 
+    /// Changes log levels of the logger installed by `create`.
+    pub fn log_levels(&self) -> LogLevels {
+        self.log_levels.clone()
+    }
+
     pub fn run(&mut self) {
         loop {
-            let cmd_line = self.liner_ctx.read_line(Prompt::from(">"), None, &mut EmptyCompleter);
+            let cmd_line = self.liner_ctx.read_line(Prompt::from(self.input_handler.prompt()), None, &mut EmptyCompleter);
             let cmd_line = match cmd_line {
                 Ok(cmd_line) => cmd_line,
                 Err(_) => break,
@@ -74,28 +106,30 @@ impl CliContext {
 
 pub struct SimpleCliContext {
     input_handler: Box<dyn InputHandler>,
+    log_levels: LogLevels,
 }
 
 impl SimpleCliContext {
     pub fn create(input_handler: Box<dyn InputHandler>, filters: Vec<(String, log::LevelFilter)>, _: &Path) -> Self {
-        let mut builder = Builder::from_default_env();
-
-        builder.format(|buf, record| {
-                write!(buf, "[{}][{}][{}] {}\n", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.target(), record.args())
-        });
-        builder.filter(None, LevelFilter::Debug);
-
-        for (module, level) in filters {
-            builder.filter(Some(&module), level);
-        }
-
-        builder.init();
+        let log_levels = LogLevels::init(Box::new(|| {
+            let mut builder = Builder::from_default_env();
+            builder.format(|buf, record| {
+                    write!(buf, "[{}][{}][{}] {}\n", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.target(), record.args())
+            });
+            builder
+        }), LevelFilter::Debug, filters);
 
         Self {
-            input_handler
+            input_handler,
+            log_levels,
         }
     }
 
+    /// Changes log levels of the logger installed by `create`.
+    pub fn log_levels(&self) -> LogLevels {
+        self.log_levels.clone()
+    }
+
     pub fn run(&mut self) {
         loop {
             let mut cmd_line = String::new();
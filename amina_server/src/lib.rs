@@ -1,3 +1,4 @@
 pub mod cli;
+pub mod rate_limit;
 pub mod rpc_web_gate;
 
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Buckets of clients idle long enough to be full again are dropped past this size.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Tokens refilled per second.
+    pub requests_per_second: f64,
+    /// Bucket capacity, the number of requests accepted in a single burst.
+    pub burst: u32,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter with one bucket per client IP.
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl RateLimiter {
+
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of `ip`, returns `false` if it is empty.
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        self.try_acquire_n(ip, 1)
+    }
+
+    /// Takes `count` tokens from the bucket of `ip` at once, e.g. one per call of a batch.
+    /// Returns `false` and takes nothing if fewer are left.
+    pub fn try_acquire_n(&self, ip: IpAddr, count: u32) -> bool {
+        self.try_acquire_at(ip, count, Instant::now())
+    }
+
+    fn try_acquire_at(&self, ip: IpAddr, count: u32, now: Instant) -> bool {
+        let burst = self.limit.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_IDLE_BUCKETS && !buckets.contains_key(&ip) {
+            let requests_per_second = self.limit.requests_per_second;
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * requests_per_second < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.requests_per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= count as f64 {
+            bucket.tokens -= count as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limit::{RateLimit, RateLimiter};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            requests_per_second: 2.0,
+            burst: 3,
+        })
+    }
+
+    #[test]
+    fn test_refill() {
+        let limiter = limiter();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at(CLIENT, 1, start));
+        }
+        assert!(!limiter.try_acquire_at(CLIENT, 1, start));
+        assert!(!limiter.try_acquire_at(CLIENT, 1, start + Duration::from_millis(400)));
+        assert!(limiter.try_acquire_at(CLIENT, 1, start + Duration::from_millis(500)));
+        // Never more than the burst, however long the client was idle
+        let later = start + Duration::from_secs(60);
+        assert!(!limiter.try_acquire_at(CLIENT, 4, later));
+        assert!(limiter.try_acquire_at(CLIENT, 3, later));
+        assert!(!limiter.try_acquire_at(CLIENT, 1, later));
+    }
+
+    #[test]
+    fn test_per_ip_limit() {
+        let limiter = limiter();
+        assert!(limiter.try_acquire_n(CLIENT, 3));
+        assert!(!limiter.try_acquire(CLIENT));
+        assert!(limiter.try_acquire(OTHER_CLIENT));
+        assert!(!limiter.try_acquire_n(OTHER_CLIENT, 3));
+        assert!(limiter.try_acquire_n(OTHER_CLIENT, 2));
+    }
+}
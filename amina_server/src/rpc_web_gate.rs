@@ -1,36 +1,297 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
 use tokio::runtime;
-use tokio::sync::{mpsc};
-use bytes::Bytes;
+use tokio::sync::Notify;
+use bytes::{Buf, Bytes};
 use warp::{Filter, reply, Rejection, Reply};
+use warp::filters::BoxedFilter;
 use warp::path::Tail;
 use warp::ws::{Message, WebSocket};
 
-use amina_core::events::EventEmitterGate;
-use amina_core::rpc::RpcGate;
+use amina_core::cmd_manager::{with_cmd_caller, CmdCaller, PermissionLevel};
+use amina_core::events::{EventEmitter, EventEmitterGate};
+use amina_core::metrics::Metrics;
+use amina_core::rpc::{current_request_id, generate_request_id, with_request_id, Rpc, RpcGate};
+use amina_core::tasks::TaskManager;
 use amina_core::service::{Context, Service};
 
+use crate::rate_limit::{RateLimit, RateLimiter};
+
+/// How often idle websocket clients are pinged.
+const WS_PING_INTERVAL: Duration = Duration::from_secs(30);
+/// A client that does not answer a ping within this time is disconnected.
+const WS_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+/// Carries the request id of an RPC call, generated when the client sends none.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const RPC_VERSION_HEADER: &str = "x-rpc-version";
+
+/// What to do with a websocket client whose event queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WsOverflowPolicy {
+    /// Drop the oldest queued event to make room for the new one.
+    Drop,
+    /// Disconnect the lagging client.
+    Disconnect,
+}
+
+/// Outgoing event queue of a single websocket client.
+struct WsUser {
+    queue: Mutex<VecDeque<Message>>,
+    notify: Notify,
+    dropped: AtomicUsize,
+    overflowed: AtomicBool,
+}
+
+impl WsUser {
+
+    fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            dropped: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    fn push(&self, user_id: usize, message: Message, capacity: usize, policy: WsOverflowPolicy) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= capacity {
+            match policy {
+                WsOverflowPolicy::Drop => {
+                    queue.pop_front();
+                    if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                        log::warn!("ws user {} is lagging, dropping oldest events", user_id);
+                    }
+                },
+                WsOverflowPolicy::Disconnect => {
+                    if !self.overflowed.swap(true, Ordering::Relaxed) {
+                        log::warn!("ws user {} is lagging, disconnecting", user_id);
+                    }
+                    self.notify.notify_one();
+                    return;
+                },
+            }
+        }
+        queue.push_back(message);
+        self.notify.notify_one();
+    }
+
+    fn pop(&self) -> Option<Message> {
+        self.queue.lock().unwrap().pop_front()
+    }
+}
+
 struct WsUsers {
     next_id: AtomicUsize,
-    users: RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>,
+    users: RwLock<HashMap<usize, Arc<WsUser>>>,
+}
+
+#[derive(Deserialize)]
+struct BatchCall {
+    key: String,
+    #[serde(default)]
+    data: serde_json::Value,
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(default)]
+    version: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BatchCallResult {
+    Ok(serde_json::Value),
+    Error(String),
 }
 
+#[derive(Debug)]
+struct RateLimited;
+
+impl warp::reject::Reject for RateLimited {}
+
 pub struct EventToUi {
     pub key: String,
     pub data: String,
 }
 
+/// Version of the `WsFrame` format, sent in the `SubscribeAck` frame on connect.
+/// Version 1 was the untagged `{"key":..,"data":..}` frame, version 2 had no binary frames.
+pub const WS_PROTOCOL_VERSION: u32 = 3;
+
+/// First byte of a binary frame, the rest is the `WsFrame` encoded with MessagePack as a map.
+pub const WS_ENCODING_MSGPACK: u8 = 1;
+
+/// Frame of the `/api/events` websocket, tagged by its `type` field. Sent as JSON text,
+/// or as a binary frame starting with an encoding byte for the events of binary keys.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsFrame {
+    /// Emitted event, with the id of the RPC call that emitted it, if any.
+    Event {
+        key: String,
+        data: serde_json::Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    /// First frame of a connection, every event after it is sent to the client.
+    SubscribeAck {
+        protocol_version: u32,
+        /// Keys whose events are sent as binary frames.
+        #[serde(default)]
+        binary_keys: Vec<String>,
+    },
+    /// Problem with the connection, e.g. the client lagging behind.
+    Error {
+        message: String,
+    },
+    /// Keepalive for browser clients, which can't see websocket pings.
+    Ping,
+}
+
+impl WsFrame {
+
+    fn event(key: &str, raw_data: &str, request_id: Option<String>) -> Self {
+        WsFrame::Event {
+            key: key.to_string(),
+            data: serde_json::from_str(raw_data).unwrap_or_else(|_| serde_json::Value::from(raw_data)),
+            request_id,
+        }
+    }
+
+    fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap())
+    }
+
+    fn to_binary_message(&self) -> Message {
+        let mut bytes = vec![WS_ENCODING_MSGPACK];
+        match rmp_serde::encode::write_named(&mut bytes, self) {
+            Ok(()) => Message::binary(bytes),
+            Err(e) => {
+                log::error!("Can't encode ws frame as MessagePack, sending it as text: {}", e);
+                self.to_message()
+            },
+        }
+    }
+
+    fn event_message(key: &str, raw_data: &str, request_id: Option<String>, binary_keys: &HashSet<String>) -> Message {
+        let frame = Self::event(key, raw_data, request_id);
+        if binary_keys.contains(key) {
+            frame.to_binary_message()
+        } else {
+            frame.to_message()
+        }
+    }
+
+}
+
+/// CORS policy applied to every route of the server.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// Origins like `https://example.com` allowed to call the server, `None` allows any.
+    /// An invalid origin panics when the server starts.
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Vec<warp::http::Method>,
+    pub allowed_headers: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: None,
+            allowed_methods: vec![warp::http::Method::GET, warp::http::Method::POST],
+            allowed_headers: ["Origin", "Content-Type", "Accept", "Authorization"].iter()
+                .map(|header| header.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl CorsConfig {
+    fn build(&self) -> warp::cors::Cors {
+        let cors = warp::cors()
+            .allow_methods(self.allowed_methods.clone())
+            .allow_headers(self.allowed_headers.iter().map(String::as_str))
+            .max_age(3600);
+        let cors = match &self.allowed_origins {
+            Some(origins) => cors.allow_origins(origins.iter().map(String::as_str)),
+            None => cors.allow_any_origin(),
+        };
+        cors.build()
+    }
+}
+
+pub struct RpcServerConfig {
+    /// Compress `rpc_call` and `get_file` responses with gzip or deflate
+    /// when the client announces support for it in `Accept-Encoding`.
+    pub compression: bool,
+    /// Level of the per-request access log line, `None` disables it.
+    pub access_log: Option<log::Level>,
+    /// Directory served as a fallback after the API routes, `index.html` answers `/`.
+    pub static_dir: Option<PathBuf>,
+    /// Per client IP limit of `rpc_call` requests, `None` means unlimited.
+    pub rate_limit: Option<RateLimit>,
+    /// Maximum number of events queued for a single websocket client.
+    pub ws_queue_capacity: usize,
+    /// Applied when a websocket client's queue is full.
+    pub ws_overflow_policy: WsOverflowPolicy,
+    /// Largest accepted `rpc_call` and `rpc_batch` body in bytes. Larger requests and
+    /// requests without a `Content-Length` are rejected before the body is read.
+    pub max_body_size: u64,
+    /// Largest accepted `upload` body in bytes, counted while it streams in.
+    pub max_upload_size: u64,
+    /// Level of commands run over RPC by clients without a known token.
+    pub default_permission_level: PermissionLevel,
+    /// Tokens sent as `Authorization: Bearer <token>`, each grants its level.
+    pub auth_tokens: HashMap<String, PermissionLevel>,
+    /// Makes loopback clients `Local` like the CLI. Only for servers no proxy or browser
+    /// page can reach, the server listens on loopback so every client would qualify.
+    pub trust_loopback: bool,
+    /// Defaults to any origin, for local development. Restrict it when the server is exposed.
+    pub cors: CorsConfig,
+    /// Event keys sent as MessagePack binary frames instead of JSON text. Smaller on the wire
+    /// for numeric data like audio levels, but the event is re-encoded from its JSON form,
+    /// which costs more CPU than text frames and turns byte buffers into arrays of integers.
+    pub binary_event_keys: HashSet<String>,
+}
+
+impl Default for RpcServerConfig {
+    fn default() -> Self {
+        Self {
+            compression: true,
+            access_log: Some(log::Level::Debug),
+            static_dir: None,
+            rate_limit: None,
+            ws_queue_capacity: 1024,
+            ws_overflow_policy: WsOverflowPolicy::Drop,
+            max_body_size: 16 * 1024 * 1024,
+            max_upload_size: 1024 * 1024 * 1024,
+            default_permission_level: PermissionLevel::User,
+            auth_tokens: HashMap::new(),
+            trust_loopback: false,
+            cors: CorsConfig::default(),
+            binary_event_keys: HashSet::new(),
+        }
+    }
+}
+
 pub struct RpcServer {
     _rt: runtime::Runtime,
 }
 
 impl RpcServer {
     pub fn run(context: &Context) -> Self {
+        Self::run_with_config(context, RpcServerConfig::default())
+    }
+
+    pub fn run_with_config(context: &Context, config: RpcServerConfig) -> Self {
         let users = Arc::new(WsUsers {
             next_id: AtomicUsize::new(1),
             users: RwLock::default(),
@@ -40,51 +301,90 @@ impl RpcServer {
         let events_gate = context.get_service::<EventEmitterGate>();
 
         let users_copy = users.clone();
+        let ws_queue_capacity = config.ws_queue_capacity.max(1);
+        let ws_overflow_policy = config.ws_overflow_policy;
+        let binary_keys = Arc::new(config.binary_event_keys);
+        let binary_keys_copy = binary_keys.clone();
         events_gate.add_raw_observer(Box::new(move |key: &str, raw_value: &str| {
+            // Observers run on the emitting thread, so this is the id of the RPC call that emitted the event
+            let msg = WsFrame::event_message(key, raw_value, current_request_id(), &binary_keys_copy);
             let users_vec = users_copy.users.read().unwrap();
-            for (_, user_id) in users_vec.iter() {
-                let msg = format!("{{\"key\":\"{ }\", \"data\":{ } }}", key, raw_value);
-                let msg = Message::text(msg);
-                if let Err(e) = user_id.send(msg.clone()) {
-                    log::trace!("Send error: {:?}", e);
-                }
+            for (user_id, user) in users_vec.iter() {
+                user.push(*user_id, msg.clone(), ws_queue_capacity, ws_overflow_policy);
             }
         }));
 
         let rpc_gate_filter = warp::any().map(move || rpc_gate.clone()).boxed();
 
-        let cors = warp::cors()
-            .allow_any_origin()
-            .allow_methods(&[warp::http::Method::GET, warp::http::Method::POST])
-            .allow_headers(vec![
-                "Origin",
-                "Content-Type",
-                "Accept",
-                "Authorization",
-            ])
-            .max_age(3600).build();
-
+        let rate_limiter = config.rate_limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+        let caller_auth = CallerAuth {
+            default_level: config.default_permission_level,
+            tokens: Arc::new(config.auth_tokens),
+            trust_loopback: config.trust_loopback,
+        };
+        let caller_filter = with_caller(caller_auth.clone());
         let prc_call_handler = warp::post()
             .and(warp::path!("api" / "rpc_call"))
+            .and(with_rate_limit(rate_limiter.clone()))
             .and(rpc_gate_filter.clone())
+            .and(caller_filter.clone())
             .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>(REQUEST_ID_HEADER))
+            .and(warp::body::content_length_limit(config.max_body_size))
             .and(warp::body::bytes())
             .and_then(handle_rpc_call)
-            .with(cors.clone());
+            .recover(handle_rate_limited);
+        let prc_call_handler = with_compression(prc_call_handler, config.compression);
+
+        let rpc_batch_handler = rpc_batch_route(rpc_gate_filter.clone(), caller_auth, rate_limiter, config.max_body_size);
+
+        let max_upload_size = config.max_upload_size;
+        let upload_handler = warp::post()
+            .and(warp::path("upload"))
+            .and(rpc_gate_filter.clone())
+            .and(warp::path::tail())
+            .and(warp::any().map(move || max_upload_size))
+            .and(warp::body::stream())
+            .and_then(handle_upload);
 
         let get_file_handler = warp::get()
             .and(warp::path("get_file"))
             .and(rpc_gate_filter.clone())
             .and(warp::path::tail())
             .and_then(handle_get_file);
+        let get_file_handler = with_compression(get_file_handler, config.compression);
+
+        // Liveness probe, kept outside of the RPC routing so it stays cheap
+        let services_view = context.services_view();
+        let health_handler = warp::get()
+            .and(warp::path!("health"))
+            .map(move || {
+                let health_body = serde_json::json!({
+                    "status": "ok",
+                    "services": services_view.count(),
+                }).to_string();
+                reply::with_header(health_body, "Content-Type", "application/json")
+            });
+
+        let metrics = Arc::new(Metrics::new(
+            context.get_service::<Rpc>(),
+            context.try_get_service::<EventEmitter>(),
+            context.try_get_service::<TaskManager>(),
+        ));
+        let metrics_handler = warp::get()
+            .and(warp::path!("metrics"))
+            .map(move || reply::with_header(metrics.snapshot().to_prometheus(), "Content-Type", "text/plain; version=0.0.4"));
 
         let users_copy = users.clone();
+        let events_gate_copy = events_gate.clone();
         let events_ws_handler = warp::path!("api" / "events")
             .and(warp::ws())
             .map(move |ws: warp::ws::Ws| {
                 let users_copy = users_copy.clone();
+                let events_gate_copy = events_gate_copy.clone();
+                let binary_keys = binary_keys.clone();
                 ws.on_upgrade(move |socket|
-                    Self::user_connected(socket, users_copy.clone())
+                    Self::user_connected(socket, users_copy, events_gate_copy, binary_keys, ws_queue_capacity, ws_overflow_policy)
                 )
             });
 
@@ -93,11 +393,29 @@ impl RpcServer {
             .enable_all()
             .build()
             .unwrap();
+        // Futures of async RPC handlers run next to the web server
+        context.get_service::<Rpc>().set_tokio_handle(rt.handle().clone());
 
         let addr = SocketAddr::from(([127, 0, 0, 1], 8090));
 
+        let routes = health_handler.or(metrics_handler).or(prc_call_handler).or(rpc_batch_handler).or(events_ws_handler).or(upload_handler).or(get_file_handler)
+            .map(|r| Box::new(r) as Box<dyn Reply>)
+            .boxed();
+        let routes = match config.static_dir {
+            Some(static_dir) => {
+                log::info!("Serving static files from {:?}", static_dir);
+                let static_handler = warp::get().and(warp::fs::dir(static_dir));
+                routes.or(with_compression(static_handler, config.compression)).unify().boxed()
+            },
+            None => routes,
+        };
+        let routes = routes.with(config.cors.build())
+            .map(|r| Box::new(r) as Box<dyn Reply>)
+            .boxed();
+        let routes = with_access_log(routes, config.access_log);
+
         rt.spawn(async move {
-            warp::serve(prc_call_handler.or(events_ws_handler).or(get_file_handler))
+            warp::serve(routes)
                 .run(addr)
                 .await;
         });
@@ -111,18 +429,91 @@ impl RpcServer {
         log::info!("Stop server");
     }
 
-    async fn user_connected(mut ws: WebSocket, ws_users: Arc<WsUsers>) {
+    async fn user_connected(ws: WebSocket, ws_users: Arc<WsUsers>, events_gate: Service<EventEmitterGate>, binary_keys: Arc<HashSet<String>>,
+                            queue_capacity: usize, overflow_policy: WsOverflowPolicy) {
         let user_id = ws_users.next_id.fetch_add(1, Ordering::Relaxed);
 
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        let user = Arc::new(WsUser::new());
 
-        ws_users.users.write().unwrap().insert(user_id, tx);
+        let mut binary_keys_list: Vec<String> = binary_keys.iter().cloned().collect();
+        binary_keys_list.sort();
+        user.push(user_id, WsFrame::SubscribeAck {
+            protocol_version: WS_PROTOCOL_VERSION,
+            binary_keys: binary_keys_list,
+        }.to_message(), queue_capacity, overflow_policy);
+        // Recent events next, so the UI doesn't start blank. Nothing is kept unless
+        // `EventEmitter::set_replay_capacity` was called. The user is added before any
+        // later event reaches the observer, so none is missed or sent twice.
+        events_gate.subscribe_with_replay(|replay| {
+            for (key, data) in replay.iter() {
+                user.push(user_id, WsFrame::event_message(key, data, None, &binary_keys), queue_capacity, overflow_policy);
+            }
+            ws_users.users.write().unwrap().insert(user_id, user.clone());
+        });
 
-        while let Some(message) = rx.recv().await {
-            let result = ws.send(message).await;
-            if let Err(e) = result {
-                log::trace!("ws send error: {:?}", e);
-                break;
+        let (mut ws_tx, mut ws_rx) = ws.split();
+        let mut ping_interval = tokio::time::interval_at(tokio::time::Instant::now() + WS_PING_INTERVAL, WS_PING_INTERVAL);
+        let pong_timeout = tokio::time::sleep(WS_PONG_TIMEOUT);
+        tokio::pin!(pong_timeout);
+        let mut awaiting_pong = false;
+
+        loop {
+            tokio::select! {
+                _ = user.notify.notified() => {
+                    if user.overflowed.load(Ordering::Relaxed) {
+                        let error = WsFrame::Error { message: "Event queue overflowed, disconnecting".to_string() };
+                        let _ = ws_tx.send(error.to_message()).await;
+                        break;
+                    }
+                    let mut send_failed = false;
+                    while let Some(message) = user.pop() {
+                        if let Err(e) = ws_tx.send(message).await {
+                            log::trace!("ws send error: {:?}", e);
+                            send_failed = true;
+                            break;
+                        }
+                    }
+                    if send_failed {
+                        break;
+                    }
+                    let dropped = user.dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        log::info!("ws user {} caught up, {} events were dropped", user_id, dropped);
+                    }
+                },
+                incoming = ws_rx.next() => {
+                    match incoming {
+                        Some(Ok(message)) if message.is_close() => break,
+                        Some(Ok(message)) => {
+                            if message.is_pong() {
+                                awaiting_pong = false;
+                            }
+                        },
+                        Some(Err(e)) => {
+                            log::trace!("ws receive error: {:?}", e);
+                            break;
+                        },
+                        None => break,
+                    }
+                },
+                _ = ping_interval.tick() => {
+                    if let Err(e) = ws_tx.send(Message::ping(Vec::new())).await {
+                        log::trace!("ws ping error: {:?}", e);
+                        break;
+                    }
+                    if let Err(e) = ws_tx.send(WsFrame::Ping.to_message()).await {
+                        log::trace!("ws ping error: {:?}", e);
+                        break;
+                    }
+                    if !awaiting_pong {
+                        awaiting_pong = true;
+                        pong_timeout.as_mut().reset(tokio::time::Instant::now() + WS_PONG_TIMEOUT);
+                    }
+                },
+                _ = &mut pong_timeout, if awaiting_pong => {
+                    log::debug!("ws user {} did not answer ping, disconnecting", user_id);
+                    break;
+                },
             }
         }
 
@@ -130,39 +521,488 @@ impl RpcServer {
     }
 }
 
-async fn handle_rpc_call(rpc_gate: Service<RpcGate>, p: HashMap<String, String>, bytes: Bytes) -> Result<impl Reply, Rejection> {
+/// Wraps `route` so every answered request is logged with its method, path, rpc `key`, status and duration.
+/// Unlike `warp::log` this also picks the `key` out of the query string.
+fn with_access_log<F, R>(route: F, level: Option<log::Level>) -> BoxedFilter<(Box<dyn Reply>,)> where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    let level = match level {
+        Some(level) => level,
+        None => return route.map(|r: R| Box::new(r) as Box<dyn Reply>).boxed(),
+    };
+
+    let key = warp::query::<HashMap<String, String>>()
+        .map(|mut query: HashMap<String, String>| query.remove("key"))
+        .or(warp::any().map(|| None))
+        .unify();
+
+    warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(key)
+        .and(route)
+        .map(move |start: Instant, method: warp::http::Method, path: warp::path::FullPath, key: Option<String>, reply: R| {
+            let response = reply.into_response();
+            let request_id = response.headers().get(REQUEST_ID_HEADER)
+                .and_then(|request_id| request_id.to_str().ok())
+                .map(|request_id| format!(" request_id={}", request_id))
+                .unwrap_or_default();
+            match key {
+                Some(key) => log::log!(level, "{} {} key={}{} -> {} in {:?}",
+                    method, path.as_str(), key, request_id, response.status().as_u16(), start.elapsed()),
+                None => log::log!(level, "{} {}{} -> {} in {:?}",
+                    method, path.as_str(), request_id, response.status().as_u16(), start.elapsed()),
+            }
+            Box::new(response) as Box<dyn Reply>
+        })
+        .boxed()
+}
+
+/// Rejects with `RateLimited` once the client's bucket is empty, passes everything without a limiter.
+fn with_rate_limit(rate_limiter: Option<Arc<RateLimiter>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and_then(move |addr: Option<SocketAddr>| {
+            let rate_limiter = rate_limiter.clone();
+            async move {
+                match (rate_limiter, addr) {
+                    (Some(rate_limiter), Some(addr)) if !rate_limiter.try_acquire(addr.ip()) => {
+                        log::debug!("Rate limit exceeded for {}", addr);
+                        Err(warp::reject::custom(RateLimited))
+                    },
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// `POST /api/rpc_batch`, behind the same rate limit as `rpc_call`, taking a token per call.
+fn rpc_batch_route(rpc_gate_filter: BoxedFilter<(Service<RpcGate>,)>, caller_auth: CallerAuth,
+                   rate_limiter: Option<Arc<RateLimiter>>, max_body_size: u64) -> BoxedFilter<(impl Reply,)> {
+    let batch_rate_limiter = rate_limiter.clone();
+    warp::post()
+        .and(warp::path!("api" / "rpc_batch"))
+        .and(with_rate_limit(rate_limiter))
+        .and(rpc_gate_filter)
+        .and(with_caller(caller_auth))
+        .and(warp::any().map(move || batch_rate_limiter.clone()))
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(max_body_size))
+        .and(warp::body::bytes())
+        .and_then(handle_rpc_batch)
+        .recover(handle_rate_limited)
+        .boxed()
+}
+
+/// How `with_caller` picks the level of a request, see `RpcServerConfig`.
+#[derive(Clone)]
+struct CallerAuth {
+    default_level: PermissionLevel,
+    tokens: Arc<HashMap<String, PermissionLevel>>,
+    trust_loopback: bool,
+}
+
+impl CallerAuth {
+    fn caller(&self, addr: Option<SocketAddr>, authorization: Option<String>) -> CmdCaller {
+        // Without the port, which changes between connections of the same client
+        let origin = addr.map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
+        if self.trust_loopback && addr.is_some_and(|addr| addr.ip().is_loopback()) {
+            return CmdCaller::local(&origin);
+        }
+        let token_level = authorization.as_deref()
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .and_then(|token| self.tokens.get(token.trim()));
+        match token_level {
+            Some(level) => CmdCaller::new(&origin, *level),
+            None => CmdCaller::new(&origin, self.default_level),
+        }
+    }
+}
+
+/// Caller of the commands the request runs, from its token and `RpcServerConfig`, never from
+/// the peer address alone.
+fn with_caller(auth: CallerAuth) -> impl Filter<Extract = (CmdCaller,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |addr: Option<SocketAddr>, authorization: Option<String>| auth.caller(addr, authorization))
+}
+
+async fn handle_rate_limited(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<RateLimited>().is_some() {
+        Ok(reply::with_status("Too many requests", warp::http::StatusCode::TOO_MANY_REQUESTS))
+    } else {
+        Err(rejection)
+    }
+}
+
+/// Wraps `route` so its responses are compressed according to the request's `Accept-Encoding`.
+fn with_compression<F, R>(route: F, enabled: bool) -> BoxedFilter<(Box<dyn Reply>,)> where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    let plain = route.clone().map(|r: R| Box::new(r) as Box<dyn Reply>);
+    if !enabled {
+        return plain.boxed();
+    }
+
+    let gzip = accepts_encoding("gzip")
+        .and(route.clone())
+        .with(warp::compression::gzip())
+        .map(|r| Box::new(r) as Box<dyn Reply>);
+    let deflate = accepts_encoding("deflate")
+        .and(route)
+        .with(warp::compression::deflate())
+        .map(|r| Box::new(r) as Box<dyn Reply>);
+
+    gzip.or(deflate).unify().or(plain).unify().boxed()
+}
+
+/// Passes only if the request's `Accept-Encoding` header lists `encoding` with a non-zero quality.
+fn accepts_encoding(encoding: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept-encoding")
+        .and_then(move |header: Option<String>| async move {
+            let accepted = header.is_some_and(|header| {
+                header.split(',').any(|item| {
+                    let mut parts = item.split(';').map(|part| part.trim());
+                    let name = parts.next().unwrap_or("");
+                    let rejected = parts.any(|param| param == "q=0" || param == "q=0.0");
+                    name.eq_ignore_ascii_case(encoding) && !rejected
+                })
+            });
+            if accepted {
+                Ok(())
+            } else {
+                Err(warp::reject())
+            }
+        })
+        .untuple_one()
+}
+
+/// The request id comes from the `request_id` query param or the `X-Request-Id` header
+/// and is returned in the `X-Request-Id` response header.
+///
+/// An optional `version` query param selects a versioned handler, the version that served
+/// the call is returned in the `X-Rpc-Version` response header.
+async fn handle_rpc_call(rpc_gate: Service<RpcGate>, caller: CmdCaller, p: HashMap<String, String>, request_id: Option<String>, bytes: Bytes) -> Result<impl Reply, Rejection> {
+    let request_id = p.get("request_id").cloned()
+        .or(request_id)
+        .unwrap_or_else(generate_request_id);
+    let version = match p.get("version").map(|version| version.parse::<u32>()).transpose() {
+        Ok(version) => version,
+        Err(_) => {
+            let response = reply::with_header(String::from("Invalid \"version\" param in query."), "Content-Type", "application/json");
+            let response = reply::with_header(response, REQUEST_ID_HEADER, request_id);
+            return Ok(reply::with_status(response, warp::http::StatusCode::BAD_REQUEST).into_response());
+        }
+    };
     match p.get("key") {
         Some(key) => {
-            let request = String::from_utf8(bytes.to_vec()).unwrap();
+            let request = match String::from_utf8(bytes.to_vec()) {
+                Ok(request) => request,
+                Err(_) => {
+                    let response = reply::with_header(String::from("Request body is not valid UTF-8."), "Content-Type", "application/json");
+                    let response = reply::with_header(response, REQUEST_ID_HEADER, request_id);
+                    return Ok(reply::with_status(response, warp::http::StatusCode::BAD_REQUEST).into_response());
+                }
+            };
             let key = key.clone();
+            // Blocking threads don't inherit the current span, so it is carried over explicitly
+            #[cfg(feature = "tracing")]
+            let span = tracing::debug_span!("http_rpc_call", key = key.as_str(), request_id = request_id.as_str());
+            let call_request_id = request_id.clone();
             let response = tokio::task::spawn_blocking(move || {
-                rpc_gate.call_raw(&key, request.as_str())
+                #[cfg(feature = "tracing")]
+                let _span = span.entered();
+                let served_version = rpc_gate.resolve_version(&key, version);
+                let response = with_request_id(Some(call_request_id), || with_cmd_caller(Some(caller), || {
+                    rpc_gate.call_raw_versioned(&key, version, request.as_str())
+                }));
+                (response, served_version)
             }).await.unwrap();
+            let (response, served_version) = response;
             let response = reply::with_header(response, "Content-Type", "application/json");
-            Ok(reply::with_status(response, warp::http::StatusCode::OK))
+            let response = reply::with_header(response, REQUEST_ID_HEADER, request_id);
+            let mut response = reply::with_status(response, warp::http::StatusCode::OK).into_response();
+            if let Some(served_version) = served_version {
+                response.headers_mut().insert(RPC_VERSION_HEADER, served_version.into());
+            }
+            Ok(response)
+        },
+        None => {
+            let response = reply::with_header(String::from("No \"key\" param in query."), "Content-Type", "application/json");
+            let response = reply::with_header(response, REQUEST_ID_HEADER, request_id);
+            Ok(reply::with_status(response, warp::http::StatusCode::BAD_REQUEST).into_response())
         },
-        None => Ok(reply::with_status(
-            reply::with_header(String::from("No \"key\" param in query."), "Content-Type", "application/json"),
-            warp::http::StatusCode::BAD_REQUEST)),
     }
 }
 
-async fn handle_get_file(rpc_gate: Service<RpcGate>, tail: Tail) -> Result<impl Reply, Rejection> {
-    let key_value: Vec<&str> = tail.as_str().splitn(2, "/").collect();
-    let key = key_value[0];
-    let path = key_value[1];
-    let file_bytes =  rpc_gate.get_file(key, path);
-    match file_bytes {
-        Ok(file_bytes) => {
-            let response = warp::http::Response::builder()
-                .body(file_bytes)
-                .unwrap();
-            Ok(reply::with_status(response, warp::http::StatusCode::OK))
-        },
+async fn handle_rpc_batch(rpc_gate: Service<RpcGate>, caller: CmdCaller, rate_limiter: Option<Arc<RateLimiter>>,
+                          addr: Option<SocketAddr>, bytes: Bytes) -> Result<impl Reply, Rejection> {
+    let calls: Vec<BatchCall> = match serde_json::from_slice(&bytes) {
+        Ok(calls) => calls,
         Err(e) => {
-            log::error!("Error: {:?}", e);
-            Err(warp::reject::not_found())
+            return Ok(reply::with_status(
+                reply::with_header(format!("Invalid batch request: {}", e), "Content-Type", "text/plain"),
+                warp::http::StatusCode::BAD_REQUEST));
+        }
+    };
+    // `with_rate_limit` took the token of the first call
+    if let (Some(rate_limiter), Some(addr)) = (rate_limiter, addr) {
+        let more_calls = calls.len().saturating_sub(1).min(u32::MAX as usize) as u32;
+        if more_calls > 0 && !rate_limiter.try_acquire_n(addr.ip(), more_calls) {
+            log::debug!("Rate limit exceeded for {} by a batch of {} calls", addr, calls.len());
+            return Err(warp::reject::custom(RateLimited));
         }
     }
+
+    // Each call runs on its own blocking thread, results are collected in request order
+    let pending = calls.into_iter().map(|call| {
+        let rpc_gate = rpc_gate.clone();
+        let caller = caller.clone();
+        let request_id = call.request_id.clone().unwrap_or_else(generate_request_id);
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("http_rpc_batch_call", key = call.key.as_str(), request_id = request_id.as_str());
+        tokio::task::spawn_blocking(move || {
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+            with_request_id(Some(request_id), || with_cmd_caller(Some(caller), || {
+                rpc_gate.call_raw_versioned(&call.key, call.version, &call.data.to_string())
+            }))
+        })
+    });
+
+    let results: Vec<BatchCallResult> = futures::future::join_all(pending).await
+        .into_iter()
+        .map(|response| match response {
+            Ok(response) => match serde_json::from_str(&response) {
+                Ok(value) => BatchCallResult::Ok(value),
+                Err(e) => BatchCallResult::Error(format!("Invalid handler response: {}", e)),
+            },
+            Err(e) => BatchCallResult::Error(format!("Handler failed: {}", e)),
+        })
+        .collect();
+
+    let response = serde_json::to_string(&results).unwrap();
+    let response = reply::with_header(response, "Content-Type", "application/json");
+    Ok(reply::with_status(response, warp::http::StatusCode::OK))
+}
+
+/// Blocking reader over body chunks sent by the async side of `handle_upload`.
+struct UploadReader {
+    chunks: tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>>,
+    current: Bytes,
+}
+
+impl std::io::Read for UploadReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.current.len());
+        buf[..len].copy_from_slice(&self.current[..len]);
+        self.current.advance(len);
+        Ok(len)
+    }
 }
 
+/// Streams the body into the `put_file` handler of the key, `/upload/{key}/{path}`.
+/// The handler runs on a blocking thread while the body is still arriving.
+async fn handle_upload<S, B>(rpc_gate: Service<RpcGate>, tail: Tail, max_upload_size: u64, body: S) -> Result<impl Reply, Rejection> where
+    S: futures::Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf + Send,
+{
+    let (key, path) = match tail.as_str().split_once('/') {
+        Some((key, path)) => (key.to_string(), path.to_string()),
+        None => (tail.as_str().to_string(), String::new()),
+    };
+    let (tx, rx) = tokio::sync::mpsc::channel(8);
+    let too_large = Arc::new(AtomicBool::new(false));
+
+    let too_large_copy = too_large.clone();
+    let pump = async move {
+        futures::pin_mut!(body);
+        let mut total = 0u64;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk
+                .map(|mut chunk| chunk.copy_to_bytes(chunk.remaining()))
+                .map_err(std::io::Error::other);
+            if let Ok(chunk) = &chunk {
+                total += chunk.len() as u64;
+                if total > max_upload_size {
+                    too_large_copy.store(true, Ordering::Relaxed);
+                    let _ = tx.send(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Upload is too large"))).await;
+                    break;
+                }
+            }
+            let failed = chunk.is_err();
+            // The handler may stop reading early, then the rest of the body is ignored
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    };
+    let handler = tokio::task::spawn_blocking(move || {
+        let mut reader = UploadReader {
+            chunks: rx,
+            current: Bytes::new(),
+        };
+        rpc_gate.put_file(&key, &path, &mut reader)
+    });
+    let (_, result) = tokio::join!(pump, handler);
+
+    let result = result.unwrap_or_else(|err| Err(std::io::Error::other(format!("Upload handler failed: {}", err))));
+    let (status, message) = match result {
+        Ok(()) => (warp::http::StatusCode::OK, String::new()),
+        Err(_) if too_large.load(Ordering::Relaxed) => (warp::http::StatusCode::PAYLOAD_TOO_LARGE, "Upload is too large.".to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (warp::http::StatusCode::NOT_FOUND, err.to_string()),
+        Err(err) => {
+            log::error!("Upload failed: {}", err);
+            (warp::http::StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        },
+    };
+    Ok(reply::with_status(message, status))
+}
+
+async fn handle_get_file(rpc_gate: Service<RpcGate>, tail: Tail) -> Result<impl Reply, Rejection> {
+    // Answer errors directly instead of rejecting, so the handler doesn't run again for other routes
+    let (key, path) = match tail.as_str().split_once('/') {
+        Some((key, path)) if !key.is_empty() => (key, path),
+        _ => return Ok(reply::with_status(Vec::new(), warp::http::StatusCode::BAD_REQUEST)),
+    };
+    // Clients percent-encode the key and the path segments, see `RpcTcpClient::get_file`
+    let key = percent_decode_str(key).decode_utf8_lossy();
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    match rpc_gate.get_file(&key, &path) {
+        Ok(file_bytes) => Ok(reply::with_status(file_bytes, warp::http::StatusCode::OK)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            log::debug!("File '{}' of '{}' not found: {}", path, key, err);
+            Ok(reply::with_status(Vec::new(), warp::http::StatusCode::NOT_FOUND))
+        },
+        Err(err) => {
+            log::error!("Can't get file '{}' of '{}': {}", path, key, err);
+            Ok(reply::with_status(Vec::new(), warp::http::StatusCode::INTERNAL_SERVER_ERROR))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use warp::Filter;
+
+    use amina_core::cmd_manager::PermissionLevel;
+    use amina_core::rpc::{Rpc, RpcGate};
+    use amina_core::service::Context;
+
+    use crate::rate_limit::{RateLimit, RateLimiter};
+    use crate::rpc_web_gate::{handle_get_file, handle_rate_limited, rpc_batch_route, with_rate_limit, CallerAuth, WsFrame, WS_ENCODING_MSGPACK};
+
+    fn rate_limiter(burst: u32) -> Option<Arc<RateLimiter>> {
+        Some(Arc::new(RateLimiter::new(RateLimit {
+            requests_per_second: 0.001,
+            burst,
+        })))
+    }
+
+    fn client_addr() -> SocketAddr {
+        "10.0.0.1:5000".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_status() {
+        let route = with_rate_limit(rate_limiter(1))
+            .map(|| "ok")
+            .recover(handle_rate_limited);
+
+        let response = warp::test::request().remote_addr(client_addr()).reply(&route).await;
+        assert_eq!(response.status(), 200);
+        let response = warp::test::request().remote_addr(client_addr()).reply(&route).await;
+        assert_eq!(response.status(), 429);
+        let response = warp::test::request().remote_addr("10.0.0.2:5000".parse().unwrap()).reply(&route).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_get_file_status() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        let rpc = context.get_service::<Rpc>();
+        rpc.add_get_file_handler("media", |path| match path {
+            "covers/a b.png" => Ok(b"png".to_vec()),
+            "broken" => Err(std::io::Error::other("disk failure")),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such file")),
+        });
+        let rpc_gate = context.get_service::<RpcGate>();
+        let route = warp::path("get_file")
+            .and(warp::any().map(move || rpc_gate.clone()))
+            .and(warp::path::tail())
+            .and_then(handle_get_file);
+        let get = |path: &str| warp::test::request().path(path).reply(&route);
+
+        let response = get("/get_file/media/covers/a%20b.png").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), b"png");
+        assert_eq!(get("/get_file/media/missing.png").await.status(), 404);
+        assert_eq!(get("/get_file/other/a.png").await.status(), 404);
+        assert_eq!(get("/get_file/media").await.status(), 400);
+        assert_eq!(get("/get_file/media/broken").await.status(), 500);
+    }
+
+    #[tokio::test]
+    async fn test_batch_rate_limit() {
+        let context = Context::new();
+        context.init_service::<Rpc>();
+        context.get_service::<Rpc>().on_generic_call_fn("test.double", |value: &i64| value * 2);
+        let rpc_gate = context.get_service::<RpcGate>();
+        let caller_auth = CallerAuth {
+            default_level: PermissionLevel::User,
+            tokens: Arc::new(HashMap::new()),
+            trust_loopback: false,
+        };
+        let route = rpc_batch_route(warp::any().map(move || rpc_gate.clone()).boxed(), caller_auth, rate_limiter(3), 1024);
+        let batch = |body: &str| warp::test::request()
+            .method("POST")
+            .path("/api/rpc_batch")
+            .remote_addr(client_addr())
+            .body(body);
+
+        let response = batch("[{\"key\":\"test.double\",\"data\":2},{\"key\":\"test.double\",\"data\":3}]").reply(&route).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), b"[{\"ok\":4},{\"ok\":6}]");
+        // One token left, not enough for two calls
+        let response = batch("[{\"key\":\"test.double\",\"data\":2},{\"key\":\"test.double\",\"data\":3}]").reply(&route).await;
+        assert_eq!(response.status(), 429);
+    }
+
+    #[test]
+    fn test_binary_event_frame() {
+        let binary_keys: HashSet<String> = vec!["audio.levels".to_string()].into_iter().collect();
+
+        let message = WsFrame::event_message("audio.levels", "{\"left\":0.5,\"right\":-2}", Some("7".to_string()), &binary_keys);
+        assert!(message.is_binary());
+        let bytes = message.as_bytes();
+        assert_eq!(bytes[0], WS_ENCODING_MSGPACK);
+        // Encoded as a map, so clients see the same field names as in text frames
+        let value: serde_json::Value = rmp_serde::from_slice(&bytes[1..]).unwrap();
+        assert_eq!(value, serde_json::json!({
+            "type": "event",
+            "key": "audio.levels",
+            "data": {"left": 0.5, "right": -2},
+            "request_id": "7",
+        }));
+        assert_eq!(rmp_serde::from_slice::<WsFrame>(&bytes[1..]).unwrap(),
+            WsFrame::event("audio.levels", "{\"left\":0.5,\"right\":-2}", Some("7".to_string())));
+
+        let message = WsFrame::event_message("player.state", "\"playing\"", None, &binary_keys);
+        assert!(message.is_text());
+        assert_eq!(message.to_str().unwrap(), "{\"type\":\"event\",\"key\":\"player.state\",\"data\":\"playing\"}");
+    }
+}